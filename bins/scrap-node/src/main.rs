@@ -1,5 +1,7 @@
 use clap::Parser;
-use scrap_linux_udp::{load_node_config, run_node, NodeConfig};
+use scrap_linux_udp::{
+    load_node_config, run_node, NodeConfig, StoreForwardConfig, DEFAULT_QUEUE_PATH,
+};
 
 #[derive(Parser, Debug)]
 #[command(name = "scrap-node", about = "SCRAP edge node (Linux UDP shim)")]
@@ -19,6 +21,10 @@ struct Args {
     #[arg(long, default_value = "inventory/routes.json")]
     routes: String,
 
+    /// Contact plan in the routes file schema
+    #[arg(long)]
+    contact_plan: Option<String>,
+
     #[arg(long, default_value = "demo/runtime/replay_cache.json")]
     replay_cache: String,
 
@@ -30,6 +36,21 @@ struct Args {
 
     #[arg(long, action = clap::ArgAction::SetTrue)]
     allow_mock_signatures: bool,
+
+    /// udp, 6lowpan or serial
+    #[arg(long, default_value = "udp")]
+    transport: String,
+
+    /// 6LoWPAN interface (lowpan0) or serial device (/dev/ttyACM0[@baud])
+    #[arg(long)]
+    interface: Option<String>,
+
+    /// Queue traffic for unreachable next hops until they are heard again
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    store_forward: bool,
+
+    #[arg(long, default_value = DEFAULT_QUEUE_PATH)]
+    queue: String,
 }
 
 fn main() {
@@ -55,10 +76,17 @@ fn main() {
             bind: args.bind,
             port: args.port,
             routes_path: args.routes,
+            contact_plan_path: args.contact_plan,
             commander_pubkey: args.commander_pubkey,
             replay_cache_path: args.replay_cache,
             revoked_path: args.revoked,
             allow_mock_signatures: args.allow_mock_signatures,
+            transport: args.transport,
+            interface: args.interface,
+            store_forward: args.store_forward.then(|| StoreForwardConfig {
+                queue_path: Some(args.queue),
+                ..StoreForwardConfig::default()
+            }),
         }
     };
 
@@ -0,0 +1,687 @@
+//! Fixed-capacity envelope types for targets without an allocator. Fields are
+//! sized by the crate's `MAX_*` limits and envelopes encode into a caller's
+//! `&mut [u8]`; the wire format is the same as the owned types.
+
+use crate::{
+    borrow_bytes, borrow_string, decode_envelope_ref, DecodeError, EncodeError, EnvelopeRef,
+    Routes, KEY_ARGS, KEY_AUDIENCE, KEY_CAPABILITY, KEY_COMMAND, KEY_COMMANDER, KEY_DETAILS,
+    KEY_DST, KEY_EXPIRES_AT, KEY_HB_TIMESTAMP, KEY_HOP_LIMIT, KEY_ISSUED_AT, KEY_MSG_TYPE,
+    KEY_OUTPUT_DIGEST, KEY_PAYLOAD, KEY_REASON, KEY_REPLY_TO, KEY_SRC, KEY_STATUS, KEY_SUBJECT,
+    KEY_TELEMETRY, KEY_TEL_DURATION_MS, KEY_TEL_NODE_ID, KEY_TOKEN, KEY_TOKEN_ID, KEY_TRACE_ID,
+    KEY_VERSION, MAX_ARGS_LEN, MAX_COMMAND_LEN, MAX_DETAILS, MAX_DETAIL_LEN, MAX_NODE_ID_LEN,
+    MAX_OUTPUT_DIGEST_LEN, MAX_REASON_LEN, MSG_HEARTBEAT, MSG_TASK_REJECTED, MSG_TASK_REQUEST,
+    MSG_TASK_RESULT, TOKEN_ID_LEN, TRACE_ID_LEN, VERSION,
+};
+use heapless::{String, Vec};
+use minicbor::decode::Decoder;
+use minicbor::encode::write::{Cursor, EndOfSlice, Write};
+use minicbor::encode::{self, Encoder};
+
+/// Longest next-hop address a route may hold, e.g. `[fe80::1]:40000`.
+pub const MAX_NEXT_HOP_LEN: usize = 48;
+pub const MAX_ROUTES: usize = 16;
+
+pub type NodeId = String<MAX_NODE_ID_LEN>;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RouteEntry {
+    pub dst: NodeId,
+    pub next_hop: String<MAX_NEXT_HOP_LEN>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct RouteTable {
+    pub entries: Vec<RouteEntry, MAX_ROUTES>,
+}
+
+impl RouteTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, dst: &str, next_hop: &str) -> Result<(), DecodeError> {
+        let entry = RouteEntry {
+            dst: fixed_str(dst)?,
+            next_hop: fixed_str(next_hop)?,
+        };
+        self.entries
+            .push(entry)
+            .map_err(|_| DecodeError::LengthExceeded("routes"))
+    }
+
+    pub fn next_hop(&self, dst: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|entry| entry.dst == dst)
+            .map(|entry| entry.next_hop.as_str())
+    }
+}
+
+impl Routes for RouteTable {
+    fn next_hop(&self, dst: &str) -> Option<&str> {
+        RouteTable::next_hop(self, dst)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Token {
+    pub token_id: Vec<u8, TOKEN_ID_LEN>,
+    pub subject: NodeId,
+    pub audience: NodeId,
+    pub capability: String<MAX_COMMAND_LEN>,
+    pub issued_at: u64,
+    pub expires_at: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TaskRequest {
+    pub token: Token,
+    pub command: String<MAX_COMMAND_LEN>,
+    pub args: String<MAX_ARGS_LEN>,
+    pub reply_to: NodeId,
+    pub commander_pubkey: NodeId,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Telemetry {
+    pub duration_ms: u32,
+    pub node_id: NodeId,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TaskResult {
+    pub status: u8,
+    pub output_digest: Vec<u8, MAX_OUTPUT_DIGEST_LEN>,
+    pub telemetry: Telemetry,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TaskRejected {
+    pub reason: String<MAX_REASON_LEN>,
+    pub details: Vec<String<MAX_DETAIL_LEN>, MAX_DETAILS>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Heartbeat {
+    pub timestamp: u64,
+}
+
+// Nothing to box into without a heap; the largest variant is bounded by
+// MAX_DETAILS * MAX_DETAIL_LEN.
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Payload {
+    TaskRequest(TaskRequest),
+    TaskResult(TaskResult),
+    TaskRejected(TaskRejected),
+    Heartbeat(Heartbeat),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Envelope {
+    pub version: u8,
+    pub msg_type: u8,
+    pub trace_id: Vec<u8, TRACE_ID_LEN>,
+    pub src: NodeId,
+    pub dst: NodeId,
+    pub hop_limit: u8,
+    pub payload: Payload,
+}
+
+impl From<encode::Error<EndOfSlice>> for EncodeError {
+    fn from(_: encode::Error<EndOfSlice>) -> Self {
+        EncodeError::BufferFull
+    }
+}
+
+/// Copies `value` into a fixed-capacity string, failing if it does not fit.
+pub fn fixed_str<const N: usize>(value: &str) -> Result<String<N>, DecodeError> {
+    let mut out = String::new();
+    out.push_str(value)
+        .map_err(|_| DecodeError::LengthExceeded("string"))?;
+    Ok(out)
+}
+
+/// Copies `value` into a fixed-capacity buffer, failing if it does not fit.
+pub fn fixed_bytes<const N: usize>(value: &[u8]) -> Result<Vec<u8, N>, DecodeError> {
+    Vec::from_slice(value).map_err(|_| DecodeError::LengthExceeded("bytes"))
+}
+
+fn decode_string<const N: usize>(dec: &mut Decoder<'_>) -> Result<String<N>, DecodeError> {
+    fixed_str(borrow_string(dec, N)?)
+}
+
+fn decode_bytes<const N: usize>(dec: &mut Decoder<'_>) -> Result<Vec<u8, N>, DecodeError> {
+    fixed_bytes(borrow_bytes(dec, N)?)
+}
+
+/// Encodes `env` into the front of `out` and returns the encoded length.
+pub fn encode_envelope(env: &Envelope, out: &mut [u8]) -> Result<usize, EncodeError> {
+    let mut enc = Encoder::new(Cursor::new(out));
+    enc.map(7)?;
+    enc.u8(KEY_VERSION)?.u8(env.version)?;
+    enc.u8(KEY_MSG_TYPE)?.u8(env.msg_type)?;
+    enc.u8(KEY_TRACE_ID)?.bytes(&env.trace_id)?;
+    enc.u8(KEY_SRC)?.str(&env.src)?;
+    enc.u8(KEY_DST)?.str(&env.dst)?;
+    enc.u8(KEY_HOP_LIMIT)?.u8(env.hop_limit)?;
+    enc.u8(KEY_PAYLOAD)?;
+
+    match &env.payload {
+        Payload::TaskRequest(task) => encode_task_request(&mut enc, task)?,
+        Payload::TaskResult(result) => encode_task_result(&mut enc, result)?,
+        Payload::TaskRejected(rejected) => encode_task_rejected(&mut enc, rejected)?,
+        Payload::Heartbeat(heartbeat) => encode_heartbeat(&mut enc, heartbeat)?,
+    }
+
+    Ok(enc.into_writer().position())
+}
+
+pub fn decode_envelope(data: &[u8]) -> Result<Envelope, DecodeError> {
+    Envelope::from_ref(&decode_envelope_ref(data)?)
+}
+
+impl Envelope {
+    pub fn from_ref(env: &EnvelopeRef<'_>) -> Result<Self, DecodeError> {
+        Ok(Envelope {
+            version: env.version,
+            msg_type: env.msg_type,
+            trace_id: fixed_bytes(env.trace_id)?,
+            src: fixed_str(env.src)?,
+            dst: fixed_str(env.dst)?,
+            hop_limit: env.hop_limit,
+            payload: decode_payload(env.msg_type, &mut Decoder::new(env.payload))?,
+        })
+    }
+}
+
+fn decode_payload(msg_type: u8, dec: &mut Decoder<'_>) -> Result<Payload, DecodeError> {
+    Ok(match msg_type {
+        MSG_TASK_REQUEST => Payload::TaskRequest(decode_task_request(dec)?),
+        MSG_TASK_RESULT => Payload::TaskResult(decode_task_result(dec)?),
+        MSG_TASK_REJECTED => Payload::TaskRejected(decode_task_rejected(dec)?),
+        MSG_HEARTBEAT => Payload::Heartbeat(decode_heartbeat(dec)?),
+        _ => return Err(DecodeError::InvalidField("msg_type")),
+    })
+}
+
+fn encode_task_request<W: Write>(
+    enc: &mut Encoder<W>,
+    task: &TaskRequest,
+) -> Result<(), encode::Error<W::Error>> {
+    enc.map(5)?;
+    enc.u8(KEY_TOKEN)?;
+    encode_token(enc, &task.token)?;
+    enc.u8(KEY_COMMAND)?.str(&task.command)?;
+    enc.u8(KEY_ARGS)?.str(&task.args)?;
+    enc.u8(KEY_REPLY_TO)?.str(&task.reply_to)?;
+    enc.u8(KEY_COMMANDER)?.str(&task.commander_pubkey)?;
+    Ok(())
+}
+
+fn decode_task_request(dec: &mut Decoder<'_>) -> Result<TaskRequest, DecodeError> {
+    let len = dec.map()?.unwrap_or(0);
+    let mut token = None;
+    let mut command = None;
+    let mut args = None;
+    let mut reply_to = None;
+    let mut commander = None;
+
+    for _ in 0..len {
+        match dec.u8()? {
+            KEY_TOKEN => token = Some(decode_token(dec)?),
+            KEY_COMMAND => command = Some(decode_string(dec)?),
+            KEY_ARGS => args = Some(decode_string(dec)?),
+            KEY_REPLY_TO => reply_to = Some(decode_string(dec)?),
+            KEY_COMMANDER => commander = Some(decode_string(dec)?),
+            _ => dec.skip()?,
+        }
+    }
+
+    Ok(TaskRequest {
+        token: token.ok_or(DecodeError::InvalidField("token"))?,
+        command: command.ok_or(DecodeError::InvalidField("command"))?,
+        args: args.ok_or(DecodeError::InvalidField("args"))?,
+        reply_to: reply_to.ok_or(DecodeError::InvalidField("reply_to"))?,
+        commander_pubkey: commander.ok_or(DecodeError::InvalidField("commander_pubkey"))?,
+    })
+}
+
+fn encode_task_result<W: Write>(
+    enc: &mut Encoder<W>,
+    result: &TaskResult,
+) -> Result<(), encode::Error<W::Error>> {
+    enc.map(3)?;
+    enc.u8(KEY_STATUS)?.u8(result.status)?;
+    enc.u8(KEY_OUTPUT_DIGEST)?.bytes(&result.output_digest)?;
+    enc.u8(KEY_TELEMETRY)?;
+    enc.map(2)?;
+    enc.u8(KEY_TEL_DURATION_MS)?
+        .u32(result.telemetry.duration_ms)?;
+    enc.u8(KEY_TEL_NODE_ID)?.str(&result.telemetry.node_id)?;
+    Ok(())
+}
+
+fn decode_task_result(dec: &mut Decoder<'_>) -> Result<TaskResult, DecodeError> {
+    let len = dec.map()?.unwrap_or(0);
+    let mut status = None;
+    let mut output = None;
+    let mut telemetry = None;
+
+    for _ in 0..len {
+        match dec.u8()? {
+            KEY_STATUS => status = Some(dec.u8()?),
+            KEY_OUTPUT_DIGEST => output = Some(decode_bytes(dec)?),
+            KEY_TELEMETRY => telemetry = Some(decode_telemetry(dec)?),
+            _ => dec.skip()?,
+        }
+    }
+
+    Ok(TaskResult {
+        status: status.ok_or(DecodeError::InvalidField("status"))?,
+        output_digest: output.ok_or(DecodeError::InvalidField("output_digest"))?,
+        telemetry: telemetry.ok_or(DecodeError::InvalidField("telemetry"))?,
+    })
+}
+
+fn decode_telemetry(dec: &mut Decoder<'_>) -> Result<Telemetry, DecodeError> {
+    let len = dec.map()?.unwrap_or(0);
+    let mut duration_ms = None;
+    let mut node_id = None;
+
+    for _ in 0..len {
+        match dec.u8()? {
+            KEY_TEL_DURATION_MS => duration_ms = Some(dec.u32()?),
+            KEY_TEL_NODE_ID => node_id = Some(decode_string(dec)?),
+            _ => dec.skip()?,
+        }
+    }
+
+    Ok(Telemetry {
+        duration_ms: duration_ms.ok_or(DecodeError::InvalidField("duration_ms"))?,
+        node_id: node_id.ok_or(DecodeError::InvalidField("node_id"))?,
+    })
+}
+
+fn encode_task_rejected<W: Write>(
+    enc: &mut Encoder<W>,
+    rejected: &TaskRejected,
+) -> Result<(), encode::Error<W::Error>> {
+    enc.map(2)?;
+    enc.u8(KEY_REASON)?.str(&rejected.reason)?;
+    enc.u8(KEY_DETAILS)?.array(rejected.details.len() as u64)?;
+    for item in &rejected.details {
+        enc.str(item)?;
+    }
+    Ok(())
+}
+
+fn decode_task_rejected(dec: &mut Decoder<'_>) -> Result<TaskRejected, DecodeError> {
+    let len = dec.map()?.unwrap_or(0);
+    let mut reason = None;
+    let mut details = Vec::new();
+
+    for _ in 0..len {
+        match dec.u8()? {
+            KEY_REASON => reason = Some(decode_string(dec)?),
+            KEY_DETAILS => {
+                // Like the owned decoder, keeps the first MAX_DETAILS.
+                let arr_len = dec.array()?.unwrap_or(0);
+                let capped = core::cmp::min(arr_len as usize, MAX_DETAILS);
+                for _ in 0..capped {
+                    details
+                        .push(decode_string(dec)?)
+                        .map_err(|_| DecodeError::LengthExceeded("details"))?;
+                }
+                for _ in capped..arr_len as usize {
+                    dec.skip()?;
+                }
+            }
+            _ => dec.skip()?,
+        }
+    }
+
+    Ok(TaskRejected {
+        reason: match reason {
+            Some(reason) => reason,
+            None => fixed_str("validation_failed")?,
+        },
+        details,
+    })
+}
+
+fn encode_heartbeat<W: Write>(
+    enc: &mut Encoder<W>,
+    heartbeat: &Heartbeat,
+) -> Result<(), encode::Error<W::Error>> {
+    enc.map(1)?;
+    enc.u8(KEY_HB_TIMESTAMP)?.u64(heartbeat.timestamp)?;
+    Ok(())
+}
+
+fn decode_heartbeat(dec: &mut Decoder<'_>) -> Result<Heartbeat, DecodeError> {
+    let len = dec.map()?.unwrap_or(0);
+    let mut timestamp = None;
+
+    for _ in 0..len {
+        match dec.u8()? {
+            KEY_HB_TIMESTAMP => timestamp = Some(dec.u64()?),
+            _ => dec.skip()?,
+        }
+    }
+
+    Ok(Heartbeat {
+        timestamp: timestamp.ok_or(DecodeError::InvalidField("timestamp"))?,
+    })
+}
+
+fn encode_token<W: Write>(
+    enc: &mut Encoder<W>,
+    token: &Token,
+) -> Result<(), encode::Error<W::Error>> {
+    enc.map(6)?;
+    enc.u8(KEY_TOKEN_ID)?.bytes(&token.token_id)?;
+    enc.u8(KEY_SUBJECT)?.str(&token.subject)?;
+    enc.u8(KEY_AUDIENCE)?.str(&token.audience)?;
+    enc.u8(KEY_CAPABILITY)?.str(&token.capability)?;
+    enc.u8(KEY_ISSUED_AT)?.u64(token.issued_at)?;
+    enc.u8(KEY_EXPIRES_AT)?.u64(token.expires_at)?;
+    Ok(())
+}
+
+fn decode_token(dec: &mut Decoder<'_>) -> Result<Token, DecodeError> {
+    let len = dec.map()?.unwrap_or(0);
+    let mut token_id = None;
+    let mut subject = None;
+    let mut audience = None;
+    let mut capability = None;
+    let mut issued_at = None;
+    let mut expires_at = None;
+
+    for _ in 0..len {
+        match dec.u8()? {
+            KEY_TOKEN_ID => token_id = Some(decode_bytes(dec)?),
+            KEY_SUBJECT => subject = Some(decode_string(dec)?),
+            KEY_AUDIENCE => audience = Some(decode_string(dec)?),
+            KEY_CAPABILITY => capability = Some(decode_string(dec)?),
+            KEY_ISSUED_AT => issued_at = Some(dec.u64()?),
+            KEY_EXPIRES_AT => expires_at = Some(dec.u64()?),
+            _ => dec.skip()?,
+        }
+    }
+
+    Ok(Token {
+        token_id: token_id.ok_or(DecodeError::InvalidField("token_id"))?,
+        subject: subject.ok_or(DecodeError::InvalidField("subject"))?,
+        audience: audience.ok_or(DecodeError::InvalidField("audience"))?,
+        capability: capability.ok_or(DecodeError::InvalidField("capability"))?,
+        issued_at: issued_at.ok_or(DecodeError::InvalidField("issued_at"))?,
+        expires_at: expires_at.ok_or(DecodeError::InvalidField("expires_at"))?,
+    })
+}
+
+pub fn build_task_result(
+    trace_id: Vec<u8, TRACE_ID_LEN>,
+    src: NodeId,
+    dst: NodeId,
+    hop_limit: u8,
+    result: TaskResult,
+) -> Envelope {
+    Envelope {
+        version: VERSION,
+        msg_type: MSG_TASK_RESULT,
+        trace_id,
+        src,
+        dst,
+        hop_limit,
+        payload: Payload::TaskResult(result),
+    }
+}
+
+pub fn build_task_rejected(
+    trace_id: Vec<u8, TRACE_ID_LEN>,
+    src: NodeId,
+    dst: NodeId,
+    hop_limit: u8,
+    reason: String<MAX_REASON_LEN>,
+    details: Vec<String<MAX_DETAIL_LEN>, MAX_DETAILS>,
+) -> Envelope {
+    Envelope {
+        version: VERSION,
+        msg_type: MSG_TASK_REJECTED,
+        trace_id,
+        src,
+        dst,
+        hop_limit,
+        payload: Payload::TaskRejected(TaskRejected { reason, details }),
+    }
+}
+
+pub fn build_task_request(
+    trace_id: Vec<u8, TRACE_ID_LEN>,
+    src: NodeId,
+    dst: NodeId,
+    hop_limit: u8,
+    task: TaskRequest,
+) -> Envelope {
+    Envelope {
+        version: VERSION,
+        msg_type: MSG_TASK_REQUEST,
+        trace_id,
+        src,
+        dst,
+        hop_limit,
+        payload: Payload::TaskRequest(task),
+    }
+}
+
+/// A link-liveness beacon for the next hop, with no destination or hops.
+pub fn build_heartbeat(src: NodeId, timestamp: u64) -> Envelope {
+    Envelope {
+        version: VERSION,
+        msg_type: MSG_HEARTBEAT,
+        trace_id: fixed_bytes(&[0; TRACE_ID_LEN]).unwrap_or_default(),
+        src,
+        dst: NodeId::new(),
+        hop_limit: 0,
+        payload: Payload::Heartbeat(Heartbeat { timestamp }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trace_id() -> Vec<u8, TRACE_ID_LEN> {
+        fixed_bytes(&[7; TRACE_ID_LEN]).unwrap()
+    }
+
+    fn node(id: &str) -> NodeId {
+        fixed_str(id).unwrap()
+    }
+
+    fn envelopes() -> [Envelope; 4] {
+        let task = TaskRequest {
+            token: Token {
+                token_id: fixed_bytes(&[1; TOKEN_ID_LEN]).unwrap(),
+                subject: node("cmd"),
+                audience: node("exec"),
+                capability: fixed_str("demo.hash").unwrap(),
+                issued_at: 100,
+                expires_at: 4_000_000_000,
+            },
+            command: fixed_str("demo.hash").unwrap(),
+            args: fixed_str("7").unwrap(),
+            reply_to: node("cmd"),
+            commander_pubkey: node("cmd"),
+        };
+        let result = TaskResult {
+            status: 0,
+            output_digest: fixed_bytes(&[9; 32]).unwrap(),
+            telemetry: Telemetry {
+                duration_ms: 70_000,
+                node_id: node("exec"),
+            },
+        };
+        let mut details = Vec::new();
+        for _ in 0..MAX_DETAILS {
+            details.push(fixed_str("token expired").unwrap()).unwrap();
+        }
+        [
+            build_task_request(trace_id(), node("cmd"), node("exec"), 24, task),
+            build_task_result(trace_id(), node("exec"), node("cmd"), 8, result),
+            build_task_rejected(
+                trace_id(),
+                node("relay"),
+                node("cmd"),
+                0,
+                fixed_str("validation_failed").unwrap(),
+                details,
+            ),
+            build_heartbeat(node("relay"), 1_700_000_000),
+        ]
+    }
+
+    #[cfg(feature = "alloc")]
+    fn owned_envelopes() -> [crate::Envelope; 4] {
+        use alloc::string::String;
+        use alloc::vec;
+
+        let task = crate::TaskRequest {
+            token: crate::Token {
+                token_id: vec![1; TOKEN_ID_LEN],
+                subject: String::from("cmd"),
+                audience: String::from("exec"),
+                capability: String::from("demo.hash"),
+                issued_at: 100,
+                expires_at: 4_000_000_000,
+            },
+            command: String::from("demo.hash"),
+            args: String::from("7"),
+            reply_to: String::from("cmd"),
+            commander_pubkey: String::from("cmd"),
+        };
+        let result = crate::TaskResult {
+            status: 0,
+            output_digest: vec![9; 32],
+            telemetry: crate::Telemetry {
+                duration_ms: 70_000,
+                node_id: String::from("exec"),
+            },
+        };
+        let trace_id = vec![7; TRACE_ID_LEN];
+        [
+            crate::build_task_request(
+                trace_id.clone(),
+                String::from("cmd"),
+                String::from("exec"),
+                24,
+                task,
+            ),
+            crate::build_task_result(
+                trace_id.clone(),
+                String::from("exec"),
+                String::from("cmd"),
+                8,
+                result,
+            ),
+            crate::build_task_rejected(
+                trace_id,
+                String::from("relay"),
+                String::from("cmd"),
+                0,
+                String::from("validation_failed"),
+                vec![String::from("token expired"); MAX_DETAILS],
+            ),
+            crate::build_heartbeat(String::from("relay"), 1_700_000_000),
+        ]
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn fixed_and_owned_types_share_the_wire_format() {
+        for (fixed, owned) in envelopes().iter().zip(owned_envelopes().iter()) {
+            let mut owned_bytes = alloc::vec::Vec::new();
+            crate::encode_envelope(owned, &mut owned_bytes).unwrap();
+            let mut buf = [0u8; 512];
+            let len = encode_envelope(fixed, &mut buf).unwrap();
+
+            assert_eq!(&buf[..len], owned_bytes.as_slice());
+            assert_eq!(&decode_envelope(&owned_bytes).unwrap(), fixed);
+            assert_eq!(&crate::decode_envelope(&buf[..len]).unwrap(), owned);
+        }
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn fixed_decoder_applies_the_owned_limits() {
+        use alloc::string::String;
+
+        // Both decoders keep the first MAX_DETAILS details.
+        let [_, _, mut rejected, _] = owned_envelopes();
+        if let crate::Payload::TaskRejected(payload) = &mut rejected.payload {
+            payload.details.push(String::from("token revoked"));
+        }
+        let mut bytes = alloc::vec::Vec::new();
+        crate::encode_envelope(&rejected, &mut bytes).unwrap();
+        let [_, _, expected, _] = envelopes();
+        assert_eq!(decode_envelope(&bytes).unwrap(), expected);
+        let owned = crate::decode_envelope(&bytes).unwrap();
+        assert!(matches!(
+            owned.payload,
+            crate::Payload::TaskRejected(payload) if payload.details.len() == MAX_DETAILS
+        ));
+
+        // A field longer than its fixed capacity is an error, not a truncation.
+        let [mut request, _, _, _] = owned_envelopes();
+        if let crate::Payload::TaskRequest(task) = &mut request.payload {
+            task.args = "9".repeat(MAX_ARGS_LEN + 1);
+        }
+        bytes.clear();
+        crate::encode_envelope(&request, &mut bytes).unwrap();
+        assert!(matches!(
+            decode_envelope(&bytes),
+            Err(DecodeError::LengthExceeded(_))
+        ));
+    }
+
+    #[test]
+    fn encode_reports_a_buffer_that_is_too_small() {
+        for env in envelopes() {
+            let mut buf = [0u8; 512];
+            let len = encode_envelope(&env, &mut buf).unwrap();
+            assert_eq!(decode_envelope(&buf[..len]).unwrap(), env);
+            for short in 0..len {
+                let mut buf = [0u8; 512];
+                assert!(matches!(
+                    encode_envelope(&env, &mut buf[..short]),
+                    Err(EncodeError::BufferFull)
+                ));
+            }
+            let mut exact = [0u8; 512];
+            assert_eq!(encode_envelope(&env, &mut exact[..len]).unwrap(), len);
+        }
+    }
+
+    #[test]
+    fn route_table_is_bounded() {
+        use core::fmt::Write as _;
+
+        let mut routes = RouteTable::new();
+        for index in 0..MAX_ROUTES {
+            let mut dst: String<8> = String::new();
+            write!(dst, "n{index}").unwrap();
+            routes.add(&dst, "10.0.0.2:7227").unwrap();
+        }
+        assert!(matches!(
+            routes.add("exec", "10.0.0.3:7227"),
+            Err(DecodeError::LengthExceeded("routes"))
+        ));
+        assert_eq!(routes.next_hop("n15"), Some("10.0.0.2:7227"));
+        assert_eq!(routes.next_hop("exec"), None);
+
+        let mut long_hop: String<{ MAX_NEXT_HOP_LEN + 1 }> = String::new();
+        while long_hop.push('1').is_ok() {}
+        assert!(RouteTable::new().add("exec", &long_hop).is_err());
+    }
+}
@@ -1,17 +1,29 @@
 #![no_std]
+// Without either feature only the borrowed decoders are built.
+#![cfg_attr(not(any(feature = "alloc", feature = "heapless")), allow(dead_code))]
 
+#[cfg(feature = "alloc")]
 extern crate alloc;
 
+#[cfg(feature = "alloc")]
 use alloc::string::String;
+#[cfg(feature = "alloc")]
 use alloc::vec::Vec;
 use core::fmt;
-use minicbor::{decode::Decoder, encode::Encoder};
+use minicbor::decode::Decoder;
+#[cfg(feature = "alloc")]
+use minicbor::encode::Encoder;
+
+#[cfg(feature = "heapless")]
+pub mod fixed;
 
 pub const VERSION: u8 = 1;
 
 pub const MSG_TASK_REQUEST: u8 = 1;
 pub const MSG_TASK_RESULT: u8 = 2;
 pub const MSG_TASK_REJECTED: u8 = 3;
+/// Link-local liveness probe: empty `dst`, hop_limit 0, never forwarded.
+pub const MSG_HEARTBEAT: u8 = 4;
 
 const KEY_VERSION: u8 = 0;
 const KEY_MSG_TYPE: u8 = 1;
@@ -44,6 +56,8 @@ const KEY_EXPIRES_AT: u8 = 5;
 const KEY_TEL_DURATION_MS: u8 = 0;
 const KEY_TEL_NODE_ID: u8 = 1;
 
+const KEY_HB_TIMESTAMP: u8 = 0;
+
 pub const MAX_NODE_ID_LEN: usize = 32;
 pub const MAX_COMMAND_LEN: usize = 32;
 pub const MAX_ARGS_LEN: usize = 64;
@@ -52,21 +66,62 @@ pub const MAX_DETAIL_LEN: usize = 64;
 pub const MAX_DETAILS: usize = 8;
 pub const TRACE_ID_LEN: usize = 16;
 pub const TOKEN_ID_LEN: usize = 16;
+pub const MAX_OUTPUT_DIGEST_LEN: usize = 64;
+
+/// Next-hop lookup, implemented by both the owned and the fixed-capacity
+/// route tables.
+pub trait Routes {
+    fn next_hop(&self, dst: &str) -> Option<&str>;
+}
 
+#[cfg(feature = "alloc")]
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct RouteEntry {
     pub dst: String,
     pub next_hop: String,
 }
 
+/// A scheduled link: `next_hop` reaches `dst` from `start` until `end` (unix
+/// seconds, end exclusive) at `bandwidth` bytes per second.
+#[cfg(feature = "alloc")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Contact {
+    pub dst: String,
+    pub next_hop: String,
+    pub start: u64,
+    pub end: u64,
+    pub bandwidth: u64,
+}
+
+#[cfg(feature = "alloc")]
+impl Contact {
+    pub fn is_open(&self, now: u64) -> bool {
+        self.start <= now && now < self.end
+    }
+}
+
+/// Static routes in `entries`, plus `contacts` for links that come and go on
+/// a schedule. `next_hop` and the `Routes` impl only see the static routes;
+/// `at` resolves the contacts for a given time.
+#[cfg(feature = "alloc")]
 #[derive(Clone, Debug)]
 pub struct RouteTable {
     pub entries: Vec<RouteEntry>,
+    pub contacts: Vec<Contact>,
 }
 
+#[cfg(feature = "alloc")]
 impl RouteTable {
     pub fn new(entries: Vec<RouteEntry>) -> Self {
-        Self { entries }
+        Self {
+            entries,
+            contacts: Vec::new(),
+        }
+    }
+
+    pub fn with_contacts(mut self, contacts: Vec<Contact>) -> Self {
+        self.contacts = contacts;
+        self
     }
 
     pub fn next_hop(&self, dst: &str) -> Option<&str> {
@@ -75,8 +130,96 @@ impl RouteTable {
             .find(|entry| entry.dst == dst)
             .map(|entry| entry.next_hop.as_str())
     }
+
+    /// The route to `dst` at `now`: the open contact with the most
+    /// bandwidth, or else the static route.
+    pub fn next_hop_at(&self, dst: &str, now: u64) -> Option<&str> {
+        self.contacts
+            .iter()
+            .filter(|contact| contact.dst == dst && contact.is_open(now))
+            .max_by_key(|contact| contact.bandwidth)
+            .map(|contact| contact.next_hop.as_str())
+            .or_else(|| self.next_hop(dst))
+    }
+
+    /// The earliest contact to `dst` opening after `now`.
+    pub fn next_contact(&self, dst: &str, now: u64) -> Option<&Contact> {
+        self.contacts
+            .iter()
+            .filter(|contact| contact.dst == dst && contact.start > now)
+            .min_by_key(|contact| (contact.start, core::cmp::Reverse(contact.bandwidth)))
+    }
+
+    /// True when `next_hop` is only reached through contacts and none of
+    /// them is open at `now`.
+    pub fn contact_closed(&self, next_hop: &str, now: u64) -> bool {
+        let mut contacts = self
+            .contacts
+            .iter()
+            .filter(|contact| contact.next_hop == next_hop)
+            .peekable();
+        contacts.peek().is_some()
+            && !contacts.any(|contact| contact.is_open(now))
+            && !self.entries.iter().any(|entry| entry.next_hop == next_hop)
+    }
+
+    /// Every next hop in the table, static or scheduled, sorted and unique.
+    pub fn next_hops(&self) -> Vec<&str> {
+        let mut next_hops = self
+            .entries
+            .iter()
+            .map(|entry| entry.next_hop.as_str())
+            .chain(
+                self.contacts
+                    .iter()
+                    .map(|contact| contact.next_hop.as_str()),
+            )
+            .collect::<Vec<_>>();
+        next_hops.sort_unstable();
+        next_hops.dedup();
+        next_hops
+    }
+
+    /// The static table in effect at `now`, with each destination routed by
+    /// `next_hop_at`. With `upcoming`, a destination that has no route yet
+    /// goes to the next hop of its next contact, for callers that hold
+    /// traffic until the contact opens.
+    pub fn at(&self, now: u64, upcoming: bool) -> RouteTable {
+        let mut dsts = self
+            .entries
+            .iter()
+            .map(|entry| entry.dst.as_str())
+            .chain(self.contacts.iter().map(|contact| contact.dst.as_str()))
+            .collect::<Vec<_>>();
+        dsts.sort_unstable();
+        dsts.dedup();
+        let entries = dsts
+            .into_iter()
+            .filter_map(|dst| {
+                let next_hop = self.next_hop_at(dst, now).or_else(|| {
+                    upcoming
+                        .then(|| self.next_contact(dst, now))
+                        .flatten()
+                        .map(|contact| contact.next_hop.as_str())
+                })?;
+                Some(RouteEntry {
+                    dst: String::from(dst),
+                    next_hop: String::from(next_hop),
+                })
+            })
+            .collect();
+        RouteTable::new(entries)
+    }
 }
 
+#[cfg(feature = "alloc")]
+impl Routes for RouteTable {
+    fn next_hop(&self, dst: &str) -> Option<&str> {
+        RouteTable::next_hop(self, dst)
+    }
+}
+
+#[cfg(feature = "alloc")]
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Token {
     pub token_id: Vec<u8>,
@@ -87,6 +230,7 @@ pub struct Token {
     pub expires_at: u64,
 }
 
+#[cfg(feature = "alloc")]
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct TaskRequest {
     pub token: Token,
@@ -96,12 +240,14 @@ pub struct TaskRequest {
     pub commander_pubkey: String,
 }
 
+#[cfg(feature = "alloc")]
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Telemetry {
     pub duration_ms: u32,
     pub node_id: String,
 }
 
+#[cfg(feature = "alloc")]
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct TaskResult {
     pub status: u8,
@@ -109,19 +255,29 @@ pub struct TaskResult {
     pub telemetry: Telemetry,
 }
 
+#[cfg(feature = "alloc")]
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct TaskRejected {
     pub reason: String,
     pub details: Vec<String>,
 }
 
+#[cfg(feature = "alloc")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Heartbeat {
+    pub timestamp: u64,
+}
+
+#[cfg(feature = "alloc")]
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Payload {
     TaskRequest(TaskRequest),
     TaskResult(TaskResult),
     TaskRejected(TaskRejected),
+    Heartbeat(Heartbeat),
 }
 
+#[cfg(feature = "alloc")]
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Envelope {
     pub version: u8,
@@ -133,11 +289,34 @@ pub struct Envelope {
     pub payload: Payload,
 }
 
+/// An envelope that borrows its fields from the input buffer. The payload is
+/// kept encoded until `decode_payload`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EnvelopeRef<'a> {
+    pub version: u8,
+    pub msg_type: u8,
+    pub trace_id: &'a [u8],
+    pub src: &'a str,
+    pub dst: &'a str,
+    pub hop_limit: u8,
+    pub payload: &'a [u8],
+}
+
+/// The fields a relay needs, read without decoding the payload.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RouteHeader<'a> {
+    pub dst: &'a str,
+    pub hop_limit: u8,
+    /// Offset of the encoded hop_limit, for `set_hop_limit`.
+    pub hop_limit_at: usize,
+}
+
 #[derive(Debug)]
 pub enum DecodeError {
     Cbor(minicbor::decode::Error),
     InvalidField(&'static str),
     LengthExceeded(&'static str),
+    NonCanonical(&'static str),
 }
 
 impl From<minicbor::decode::Error> for DecodeError {
@@ -152,6 +331,7 @@ impl fmt::Display for DecodeError {
             DecodeError::Cbor(err) => write!(f, "cbor decode error: {err}"),
             DecodeError::InvalidField(field) => write!(f, "invalid field: {field}"),
             DecodeError::LengthExceeded(field) => write!(f, "length exceeded: {field}"),
+            DecodeError::NonCanonical(what) => write!(f, "non-canonical cbor: {what}"),
         }
     }
 }
@@ -159,6 +339,8 @@ impl fmt::Display for DecodeError {
 #[derive(Debug)]
 pub enum EncodeError {
     Cbor(minicbor::encode::Error<core::convert::Infallible>),
+    /// The output slice is too small for the envelope.
+    BufferFull,
 }
 
 impl From<minicbor::encode::Error<core::convert::Infallible>> for EncodeError {
@@ -167,32 +349,45 @@ impl From<minicbor::encode::Error<core::convert::Infallible>> for EncodeError {
     }
 }
 
-fn decode_string(dec: &mut Decoder<'_>, max_len: usize) -> Result<String, DecodeError> {
+fn borrow_string<'b>(dec: &mut Decoder<'b>, max_len: usize) -> Result<&'b str, DecodeError> {
     let value = dec.str()?;
     if value.len() > max_len {
         return Err(DecodeError::LengthExceeded("string"));
     }
-    Ok(String::from(value))
+    Ok(value)
 }
 
-fn decode_bytes(dec: &mut Decoder<'_>, max_len: usize) -> Result<Vec<u8>, DecodeError> {
+fn borrow_bytes<'b>(dec: &mut Decoder<'b>, max_len: usize) -> Result<&'b [u8], DecodeError> {
     let value = dec.bytes()?;
     if value.len() > max_len {
         return Err(DecodeError::LengthExceeded("bytes"));
     }
-    Ok(value.to_vec())
+    Ok(value)
+}
+
+#[cfg(feature = "alloc")]
+fn decode_string(dec: &mut Decoder<'_>, max_len: usize) -> Result<String, DecodeError> {
+    Ok(String::from(borrow_string(dec, max_len)?))
+}
+
+#[cfg(feature = "alloc")]
+fn decode_bytes(dec: &mut Decoder<'_>, max_len: usize) -> Result<Vec<u8>, DecodeError> {
+    Ok(borrow_bytes(dec, max_len)?.to_vec())
 }
 
+#[cfg(feature = "alloc")]
 fn encode_string(enc: &mut Encoder<&mut Vec<u8>>, value: &str) -> Result<(), EncodeError> {
     enc.str(value)?;
     Ok(())
 }
 
+#[cfg(feature = "alloc")]
 fn encode_bytes(enc: &mut Encoder<&mut Vec<u8>>, value: &[u8]) -> Result<(), EncodeError> {
     enc.bytes(value)?;
     Ok(())
 }
 
+#[cfg(feature = "alloc")]
 pub fn encode_envelope(env: &Envelope, out: &mut Vec<u8>) -> Result<(), EncodeError> {
     let mut enc = Encoder::new(out);
     enc.map(7)?;
@@ -211,11 +406,13 @@ pub fn encode_envelope(env: &Envelope, out: &mut Vec<u8>) -> Result<(), EncodeEr
         Payload::TaskRequest(task) => encode_task_request(&mut enc, task)?,
         Payload::TaskResult(result) => encode_task_result(&mut enc, result)?,
         Payload::TaskRejected(rejected) => encode_task_rejected(&mut enc, rejected)?,
+        Payload::Heartbeat(heartbeat) => encode_heartbeat(&mut enc, heartbeat)?,
     }
 
     Ok(())
 }
 
+#[cfg(feature = "alloc")]
 pub fn decode_envelope(data: &[u8]) -> Result<Envelope, DecodeError> {
     let mut dec = Decoder::new(data);
     let mut version = None;
@@ -237,13 +434,7 @@ pub fn decode_envelope(data: &[u8]) -> Result<Envelope, DecodeError> {
             KEY_DST => dst = Some(decode_string(&mut dec, MAX_NODE_ID_LEN)?),
             KEY_HOP_LIMIT => hop_limit = Some(dec.u8()?),
             KEY_PAYLOAD => {
-                let msg_type_val = msg_type.unwrap_or(0);
-                payload = Some(match msg_type_val {
-                    MSG_TASK_REQUEST => Payload::TaskRequest(decode_task_request(&mut dec)?),
-                    MSG_TASK_RESULT => Payload::TaskResult(decode_task_result(&mut dec)?),
-                    MSG_TASK_REJECTED => Payload::TaskRejected(decode_task_rejected(&mut dec)?),
-                    _ => return Err(DecodeError::InvalidField("msg_type")),
-                });
+                payload = Some(decode_payload(msg_type.unwrap_or(0), &mut dec)?);
             }
             _ => {
                 dec.skip()?;
@@ -262,6 +453,209 @@ pub fn decode_envelope(data: &[u8]) -> Result<Envelope, DecodeError> {
     })
 }
 
+#[cfg(feature = "alloc")]
+fn decode_payload(msg_type: u8, dec: &mut Decoder<'_>) -> Result<Payload, DecodeError> {
+    Ok(match msg_type {
+        MSG_TASK_REQUEST => Payload::TaskRequest(decode_task_request(dec)?),
+        MSG_TASK_RESULT => Payload::TaskResult(decode_task_result(dec)?),
+        MSG_TASK_REJECTED => Payload::TaskRejected(decode_task_rejected(dec)?),
+        MSG_HEARTBEAT => Payload::Heartbeat(decode_heartbeat(dec)?),
+        _ => return Err(DecodeError::InvalidField("msg_type")),
+    })
+}
+
+/// Decodes an envelope without copying: strings and bytes point into `data`
+/// and the payload is only located, not decoded.
+pub fn decode_envelope_ref(data: &[u8]) -> Result<EnvelopeRef<'_>, DecodeError> {
+    let mut dec = Decoder::new(data);
+    let mut version = None;
+    let mut msg_type = None;
+    let mut trace_id = None;
+    let mut src = None;
+    let mut dst = None;
+    let mut hop_limit = None;
+    let mut payload = None;
+
+    let len = dec.map()?.ok_or(DecodeError::InvalidField("envelope"))?;
+    for _ in 0..len {
+        match dec.u8()? {
+            KEY_VERSION => version = Some(dec.u8()?),
+            KEY_MSG_TYPE => msg_type = Some(dec.u8()?),
+            KEY_TRACE_ID => trace_id = Some(borrow_bytes(&mut dec, TRACE_ID_LEN)?),
+            KEY_SRC => src = Some(borrow_string(&mut dec, MAX_NODE_ID_LEN)?),
+            KEY_DST => dst = Some(borrow_string(&mut dec, MAX_NODE_ID_LEN)?),
+            KEY_HOP_LIMIT => hop_limit = Some(dec.u8()?),
+            KEY_PAYLOAD => {
+                let start = dec.position();
+                dec.skip()?;
+                payload = Some(&data[start..dec.position()]);
+            }
+            _ => dec.skip()?,
+        }
+    }
+
+    Ok(EnvelopeRef {
+        version: version.ok_or(DecodeError::InvalidField("version"))?,
+        msg_type: msg_type.ok_or(DecodeError::InvalidField("msg_type"))?,
+        trace_id: trace_id.ok_or(DecodeError::InvalidField("trace_id"))?,
+        src: src.ok_or(DecodeError::InvalidField("src"))?,
+        dst: dst.ok_or(DecodeError::InvalidField("dst"))?,
+        hop_limit: hop_limit.ok_or(DecodeError::InvalidField("hop_limit"))?,
+        payload: payload.ok_or(DecodeError::InvalidField("payload"))?,
+    })
+}
+
+#[cfg(feature = "alloc")]
+impl EnvelopeRef<'_> {
+    pub fn decode_payload(&self) -> Result<Payload, DecodeError> {
+        decode_payload(self.msg_type, &mut Decoder::new(self.payload))
+    }
+
+    pub fn to_envelope(&self) -> Result<Envelope, DecodeError> {
+        Ok(Envelope {
+            version: self.version,
+            msg_type: self.msg_type,
+            trace_id: self.trace_id.to_vec(),
+            src: String::from(self.src),
+            dst: String::from(self.dst),
+            hop_limit: self.hop_limit,
+            payload: self.decode_payload()?,
+        })
+    }
+}
+
+/// Reads `dst` and `hop_limit`, skipping everything else.
+pub fn read_route_header(data: &[u8]) -> Result<RouteHeader<'_>, DecodeError> {
+    let mut dec = Decoder::new(data);
+    let mut dst = None;
+    let mut hop_limit = None;
+
+    let len = dec.map()?.ok_or(DecodeError::InvalidField("envelope"))?;
+    for _ in 0..len {
+        match dec.u8()? {
+            KEY_DST => dst = Some(borrow_string(&mut dec, MAX_NODE_ID_LEN)?),
+            KEY_HOP_LIMIT => {
+                let at = dec.position();
+                hop_limit = Some((dec.u8()?, at));
+            }
+            _ => dec.skip()?,
+        }
+    }
+
+    let (hop_limit, hop_limit_at) = hop_limit.ok_or(DecodeError::InvalidField("hop_limit"))?;
+    Ok(RouteHeader {
+        dst: dst.ok_or(DecodeError::InvalidField("dst"))?,
+        hop_limit,
+        hop_limit_at,
+    })
+}
+
+/// Overwrites the hop_limit encoded at `at` (from `read_route_header`). Values
+/// up to 23 fit the initial byte and larger ones take a second byte, so a
+/// change across that boundary fails and the envelope has to be re-encoded.
+pub fn set_hop_limit(data: &mut [u8], at: usize, hop_limit: u8) -> Result<(), DecodeError> {
+    match (data.get(at).copied(), hop_limit) {
+        (Some(0x00..=0x17), 0..=23) => data[at] = hop_limit,
+        (Some(0x18), 24..) if at + 1 < data.len() => data[at + 1] = hop_limit,
+        _ => return Err(DecodeError::InvalidField("hop_limit")),
+    }
+    Ok(())
+}
+
+#[cfg(feature = "alloc")]
+/// `decode_envelope` for inputs that must be deterministic CBOR (RFC 8949
+/// §4.2.1): definite lengths, shortest integers and lengths, and sorted,
+/// unique map keys. Use it wherever the envelope feeds token verification.
+pub fn decode_envelope_canonical(data: &[u8]) -> Result<Envelope, DecodeError> {
+    check_canonical(data)?;
+    decode_envelope(data)
+}
+
+/// Checks that `data` is exactly one deterministically encoded CBOR item:
+/// definite lengths only, integers and lengths in their shortest form, and
+/// map keys unique and sorted by their encoded bytes. Float width is not
+/// checked. `scrap-protocol` applies the same check to spec messages.
+pub fn check_canonical(data: &[u8]) -> Result<(), DecodeError> {
+    let end = canonical_item(data, 0, 0)?;
+    if end != data.len() {
+        return Err(DecodeError::NonCanonical("trailing bytes"));
+    }
+    Ok(())
+}
+
+const MAX_CANONICAL_DEPTH: usize = 16;
+
+fn canonical_take(data: &[u8], pos: usize, len: u64) -> Result<usize, DecodeError> {
+    usize::try_from(len)
+        .ok()
+        .and_then(|len| pos.checked_add(len))
+        .filter(|end| *end <= data.len())
+        .ok_or(DecodeError::NonCanonical("truncated"))
+}
+
+/// Returns the major type, argument and the position after the head.
+fn canonical_head(data: &[u8], pos: usize) -> Result<(u8, u64, usize), DecodeError> {
+    let initial = *data
+        .get(pos)
+        .ok_or(DecodeError::NonCanonical("truncated"))?;
+    let major = initial >> 5;
+    let info = initial & 0x1f;
+    let (width, min) = match info {
+        0..=23 => return Ok((major, u64::from(info), pos + 1)),
+        24 => (1, 24),
+        25 => (2, 0x100),
+        26 => (4, 0x1_0000),
+        27 => (8, 0x1_0000_0000),
+        31 => return Err(DecodeError::NonCanonical("indefinite length")),
+        _ => return Err(DecodeError::NonCanonical("reserved additional info")),
+    };
+    let end = canonical_take(data, pos + 1, width)?;
+    let arg = data[pos + 1..end]
+        .iter()
+        .fold(0u64, |acc, byte| (acc << 8) | u64::from(*byte));
+    // Floats keep their width; only one-byte simple values have a shorter form.
+    if (major != 7 || info == 24) && arg < min {
+        return Err(DecodeError::NonCanonical("non-minimal integer"));
+    }
+    Ok((major, arg, end))
+}
+
+fn canonical_item(data: &[u8], pos: usize, depth: usize) -> Result<usize, DecodeError> {
+    if depth > MAX_CANONICAL_DEPTH {
+        return Err(DecodeError::NonCanonical("nesting too deep"));
+    }
+    let (major, arg, mut pos) = canonical_head(data, pos)?;
+    match major {
+        2 | 3 => pos = canonical_take(data, pos, arg)?,
+        4 => {
+            for _ in 0..arg {
+                pos = canonical_item(data, pos, depth + 1)?;
+            }
+        }
+        5 => {
+            let mut previous: Option<&[u8]> = None;
+            for _ in 0..arg {
+                let key_end = canonical_item(data, pos, depth + 1)?;
+                let key = &data[pos..key_end];
+                if let Some(previous) = previous {
+                    if key == previous {
+                        return Err(DecodeError::NonCanonical("duplicate map key"));
+                    }
+                    if key < previous {
+                        return Err(DecodeError::NonCanonical("map keys out of order"));
+                    }
+                }
+                previous = Some(key);
+                pos = canonical_item(data, key_end, depth + 1)?;
+            }
+        }
+        6 => pos = canonical_item(data, pos, depth + 1)?,
+        _ => {}
+    }
+    Ok(pos)
+}
+
+#[cfg(feature = "alloc")]
 fn encode_task_request(enc: &mut Encoder<&mut Vec<u8>>, task: &TaskRequest) -> Result<(), EncodeError> {
     enc.map(5)?;
     enc.u8(KEY_TOKEN)?;
@@ -277,6 +671,7 @@ fn encode_task_request(enc: &mut Encoder<&mut Vec<u8>>, task: &TaskRequest) -> R
     Ok(())
 }
 
+#[cfg(feature = "alloc")]
 fn decode_task_request(dec: &mut Decoder<'_>) -> Result<TaskRequest, DecodeError> {
     let len = dec.map()?.unwrap_or(0);
     let mut token = None;
@@ -306,6 +701,7 @@ fn decode_task_request(dec: &mut Decoder<'_>) -> Result<TaskRequest, DecodeError
     })
 }
 
+#[cfg(feature = "alloc")]
 fn encode_task_result(enc: &mut Encoder<&mut Vec<u8>>, result: &TaskResult) -> Result<(), EncodeError> {
     enc.map(3)?;
     enc.u8(KEY_STATUS)?.u8(result.status)?;
@@ -316,6 +712,7 @@ fn encode_task_result(enc: &mut Encoder<&mut Vec<u8>>, result: &TaskResult) -> R
     Ok(())
 }
 
+#[cfg(feature = "alloc")]
 fn decode_task_result(dec: &mut Decoder<'_>) -> Result<TaskResult, DecodeError> {
     let len = dec.map()?.unwrap_or(0);
     let mut status = None;
@@ -326,7 +723,7 @@ fn decode_task_result(dec: &mut Decoder<'_>) -> Result<TaskResult, DecodeError>
         let key = dec.u8()?;
         match key {
             KEY_STATUS => status = Some(dec.u8()?),
-            KEY_OUTPUT_DIGEST => output = Some(decode_bytes(dec, 64)?),
+            KEY_OUTPUT_DIGEST => output = Some(decode_bytes(dec, MAX_OUTPUT_DIGEST_LEN)?),
             KEY_TELEMETRY => telemetry = Some(decode_telemetry(dec)?),
             _ => dec.skip()?,
         }
@@ -339,6 +736,7 @@ fn decode_task_result(dec: &mut Decoder<'_>) -> Result<TaskResult, DecodeError>
     })
 }
 
+#[cfg(feature = "alloc")]
 fn encode_task_rejected(enc: &mut Encoder<&mut Vec<u8>>, rejected: &TaskRejected) -> Result<(), EncodeError> {
     enc.map(2)?;
     enc.u8(KEY_REASON)?;
@@ -351,6 +749,7 @@ fn encode_task_rejected(enc: &mut Encoder<&mut Vec<u8>>, rejected: &TaskRejected
     Ok(())
 }
 
+#[cfg(feature = "alloc")]
 fn decode_task_rejected(dec: &mut Decoder<'_>) -> Result<TaskRejected, DecodeError> {
     let len = dec.map()?.unwrap_or(0);
     let mut reason = None;
@@ -380,6 +779,7 @@ fn decode_task_rejected(dec: &mut Decoder<'_>) -> Result<TaskRejected, DecodeErr
     })
 }
 
+#[cfg(feature = "alloc")]
 fn encode_token(enc: &mut Encoder<&mut Vec<u8>>, token: &Token) -> Result<(), EncodeError> {
     enc.map(6)?;
     enc.u8(KEY_TOKEN_ID)?;
@@ -395,6 +795,7 @@ fn encode_token(enc: &mut Encoder<&mut Vec<u8>>, token: &Token) -> Result<(), En
     Ok(())
 }
 
+#[cfg(feature = "alloc")]
 fn decode_token(dec: &mut Decoder<'_>) -> Result<Token, DecodeError> {
     let len = dec.map()?.unwrap_or(0);
     let mut token_id = None;
@@ -427,6 +828,7 @@ fn decode_token(dec: &mut Decoder<'_>) -> Result<Token, DecodeError> {
     })
 }
 
+#[cfg(feature = "alloc")]
 fn encode_telemetry(enc: &mut Encoder<&mut Vec<u8>>, telemetry: &Telemetry) -> Result<(), EncodeError> {
     enc.map(2)?;
     enc.u8(KEY_TEL_DURATION_MS)?.u32(telemetry.duration_ms)?;
@@ -435,6 +837,7 @@ fn encode_telemetry(enc: &mut Encoder<&mut Vec<u8>>, telemetry: &Telemetry) -> R
     Ok(())
 }
 
+#[cfg(feature = "alloc")]
 fn decode_telemetry(dec: &mut Decoder<'_>) -> Result<Telemetry, DecodeError> {
     let len = dec.map()?.unwrap_or(0);
     let mut duration_ms = None;
@@ -455,6 +858,32 @@ fn decode_telemetry(dec: &mut Decoder<'_>) -> Result<Telemetry, DecodeError> {
     })
 }
 
+#[cfg(feature = "alloc")]
+fn encode_heartbeat(enc: &mut Encoder<&mut Vec<u8>>, heartbeat: &Heartbeat) -> Result<(), EncodeError> {
+    enc.map(1)?;
+    enc.u8(KEY_HB_TIMESTAMP)?.u64(heartbeat.timestamp)?;
+    Ok(())
+}
+
+#[cfg(feature = "alloc")]
+fn decode_heartbeat(dec: &mut Decoder<'_>) -> Result<Heartbeat, DecodeError> {
+    let len = dec.map()?.unwrap_or(0);
+    let mut timestamp = None;
+
+    for _ in 0..len {
+        let key = dec.u8()?;
+        match key {
+            KEY_HB_TIMESTAMP => timestamp = Some(dec.u64()?),
+            _ => dec.skip()?,
+        }
+    }
+
+    Ok(Heartbeat {
+        timestamp: timestamp.ok_or(DecodeError::InvalidField("timestamp"))?,
+    })
+}
+
+#[cfg(feature = "alloc")]
 pub fn build_task_result(
     trace_id: Vec<u8>,
     src: String,
@@ -473,6 +902,7 @@ pub fn build_task_result(
     }
 }
 
+#[cfg(feature = "alloc")]
 pub fn build_task_rejected(
     trace_id: Vec<u8>,
     src: String,
@@ -492,6 +922,7 @@ pub fn build_task_rejected(
     }
 }
 
+#[cfg(feature = "alloc")]
 pub fn build_task_request(
     trace_id: Vec<u8>,
     src: String,
@@ -510,6 +941,20 @@ pub fn build_task_request(
     }
 }
 
+#[cfg(feature = "alloc")]
+pub fn build_heartbeat(src: String, timestamp: u64) -> Envelope {
+    Envelope {
+        version: VERSION,
+        msg_type: MSG_HEARTBEAT,
+        trace_id: alloc::vec![0; TRACE_ID_LEN],
+        src,
+        dst: String::new(),
+        hop_limit: 0,
+        payload: Payload::Heartbeat(Heartbeat { timestamp }),
+    }
+}
+
+#[cfg(feature = "alloc")]
 impl Token {
     pub fn token_id_hex(&self) -> String {
         let mut out = String::with_capacity(self.token_id.len() * 2);
@@ -520,3 +965,306 @@ impl Token {
         out
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn non_canonical(data: &[u8]) -> &'static str {
+        match check_canonical(data) {
+            Err(DecodeError::NonCanonical(what)) => what,
+            other => panic!("expected a non-canonical error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn canonical_map_keys_are_sorted_and_unique() {
+        // {"a": 1, "b": 2}
+        assert!(check_canonical(&[0xa2, 0x61, 0x61, 0x01, 0x61, 0x62, 0x02]).is_ok());
+        assert_eq!(
+            non_canonical(&[0xa2, 0x61, 0x62, 0x02, 0x61, 0x61, 0x01]),
+            "map keys out of order"
+        );
+        assert_eq!(
+            non_canonical(&[0xa2, 0x61, 0x61, 0x01, 0x61, 0x61, 0x02]),
+            "duplicate map key"
+        );
+        // Shorter keys sort first: {"b": 1, "aa": 2}
+        assert!(check_canonical(&[0xa2, 0x61, 0x62, 0x01, 0x62, 0x61, 0x61, 0x02]).is_ok());
+        // Integer keys, as the envelope uses: {0: 1, 1: 2} but not {1: 2, 0: 1}.
+        assert!(check_canonical(&[0xa2, 0x00, 0x01, 0x01, 0x02]).is_ok());
+        assert_eq!(
+            non_canonical(&[0xa2, 0x01, 0x02, 0x00, 0x01]),
+            "map keys out of order"
+        );
+    }
+
+    #[test]
+    fn canonical_integers_and_lengths_are_minimal() {
+        assert!(check_canonical(&[0x17]).is_ok());
+        assert!(check_canonical(&[0x18, 0x18]).is_ok());
+        assert_eq!(non_canonical(&[0x18, 0x17]), "non-minimal integer");
+        assert_eq!(non_canonical(&[0x19, 0x00, 0xff]), "non-minimal integer");
+        assert_eq!(
+            non_canonical(&[0x1a, 0x00, 0x00, 0xff, 0xff]),
+            "non-minimal integer"
+        );
+        assert_eq!(
+            non_canonical(&[0x1b, 0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff]),
+            "non-minimal integer"
+        );
+        // A one-byte string whose length took a second byte.
+        assert_eq!(non_canonical(&[0x58, 0x01, 0x00]), "non-minimal integer");
+        assert_eq!(non_canonical(&[0x98, 0x01, 0x00]), "non-minimal integer");
+        // Floats keep their width.
+        assert!(check_canonical(&[0xfb, 0x40, 0x24, 0, 0, 0, 0, 0, 0]).is_ok());
+        assert!(check_canonical(&[0xf9, 0x3c, 0x00]).is_ok());
+    }
+
+    #[test]
+    fn canonical_rejects_indefinite_lengths_and_framing_errors() {
+        assert_eq!(
+            non_canonical(&[0xbf, 0x61, 0x61, 0x01, 0xff]),
+            "indefinite length"
+        );
+        assert_eq!(
+            non_canonical(&[0x5f, 0x41, 0x00, 0xff]),
+            "indefinite length"
+        );
+        assert_eq!(non_canonical(&[0x9f, 0x01, 0xff]), "indefinite length");
+        assert_eq!(non_canonical(&[0x1c]), "reserved additional info");
+        assert_eq!(non_canonical(&[0x41, 0x00, 0x00]), "trailing bytes");
+        assert_eq!(non_canonical(&[0x42, 0x00]), "truncated");
+        assert_eq!(non_canonical(&[0x19, 0x01]), "truncated");
+        assert_eq!(non_canonical(&[]), "truncated");
+    }
+
+    #[test]
+    fn canonical_nesting_is_limited_to_sixteen_levels() {
+        // Sixteen one-element arrays around a zero.
+        let mut nested = [0x81u8; 17];
+        nested[16] = 0x00;
+        assert!(check_canonical(&nested).is_ok());
+        let mut deeper = [0x81u8; 18];
+        deeper[17] = 0x00;
+        assert_eq!(non_canonical(&deeper), "nesting too deep");
+    }
+
+    #[cfg(feature = "alloc")]
+    fn relay_envelope(hop_limit: u8) -> Vec<u8> {
+        let task = TaskRequest {
+            token: Token {
+                token_id: alloc::vec![1; TOKEN_ID_LEN],
+                subject: String::from("cmd"),
+                audience: String::from("exec"),
+                capability: String::from("demo.hash"),
+                issued_at: 100,
+                expires_at: 200,
+            },
+            command: String::from("demo.hash"),
+            args: String::from("7"),
+            reply_to: String::from("cmd"),
+            commander_pubkey: String::from("cmd"),
+        };
+        let env = build_task_request(
+            alloc::vec![7; TRACE_ID_LEN],
+            String::from("cmd"),
+            String::from("exec"),
+            hop_limit,
+            task,
+        );
+        let mut out = Vec::new();
+        encode_envelope(&env, &mut out).unwrap();
+        out
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn route_header_locates_hop_limit_on_both_sides_of_the_width_boundary() {
+        for hop_limit in [0, 1, 23, 24, 255] {
+            let data = relay_envelope(hop_limit);
+            let header = read_route_header(&data).unwrap();
+            assert_eq!(header.dst, "exec");
+            assert_eq!(header.hop_limit, hop_limit);
+            if hop_limit <= 23 {
+                assert_eq!(data[header.hop_limit_at], hop_limit);
+            } else {
+                assert_eq!(data[header.hop_limit_at], 0x18);
+                assert_eq!(data[header.hop_limit_at + 1], hop_limit);
+            }
+        }
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn set_hop_limit_rewrites_within_one_width() {
+        for (from, to) in [(23, 22), (1, 0), (255, 254), (25, 24)] {
+            let mut data = relay_envelope(from);
+            let at = read_route_header(&data).unwrap().hop_limit_at;
+            set_hop_limit(&mut data, at, to).unwrap();
+            assert_eq!(data, relay_envelope(to));
+            assert_eq!(decode_envelope(&data).unwrap().hop_limit, to);
+        }
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn set_hop_limit_refuses_to_cross_the_width_boundary() {
+        for (from, to) in [(24, 23), (23, 24)] {
+            let original = relay_envelope(from);
+            let mut data = original.clone();
+            let at = read_route_header(&data).unwrap().hop_limit_at;
+            assert!(matches!(
+                set_hop_limit(&mut data, at, to),
+                Err(DecodeError::InvalidField("hop_limit"))
+            ));
+            assert_eq!(data, original);
+
+            // The caller falls back to a full re-encode, which picks the width.
+            let mut env = decode_envelope(&data).unwrap();
+            env.hop_limit = to;
+            let mut reencoded = Vec::new();
+            encode_envelope(&env, &mut reencoded).unwrap();
+            assert_eq!(reencoded, relay_envelope(to));
+            assert_eq!(read_route_header(&reencoded).unwrap().hop_limit, to);
+        }
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn non_minimal_hop_limit_is_read_but_not_rewritten() {
+        let minimal = relay_envelope(5);
+        let at = read_route_header(&minimal).unwrap().hop_limit_at;
+        // The same envelope with hop_limit 5 spelled as 0x18 0x05.
+        let mut data = minimal[..at].to_vec();
+        data.extend_from_slice(&[0x18, 0x05]);
+        data.extend_from_slice(&minimal[at + 1..]);
+
+        let header = read_route_header(&data).unwrap();
+        assert_eq!(header.hop_limit, 5);
+        assert_eq!(header.hop_limit_at, at);
+        assert_eq!(non_canonical(&data), "non-minimal integer");
+
+        let original = data.clone();
+        assert!(set_hop_limit(&mut data, at, 4).is_err());
+        assert_eq!(data, original);
+    }
+
+    #[test]
+    fn set_hop_limit_checks_the_offset() {
+        assert!(set_hop_limit(&mut [0x05], 1, 4).is_err());
+        assert!(set_hop_limit(&mut [0x18], 0, 30).is_err());
+        let mut data = [0x18, 0x1e];
+        set_hop_limit(&mut data, 0, 29).unwrap();
+        assert_eq!(data, [0x18, 0x1d]);
+    }
+
+    #[cfg(feature = "alloc")]
+    fn contact(dst: &str, next_hop: &str, start: u64, end: u64, bandwidth: u64) -> Contact {
+        Contact {
+            dst: String::from(dst),
+            next_hop: String::from(next_hop),
+            start,
+            end,
+            bandwidth,
+        }
+    }
+
+    /// "sat" through "gs1" by default, and through contacts with "gs2" (wide)
+    /// and "gs3" (narrow) that overlap from 150 to 200.
+    #[cfg(feature = "alloc")]
+    fn scheduled_routes() -> RouteTable {
+        RouteTable::new(alloc::vec![RouteEntry {
+            dst: String::from("sat"),
+            next_hop: String::from("gs1"),
+        }])
+        .with_contacts(alloc::vec![
+            contact("sat", "gs2", 100, 200, 1_000),
+            contact("sat", "gs3", 150, 300, 10),
+            contact("probe", "gs3", 400, 500, 10),
+        ])
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn contact_includes_its_start_but_not_its_end() {
+        let window = contact("sat", "gs2", 100, 200, 1_000);
+        assert!(!window.is_open(99));
+        assert!(window.is_open(100));
+        assert!(window.is_open(199));
+        assert!(!window.is_open(200));
+        assert!(!contact("sat", "gs2", 100, 100, 1_000).is_open(100));
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn next_hop_at_prefers_the_widest_open_contact() {
+        let routes = scheduled_routes();
+        assert_eq!(routes.next_hop_at("sat", 99), Some("gs1"));
+        assert_eq!(routes.next_hop_at("sat", 100), Some("gs2"));
+        // Overlapping contacts: the wider one wins until it closes at 200.
+        assert_eq!(routes.next_hop_at("sat", 150), Some("gs2"));
+        assert_eq!(routes.next_hop_at("sat", 200), Some("gs3"));
+        assert_eq!(routes.next_hop_at("sat", 300), Some("gs1"));
+        // Only contacts and none open: no route.
+        assert_eq!(routes.next_hop_at("probe", 399), None);
+        assert_eq!(routes.next_hop_at("probe", 400), Some("gs3"));
+        assert_eq!(routes.next_hop_at("probe", 500), None);
+        assert_eq!(routes.next_hop_at("nowhere", 150), None);
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn next_contact_is_the_earliest_still_to_open() {
+        let routes = scheduled_routes();
+        let start = |dst, now| routes.next_contact(dst, now).map(|contact| contact.start);
+        assert_eq!(start("sat", 0), Some(100));
+        // A contact opening now is open, not upcoming.
+        assert_eq!(start("sat", 100), Some(150));
+        assert_eq!(start("sat", 150), None);
+        assert_eq!(start("probe", 450), None);
+
+        // Same start: the wider contact.
+        let tied = RouteTable::new(Vec::new()).with_contacts(alloc::vec![
+            contact("sat", "gs3", 100, 200, 10),
+            contact("sat", "gs2", 100, 200, 1_000),
+        ]);
+        assert_eq!(tied.next_contact("sat", 0).unwrap().next_hop, "gs2");
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn contact_closed_only_for_next_hops_without_a_static_route() {
+        let routes = scheduled_routes();
+        assert!(routes.contact_closed("gs2", 99));
+        assert!(!routes.contact_closed("gs2", 100));
+        assert!(routes.contact_closed("gs2", 200));
+        // gs3 has a contact open from 150 to 300 and another from 400.
+        assert!(!routes.contact_closed("gs3", 299));
+        assert!(routes.contact_closed("gs3", 300));
+        assert!(!routes.contact_closed("gs3", 400));
+        // A static next hop, and one the table does not know.
+        assert!(!routes.contact_closed("gs1", 0));
+        assert!(!routes.contact_closed("gs9", 0));
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn at_resolves_contacts_into_a_static_table() {
+        let routes = scheduled_routes();
+        let hop = |table: &RouteTable, dst| table.next_hop(dst).map(String::from);
+        let now = routes.at(150, false);
+        assert_eq!(hop(&now, "sat").as_deref(), Some("gs2"));
+        assert_eq!(hop(&now, "probe"), None);
+        assert!(now.contacts.is_empty());
+
+        // With `upcoming`, probe waits for the contact that opens at 400.
+        let held = routes.at(150, true);
+        assert_eq!(hop(&held, "probe").as_deref(), Some("gs3"));
+        // After the last contact there is nothing to wait for.
+        assert_eq!(hop(&routes.at(500, true), "probe"), None);
+        assert_eq!(hop(&routes.at(500, true), "sat").as_deref(), Some("gs1"));
+
+        assert_eq!(routes.next_hops(), ["gs1", "gs2", "gs3"]);
+    }
+}
@@ -0,0 +1,487 @@
+//! `handle_envelope` over `scrap_core_lite::fixed`, for nodes without an
+//! allocator.
+
+use crate::{dev_token_checks, digest, route, ReplayCache, Route, TaskClaims, DETAIL_REPLAY};
+use heapless::{String, Vec};
+use scrap_core_lite::fixed::{
+    build_task_rejected, build_task_result, fixed_bytes, fixed_str, Envelope, NodeId, Payload,
+    RouteTable, TaskRequest, TaskResult, Telemetry, MAX_NEXT_HOP_LEN,
+};
+use scrap_core_lite::{
+    DecodeError, MAX_DETAILS, MAX_DETAIL_LEN, MSG_HEARTBEAT, MSG_TASK_REQUEST, TOKEN_ID_LEN,
+};
+
+pub type Details = Vec<String<MAX_DETAIL_LEN>, MAX_DETAILS>;
+pub type TokenId = Vec<u8, TOKEN_ID_LEN>;
+
+pub trait TokenVerifier {
+    /// Checks `task.token` against this node (`expected_audience`) and the
+    /// requested command, appending one detail per failed check.
+    fn verify(
+        &self,
+        task: &TaskRequest,
+        expected_audience: &str,
+        now: u64,
+        revoked: &[TokenId],
+        expected_commander_pubkey: Option<&str>,
+        details: &mut Details,
+    );
+}
+
+/// The checks of the Linux node's dev verifier: no signature verification,
+/// so tokens are only accepted with `allow_mock_signatures`.
+#[derive(Debug)]
+pub struct DevTokenVerifier {
+    pub allow_mock_signatures: bool,
+}
+
+impl TokenVerifier for DevTokenVerifier {
+    fn verify(
+        &self,
+        task: &TaskRequest,
+        expected_audience: &str,
+        now: u64,
+        revoked: &[TokenId],
+        expected_commander_pubkey: Option<&str>,
+        details: &mut Details,
+    ) {
+        let token = &task.token;
+        let claims = TaskClaims {
+            token_id: &token.token_id,
+            subject: &token.subject,
+            audience: &token.audience,
+            capability: &token.capability,
+            expires_at: token.expires_at,
+            command: &task.command,
+            commander_pubkey: &task.commander_pubkey,
+        };
+        dev_token_checks(
+            self.allow_mock_signatures,
+            &claims,
+            expected_audience,
+            now,
+            revoked.contains(&token.token_id),
+            expected_commander_pubkey,
+            |detail| push_detail(details, detail),
+        );
+    }
+}
+
+#[derive(Debug)]
+pub struct Context<'a, R: ReplayCache, V: TokenVerifier> {
+    pub node_id: &'a str,
+    pub routes: &'a RouteTable,
+    pub replay: &'a mut R,
+    pub revoked: &'a [TokenId],
+    pub commander_pubkey: Option<&'a str>,
+    pub verifier: &'a V,
+}
+
+// Like `Payload`, the variants are bounded by the fixed capacities.
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug)]
+pub enum Action {
+    Forward {
+        next_hop: String<MAX_NEXT_HOP_LEN>,
+        envelope: Envelope,
+    },
+    Execute {
+        task: TaskRequest,
+        envelope: Envelope,
+    },
+    Reply {
+        envelope: Envelope,
+    },
+    Drop,
+}
+
+/// Stands in for the details that did not fit in a reply.
+pub const DETAIL_OMITTED: &str = "further details omitted";
+
+/// Appends `detail`, cut to `MAX_DETAIL_LEN` bytes. Once `MAX_DETAILS` are
+/// collected the last one becomes `DETAIL_OMITTED`, so a full reply still
+/// shows that more checks failed.
+pub fn push_detail(details: &mut Details, detail: &str) {
+    if details.push(fit_detail(detail)).is_err() {
+        if let Some(last) = details.last_mut() {
+            *last = fit_detail(DETAIL_OMITTED);
+        }
+    }
+}
+
+fn fit_detail(detail: &str) -> String<MAX_DETAIL_LEN> {
+    let mut end = detail.len().min(MAX_DETAIL_LEN);
+    while !detail.is_char_boundary(end) {
+        end -= 1;
+    }
+    fixed_str(&detail[..end]).unwrap_or_default()
+}
+
+fn reject(
+    node_id: &str,
+    env: &Envelope,
+    dst: NodeId,
+    hop_limit: u8,
+    reason: &str,
+    details: Details,
+) -> Action {
+    let (Ok(src), Ok(reason)) = (fixed_str(node_id), fixed_str(reason)) else {
+        return Action::Drop;
+    };
+    let envelope = build_task_rejected(env.trace_id.clone(), src, dst, hop_limit, reason, details);
+    Action::Reply { envelope }
+}
+
+pub fn handle_envelope<R: ReplayCache, V: TokenVerifier>(
+    ctx: &mut Context<'_, R, V>,
+    mut env: Envelope,
+    now: u64,
+) -> Action {
+    // As with the owned types, heartbeats are the caller's.
+    if env.msg_type == MSG_HEARTBEAT {
+        return Action::Drop;
+    }
+    match route(ctx.node_id, ctx.routes, &env.dst, env.hop_limit) {
+        Route::Local => {}
+        Route::Forward {
+            next_hop,
+            hop_limit,
+        } => {
+            let Ok(next_hop) = fixed_str(next_hop) else {
+                return Action::Drop;
+            };
+            env.hop_limit = hop_limit;
+            return Action::Forward {
+                next_hop,
+                envelope: env,
+            };
+        }
+        Route::Reject {
+            reason,
+            detail,
+            hop_limit,
+        } => {
+            let mut details = Details::new();
+            push_detail(&mut details, detail);
+            return reject(
+                ctx.node_id,
+                &env,
+                env.src.clone(),
+                hop_limit,
+                reason,
+                details,
+            );
+        }
+    }
+
+    if env.msg_type != MSG_TASK_REQUEST {
+        return Action::Drop;
+    }
+
+    let task = match &env.payload {
+        Payload::TaskRequest(task) => task.clone(),
+        _ => return Action::Drop,
+    };
+
+    // Command and args lengths are bounded by the fixed-capacity types.
+    let mut details = Details::new();
+    ctx.verifier.verify(
+        &task,
+        ctx.node_id,
+        now,
+        ctx.revoked,
+        ctx.commander_pubkey,
+        &mut details,
+    );
+
+    if details.is_empty() && !ctx.replay.check_and_add(&task.token.token_id) {
+        push_detail(&mut details, DETAIL_REPLAY);
+    }
+
+    if !details.is_empty() {
+        return reject(
+            ctx.node_id,
+            &env,
+            task.reply_to.clone(),
+            env.hop_limit,
+            "validation_failed",
+            details,
+        );
+    }
+
+    Action::Execute {
+        task,
+        envelope: env,
+    }
+}
+
+/// Builds the result for an executed `envelope`, carrying the digest of
+/// `output`, addressed to the task's reply_to.
+pub fn build_result_envelope(
+    node_id: &str,
+    envelope: &Envelope,
+    task: &TaskRequest,
+    status: u8,
+    output: u64,
+    duration_ms: u32,
+) -> Result<Envelope, DecodeError> {
+    let src = fixed_str(node_id)?;
+    let result = TaskResult {
+        status,
+        output_digest: fixed_bytes(&digest(output))?,
+        telemetry: Telemetry {
+            duration_ms,
+            node_id: src.clone(),
+        },
+    };
+    Ok(build_task_result(
+        envelope.trace_id.clone(),
+        src,
+        task.reply_to.clone(),
+        envelope.hop_limit,
+        result,
+    ))
+}
+
+pub fn parse_command(task: &TaskRequest) -> Option<(&str, u64)> {
+    let cmd = task.command.as_str();
+    if cmd == "demo.hash" || cmd == "demo.sleep" {
+        let arg = task.args.parse::<u64>().ok()?;
+        return Some((cmd, arg));
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scrap_core_lite::fixed::{build_heartbeat, build_task_request, fixed_str, Token};
+    use scrap_core_lite::TRACE_ID_LEN;
+
+    #[derive(Default)]
+    struct SeenOnce(Vec<TokenId, 4>);
+
+    impl ReplayCache for SeenOnce {
+        fn check_and_add(&mut self, token_id: &[u8]) -> bool {
+            if self.0.iter().any(|seen| seen.as_slice() == token_id) {
+                return false;
+            }
+            self.0.push(fixed_bytes(token_id).unwrap()).is_ok()
+        }
+    }
+
+    fn request(dst: &str, hop_limit: u8, expires_at: u64) -> Envelope {
+        let task = TaskRequest {
+            token: Token {
+                token_id: fixed_bytes(&[1; TOKEN_ID_LEN]).unwrap(),
+                subject: fixed_str("cmd").unwrap(),
+                audience: fixed_str(dst).unwrap(),
+                capability: fixed_str("demo.hash").unwrap(),
+                issued_at: 100,
+                expires_at,
+            },
+            command: fixed_str("demo.hash").unwrap(),
+            args: fixed_str("7").unwrap(),
+            reply_to: fixed_str("cmd").unwrap(),
+            commander_pubkey: fixed_str("cmd").unwrap(),
+        };
+        build_task_request(
+            fixed_bytes(&[7; TRACE_ID_LEN]).unwrap(),
+            fixed_str("cmd").unwrap(),
+            fixed_str(dst).unwrap(),
+            hop_limit,
+            task,
+        )
+    }
+
+    fn rejection(action: &Action) -> (&str, usize, u8) {
+        match action {
+            Action::Reply { envelope } => match &envelope.payload {
+                Payload::TaskRejected(rejected) => (
+                    rejected.reason.as_str(),
+                    rejected.details.len(),
+                    envelope.hop_limit,
+                ),
+                other => panic!("expected a rejection, got {other:?}"),
+            },
+            other => panic!("expected a reply, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn push_detail_flags_what_does_not_fit() {
+        // "a" then two-byte characters, so the cut lands inside one and has
+        // to back off to a boundary.
+        let mut long: String<{ MAX_DETAIL_LEN + 2 }> = String::new();
+        long.push('a').unwrap();
+        while long.push('é').is_ok() {}
+        assert_eq!(long.len(), MAX_DETAIL_LEN + 1);
+        let mut details = Details::new();
+        push_detail(&mut details, &long);
+        assert_eq!(details[0].len(), MAX_DETAIL_LEN - 1);
+        assert!(long.starts_with(details[0].as_str()));
+
+        for _ in 1..MAX_DETAILS {
+            push_detail(&mut details, "token expired");
+        }
+        assert_eq!(details[MAX_DETAILS - 1], "token expired");
+        push_detail(&mut details, "token revoked");
+        assert_eq!(details.len(), MAX_DETAILS);
+        assert_eq!(details[MAX_DETAILS - 1], DETAIL_OMITTED);
+    }
+
+    #[test]
+    fn handle_envelope_routes_verifies_and_checks_replays() {
+        let mut routes = RouteTable::new();
+        routes.add("exec", "10.0.0.3:7227").unwrap();
+        let mut replay = SeenOnce::default();
+        let verifier = DevTokenVerifier {
+            allow_mock_signatures: true,
+        };
+        let mut ctx = Context {
+            node_id: "relay",
+            routes: &routes,
+            replay: &mut replay,
+            revoked: &[],
+            commander_pubkey: None,
+            verifier: &verifier,
+        };
+
+        match handle_envelope(&mut ctx, request("exec", 24, 200), 150) {
+            Action::Forward { next_hop, envelope } => {
+                assert_eq!(next_hop, "10.0.0.3:7227");
+                assert_eq!(envelope, request("exec", 23, 200));
+            }
+            other => panic!("expected a forward, got {other:?}"),
+        }
+        let action = handle_envelope(&mut ctx, request("exec", 0, 200), 150);
+        assert_eq!(rejection(&action), ("hop_limit_exceeded", 1, 0));
+        let action = handle_envelope(&mut ctx, request("ground", 5, 200), 150);
+        assert_eq!(rejection(&action), ("no_route", 1, 4));
+        let heartbeat = build_heartbeat(fixed_str("exec").unwrap(), 150);
+        assert!(matches!(
+            handle_envelope(&mut ctx, heartbeat, 150),
+            Action::Drop
+        ));
+
+        // Expired and for another audience: both checks are reported.
+        let action = handle_envelope(&mut ctx, request("relay", 5, 100), 150);
+        let mut wrong_audience = request("relay", 5, 100);
+        if let Payload::TaskRequest(task) = &mut wrong_audience.payload {
+            task.token.audience = fixed_str("exec").unwrap();
+        }
+        assert_eq!(rejection(&action), ("validation_failed", 1, 5));
+        let action = handle_envelope(&mut ctx, wrong_audience, 150);
+        assert_eq!(rejection(&action), ("validation_failed", 2, 5));
+
+        let env = request("relay", 5, 200);
+        assert!(matches!(
+            handle_envelope(&mut ctx, env.clone(), 150),
+            Action::Execute { .. }
+        ));
+        let action = handle_envelope(&mut ctx, env, 150);
+        assert_eq!(rejection(&action), ("validation_failed", 1, 5));
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn fixed_and_owned_dev_verifiers_agree() {
+        use alloc::string::{String, ToString};
+        use scrap_core_lite::fixed::encode_envelope;
+
+        struct OwnedDev;
+
+        impl crate::TokenVerifier for OwnedDev {
+            fn verify(
+                &self,
+                token: &scrap_core_lite::Token,
+                commander_pubkey: &str,
+                expected_audience: &str,
+                required_capability: &str,
+                now: u64,
+                revoked: &[alloc::vec::Vec<u8>],
+                expected_commander_pubkey: Option<&str>,
+            ) -> Result<(), alloc::vec::Vec<String>> {
+                let claims = TaskClaims {
+                    token_id: &token.token_id,
+                    subject: &token.subject,
+                    audience: &token.audience,
+                    capability: &token.capability,
+                    expires_at: token.expires_at,
+                    command: required_capability,
+                    commander_pubkey,
+                };
+                let mut details = alloc::vec::Vec::new();
+                dev_token_checks(
+                    false,
+                    &claims,
+                    expected_audience,
+                    now,
+                    revoked.contains(&token.token_id),
+                    expected_commander_pubkey,
+                    |detail| details.push(detail.to_string()),
+                );
+                if details.is_empty() {
+                    Ok(())
+                } else {
+                    Err(details)
+                }
+            }
+        }
+
+        let mut env = request("relay", 5, 100);
+        if let Payload::TaskRequest(task) = &mut env.payload {
+            task.commander_pubkey = fixed_str("other").unwrap();
+        }
+        let mut buf = [0u8; 512];
+        let len = encode_envelope(&env, &mut buf).unwrap();
+
+        let revoked = [fixed_bytes(&[1; TOKEN_ID_LEN]).unwrap()];
+        let fixed_routes = RouteTable::new();
+        let mut fixed_replay = SeenOnce::default();
+        let fixed_action = handle_envelope(
+            &mut Context {
+                node_id: "relay",
+                routes: &fixed_routes,
+                replay: &mut fixed_replay,
+                revoked: &revoked,
+                commander_pubkey: Some("cmd"),
+                verifier: &DevTokenVerifier {
+                    allow_mock_signatures: false,
+                },
+            },
+            env,
+            150,
+        );
+
+        let owned_routes = scrap_core_lite::RouteTable::new(alloc::vec::Vec::new());
+        let owned_revoked = [alloc::vec![1; TOKEN_ID_LEN]];
+        let owned_action = crate::handle_envelope(
+            &mut crate::Context {
+                node_id: "relay",
+                routes: &owned_routes,
+                replay: &mut SeenOnce::default(),
+                revoked: &owned_revoked,
+                commander_pubkey: Some("cmd"),
+                allow_mock_signatures: false,
+                verifier: &OwnedDev,
+            },
+            scrap_core_lite::decode_envelope(&buf[..len]).unwrap(),
+            150,
+        );
+
+        let (Action::Reply { envelope: fixed }, crate::Action::Reply { envelope: owned }) =
+            (fixed_action, owned_action)
+        else {
+            panic!("expected both verifiers to reject");
+        };
+        let len = encode_envelope(&fixed, &mut buf).unwrap();
+        let mut owned_bytes = alloc::vec::Vec::new();
+        scrap_core_lite::encode_envelope(&owned, &mut owned_bytes).unwrap();
+        assert_eq!(&buf[..len], owned_bytes.as_slice());
+        assert_eq!(
+            rejection(&Action::Reply { envelope: fixed }),
+            ("validation_failed", 5, 5)
+        );
+    }
+}
@@ -1,14 +1,23 @@
 #![no_std]
 
+#[cfg(feature = "alloc")]
 extern crate alloc;
 
+#[cfg(feature = "heapless")]
+pub mod fixed;
+
+#[cfg(feature = "alloc")]
 use alloc::string::{String, ToString};
+#[cfg(feature = "alloc")]
 use alloc::vec;
+#[cfg(feature = "alloc")]
 use alloc::vec::Vec;
+#[cfg(feature = "alloc")]
 use scrap_core_lite::{
     build_task_rejected, Envelope, Payload, RouteTable, TaskRequest, TaskResult, Token,
-    MAX_ARGS_LEN, MAX_COMMAND_LEN, MAX_NODE_ID_LEN, MSG_TASK_REQUEST,
+    MAX_ARGS_LEN, MAX_COMMAND_LEN, MSG_HEARTBEAT, MSG_TASK_REQUEST,
 };
+use scrap_core_lite::{read_route_header, set_hop_limit, Routes, MAX_NODE_ID_LEN};
 
 pub const DETAIL_SUBJECT_MISMATCH: &str = "token subject does not match commander_pubkey";
 pub const DETAIL_REPLAY: &str = "replay detected (token_id already used)";
@@ -17,6 +26,7 @@ pub trait ReplayCache {
     fn check_and_add(&mut self, token_id: &[u8]) -> bool;
 }
 
+#[cfg(feature = "alloc")]
 pub trait TokenVerifier {
     fn verify(
         &self,
@@ -30,6 +40,7 @@ pub trait TokenVerifier {
     ) -> Result<(), Vec<String>>;
 }
 
+#[cfg(feature = "alloc")]
 #[derive(Debug)]
 pub struct Context<'a, R: ReplayCache, V: TokenVerifier> {
     pub node_id: &'a str,
@@ -41,6 +52,7 @@ pub struct Context<'a, R: ReplayCache, V: TokenVerifier> {
     pub verifier: &'a V,
 }
 
+#[cfg(feature = "alloc")]
 #[derive(Debug)]
 pub enum Action {
     Forward { next_hop: String, envelope: Envelope },
@@ -49,39 +61,43 @@ pub enum Action {
     Drop,
 }
 
+#[cfg(feature = "alloc")]
 pub fn handle_envelope<R: ReplayCache, V: TokenVerifier>(
     ctx: &mut Context<'_, R, V>,
     mut env: Envelope,
     now: u64,
 ) -> Action {
-    if env.dst != ctx.node_id {
-        if env.hop_limit == 0 {
+    // Heartbeats belong to the link they arrived on; the caller handles them.
+    if env.msg_type == MSG_HEARTBEAT {
+        return Action::Drop;
+    }
+    match route(ctx.node_id, ctx.routes, &env.dst, env.hop_limit) {
+        Route::Local => {}
+        Route::Forward {
+            next_hop,
+            hop_limit,
+        } => {
+            env.hop_limit = hop_limit;
+            return Action::Forward {
+                next_hop: next_hop.to_string(),
+                envelope: env,
+            };
+        }
+        Route::Reject {
+            reason,
+            detail,
+            hop_limit,
+        } => {
             let reject = build_task_rejected(
                 env.trace_id.clone(),
                 ctx.node_id.to_string(),
                 env.src.clone(),
-                0,
-                "hop_limit_exceeded".to_string(),
-                vec!["hop limit exceeded".to_string()],
+                hop_limit,
+                reason.to_string(),
+                vec![detail.to_string()],
             );
             return Action::Reply { envelope: reject };
         }
-        env.hop_limit = env.hop_limit.saturating_sub(1);
-        if let Some(next_hop) = ctx.routes.next_hop(&env.dst) {
-            return Action::Forward {
-                next_hop: next_hop.to_string(),
-                envelope: env,
-            };
-        }
-        let reject = build_task_rejected(
-            env.trace_id.clone(),
-            ctx.node_id.to_string(),
-            env.src.clone(),
-            env.hop_limit,
-            "no_route".to_string(),
-            vec!["no route to destination".to_string()],
-        );
-        return Action::Reply { envelope: reject };
     }
 
     if env.msg_type != MSG_TASK_REQUEST {
@@ -134,6 +150,128 @@ pub fn handle_envelope<R: ReplayCache, V: TokenVerifier>(
     Action::Execute { task, envelope: env }
 }
 
+/// Relay fast path: when the encoded envelope in `data` is for another node,
+/// has hops left and a route, decrements its hop_limit in place and returns
+/// the next hop. On `None` `data` is unchanged and `handle_envelope` decides,
+/// including any reject.
+pub fn forward_in_place<'r, T: Routes + ?Sized>(
+    node_id: &str,
+    routes: &'r T,
+    data: &mut [u8],
+) -> Option<&'r str> {
+    let header = read_route_header(data).ok()?;
+    let Route::Forward {
+        next_hop,
+        hop_limit,
+    } = route(node_id, routes, header.dst, header.hop_limit)
+    else {
+        return None;
+    };
+    set_hop_limit(data, header.hop_limit_at, hop_limit).ok()?;
+    Some(next_hop)
+}
+
+/// Where an envelope goes, decided from its `dst` and `hop_limit` alone.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Route<'r> {
+    Local,
+    /// `hop_limit` is already decremented.
+    Forward {
+        next_hop: &'r str,
+        hop_limit: u8,
+    },
+    /// Reply to the sender with `reason` and the single `detail`.
+    Reject {
+        reason: &'static str,
+        detail: &'static str,
+        hop_limit: u8,
+    },
+}
+
+/// The routing step of both `handle_envelope`s and `forward_in_place`.
+pub fn route<'r, T: Routes + ?Sized>(
+    node_id: &str,
+    routes: &'r T,
+    dst: &str,
+    hop_limit: u8,
+) -> Route<'r> {
+    if dst == node_id {
+        return Route::Local;
+    }
+    if hop_limit == 0 {
+        return Route::Reject {
+            reason: "hop_limit_exceeded",
+            detail: "hop limit exceeded",
+            hop_limit: 0,
+        };
+    }
+    let hop_limit = hop_limit - 1;
+    match routes.next_hop(dst) {
+        Some(next_hop) => Route::Forward {
+            next_hop,
+            hop_limit,
+        },
+        None => Route::Reject {
+            reason: "no_route",
+            detail: "no route to destination",
+            hop_limit,
+        },
+    }
+}
+
+/// The fields of a task request that the dev verifiers check, borrowed from
+/// either the owned or the fixed-capacity types.
+#[derive(Clone, Copy, Debug)]
+pub struct TaskClaims<'a> {
+    pub token_id: &'a [u8],
+    pub subject: &'a str,
+    pub audience: &'a str,
+    pub capability: &'a str,
+    pub expires_at: u64,
+    pub command: &'a str,
+    pub commander_pubkey: &'a str,
+}
+
+/// The checks of the dev verifiers, which do not verify signatures and so
+/// accept tokens only with `allow_mock_signatures`. Calls `fail` once per
+/// failed check, in a fixed order.
+pub fn dev_token_checks(
+    allow_mock_signatures: bool,
+    task: &TaskClaims<'_>,
+    expected_audience: &str,
+    now: u64,
+    revoked: bool,
+    expected_commander_pubkey: Option<&str>,
+    mut fail: impl FnMut(&'static str),
+) {
+    let mut check = |failed: bool, detail| {
+        if failed {
+            fail(detail);
+        }
+    };
+
+    check(
+        !allow_mock_signatures,
+        "signature verification not implemented",
+    );
+    check(task.expires_at < now, "token expired");
+    check(
+        task.subject != task.commander_pubkey,
+        DETAIL_SUBJECT_MISMATCH,
+    );
+    check(
+        expected_commander_pubkey.is_some_and(|expected| task.commander_pubkey != expected),
+        "commander_pubkey not authorized",
+    );
+    check(
+        task.audience != expected_audience,
+        "token audience mismatch",
+    );
+    check(task.capability != task.command, "token capability mismatch");
+    check(revoked, "token revoked");
+}
+
+#[cfg(feature = "alloc")]
 pub fn build_result_envelope(
     trace_id: Vec<u8>,
     src: String,
@@ -154,6 +292,7 @@ pub fn build_result_envelope(
     scrap_core_lite::build_task_result(trace_id, src, dst, hop_limit, result)
 }
 
+#[cfg(feature = "alloc")]
 pub fn parse_command(task: &TaskRequest) -> Option<(&str, u64)> {
     let cmd = task.command.as_str();
     if cmd == "demo.hash" || cmd == "demo.sleep" {
@@ -163,12 +302,18 @@ pub fn parse_command(task: &TaskRequest) -> Option<(&str, u64)> {
     None
 }
 
+#[cfg(feature = "alloc")]
 pub fn simple_digest(input: u64) -> Vec<u8> {
+    digest(input).to_vec()
+}
+
+/// `simple_digest` without the allocation.
+pub fn digest(input: u64) -> [u8; 32] {
     let mut acc = input ^ 0xA5A5_A5A5_A5A5_A5A5u64;
-    let mut out = Vec::with_capacity(32);
-    for _ in 0..4 {
+    let mut out = [0u8; 32];
+    for chunk in out.chunks_exact_mut(8) {
         acc = acc.wrapping_mul(6364136223846793005).wrapping_add(1);
-        out.extend_from_slice(&acc.to_be_bytes());
+        chunk.copy_from_slice(&acc.to_be_bytes());
     }
     out
 }
@@ -177,6 +322,158 @@ pub fn validate_node_id(node_id: &str) -> bool {
     !node_id.is_empty() && node_id.len() <= MAX_NODE_ID_LEN
 }
 
+#[cfg(feature = "alloc")]
 pub fn validate_token_subject(token: &Token) -> bool {
     !token.subject.is_empty() && token.subject.len() <= MAX_NODE_ID_LEN
 }
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use super::*;
+    use scrap_core_lite::{
+        build_task_request, decode_envelope, encode_envelope, RouteEntry, TOKEN_ID_LEN,
+        TRACE_ID_LEN,
+    };
+
+    struct AcceptAll;
+
+    impl ReplayCache for AcceptAll {
+        fn check_and_add(&mut self, _token_id: &[u8]) -> bool {
+            true
+        }
+    }
+
+    impl TokenVerifier for AcceptAll {
+        fn verify(
+            &self,
+            _token: &Token,
+            _commander_pubkey: &str,
+            _expected_audience: &str,
+            _required_capability: &str,
+            _now: u64,
+            _revoked: &[Vec<u8>],
+            _expected_commander_pubkey: Option<&str>,
+        ) -> Result<(), Vec<String>> {
+            Ok(())
+        }
+    }
+
+    fn routes() -> RouteTable {
+        RouteTable::new(vec![RouteEntry {
+            dst: "exec".to_string(),
+            next_hop: "10.0.0.3:7227".to_string(),
+        }])
+    }
+
+    fn relay_envelope(hop_limit: u8) -> Vec<u8> {
+        let task = TaskRequest {
+            token: Token {
+                token_id: vec![1; TOKEN_ID_LEN],
+                subject: "cmd".to_string(),
+                audience: "exec".to_string(),
+                capability: "demo.hash".to_string(),
+                issued_at: 100,
+                expires_at: 200,
+            },
+            command: "demo.hash".to_string(),
+            args: "7".to_string(),
+            reply_to: "cmd".to_string(),
+            commander_pubkey: "cmd".to_string(),
+        };
+        let env = build_task_request(
+            vec![7; TRACE_ID_LEN],
+            "cmd".to_string(),
+            "exec".to_string(),
+            hop_limit,
+            task,
+        );
+        let mut out = Vec::new();
+        encode_envelope(&env, &mut out).unwrap();
+        out
+    }
+
+    /// Decode, `handle_envelope` and re-encode, as a relay does when the fast
+    /// path declines.
+    fn slow_path(data: &[u8]) -> (String, Vec<u8>) {
+        let routes = routes();
+        let mut ctx = Context {
+            node_id: "relay",
+            routes: &routes,
+            replay: &mut AcceptAll,
+            revoked: &[],
+            commander_pubkey: None,
+            allow_mock_signatures: false,
+            verifier: &AcceptAll,
+        };
+        match handle_envelope(&mut ctx, decode_envelope(data).unwrap(), 150) {
+            Action::Forward { next_hop, envelope } => {
+                let mut out = Vec::new();
+                encode_envelope(&envelope, &mut out).unwrap();
+                (next_hop, out)
+            }
+            other => panic!("expected a forward, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn forward_in_place_matches_the_slow_path() {
+        for hop_limit in [1, 10, 23, 25, 255] {
+            let original = relay_envelope(hop_limit);
+            let mut data = original.clone();
+            let routes = routes();
+            let next_hop = forward_in_place("relay", &routes, &mut data);
+            assert_eq!(next_hop, Some("10.0.0.3:7227"));
+
+            let (slow_next_hop, slow) = slow_path(&original);
+            assert_eq!(slow_next_hop, "10.0.0.3:7227");
+            assert_eq!(data, slow);
+
+            let mut forwarded = decode_envelope(&data).unwrap();
+            assert_eq!(forwarded.hop_limit, hop_limit - 1);
+            forwarded.hop_limit = hop_limit;
+            assert_eq!(forwarded, decode_envelope(&original).unwrap());
+        }
+    }
+
+    #[test]
+    fn forward_in_place_leaves_the_width_boundary_to_the_slow_path() {
+        let original = relay_envelope(24);
+        let mut data = original.clone();
+        assert_eq!(forward_in_place("relay", &routes(), &mut data), None);
+        assert_eq!(data, original);
+
+        let (next_hop, slow) = slow_path(&original);
+        assert_eq!(next_hop, "10.0.0.3:7227");
+        assert_eq!(slow, relay_envelope(23));
+        assert_eq!(slow.len(), original.len() - 1);
+    }
+
+    #[test]
+    fn forward_in_place_declines_without_changing_the_frame() {
+        let routes = routes();
+        let original = relay_envelope(0);
+        let mut data = original.clone();
+        assert_eq!(forward_in_place("relay", &routes, &mut data), None);
+        assert_eq!(data, original);
+
+        let original = relay_envelope(5);
+        let mut data = original.clone();
+        assert_eq!(forward_in_place("exec", &routes, &mut data), None);
+        assert_eq!(
+            forward_in_place("relay", &RouteTable::new(vec![]), &mut data),
+            None
+        );
+        assert_eq!(data, original);
+
+        // hop_limit 5 spelled as 0x18 0x05 decodes but is not rewritten.
+        let at = read_route_header(&original).unwrap().hop_limit_at;
+        let mut non_minimal = original[..at].to_vec();
+        non_minimal.extend_from_slice(&[0x18, 0x05]);
+        non_minimal.extend_from_slice(&original[at + 1..]);
+        let mut data = non_minimal.clone();
+        assert_eq!(forward_in_place("relay", &routes, &mut data), None);
+        assert_eq!(data, non_minimal);
+        let (_, slow) = slow_path(&non_minimal);
+        assert_eq!(slow, relay_envelope(4));
+    }
+}
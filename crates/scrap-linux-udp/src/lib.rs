@@ -1,18 +1,30 @@
 use scrap_core_lite::{
-    decode_envelope, encode_envelope, Envelope, RouteEntry, RouteTable, MSG_TASK_REJECTED,
+    build_heartbeat, build_task_rejected, decode_envelope, decode_envelope_canonical,
+    decode_envelope_ref, encode_envelope, Contact, Envelope, Payload, RouteEntry, RouteTable,
+    MSG_HEARTBEAT, MSG_TASK_REJECTED,
 };
 use scrap_edge::{
-    handle_envelope, Action, Context, ReplayCache, TokenVerifier, DETAIL_SUBJECT_MISMATCH,
+    dev_token_checks, forward_in_place, handle_envelope, Action, Context, ReplayCache, TaskClaims,
+    TokenVerifier,
 };
 use serde::Deserialize;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 use std::fs;
 use std::fs::OpenOptions;
 use std::io::Write;
-use std::net::UdpSocket;
 use std::path::Path;
 use std::thread;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+pub mod serial;
+pub mod store_forward;
+pub mod transport;
+
+pub use serial::SerialTransport;
+pub use store_forward::{QueuedFrame, StoreForward, StoreForwardConfig};
+pub use transport::{
+    LoopbackNetwork, LoopbackTransport, LowpanTransport, MeshTransport, Transport, UdpTransport,
+};
 
 #[derive(Debug, Deserialize)]
 pub struct RoutesFile {
@@ -21,7 +33,34 @@ pub struct RoutesFile {
 
 #[derive(Debug, Deserialize)]
 pub struct NodeRoutes {
+    #[serde(default)]
     pub routes: HashMap<String, String>,
+    #[serde(default)]
+    pub contacts: Vec<ContactEntry>,
+}
+
+/// One entry of a node's `contacts` list: `next_hop` reaches `dst` from
+/// `start` until `end` (unix seconds) at `bandwidth` bytes per second.
+#[derive(Debug, Deserialize)]
+pub struct ContactEntry {
+    pub dst: String,
+    pub next_hop: String,
+    pub start: u64,
+    pub end: u64,
+    #[serde(default)]
+    pub bandwidth: u64,
+}
+
+impl From<&ContactEntry> for Contact {
+    fn from(entry: &ContactEntry) -> Self {
+        Contact {
+            dst: entry.dst.clone(),
+            next_hop: entry.next_hop.clone(),
+            start: entry.start,
+            end: entry.end,
+            bandwidth: entry.bandwidth,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -30,10 +69,19 @@ pub struct NodeConfig {
     pub bind: String,
     pub port: u16,
     pub routes_path: String,
+    /// Extra contacts in the routes file schema, added to the route table.
+    pub contact_plan_path: Option<String>,
     pub commander_pubkey: Option<String>,
     pub replay_cache_path: String,
     pub revoked_path: String,
     pub allow_mock_signatures: bool,
+    /// `udp` (default), `6lowpan` or `serial`.
+    pub transport: String,
+    /// Network interface for 6lowpan (`lowpan0`), or the serial device
+    /// (`/dev/ttyACM0[@baud]`).
+    pub interface: Option<String>,
+    /// Queue traffic for next hops that are down instead of sending it.
+    pub store_forward: Option<StoreForwardConfig>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -42,12 +90,26 @@ struct NodeConfigFile {
     bind: Option<String>,
     port: Option<u16>,
     routes_path: Option<String>,
+    contact_plan_path: Option<String>,
     commander_pubkey: Option<String>,
     replay_cache_path: Option<String>,
     revoked_path: Option<String>,
     allow_mock_signatures: Option<bool>,
+    transport: Option<String>,
+    interface: Option<String>,
+    store_forward: Option<StoreForwardConfigFile>,
+}
+
+#[derive(Debug, Deserialize)]
+struct StoreForwardConfigFile {
+    queue_path: Option<String>,
+    heartbeat_interval_sec: Option<u64>,
+    link_timeout_sec: Option<u64>,
+    ttl_sec: Option<u64>,
 }
 
+pub const DEFAULT_QUEUE_PATH: &str = "demo/runtime/store_forward.json";
+
 #[derive(Debug, Clone)]
 pub struct OrchestratorConfig {
     pub node_id: String,
@@ -71,40 +133,25 @@ impl TokenVerifier for DevTokenVerifier {
         revoked: &[Vec<u8>],
         expected_commander_pubkey: Option<&str>,
     ) -> Result<(), Vec<String>> {
+        let claims = TaskClaims {
+            token_id: &token.token_id,
+            subject: &token.subject,
+            audience: &token.audience,
+            capability: &token.capability,
+            expires_at: token.expires_at,
+            command: required_capability,
+            commander_pubkey,
+        };
         let mut details = Vec::new();
-
-        if !self.allow_mock_signatures {
-            details.push("signature verification not implemented".to_string());
-        }
-
-        if token.expires_at < now {
-            details.push("token expired".to_string());
-        }
-
-        if token.subject != commander_pubkey {
-            details.push(DETAIL_SUBJECT_MISMATCH.to_string());
-        }
-
-        if let Some(expected) = expected_commander_pubkey {
-            if commander_pubkey != expected {
-                details.push("commander_pubkey not authorized".to_string());
-            }
-        }
-
-        if token.audience != expected_audience {
-            details.push("token audience mismatch".to_string());
-        }
-
-        if token.capability != required_capability {
-            details.push("token capability mismatch".to_string());
-        }
-
-        if revoked
-            .iter()
-            .any(|token_id| token_id.as_slice() == token.token_id.as_slice())
-        {
-            details.push("token revoked".to_string());
-        }
+        dev_token_checks(
+            self.allow_mock_signatures,
+            &claims,
+            expected_audience,
+            now,
+            revoked.contains(&token.token_id),
+            expected_commander_pubkey,
+            |detail| details.push(detail.to_string()),
+        );
 
         if details.is_empty() {
             Ok(())
@@ -208,7 +255,32 @@ pub fn load_routes(path: &str, node_id: &str) -> Result<RouteTable, String> {
             next_hop: next_hop.clone(),
         });
     }
-    Ok(RouteTable::new(entries))
+    let contacts = node_routes.contacts.iter().map(Contact::from).collect();
+    Ok(RouteTable::new(entries).with_contacts(contacts))
+}
+
+/// The contacts for `node_id` in a contact plan, a file in the routes file
+/// schema. A node missing from the plan has no contacts.
+pub fn load_contact_plan(path: &str, node_id: &str) -> Result<Vec<Contact>, String> {
+    let raw = fs::read_to_string(path).map_err(|e| format!("contact plan read failed: {e}"))?;
+    let plan: RoutesFile =
+        serde_json::from_str(&raw).map_err(|e| format!("contact plan parse failed: {e}"))?;
+    Ok(plan
+        .nodes
+        .get(node_id)
+        .map(|node| node.contacts.iter().map(Contact::from).collect())
+        .unwrap_or_default())
+}
+
+/// The node's routes file plus its contact plan, if it has one.
+pub fn load_node_routes(config: &NodeConfig) -> Result<RouteTable, String> {
+    let mut routes = load_routes(&config.routes_path, &config.node_id)?;
+    if let Some(path) = config.contact_plan_path.as_deref() {
+        routes
+            .contacts
+            .extend(load_contact_plan(path, &config.node_id)?);
+    }
+    Ok(routes)
 }
 
 pub fn load_node_config(path: &str) -> Result<NodeConfig, String> {
@@ -227,6 +299,7 @@ pub fn load_node_config(path: &str) -> Result<NodeConfig, String> {
         routes_path: cfg
             .routes_path
             .unwrap_or_else(|| "inventory/routes.json".to_string()),
+        contact_plan_path: cfg.contact_plan_path,
         commander_pubkey: cfg.commander_pubkey,
         replay_cache_path: cfg
             .replay_cache_path
@@ -235,6 +308,22 @@ pub fn load_node_config(path: &str) -> Result<NodeConfig, String> {
             .revoked_path
             .unwrap_or_else(|| "demo/config/revoked.json".to_string()),
         allow_mock_signatures: cfg.allow_mock_signatures.unwrap_or(false),
+        transport: cfg.transport.unwrap_or_else(|| "udp".to_string()),
+        interface: cfg.interface,
+        store_forward: cfg.store_forward.map(|sf| {
+            let defaults = StoreForwardConfig::default();
+            StoreForwardConfig {
+                queue_path: Some(
+                    sf.queue_path
+                        .unwrap_or_else(|| DEFAULT_QUEUE_PATH.to_string()),
+                ),
+                heartbeat_interval_sec: sf
+                    .heartbeat_interval_sec
+                    .unwrap_or(defaults.heartbeat_interval_sec),
+                link_timeout_sec: sf.link_timeout_sec.unwrap_or(defaults.link_timeout_sec),
+                ttl_sec: sf.ttl_sec.unwrap_or(defaults.ttl_sec),
+            }
+        }),
     })
 }
 
@@ -250,50 +339,249 @@ pub fn load_revoked(path: &str) -> Vec<Vec<u8>> {
     Vec::new()
 }
 
+/// Opens the transport named by `config.transport` and runs the node on it.
+/// The 6lowpan transport binds `[::]` on `config.port` and ignores `bind`.
+/// With udp, any `serial:` next hops in the route table are opened too.
 pub fn run_node(config: NodeConfig) -> Result<(), String> {
-    let routes = load_routes(&config.routes_path, &config.node_id)?;
-    let revoked = load_revoked(&config.revoked_path);
-    let mut replay_cache = FileReplayCache::new(config.replay_cache_path.clone());
-    let verifier = DevTokenVerifier {
-        allow_mock_signatures: config.allow_mock_signatures,
-    };
+    match config.transport.as_str() {
+        "udp" => {
+            let udp = UdpTransport::bind(&format!("{}:{}", config.bind, config.port))?;
+            let routes = load_node_routes(&config)?;
+            let serial_hops = routes
+                .next_hops()
+                .into_iter()
+                .filter(|next_hop| next_hop.starts_with("serial:"))
+                .collect::<Vec<_>>();
+            if serial_hops.is_empty() {
+                let mut transport = udp;
+                return run_node_on(&config, &mut transport);
+            }
+            let mut transport = MeshTransport::new(udp)?;
+            for next_hop in serial_hops {
+                transport.add_serial(next_hop)?;
+            }
+            run_node_on(&config, &mut transport)
+        }
+        "6lowpan" => {
+            let interface = config
+                .interface
+                .as_deref()
+                .ok_or_else(|| "6lowpan transport requires an interface".to_string())?;
+            let mut transport = LowpanTransport::bind(interface, config.port)?;
+            run_node_on(&config, &mut transport)
+        }
+        "serial" => {
+            let device = config
+                .interface
+                .as_deref()
+                .ok_or_else(|| "serial transport requires an interface".to_string())?;
+            let hop = format!("serial:{device}");
+            let (path, baud) = serial::parse_serial_hop(&hop)
+                .ok_or_else(|| format!("invalid serial device: {device}"))?;
+            let mut transport = SerialTransport::open(path, baud)?;
+            run_node_on(&config, &mut transport)
+        }
+        other => Err(format!("unknown transport: {other}")),
+    }
+}
 
-    let bind_addr = format!("{}:{}", config.bind, config.port);
-    let socket = UdpSocket::bind(&bind_addr).map_err(|e| format!("bind failed: {e}"))?;
+/// How often a store-and-forward node wakes without traffic.
+const STORE_FORWARD_TICK: Duration = Duration::from_secs(1);
+
+/// Runs the node loop until `transport.recv` fails.
+pub fn run_node_on<T: Transport>(config: &NodeConfig, transport: &mut T) -> Result<(), String> {
+    let mut node = Node::from_config(config)?;
 
     log_json("executor_started", serde_json::json!({
         "bind": config.bind,
         "port": config.port,
+        "transport": config.transport,
         "node_id": config.node_id,
         "allow_mock_signatures": config.allow_mock_signatures
     }));
 
-    let mut buf = [0u8; 2048];
+    let mut buf = [0u8; serial::MAX_FRAME_LEN];
     loop {
-        let (len, addr) = socket.recv_from(&mut buf).map_err(|e| format!("recv failed: {e}"))?;
-        let env = match decode_envelope(&buf[..len]) {
+        let received = if node.store_forward.is_some() {
+            let out = node.tick(unix_ts());
+            send_all(&mut node, transport, out);
+            transport.recv_timeout(&mut buf, STORE_FORWARD_TICK)?
+        } else {
+            Some(transport.recv(&mut buf)?)
+        };
+        let Some((len, source)) = received else {
+            continue;
+        };
+        let out = node.handle_frame(&mut buf[..len], &source, unix_ts());
+        send_all(&mut node, transport, out);
+    }
+}
+
+fn send_all<R: ReplayCache, C: TaskClock, T: Transport>(
+    node: &mut Node<R, C>,
+    transport: &mut T,
+    out: Vec<(String, Vec<u8>)>,
+) {
+    for (next_hop, frame) in out {
+        if let Err(err) = transport.send(&next_hop, &frame) {
+            if node.store_forward.is_some() {
+                log_json("send_failed", serde_json::json!({"next_hop": next_hop, "error": err}));
+                node.undelivered(next_hop, frame, unix_ts());
+            }
+        }
+    }
+    node.sent();
+}
+
+/// Time as seen by task execution. `run_node` uses the host's clock; the
+/// simulator supplies a virtual one so that its runs never wait and always
+/// report the same durations.
+pub trait TaskClock {
+    /// Milliseconds since some fixed point.
+    fn now_ms(&mut self) -> u64;
+    fn sleep(&mut self, duration: Duration);
+}
+
+/// The host's monotonic clock and `thread::sleep`.
+#[derive(Debug)]
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl TaskClock for SystemClock {
+    fn now_ms(&mut self) -> u64 {
+        u64::try_from(self.start.elapsed().as_millis()).unwrap_or(u64::MAX)
+    }
+
+    fn sleep(&mut self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+/// A node's state, independent of how frames reach it.
+pub struct Node<R: ReplayCache, C: TaskClock = SystemClock> {
+    pub node_id: String,
+    pub commander_pubkey: Option<String>,
+    routes: RouteTable,
+    /// `routes.next_hops()`, checked against the source of every frame.
+    next_hops: BTreeSet<String>,
+    pub revoked: Vec<Vec<u8>>,
+    pub replay: R,
+    pub verifier: DevTokenVerifier,
+    pub store_forward: Option<StoreForward>,
+    pub clock: C,
+}
+
+impl Node<FileReplayCache> {
+    pub fn from_config(config: &NodeConfig) -> Result<Self, String> {
+        let mut node = Node::new(
+            config.node_id.clone(),
+            load_node_routes(config)?,
+            FileReplayCache::new(config.replay_cache_path.clone()),
+            DevTokenVerifier {
+                allow_mock_signatures: config.allow_mock_signatures,
+            },
+            SystemClock::default(),
+        );
+        node.commander_pubkey = config.commander_pubkey.clone();
+        node.revoked = load_revoked(&config.revoked_path);
+        node.store_forward = config
+            .store_forward
+            .clone()
+            .map(StoreForward::open)
+            .transpose()?;
+        Ok(node)
+    }
+}
+
+impl<R: ReplayCache, C: TaskClock> Node<R, C> {
+    /// A node without a commander key, revocations or store-and-forward.
+    pub fn new(
+        node_id: String,
+        routes: RouteTable,
+        replay: R,
+        verifier: DevTokenVerifier,
+        clock: C,
+    ) -> Self {
+        let mut node = Self {
+            node_id,
+            commander_pubkey: None,
+            routes: RouteTable::new(Vec::new()),
+            next_hops: BTreeSet::new(),
+            revoked: Vec::new(),
+            replay,
+            verifier,
+            store_forward: None,
+            clock,
+        };
+        node.set_routes(routes);
+        node
+    }
+
+    pub fn routes(&self) -> &RouteTable {
+        &self.routes
+    }
+
+    pub fn set_routes(&mut self, routes: RouteTable) {
+        self.next_hops = routes.next_hops().into_iter().map(str::to_string).collect();
+        self.routes = routes;
+    }
+
+    /// Handles one received frame at unix time `now` and returns the frames
+    /// to send, each with its next hop.
+    pub fn handle_frame(
+        &mut self,
+        frame: &mut [u8],
+        source: &str,
+        now: u64,
+    ) -> Vec<(String, Vec<u8>)> {
+        let mut out = Vec::new();
+        self.heard(source, now, &mut out);
+        let scheduled = self.scheduled_routes(now);
+        let routes = scheduled.as_ref().unwrap_or(&self.routes);
+        if let Some(next_hop) = forward_in_place(&self.node_id, routes, frame) {
+            let next_hop = next_hop.to_string();
+            self.dispatch(next_hop, frame.to_vec(), now, &mut out);
+            return out;
+        }
+        let env = match decode_envelope_canonical(frame) {
             Ok(env) => env,
             Err(err) => {
-                log_json("invalid_cbor", serde_json::json!({"error": format!("{err}"), "source": addr.to_string()}));
-                continue;
+                log_json("invalid_cbor", serde_json::json!({"error": format!("{err}"), "source": source}));
+                return out;
             }
         };
 
+        if let Payload::Heartbeat(_) = env.payload {
+            if let Some(next_hop) = routes.next_hop(&env.src).map(str::to_string) {
+                self.heard(&next_hop, now, &mut out);
+            }
+            return out;
+        }
+
         let mut ctx = Context {
-            node_id: &config.node_id,
-            routes: &routes,
-            replay: &mut replay_cache,
-            revoked: &revoked,
-            commander_pubkey: config.commander_pubkey.as_deref(),
-            allow_mock_signatures: config.allow_mock_signatures,
-            verifier: &verifier,
+            node_id: &self.node_id,
+            routes,
+            replay: &mut self.replay,
+            revoked: &self.revoked,
+            commander_pubkey: self.commander_pubkey.as_deref(),
+            allow_mock_signatures: self.verifier.allow_mock_signatures,
+            verifier: &self.verifier,
         };
 
-        let now = unix_ts();
+        let mut sends = Vec::new();
         match handle_envelope(&mut ctx, env, now) {
             Action::Forward { next_hop, envelope } => {
                 if let Ok(payload) = encode_to_vec(&envelope) {
-                    let _ = socket.send_to(&payload, next_hop);
+                    sends.push((next_hop, payload));
                 }
             }
             Action::Reply { envelope } => {
@@ -306,7 +594,7 @@ pub fn run_node(config: NodeConfig) -> Result<(), String> {
                 }
                 if let Ok(payload) = encode_to_vec(&envelope) {
                     if let Some(next_hop) = routes.next_hop(&envelope.dst) {
-                        let _ = socket.send_to(&payload, next_hop);
+                        sends.push((next_hop.to_string(), payload));
                     }
                 }
             }
@@ -316,12 +604,14 @@ pub fn run_node(config: NodeConfig) -> Result<(), String> {
                     "command": task.command,
                     "dst": envelope.dst
                 }));
-                let start = std::time::Instant::now();
-                let (status, output_digest) = execute_stub(&task.command, &task.args);
-                let duration_ms = start.elapsed().as_millis() as u32;
+                let start = self.clock.now_ms();
+                let (status, output_digest) =
+                    execute_stub(&task.command, &task.args, &mut self.clock);
+                let duration_ms =
+                    u32::try_from(self.clock.now_ms().saturating_sub(start)).unwrap_or(u32::MAX);
                 let result = scrap_edge::build_result_envelope(
                     envelope.trace_id.clone(),
-                    config.node_id.clone(),
+                    self.node_id.clone(),
                     task.reply_to.clone(),
                     envelope.hop_limit,
                     status,
@@ -331,7 +621,7 @@ pub fn run_node(config: NodeConfig) -> Result<(), String> {
 
                 if let Ok(payload) = encode_to_vec(&result) {
                     if let Some(next_hop) = routes.next_hop(&result.dst) {
-                        let _ = socket.send_to(&payload, next_hop);
+                        sends.push((next_hop.to_string(), payload));
                     }
                 }
 
@@ -343,10 +633,172 @@ pub fn run_node(config: NodeConfig) -> Result<(), String> {
             }
             Action::Drop => {}
         }
+        for (next_hop, frame) in sends {
+            self.dispatch(next_hop, frame, now, &mut out);
+        }
+        out
+    }
+
+    /// Periodic store-and-forward work at unix time `now`: releases queues
+    /// whose next hop is up, heartbeats every next hop, and turns queued
+    /// requests past their token's expires_at into rejections for reply_to.
+    /// Returns nothing without `store_forward`.
+    pub fn tick(&mut self, now: u64) -> Vec<(String, Vec<u8>)> {
+        let mut out = Vec::new();
+        let Some(store) = self.store_forward.as_mut() else {
+            return out;
+        };
+        let expired = store.expire(now);
+        let heartbeat_due = store.heartbeat_due(now);
+        for next_hop in store.ready(now) {
+            if self.routes.contact_closed(&next_hop, now) {
+                continue;
+            }
+            let frames = store.take(&next_hop);
+            out.extend(frames.into_iter().map(|frame| (next_hop.clone(), frame)));
+        }
+        if heartbeat_due {
+            if let Ok(frame) = encode_to_vec(&build_heartbeat(self.node_id.clone(), now)) {
+                for next_hop in &self.next_hops {
+                    out.push((next_hop.clone(), frame.clone()));
+                }
+            }
+        }
+        for (next_hop, queued) in expired {
+            self.reject_expired(&next_hop, queued, now, &mut out);
+        }
+        out
+    }
+
+    /// Queues a frame the transport failed to send and marks its next hop
+    /// down. Heartbeats are dropped.
+    pub fn undelivered(&mut self, next_hop: String, frame: Vec<u8>, now: u64) {
+        let heartbeat = decode_envelope_ref(&frame).is_ok_and(|env| env.msg_type == MSG_HEARTBEAT);
+        let Some(store) = self.store_forward.as_mut() else {
+            return;
+        };
+        if heartbeat {
+            return;
+        }
+        store.mark_down(&next_hop);
+        let mut out = Vec::new();
+        self.dispatch(next_hop, frame, now, &mut out);
+    }
+
+    /// Called once the frames returned by `tick` or `handle_frame` have been
+    /// given to the transport, with any it failed to send passed to
+    /// `undelivered`. Only then are released frames dropped from disk.
+    pub fn sent(&mut self) {
+        if let Some(store) = self.store_forward.as_mut() {
+            store.sent();
+        }
+    }
+
+    /// Marks `next_hop` up if it is one of ours, releasing its queue when it
+    /// was down.
+    fn heard(&mut self, next_hop: &str, now: u64, out: &mut Vec<(String, Vec<u8>)>) {
+        let Some(store) = self.store_forward.as_mut() else {
+            return;
+        };
+        if !self.next_hops.contains(next_hop) {
+            return;
+        }
+        if store.observe(next_hop, now) && !self.routes.contact_closed(next_hop, now) {
+            let frames = store.take(next_hop);
+            log_json("link_up", serde_json::json!({
+                "next_hop": next_hop,
+                "released": frames.len()
+            }));
+            for frame in frames {
+                out.push((next_hop.to_string(), frame));
+            }
+        }
+    }
+
+    /// Sends `frame` now, or queues it while `next_hop` is down, outside its
+    /// contacts, or still has older frames waiting.
+    fn dispatch(
+        &mut self,
+        next_hop: String,
+        frame: Vec<u8>,
+        now: u64,
+        out: &mut Vec<(String, Vec<u8>)>,
+    ) {
+        let Some(store) = self.store_forward.as_mut() else {
+            out.push((next_hop, frame));
+            return;
+        };
+        if store.is_up(&next_hop, now)
+            && store.queued(&next_hop) == 0
+            && !self.routes.contact_closed(&next_hop, now)
+        {
+            out.push((next_hop, frame));
+            return;
+        }
+        let expires_at = match decode_envelope(&frame).map(|env| env.payload) {
+            Ok(Payload::TaskRequest(task)) => task.token.expires_at,
+            _ => now.saturating_add(store.config().ttl_sec),
+        };
+        store.push(&next_hop, frame, expires_at);
+        log_json("queued", serde_json::json!({
+            "next_hop": next_hop,
+            "queued": store.queued(&next_hop),
+            "expires_at": expires_at
+        }));
+    }
+
+    fn reject_expired(
+        &mut self,
+        next_hop: &str,
+        queued: QueuedFrame,
+        now: u64,
+        out: &mut Vec<(String, Vec<u8>)>,
+    ) {
+        let env = decode_envelope(&queued.frame).ok();
+        log_json("queue_expired", serde_json::json!({
+            "next_hop": next_hop,
+            "trace_id": env.as_ref().map(|env| hex_encode(&env.trace_id)),
+            "expires_at": queued.expires_at
+        }));
+        let Some(env) = env else {
+            return;
+        };
+        let Payload::TaskRequest(task) = &env.payload else {
+            return;
+        };
+        let reject = build_task_rejected(
+            env.trace_id.clone(),
+            self.node_id.clone(),
+            task.reply_to.clone(),
+            env.hop_limit,
+            "queue_expired".to_string(),
+            vec!["token expired while queued".to_string()],
+        );
+        let scheduled = self.scheduled_routes(now);
+        let routes = scheduled.as_ref().unwrap_or(&self.routes);
+        if let Ok(payload) = encode_to_vec(&reject) {
+            if let Some(next_hop) = routes.next_hop(&reject.dst).map(str::to_string) {
+                self.dispatch(next_hop, payload, now, out);
+            }
+        }
+    }
+
+    /// The routes in effect at `now` when the table has contacts. With
+    /// store-and-forward, destinations between contacts route to the next
+    /// contact and wait in its queue.
+    fn scheduled_routes(&self, now: u64) -> Option<RouteTable> {
+        if self.routes.contacts.is_empty() {
+            return None;
+        }
+        Some(self.routes.at(now, self.store_forward.is_some()))
     }
 }
 
-pub fn execute_stub(command: &str, args: &str) -> (u8, Vec<u8>) {
+pub fn execute_stub<C: TaskClock + ?Sized>(
+    command: &str,
+    args: &str,
+    clock: &mut C,
+) -> (u8, Vec<u8>) {
     match command {
         "demo.hash" => {
             let val = args.parse::<u64>().unwrap_or(0);
@@ -355,7 +807,7 @@ pub fn execute_stub(command: &str, args: &str) -> (u8, Vec<u8>) {
         "demo.sleep" => {
             let val = args.parse::<u64>().unwrap_or(0);
             let dur = Duration::from_millis(val.min(5000));
-            thread::sleep(dur);
+            clock.sleep(dur);
             (0, scrap_edge::simple_digest(val))
         }
         _ => (1, scrap_edge::simple_digest(0)),
@@ -375,7 +827,7 @@ fn unix_ts() -> u64 {
         .as_secs()
 }
 
-fn log_json(event: &str, payload: serde_json::Value) {
+pub(crate) fn log_json(event: &str, payload: serde_json::Value) {
     let log = serde_json::json!({
         "ts": unix_ts(),
         "event": event,
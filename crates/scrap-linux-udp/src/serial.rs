@@ -0,0 +1,480 @@
+//! Envelopes over a serial line. Each frame is the payload followed by a
+//! big-endian CRC-16/CCITT-FALSE, COBS-encoded and wrapped in 0x00
+//! delimiters, so a receiver that starts mid-stream or sees line noise
+//! resynchronises at the next delimiter.
+
+use crate::transport::{copy_frame, Transport};
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::time::{Duration, Instant};
+
+/// Largest payload a frame may carry; matches the node's receive buffer.
+pub const MAX_FRAME_LEN: usize = 2048;
+pub const DEFAULT_BAUD: u32 = 115_200;
+
+// Payload and CRC, plus one COBS code byte per 254 bytes.
+const MAX_ENCODED_LEN: usize = MAX_FRAME_LEN + 2 + MAX_FRAME_LEN.div_ceil(254) + 1;
+
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xFFFFu16;
+    for byte in data {
+        crc ^= u16::from(*byte) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+fn cobs_encode(data: &[u8], out: &mut Vec<u8>) {
+    let mut code_at = out.len();
+    let mut code = 1u8;
+    out.push(0);
+    for byte in data {
+        if *byte != 0 {
+            out.push(*byte);
+            code += 1;
+        }
+        if *byte == 0 || code == 0xFF {
+            out[code_at] = code;
+            code_at = out.len();
+            code = 1;
+            out.push(0);
+        }
+    }
+    out[code_at] = code;
+}
+
+fn cobs_decode(data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut pos = 0;
+    while pos < data.len() {
+        let code = usize::from(data[pos]);
+        let end = pos + code;
+        if code == 0 || end > data.len() {
+            return None;
+        }
+        out.extend_from_slice(&data[pos + 1..end]);
+        pos = end;
+        if code < 0xFF && pos < data.len() {
+            out.push(0);
+        }
+    }
+    Some(out)
+}
+
+/// Encodes one frame, delimiters included.
+pub fn encode_frame(payload: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(payload.len() + 2);
+    data.extend_from_slice(payload);
+    data.extend_from_slice(&crc16(payload).to_be_bytes());
+    let mut out = Vec::with_capacity(data.len() + data.len() / 254 + 3);
+    out.push(0);
+    cobs_encode(&data, &mut out);
+    out.push(0);
+    out
+}
+
+fn decode_frame(encoded: &[u8]) -> Option<Vec<u8>> {
+    let mut data = cobs_decode(encoded)?;
+    let split = data.len().checked_sub(2)?;
+    let crc = u16::from_be_bytes([data[split], data[split + 1]]);
+    data.truncate(split);
+    (crc16(&data) == crc).then_some(data)
+}
+
+/// Reassembles frames from arbitrarily split reads. Frames that are too
+/// long, fail COBS decoding or fail the CRC are counted in `dropped`.
+#[derive(Default)]
+pub struct FrameDecoder {
+    buf: Vec<u8>,
+    overflow: bool,
+    pub dropped: u64,
+}
+
+impl FrameDecoder {
+    pub fn push(&mut self, bytes: &[u8], frames: &mut VecDeque<Vec<u8>>) {
+        for byte in bytes {
+            if *byte != 0 {
+                if self.buf.len() < MAX_ENCODED_LEN {
+                    self.buf.push(*byte);
+                } else {
+                    self.overflow = true;
+                }
+                continue;
+            }
+            if self.buf.is_empty() && !self.overflow {
+                continue;
+            }
+            match decode_frame(&self.buf) {
+                Some(frame) if !self.overflow => frames.push_back(frame),
+                _ => self.dropped += 1,
+            }
+            self.buf.clear();
+            self.overflow = false;
+        }
+    }
+}
+
+/// A serial device or pty carrying framed envelopes. It is a point-to-point
+/// link, so `send` ignores the next hop beyond it having selected this port.
+pub struct SerialTransport {
+    port: File,
+    name: String,
+    decoder: FrameDecoder,
+    frames: VecDeque<Vec<u8>>,
+}
+
+impl SerialTransport {
+    /// Opens `path` in raw mode at `baud`.
+    pub fn open(path: &str, baud: u32) -> Result<Self, String> {
+        let port = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(path)
+            .map_err(|e| format!("open {path} failed: {e}"))?;
+        set_raw(&port, baud).map_err(|e| format!("configure {path} failed: {e}"))?;
+        Ok(Self::from_file(port, &format!("serial:{path}")))
+    }
+
+    /// Wraps an already configured port; `name` is reported as the source.
+    pub fn from_file(port: File, name: &str) -> Self {
+        Self {
+            port,
+            name: name.to_string(),
+            decoder: FrameDecoder::default(),
+            frames: VecDeque::new(),
+        }
+    }
+
+    pub fn try_clone(&self) -> Result<Self, String> {
+        let port = self
+            .port
+            .try_clone()
+            .map_err(|e| format!("clone {} failed: {e}", self.name))?;
+        Ok(Self::from_file(port, &self.name))
+    }
+
+    /// Frames discarded as corrupt since the port was opened.
+    pub fn dropped(&self) -> u64 {
+        self.decoder.dropped
+    }
+
+    fn fill(&mut self) -> Result<(), String> {
+        let mut chunk = [0u8; 256];
+        let read = self
+            .port
+            .read(&mut chunk)
+            .map_err(|e| format!("recv failed: {e}"))?;
+        if read == 0 {
+            return Err(format!("{} closed", self.name));
+        }
+        self.decoder.push(&chunk[..read], &mut self.frames);
+        Ok(())
+    }
+
+    fn take_frame(&mut self, buf: &mut [u8]) -> Result<Option<(usize, String)>, String> {
+        let Some(frame) = self.frames.pop_front() else {
+            return Ok(None);
+        };
+        Ok(Some((copy_frame(&frame, buf)?, self.name.clone())))
+    }
+
+    /// Waits up to `timeout` for the port to become readable. An interrupted
+    /// wait reports not readable and the caller retries.
+    fn readable(&self, timeout: Duration) -> Result<bool, String> {
+        let mut fds = libc::pollfd {
+            fd: self.port.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let millis = timeout.as_millis().min(i32::MAX as u128) as libc::c_int;
+        // SAFETY: `fds` is a single valid pollfd for the duration of the call.
+        let ready = unsafe { libc::poll(&mut fds, 1, millis) };
+        if ready < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() == std::io::ErrorKind::Interrupted {
+                return Ok(false);
+            }
+            return Err(format!("poll {} failed: {err}", self.name));
+        }
+        Ok(ready > 0)
+    }
+}
+
+impl Transport for SerialTransport {
+    fn recv(&mut self, buf: &mut [u8]) -> Result<(usize, String), String> {
+        loop {
+            if let Some(received) = self.take_frame(buf)? {
+                return Ok(received);
+            }
+            self.fill()?;
+        }
+    }
+
+    fn recv_timeout(
+        &mut self,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> Result<Option<(usize, String)>, String> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(received) = self.take_frame(buf)? {
+                return Ok(Some(received));
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Ok(None);
+            }
+            if self.readable(remaining)? {
+                self.fill()?;
+            }
+        }
+    }
+
+    fn send(&mut self, _next_hop: &str, frame: &[u8]) -> Result<(), String> {
+        if frame.len() > MAX_FRAME_LEN {
+            return Err(format!("frame too long for {}", self.name));
+        }
+        self.port
+            .write_all(&encode_frame(frame))
+            .map_err(|e| format!("send to {} failed: {e}", self.name))
+    }
+}
+
+/// Splits a `serial:<path>[@<baud>]` next hop into path and baud rate.
+pub fn parse_serial_hop(next_hop: &str) -> Option<(&str, u32)> {
+    let spec = next_hop.strip_prefix("serial:")?;
+    match spec.rsplit_once('@') {
+        Some((path, baud)) => Some((path, baud.parse().ok()?)),
+        None => Some((spec, DEFAULT_BAUD)),
+    }
+}
+
+fn baud_constant(baud: u32) -> Option<libc::speed_t> {
+    Some(match baud {
+        9_600 => libc::B9600,
+        19_200 => libc::B19200,
+        38_400 => libc::B38400,
+        57_600 => libc::B57600,
+        115_200 => libc::B115200,
+        230_400 => libc::B230400,
+        460_800 => libc::B460800,
+        921_600 => libc::B921600,
+        _ => return None,
+    })
+}
+
+fn set_raw(port: &File, baud: u32) -> std::io::Result<()> {
+    let speed = baud_constant(baud).ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("unsupported baud rate {baud}"),
+        )
+    })?;
+    let fd = port.as_raw_fd();
+    // SAFETY: `fd` is an open descriptor owned by `port`, and `termios` is
+    // fully initialised by tcgetattr before it is read.
+    unsafe {
+        let mut termios = std::mem::zeroed::<libc::termios>();
+        if libc::tcgetattr(fd, &mut termios) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        libc::cfmakeraw(&mut termios);
+        termios.c_cflag |= libc::CLOCAL | libc::CREAD;
+        termios.c_cc[libc::VMIN] = 1;
+        termios.c_cc[libc::VTIME] = 0;
+        if libc::cfsetspeed(&mut termios, speed) != 0
+            || libc::tcsetattr(fd, libc::TCSANOW, &termios) != 0
+        {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{run_node_on, NodeConfig};
+    use scrap_core_lite::{
+        build_task_request, decode_envelope, encode_envelope, Payload, TaskRequest, Token,
+    };
+    use std::ffi::CStr;
+    use std::os::unix::io::FromRawFd;
+
+    /// Opens a pty and returns its master side and the path of its slave.
+    fn pty_pair() -> (File, String) {
+        // SAFETY: the descriptor is checked before use and then owned by the
+        // returned File; ptsname_r writes a NUL-terminated name into `name`.
+        unsafe {
+            let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+            assert!(fd >= 0, "posix_openpt failed");
+            assert_eq!(libc::grantpt(fd), 0);
+            assert_eq!(libc::unlockpt(fd), 0);
+            let mut name = [0 as libc::c_char; 128];
+            assert_eq!(libc::ptsname_r(fd, name.as_mut_ptr(), name.len()), 0);
+            let path = CStr::from_ptr(name.as_ptr()).to_string_lossy().into_owned();
+            (File::from_raw_fd(fd), path)
+        }
+    }
+
+    fn task_frame() -> Vec<u8> {
+        let token = Token {
+            token_id: vec![9; 16],
+            subject: "DEV-COMMANDER".to_string(),
+            audience: "BCF".to_string(),
+            capability: "demo.hash".to_string(),
+            issued_at: 0,
+            expires_at: u64::MAX,
+        };
+        let env = build_task_request(
+            vec![0; 16],
+            "ORCH".to_string(),
+            "BCF".to_string(),
+            4,
+            TaskRequest {
+                token,
+                command: "demo.hash".to_string(),
+                args: "5".to_string(),
+                reply_to: "ORCH".to_string(),
+                commander_pubkey: "DEV-COMMANDER".to_string(),
+            },
+        );
+        let mut out = Vec::new();
+        encode_envelope(&env, &mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn frames_round_trip_without_interior_zeros() {
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+
+        let long = (0..600u32)
+            .map(|i| {
+                if i.is_multiple_of(300) {
+                    0
+                } else {
+                    i as u8 | 1
+                }
+            })
+            .collect::<Vec<_>>();
+        for payload in [
+            Vec::new(),
+            vec![0],
+            vec![0, 0],
+            vec![7; 254],
+            vec![7; 255],
+            long,
+            task_frame(),
+        ] {
+            let encoded = encode_frame(&payload);
+            let body = &encoded[1..encoded.len() - 1];
+            assert!(!body.contains(&0));
+            let mut frames = VecDeque::new();
+            FrameDecoder::default().push(&encoded, &mut frames);
+            assert_eq!(frames, [payload]);
+        }
+    }
+
+    #[test]
+    fn decoder_resyncs_after_noise_and_split_reads() {
+        let good = encode_frame(b"first");
+        let mut corrupt = encode_frame(b"second");
+        corrupt[3] ^= 0x40;
+        let last = encode_frame(&task_frame());
+
+        let mut stream = vec![0x13, 0x37];
+        stream.extend_from_slice(&good);
+        stream.extend_from_slice(&corrupt);
+        stream.extend_from_slice(&last);
+
+        let mut decoder = FrameDecoder::default();
+        let mut frames = VecDeque::new();
+        for chunk in stream.chunks(3) {
+            decoder.push(chunk, &mut frames);
+        }
+        assert_eq!(frames, [b"first".to_vec(), task_frame()]);
+        // The leading noise and the corrupted frame.
+        assert_eq!(decoder.dropped, 2);
+    }
+
+    #[test]
+    fn pty_pair_carries_frames_both_ways() {
+        let (master, path) = pty_pair();
+        let mut device = SerialTransport::open(&path, DEFAULT_BAUD).unwrap();
+        let mut host = SerialTransport::from_file(master, "master");
+        let mut buf = [0u8; MAX_FRAME_LEN];
+
+        host.send("serial:device", &task_frame()).unwrap();
+        let (len, source) = device.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], &task_frame()[..]);
+        assert_eq!(source, format!("serial:{path}"));
+
+        device.send("serial:host", b"reply").unwrap();
+        let (len, _) = host.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"reply");
+
+        // A frame dribbled out a few bytes per write.
+        let encoded = encode_frame(b"partial");
+        for chunk in encoded.chunks(2) {
+            host.port.write_all(chunk).unwrap();
+            host.port.flush().unwrap();
+        }
+        let (len, _) = device.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"partial");
+        assert_eq!(device.dropped(), 0);
+    }
+
+    #[test]
+    fn node_runs_over_a_serial_link() {
+        let dir = std::env::temp_dir().join(format!("scrap-serial-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let routes = dir.join("routes.json");
+        std::fs::write(
+            &routes,
+            r#"{"nodes": {"BCF": {"routes": {"ORCH": "serial:/dev/ttyGS0"}}}}"#,
+        )
+        .unwrap();
+        let config = NodeConfig {
+            node_id: "BCF".to_string(),
+            bind: String::new(),
+            port: 0,
+            routes_path: routes.display().to_string(),
+            contact_plan_path: None,
+            commander_pubkey: None,
+            replay_cache_path: dir.join("replay.json").display().to_string(),
+            revoked_path: dir.join("revoked.json").display().to_string(),
+            allow_mock_signatures: true,
+            transport: "serial".to_string(),
+            interface: None,
+            store_forward: None,
+        };
+
+        let (master, path) = pty_pair();
+        let mut device = SerialTransport::open(&path, DEFAULT_BAUD).unwrap();
+        let node = std::thread::spawn(move || run_node_on(&config, &mut device));
+
+        let mut host = SerialTransport::from_file(master, "master");
+        host.send("serial:device", &task_frame()).unwrap();
+        let mut buf = [0u8; MAX_FRAME_LEN];
+        let (len, _) = host.recv(&mut buf).unwrap();
+        match decode_envelope(&buf[..len]).unwrap().payload {
+            Payload::TaskResult(result) => assert_eq!(result.telemetry.node_id, "BCF"),
+            other => panic!("expected result, got {other:?}"),
+        }
+
+        // Hanging up the master ends the node's receive loop.
+        drop(host);
+        assert!(node.join().unwrap().is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
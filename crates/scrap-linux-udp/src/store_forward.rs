@@ -0,0 +1,396 @@
+//! Store-and-forward for intermittent links. A frame for a next hop that has
+//! not been heard from within `link_timeout_sec` waits in that hop's queue,
+//! behind anything already queued there, until the hop is heard again. The
+//! queues are rewritten on disk after every change so they survive restarts;
+//! released frames leave the file only once they have been sent.
+
+use crate::{hex_decode, hex_encode, log_json, FileReplayCache};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::{ErrorKind, Write};
+
+#[derive(Debug, Clone)]
+pub struct StoreForwardConfig {
+    /// Where queued frames are kept; `None` keeps them in memory only.
+    pub queue_path: Option<String>,
+    /// Seconds between heartbeats to each next hop.
+    pub heartbeat_interval_sec: u64,
+    /// Seconds of silence after which a next hop counts as down.
+    pub link_timeout_sec: u64,
+    /// Lifetime of queued frames without a token, such as results.
+    pub ttl_sec: u64,
+}
+
+impl Default for StoreForwardConfig {
+    fn default() -> Self {
+        Self {
+            queue_path: None,
+            heartbeat_interval_sec: 5,
+            link_timeout_sec: 15,
+            ttl_sec: 3600,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueuedFrame {
+    pub frame: Vec<u8>,
+    /// Unix time after which the frame is no longer delivered.
+    pub expires_at: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredFrame {
+    frame: String,
+    expires_at: u64,
+}
+
+pub struct StoreForward {
+    config: StoreForwardConfig,
+    queues: BTreeMap<String, VecDeque<QueuedFrame>>,
+    /// Frames handed out by `take` that the transport has not yet taken.
+    in_flight: BTreeMap<String, Vec<QueuedFrame>>,
+    heard: HashMap<String, u64>,
+    last_heartbeat: Option<u64>,
+}
+
+impl StoreForward {
+    /// Starts with every next hop down and any queues left at
+    /// `config.queue_path`. A missing file is an empty queue; one that cannot
+    /// be read or parsed is an error rather than a silent loss of its frames.
+    pub fn open(config: StoreForwardConfig) -> Result<Self, String> {
+        let queues = match config.queue_path.as_deref() {
+            Some(path) => load_queues(path)?,
+            None => BTreeMap::new(),
+        };
+        Ok(Self {
+            config,
+            queues,
+            in_flight: BTreeMap::new(),
+            heard: HashMap::new(),
+            last_heartbeat: None,
+        })
+    }
+
+    pub fn config(&self) -> &StoreForwardConfig {
+        &self.config
+    }
+
+    /// Records that `next_hop` was heard at `now`. Returns true if it was
+    /// down until then.
+    pub fn observe(&mut self, next_hop: &str, now: u64) -> bool {
+        let was_up = self.is_up(next_hop, now);
+        self.heard.insert(next_hop.to_string(), now);
+        !was_up
+    }
+
+    /// Treats `next_hop` as down until it is heard again.
+    pub fn mark_down(&mut self, next_hop: &str) {
+        self.heard.remove(next_hop);
+    }
+
+    pub fn is_up(&self, next_hop: &str, now: u64) -> bool {
+        self.heard
+            .get(next_hop)
+            .is_some_and(|last| now < last.saturating_add(self.config.link_timeout_sec))
+    }
+
+    pub fn queued(&self, next_hop: &str) -> usize {
+        self.queues.get(next_hop).map_or(0, VecDeque::len)
+    }
+
+    /// Next hops that are up and have frames waiting.
+    pub fn ready(&self, now: u64) -> Vec<String> {
+        self.queues
+            .keys()
+            .filter(|next_hop| self.is_up(next_hop, now))
+            .cloned()
+            .collect()
+    }
+
+    pub fn push(&mut self, next_hop: &str, frame: Vec<u8>, expires_at: u64) {
+        self.queues
+            .entry(next_hop.to_string())
+            .or_default()
+            .push_back(QueuedFrame { frame, expires_at });
+        self.save();
+    }
+
+    /// Removes and returns everything queued for `next_hop`, oldest first.
+    /// The frames stay on disk until `sent`, so a crash before they reach
+    /// the transport sends them again after the restart instead of losing
+    /// them.
+    pub fn take(&mut self, next_hop: &str) -> Vec<Vec<u8>> {
+        let Some(queue) = self.queues.remove(next_hop) else {
+            return Vec::new();
+        };
+        let frames = queue.iter().map(|queued| queued.frame.clone()).collect();
+        self.in_flight
+            .entry(next_hop.to_string())
+            .or_default()
+            .extend(queue);
+        frames
+    }
+
+    /// Drops the frames handed out by `take` from disk once the transport has
+    /// been given them. Any it failed to send must already be queued again.
+    pub fn sent(&mut self) {
+        if !self.in_flight.is_empty() {
+            self.in_flight.clear();
+            self.save();
+        }
+    }
+
+    /// Removes and returns the frames whose `expires_at` is before `now`.
+    pub fn expire(&mut self, now: u64) -> Vec<(String, QueuedFrame)> {
+        let mut expired = Vec::new();
+        for (next_hop, queue) in self.queues.iter_mut() {
+            let (stale, kept) = queue
+                .drain(..)
+                .partition::<VecDeque<_>, _>(|queued| queued.expires_at < now);
+            *queue = kept;
+            expired.extend(stale.into_iter().map(|queued| (next_hop.clone(), queued)));
+        }
+        if !expired.is_empty() {
+            self.queues.retain(|_, queue| !queue.is_empty());
+            self.save();
+        }
+        expired
+    }
+
+    /// True once every `heartbeat_interval_sec`, starting with the first call.
+    pub fn heartbeat_due(&mut self, now: u64) -> bool {
+        let due = match self.last_heartbeat {
+            Some(last) => now >= last.saturating_add(self.config.heartbeat_interval_sec),
+            None => true,
+        };
+        if due {
+            self.last_heartbeat = Some(now);
+        }
+        due
+    }
+
+    /// Writes the queues out, logging a failure. The queues in memory stay
+    /// authoritative, so frames are still delivered while the node runs.
+    fn save(&self) {
+        let Some(path) = self.config.queue_path.as_deref() else {
+            return;
+        };
+        if let Err(err) = self.write_queues(path) {
+            log_json(
+                "queue_save_failed",
+                serde_json::json!({"path": path, "error": err}),
+            );
+        }
+    }
+
+    fn write_queues(&self, path: &str) -> Result<(), String> {
+        let mut stored = BTreeMap::<&String, Vec<StoredFrame>>::new();
+        for (next_hop, frames) in &self.in_flight {
+            stored
+                .entry(next_hop)
+                .or_default()
+                .extend(frames.iter().map(stored_frame));
+        }
+        for (next_hop, queue) in &self.queues {
+            stored
+                .entry(next_hop)
+                .or_default()
+                .extend(queue.iter().map(stored_frame));
+        }
+        let payload =
+            serde_json::to_vec_pretty(&stored).map_err(|e| format!("queue encode failed: {e}"))?;
+        FileReplayCache::ensure_parent(path);
+        let tmp_path = format!("{path}.tmp");
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp_path)
+            .map_err(|e| format!("queue open failed: {e}"))?;
+        file.write_all(&payload)
+            .and_then(|_| file.sync_all())
+            .map_err(|e| format!("queue write failed: {e}"))?;
+        fs::rename(&tmp_path, path).map_err(|e| format!("queue rename failed: {e}"))
+    }
+}
+
+fn stored_frame(queued: &QueuedFrame) -> StoredFrame {
+    StoredFrame {
+        frame: hex_encode(&queued.frame),
+        expires_at: queued.expires_at,
+    }
+}
+
+fn load_queues(path: &str) -> Result<BTreeMap<String, VecDeque<QueuedFrame>>, String> {
+    let raw = match fs::read_to_string(path) {
+        Ok(raw) => raw,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(e) => return Err(format!("queue read failed: {path}: {e}")),
+    };
+    let stored = serde_json::from_str::<BTreeMap<String, Vec<StoredFrame>>>(&raw)
+        .map_err(|e| format!("queue parse failed: {path}: {e}"))?;
+    let mut queues = BTreeMap::new();
+    for (next_hop, frames) in stored {
+        let queue = frames
+            .into_iter()
+            .map(|stored| {
+                let frame = hex_decode(&stored.frame).ok_or_else(|| {
+                    format!("queue parse failed: {path}: bad frame for {next_hop}")
+                })?;
+                Ok(QueuedFrame {
+                    frame,
+                    expires_at: stored.expires_at,
+                })
+            })
+            .collect::<Result<VecDeque<_>, String>>()?;
+        if !queue.is_empty() {
+            queues.insert(next_hop, queue);
+        }
+    }
+    Ok(queues)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn temp_path(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("scrap-sf-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir.join("queue.json").to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn queues_survive_a_restart_in_order() {
+        let path = temp_path("restart");
+        let config = StoreForwardConfig {
+            queue_path: Some(path.clone()),
+            ..StoreForwardConfig::default()
+        };
+        let mut store = StoreForward::open(config.clone()).unwrap();
+        store.push("10.0.0.2:7227", vec![1], 100);
+        store.push("10.0.0.2:7227", vec![2], 200);
+        store.push("serial:/dev/ttyACM0", vec![3], 100);
+        drop(store);
+
+        let mut store = StoreForward::open(config.clone()).unwrap();
+        assert!(!store.is_up("10.0.0.2:7227", 50));
+        assert_eq!(store.queued("10.0.0.2:7227"), 2);
+        assert_eq!(store.take("10.0.0.2:7227"), vec![vec![1], vec![2]]);
+        assert_eq!(store.queued("10.0.0.2:7227"), 0);
+        // Stopping before the released frames were sent keeps them.
+        drop(store);
+
+        let mut store = StoreForward::open(config).unwrap();
+        assert_eq!(store.queued("10.0.0.2:7227"), 2);
+        assert_eq!(store.take("10.0.0.2:7227"), vec![vec![1], vec![2]]);
+        store.sent();
+        drop(store);
+
+        let store = StoreForward::open(StoreForwardConfig {
+            queue_path: Some(path),
+            ..StoreForwardConfig::default()
+        })
+        .unwrap();
+        assert_eq!(store.queued("10.0.0.2:7227"), 0);
+        assert_eq!(store.queued("serial:/dev/ttyACM0"), 1);
+    }
+
+    #[test]
+    fn frames_that_fail_to_send_are_stored_once() {
+        let path = temp_path("resend");
+        let config = StoreForwardConfig {
+            queue_path: Some(path.clone()),
+            ..StoreForwardConfig::default()
+        };
+        let mut store = StoreForward::open(config.clone()).unwrap();
+        store.push("B", vec![1], 100);
+        store.push("B", vec![2], 100);
+        let released = store.take("B");
+        // The first frame goes out; the second fails and is queued again
+        // while the released frames are still on disk.
+        store.push("B", released[1].clone(), 100);
+        store.sent();
+        drop(store);
+
+        let mut store = StoreForward::open(config).unwrap();
+        assert_eq!(store.take("B"), vec![vec![2]]);
+    }
+
+    #[test]
+    fn links_go_down_after_the_timeout_and_frames_expire() {
+        let mut store = StoreForward::open(StoreForwardConfig::default()).unwrap();
+        assert!(store.observe("B", 1000));
+        assert!(!store.observe("B", 1005));
+        assert!(store.is_up("B", 1019));
+        assert!(!store.is_up("B", 1020));
+
+        store.push("C", vec![1], 1010);
+        store.push("C", vec![2], 1030);
+        assert!(store.expire(1010).is_empty());
+        let expired = store.expire(1011);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].0, "C");
+        assert_eq!(expired[0].1.frame, vec![1]);
+        assert_eq!(store.queued("C"), 1);
+
+        assert!(store.ready(1011).is_empty());
+        store.observe("C", 1011);
+        assert_eq!(store.ready(1011), vec!["C".to_string()]);
+    }
+
+    #[test]
+    fn heartbeats_follow_the_interval() {
+        let mut store = StoreForward::open(StoreForwardConfig::default()).unwrap();
+        assert!(store.heartbeat_due(100));
+        assert!(!store.heartbeat_due(104));
+        assert!(store.heartbeat_due(105));
+    }
+
+    #[test]
+    fn unreadable_queues_fail_to_open() {
+        let path = temp_path("corrupt");
+        let config = StoreForwardConfig {
+            queue_path: Some(path.clone()),
+            ..StoreForwardConfig::default()
+        };
+        // Nothing on disk yet is an empty queue.
+        assert_eq!(StoreForward::open(config.clone()).unwrap().queued("B"), 0);
+
+        FileReplayCache::ensure_parent(&path);
+        fs::write(&path, "{\"B\": [").unwrap();
+        let err = StoreForward::open(config.clone()).err().unwrap();
+        assert!(err.starts_with("queue parse failed"), "{err}");
+
+        fs::write(&path, r#"{"B": [{"frame": "0g", "expires_at": 1}]}"#).unwrap();
+        let err = StoreForward::open(config.clone()).err().unwrap();
+        assert!(err.contains("bad frame for B"), "{err}");
+
+        fs::write(&path, r#"{"B": [{"frame": "0a0b", "expires_at": 1}]}"#).unwrap();
+        let mut store = StoreForward::open(config).unwrap();
+        assert_eq!(store.take("B"), vec![vec![0x0a, 0x0b]]);
+        assert!(!Path::new(&format!("{path}.tmp")).exists());
+    }
+
+    #[test]
+    fn failed_saves_keep_the_queues_in_memory() {
+        let path = temp_path("blocked");
+        let mut store = StoreForward::open(StoreForwardConfig {
+            queue_path: Some(path.clone()),
+            ..StoreForwardConfig::default()
+        })
+        .unwrap();
+        // A directory where the temporary file goes makes every write fail.
+        fs::create_dir_all(format!("{path}.tmp")).unwrap();
+        assert!(store.write_queues(&path).is_err());
+
+        store.push("B", vec![1], 100);
+        assert_eq!(store.queued("B"), 1);
+        assert_eq!(store.take("B"), vec![vec![1]]);
+        assert!(!Path::new(&path).exists());
+    }
+}
@@ -0,0 +1,593 @@
+use crate::serial::{parse_serial_hop, SerialTransport, MAX_FRAME_LEN};
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::io::ErrorKind;
+use std::net::{Ipv6Addr, SocketAddrV6, UdpSocket};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Moves encoded envelopes between this node and its neighbours. `next_hop`
+/// is the route table entry, in whatever address form the transport uses.
+pub trait Transport {
+    /// Blocks until a frame arrives, copies it into `buf` and returns its
+    /// length and a printable source address.
+    fn recv(&mut self, buf: &mut [u8]) -> Result<(usize, String), String>;
+
+    /// Like `recv`, but returns `Ok(None)` once `timeout` passes without a
+    /// frame. The default blocks as `recv` does.
+    fn recv_timeout(
+        &mut self,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> Result<Option<(usize, String)>, String> {
+        let _ = timeout;
+        self.recv(buf).map(Some)
+    }
+
+    fn send(&mut self, next_hop: &str, frame: &[u8]) -> Result<(), String>;
+}
+
+/// Copies a received frame into `buf`, failing rather than truncating it.
+pub(crate) fn copy_frame(frame: &[u8], buf: &mut [u8]) -> Result<usize, String> {
+    let capacity = buf.len();
+    buf.get_mut(..frame.len())
+        .ok_or_else(|| {
+            format!(
+                "frame of {} bytes does not fit a {capacity}-byte buffer",
+                frame.len()
+            )
+        })?
+        .copy_from_slice(frame);
+    Ok(frame.len())
+}
+
+fn recv_from_timeout(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+    timeout: Duration,
+) -> Result<Option<(usize, String)>, String> {
+    socket
+        .set_read_timeout(Some(timeout.max(Duration::from_millis(1))))
+        .map_err(|e| format!("set timeout failed: {e}"))?;
+    let received = socket.recv_from(buf);
+    let _ = socket.set_read_timeout(None);
+    match received {
+        Ok((len, addr)) => Ok(Some((len, addr.to_string()))),
+        Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => Ok(None),
+        Err(e) => Err(format!("recv failed: {e}")),
+    }
+}
+
+pub struct UdpTransport {
+    socket: UdpSocket,
+}
+
+impl UdpTransport {
+    pub fn bind(addr: &str) -> Result<Self, String> {
+        let socket = UdpSocket::bind(addr).map_err(|e| format!("bind failed: {e}"))?;
+        Ok(Self { socket })
+    }
+
+    pub fn try_clone(&self) -> Result<Self, String> {
+        let socket = self
+            .socket
+            .try_clone()
+            .map_err(|e| format!("clone socket failed: {e}"))?;
+        Ok(Self { socket })
+    }
+}
+
+impl Transport for UdpTransport {
+    fn recv(&mut self, buf: &mut [u8]) -> Result<(usize, String), String> {
+        let (len, addr) = self
+            .socket
+            .recv_from(buf)
+            .map_err(|e| format!("recv failed: {e}"))?;
+        Ok((len, addr.to_string()))
+    }
+
+    fn recv_timeout(
+        &mut self,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> Result<Option<(usize, String)>, String> {
+        recv_from_timeout(&self.socket, buf, timeout)
+    }
+
+    fn send(&mut self, next_hop: &str, frame: &[u8]) -> Result<(), String> {
+        self.socket
+            .send_to(frame, next_hop)
+            .map(|_| ())
+            .map_err(|e| format!("send to {next_hop} failed: {e}"))
+    }
+}
+
+/// UDP over IPv6 on a 6LoWPAN interface (`lowpan0` on top of `wpan0`, e.g.
+/// a BeagleConnect Freedom attached over USB). The kernel's 6LoWPAN layer
+/// compresses and fragments to the 127-byte IEEE 802.15.4 frames.
+///
+/// Next hops are link-local addresses, `fe80::2` or `[fe80::2]:7227`; the
+/// interface scope is filled in and a missing port defaults to the node's.
+pub struct LowpanTransport {
+    socket: UdpSocket,
+    scope_id: u32,
+    port: u16,
+}
+
+impl LowpanTransport {
+    pub fn bind(interface: &str, port: u16) -> Result<Self, String> {
+        let index_path = format!("/sys/class/net/{interface}/ifindex");
+        let scope_id = fs::read_to_string(&index_path)
+            .map_err(|e| format!("interface {interface} not found: {e}"))?
+            .trim()
+            .parse::<u32>()
+            .map_err(|e| format!("interface {interface} index invalid: {e}"))?;
+        let socket = UdpSocket::bind(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, port, 0, 0))
+            .map_err(|e| format!("bind failed: {e}"))?;
+        Ok(Self {
+            socket,
+            scope_id,
+            port,
+        })
+    }
+
+    fn resolve(&self, next_hop: &str) -> Result<SocketAddrV6, String> {
+        let mut addr = match next_hop.parse::<Ipv6Addr>() {
+            Ok(ip) => SocketAddrV6::new(ip, self.port, 0, 0),
+            Err(_) => next_hop
+                .parse::<SocketAddrV6>()
+                .map_err(|_| format!("invalid 6lowpan next hop: {next_hop}"))?,
+        };
+        if addr.scope_id() == 0 {
+            addr.set_scope_id(self.scope_id);
+        }
+        Ok(addr)
+    }
+}
+
+impl Transport for LowpanTransport {
+    fn recv(&mut self, buf: &mut [u8]) -> Result<(usize, String), String> {
+        let (len, addr) = self
+            .socket
+            .recv_from(buf)
+            .map_err(|e| format!("recv failed: {e}"))?;
+        Ok((len, addr.to_string()))
+    }
+
+    fn recv_timeout(
+        &mut self,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> Result<Option<(usize, String)>, String> {
+        recv_from_timeout(&self.socket, buf, timeout)
+    }
+
+    fn send(&mut self, next_hop: &str, frame: &[u8]) -> Result<(), String> {
+        let addr = self.resolve(next_hop)?;
+        self.socket
+            .send_to(frame, addr)
+            .map(|_| ())
+            .map_err(|e| format!("send to {next_hop} failed: {e}"))
+    }
+}
+
+type Incoming = Result<(Vec<u8>, String), String>;
+
+/// UDP plus any number of serial ports, received from together. Next hops
+/// of the form `serial:<path>[@<baud>]` go to their port and everything else
+/// goes over UDP. A UDP receive error ends `recv` as with `UdpTransport`; a
+/// serial port that fails only stops delivering.
+pub struct MeshTransport {
+    udp: UdpTransport,
+    serial: HashMap<String, SerialTransport>,
+    incoming: Receiver<Incoming>,
+    feed: Sender<Incoming>,
+}
+
+impl MeshTransport {
+    pub fn new(udp: UdpTransport) -> Result<Self, String> {
+        let (feed, incoming) = mpsc::channel();
+        let mesh = Self {
+            udp,
+            serial: HashMap::new(),
+            incoming,
+            feed,
+        };
+        mesh.spawn_reader(mesh.udp.try_clone()?, true);
+        Ok(mesh)
+    }
+
+    /// Opens the port named by a `serial:` next hop.
+    pub fn add_serial(&mut self, next_hop: &str) -> Result<(), String> {
+        let (path, baud) = parse_serial_hop(next_hop)
+            .ok_or_else(|| format!("invalid serial next hop: {next_hop}"))?;
+        let port = SerialTransport::open(path, baud)?;
+        self.add_serial_port(next_hop, port)
+    }
+
+    pub fn add_serial_port(&mut self, next_hop: &str, port: SerialTransport) -> Result<(), String> {
+        self.spawn_reader(port.try_clone()?, false);
+        self.serial.insert(next_hop.to_string(), port);
+        Ok(())
+    }
+
+    fn spawn_reader<T: Transport + Send + 'static>(&self, mut transport: T, fatal: bool) {
+        let feed = self.feed.clone();
+        thread::spawn(move || {
+            let mut buf = [0u8; MAX_FRAME_LEN];
+            loop {
+                match transport.recv(&mut buf) {
+                    Ok((len, source)) => {
+                        if feed.send(Ok((buf[..len].to_vec(), source))).is_err() {
+                            break;
+                        }
+                    }
+                    Err(err) => {
+                        if fatal {
+                            let _ = feed.send(Err(err));
+                        }
+                        break;
+                    }
+                }
+            }
+        });
+    }
+}
+
+impl Transport for MeshTransport {
+    fn recv(&mut self, buf: &mut [u8]) -> Result<(usize, String), String> {
+        let (frame, source) = self
+            .incoming
+            .recv()
+            .map_err(|_| "transport closed".to_string())??;
+        Ok((copy_frame(&frame, buf)?, source))
+    }
+
+    fn recv_timeout(
+        &mut self,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> Result<Option<(usize, String)>, String> {
+        let (frame, source) = match self.incoming.recv_timeout(timeout) {
+            Ok(incoming) => incoming?,
+            Err(RecvTimeoutError::Timeout) => return Ok(None),
+            Err(RecvTimeoutError::Disconnected) => return Err("transport closed".to_string()),
+        };
+        Ok(Some((copy_frame(&frame, buf)?, source)))
+    }
+
+    fn send(&mut self, next_hop: &str, frame: &[u8]) -> Result<(), String> {
+        if next_hop.starts_with("serial:") {
+            return self
+                .serial
+                .get_mut(next_hop)
+                .ok_or_else(|| format!("serial port {next_hop} not open"))?
+                .send(next_hop, frame);
+        }
+        self.udp.send(next_hop, frame)
+    }
+}
+
+#[derive(Default)]
+struct LoopbackState {
+    queues: HashMap<String, VecDeque<(String, Vec<u8>)>>,
+    closed: bool,
+}
+
+/// An in-memory network for running nodes without sockets or radios. Each
+/// endpoint is addressed by its name, which is what route tables use as the
+/// next hop.
+#[derive(Clone, Default)]
+pub struct LoopbackNetwork {
+    state: Arc<(Mutex<LoopbackState>, Condvar)>,
+}
+
+impl LoopbackNetwork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn endpoint(&self, name: &str) -> LoopbackTransport {
+        let (lock, _) = &*self.state;
+        if let Ok(mut state) = lock.lock() {
+            state.queues.entry(name.to_string()).or_default();
+        }
+        LoopbackTransport {
+            network: self.clone(),
+            name: name.to_string(),
+            timeout: None,
+        }
+    }
+
+    /// Fails every pending and future `recv`, which ends a `run_node_on`
+    /// loop.
+    pub fn close(&self) {
+        let (lock, cvar) = &*self.state;
+        if let Ok(mut state) = lock.lock() {
+            state.closed = true;
+        }
+        cvar.notify_all();
+    }
+}
+
+pub struct LoopbackTransport {
+    network: LoopbackNetwork,
+    name: String,
+    timeout: Option<Duration>,
+}
+
+impl LoopbackTransport {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Makes `recv` fail after `timeout` without a frame.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+}
+
+impl LoopbackTransport {
+    /// Waits up to `timeout` (forever if `None`) for a frame. Wakeups for
+    /// other endpoints do not extend the wait.
+    fn wait(
+        &self,
+        buf: &mut [u8],
+        timeout: Option<Duration>,
+    ) -> Result<Option<(usize, String)>, String> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let (lock, cvar) = &*self.network.state;
+        let mut state = lock.lock().map_err(|_| "loopback poisoned".to_string())?;
+        loop {
+            if state.closed {
+                return Err("loopback closed".to_string());
+            }
+            if let Some((src, frame)) = state
+                .queues
+                .get_mut(&self.name)
+                .and_then(|queue| queue.pop_front())
+            {
+                return Ok(Some((copy_frame(&frame, buf)?, src)));
+            }
+            state = match deadline {
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    if remaining.is_zero() {
+                        return Ok(None);
+                    }
+                    cvar.wait_timeout(state, remaining)
+                        .map_err(|_| "loopback poisoned".to_string())?
+                        .0
+                }
+                None => cvar
+                    .wait(state)
+                    .map_err(|_| "loopback poisoned".to_string())?,
+            };
+        }
+    }
+}
+
+impl Transport for LoopbackTransport {
+    fn recv(&mut self, buf: &mut [u8]) -> Result<(usize, String), String> {
+        self.wait(buf, self.timeout)?
+            .ok_or_else(|| "recv timed out".to_string())
+    }
+
+    /// A timeout set with `set_timeout` still fails the call when it is the
+    /// shorter of the two.
+    fn recv_timeout(
+        &mut self,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> Result<Option<(usize, String)>, String> {
+        match self.timeout {
+            Some(limit) if limit <= timeout => self.recv(buf).map(Some),
+            _ => self.wait(buf, Some(timeout)),
+        }
+    }
+
+    /// Like a serial link, carries frames of up to `MAX_FRAME_LEN` bytes.
+    fn send(&mut self, next_hop: &str, frame: &[u8]) -> Result<(), String> {
+        if frame.len() > MAX_FRAME_LEN {
+            return Err(format!("frame too long for loopback {next_hop}"));
+        }
+        let (lock, cvar) = &*self.network.state;
+        let mut state = lock.lock().map_err(|_| "loopback poisoned".to_string())?;
+        let queue = state
+            .queues
+            .get_mut(next_hop)
+            .ok_or_else(|| format!("no loopback endpoint {next_hop}"))?;
+        queue.push_back((self.name.clone(), frame.to_vec()));
+        cvar.notify_all();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{run_node_on, NodeConfig};
+    use scrap_core_lite::{
+        build_task_request, decode_envelope, encode_envelope, Payload, TaskRequest, Token,
+    };
+
+    fn task_frame(token_id: u8, hop_limit: u8) -> Vec<u8> {
+        let token = Token {
+            token_id: vec![token_id; 16],
+            subject: "DEV-COMMANDER".to_string(),
+            audience: "exec".to_string(),
+            capability: "demo.hash".to_string(),
+            issued_at: 0,
+            expires_at: u64::MAX,
+        };
+        let env = build_task_request(
+            vec![token_id; 16],
+            "cmd".to_string(),
+            "exec".to_string(),
+            hop_limit,
+            TaskRequest {
+                token,
+                command: "demo.hash".to_string(),
+                args: "5".to_string(),
+                reply_to: "cmd".to_string(),
+                commander_pubkey: "DEV-COMMANDER".to_string(),
+            },
+        );
+        let mut out = Vec::new();
+        encode_envelope(&env, &mut out).unwrap();
+        out
+    }
+
+    fn node_config(dir: &std::path::Path, node_id: &str) -> NodeConfig {
+        NodeConfig {
+            node_id: node_id.to_string(),
+            bind: String::new(),
+            port: 0,
+            routes_path: dir.join("routes.json").display().to_string(),
+            contact_plan_path: None,
+            commander_pubkey: None,
+            replay_cache_path: dir
+                .join(format!("{node_id}-replay.json"))
+                .display()
+                .to_string(),
+            revoked_path: dir.join("revoked.json").display().to_string(),
+            allow_mock_signatures: true,
+            transport: "loopback".to_string(),
+            interface: None,
+            store_forward: None,
+        }
+    }
+
+    fn payload(client: &mut LoopbackTransport) -> (Payload, String) {
+        let mut buf = [0u8; MAX_FRAME_LEN];
+        let (len, source) = client
+            .recv_timeout(&mut buf, Duration::from_secs(5))
+            .unwrap()
+            .expect("no reply within 5s");
+        (decode_envelope(&buf[..len]).unwrap().payload, source)
+    }
+
+    #[test]
+    fn nodes_relay_and_execute_over_loopback() {
+        let dir = std::env::temp_dir().join(format!("scrap-loopback-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("routes.json"),
+            r#"{"nodes": {
+                "relay": {"routes": {"exec": "exec", "cmd": "cmd"}},
+                "exec": {"routes": {"cmd": "relay"}}
+            }}"#,
+        )
+        .unwrap();
+
+        let network = LoopbackNetwork::new();
+        let mut client = network.endpoint("cmd");
+        let nodes = ["relay", "exec"].map(|node_id| {
+            let config = node_config(&dir, node_id);
+            let mut transport = network.endpoint(node_id);
+            thread::spawn(move || run_node_on(&config, &mut transport))
+        });
+
+        client.send("relay", &task_frame(1, 4)).unwrap();
+        match payload(&mut client) {
+            (Payload::TaskResult(result), source) => {
+                assert_eq!(result.telemetry.node_id, "exec");
+                assert_eq!(source, "relay");
+            }
+            other => panic!("expected a result, got {other:?}"),
+        }
+
+        // The relay rejects what it cannot pass on, and exec what it has seen.
+        client.send("relay", &task_frame(2, 0)).unwrap();
+        match payload(&mut client) {
+            (Payload::TaskRejected(rejected), _) => {
+                assert_eq!(rejected.reason, "hop_limit_exceeded")
+            }
+            other => panic!("expected a rejection, got {other:?}"),
+        }
+        client.send("relay", &task_frame(1, 4)).unwrap();
+        match payload(&mut client) {
+            (Payload::TaskRejected(rejected), _) => {
+                assert_eq!(rejected.details, [scrap_edge::DETAIL_REPLAY])
+            }
+            other => panic!("expected a rejection, got {other:?}"),
+        }
+
+        // Closing the network ends both receive loops.
+        network.close();
+        for node in nodes {
+            assert_eq!(node.join().unwrap(), Err("loopback closed".to_string()));
+        }
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn loopback_timeout_is_a_deadline() {
+        let network = LoopbackNetwork::new();
+        let mut quiet = network.endpoint("quiet");
+        let mut busy = network.endpoint("busy");
+        let mut sender = network.endpoint("sender");
+
+        // Traffic for another endpoint keeps waking `quiet` up.
+        let chatter = thread::spawn(move || {
+            for _ in 0..100 {
+                sender.send("busy", b"noise").unwrap();
+                thread::sleep(Duration::from_millis(10));
+            }
+        });
+        let start = Instant::now();
+        let mut buf = [0u8; 16];
+        let received = quiet
+            .recv_timeout(&mut buf, Duration::from_millis(100))
+            .unwrap();
+        let waited = start.elapsed();
+        assert!(received.is_none());
+        assert!(waited >= Duration::from_millis(100));
+        assert!(waited < Duration::from_millis(600), "waited {waited:?}");
+
+        chatter.join().unwrap();
+        assert_eq!(busy.recv(&mut buf).unwrap(), (5, "sender".to_string()));
+    }
+
+    #[test]
+    fn loopback_rejects_frames_that_do_not_fit() {
+        let network = LoopbackNetwork::new();
+        let mut a = network.endpoint("a");
+        let mut b = network.endpoint("b");
+
+        assert!(a.send("b", &[0; MAX_FRAME_LEN + 1]).is_err());
+        a.send("b", &[7; 32]).unwrap();
+        let err = b.recv(&mut [0u8; 16]).unwrap_err();
+        assert!(err.contains("does not fit"), "{err}");
+
+        a.send("b", &[7; 32]).unwrap();
+        let mut buf = [0u8; 32];
+        assert_eq!(b.recv(&mut buf).unwrap(), (32, "a".to_string()));
+        assert_eq!(
+            a.send("nobody", b"x"),
+            Err("no loopback endpoint nobody".to_string())
+        );
+    }
+
+    #[test]
+    fn mesh_rejects_frames_that_do_not_fit() {
+        let udp = UdpTransport::bind("127.0.0.1:0").unwrap();
+        let addr = udp.socket.local_addr().unwrap().to_string();
+        let mut mesh = MeshTransport::new(udp).unwrap();
+        let mut peer = UdpTransport::bind("127.0.0.1:0").unwrap();
+
+        peer.send(&addr, &[7; 32]).unwrap();
+        let err = mesh
+            .recv_timeout(&mut [0u8; 16], Duration::from_secs(5))
+            .unwrap_err();
+        assert!(err.contains("does not fit"), "{err}");
+
+        peer.send(&addr, &[7; 32]).unwrap();
+        let mut buf = [0u8; 32];
+        let (len, _) = mesh.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], &[7; 32]);
+    }
+}
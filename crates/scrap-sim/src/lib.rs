@@ -0,0 +1,743 @@
+//! Runs many `scrap_linux_udp::Node`s in one process over a virtual network.
+//! Everything is driven by a simulated clock and a seeded RNG, so a seed
+//! reproduces the same deliveries, losses and timings on every run.
+
+use scrap_core_lite::{RouteEntry, RouteTable};
+use scrap_edge::ReplayCache;
+use scrap_linux_udp::{DevTokenVerifier, Node, TaskClock};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::time::Duration;
+
+/// Unix time at simulated time zero.
+pub const START_UNIX: u64 = 1_700_000_000;
+
+/// How frames behave on a link. Frames whose latencies overlap through
+/// `jitter_ms` can arrive out of order.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LinkConfig {
+    pub latency_ms: u64,
+    pub jitter_ms: u64,
+    /// Probability in `[0, 1]` that a frame is lost.
+    pub loss: f64,
+    /// Probability in `[0, 1]` that a delivered frame arrives twice.
+    pub duplicate: f64,
+}
+
+impl Default for LinkConfig {
+    fn default() -> Self {
+        Self {
+            latency_ms: 5,
+            jitter_ms: 0,
+            loss: 0.0,
+            duplicate: 0.0,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryReplayCache {
+    seen: HashSet<Vec<u8>>,
+}
+
+impl ReplayCache for MemoryReplayCache {
+    fn check_and_add(&mut self, token_id: &[u8]) -> bool {
+        self.seen.insert(token_id.to_vec())
+    }
+}
+
+/// A node's task clock. `sleep` moves it forward instead of waiting, and the
+/// simulator holds back what the node sends by the time it spent.
+#[derive(Debug, Default)]
+pub struct SimClock {
+    now_ms: u64,
+}
+
+impl TaskClock for SimClock {
+    fn now_ms(&mut self) -> u64 {
+        self.now_ms
+    }
+
+    fn sleep(&mut self, duration: Duration) {
+        let ms = u64::try_from(duration.as_millis()).unwrap_or(u64::MAX);
+        self.now_ms = self.now_ms.saturating_add(ms);
+    }
+}
+
+pub type SimNode = Node<MemoryReplayCache, SimClock>;
+
+/// A node accepting mock signatures, with `routes` as (dst, next hop) pairs.
+pub fn sim_node(node_id: &str, routes: &[(&str, &str)]) -> SimNode {
+    let entries = routes
+        .iter()
+        .map(|(dst, next_hop)| RouteEntry {
+            dst: dst.to_string(),
+            next_hop: next_hop.to_string(),
+        })
+        .collect();
+    Node::new(
+        node_id.to_string(),
+        RouteTable::new(entries),
+        MemoryReplayCache::default(),
+        DevTokenVerifier {
+            allow_mock_signatures: true,
+        },
+        SimClock::default(),
+    )
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Received {
+    pub at_ms: u64,
+    pub from: String,
+    pub frame: Vec<u8>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub sent: u64,
+    pub delivered: u64,
+    pub lost: u64,
+    pub partitioned: u64,
+    pub duplicated: u64,
+    /// Frames addressed to an endpoint that does not exist.
+    pub unroutable: u64,
+}
+
+struct Delivery {
+    at_ms: u64,
+    seq: u64,
+    from: String,
+    to: String,
+    frame: Vec<u8>,
+}
+
+/// SplitMix64; small, fast and good enough for choosing delays and drops.
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && ((self.next_u64() >> 11) as f64 / (1u64 << 53) as f64) < probability
+    }
+
+    fn below(&mut self, bound: u64) -> u64 {
+        if bound == 0 {
+            0
+        } else {
+            self.next_u64() % bound
+        }
+    }
+}
+
+/// The virtual network. Endpoints are addressed by name, and those names are
+/// the next hops in the nodes' route tables. Clients are endpoints that only
+/// collect what reaches them.
+pub struct Simulator {
+    now_ms: u64,
+    rng: Rng,
+    seq: u64,
+    default_link: LinkConfig,
+    links: BTreeMap<(String, String), LinkConfig>,
+    partitions: BTreeSet<(String, String)>,
+    nodes: BTreeMap<String, SimNode>,
+    inboxes: BTreeMap<String, Vec<Received>>,
+    pending: Vec<Delivery>,
+    stats: Stats,
+}
+
+fn link_key(a: &str, b: &str) -> (String, String) {
+    if a <= b {
+        (a.to_string(), b.to_string())
+    } else {
+        (b.to_string(), a.to_string())
+    }
+}
+
+impl Simulator {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            now_ms: 0,
+            rng: Rng(seed),
+            seq: 0,
+            default_link: LinkConfig::default(),
+            links: BTreeMap::new(),
+            partitions: BTreeSet::new(),
+            nodes: BTreeMap::new(),
+            inboxes: BTreeMap::new(),
+            pending: Vec::new(),
+            stats: Stats::default(),
+        }
+    }
+
+    pub fn add_node(&mut self, address: &str, node: SimNode) {
+        self.nodes.insert(address.to_string(), node);
+    }
+
+    pub fn add_client(&mut self, address: &str) {
+        self.inboxes.entry(address.to_string()).or_default();
+    }
+
+    pub fn node(&self, address: &str) -> Option<&SimNode> {
+        self.nodes.get(address)
+    }
+
+    pub fn set_default_link(&mut self, link: LinkConfig) {
+        self.default_link = link;
+    }
+
+    /// Overrides the link between `a` and `b`, in both directions.
+    pub fn set_link(&mut self, a: &str, b: &str, link: LinkConfig) {
+        self.links.insert(link_key(a, b), link);
+    }
+
+    /// Drops every frame between `a` and `b`, including those in flight when
+    /// they arrive.
+    pub fn partition(&mut self, a: &str, b: &str) {
+        self.partitions.insert(link_key(a, b));
+    }
+
+    pub fn heal(&mut self, a: &str, b: &str) {
+        self.partitions.remove(&link_key(a, b));
+    }
+
+    pub fn now_ms(&self) -> u64 {
+        self.now_ms
+    }
+
+    /// The clock nodes see, for token issued_at/expires_at.
+    pub fn unix_now(&self) -> u64 {
+        START_UNIX + self.now_ms / 1000
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+
+    pub fn inbox(&self, address: &str) -> &[Received] {
+        self.inboxes.get(address).map(Vec::as_slice).unwrap_or(&[])
+    }
+
+    pub fn take_inbox(&mut self, address: &str) -> Vec<Received> {
+        self.inboxes
+            .get_mut(address)
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    /// Puts `frame` on the link from `from` to `to`.
+    pub fn send(&mut self, from: &str, to: &str, frame: Vec<u8>) {
+        self.send_after(from, to, frame, 0);
+    }
+
+    /// `send`, with the frame leaving `delay_ms` from now.
+    fn send_after(&mut self, from: &str, to: &str, frame: Vec<u8>, delay_ms: u64) {
+        self.stats.sent += 1;
+        let link = self
+            .links
+            .get(&link_key(from, to))
+            .copied()
+            .unwrap_or(self.default_link);
+        if self.rng.chance(link.loss) {
+            self.stats.lost += 1;
+            return;
+        }
+        let copies = if self.rng.chance(link.duplicate) {
+            self.stats.duplicated += 1;
+            2
+        } else {
+            1
+        };
+        for _ in 0..copies {
+            let at_ms =
+                self.now_ms + delay_ms + link.latency_ms + self.rng.below(link.jitter_ms + 1);
+            self.seq += 1;
+            self.pending.push(Delivery {
+                at_ms,
+                seq: self.seq,
+                from: from.to_string(),
+                to: to.to_string(),
+                frame: frame.clone(),
+            });
+        }
+    }
+
+    /// Delivers the next frame in flight, advancing the clock to its arrival.
+    /// Returns false when nothing is in flight.
+    pub fn step(&mut self) -> bool {
+        let Some(index) = self
+            .pending
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, delivery)| (delivery.at_ms, delivery.seq))
+            .map(|(index, _)| index)
+        else {
+            return false;
+        };
+        let mut delivery = self.pending.swap_remove(index);
+        self.now_ms = self.now_ms.max(delivery.at_ms);
+
+        if self
+            .partitions
+            .contains(&link_key(&delivery.from, &delivery.to))
+        {
+            self.stats.partitioned += 1;
+            return true;
+        }
+
+        let now = self.unix_now();
+        if let Some(node) = self.nodes.get_mut(&delivery.to) {
+            self.stats.delivered += 1;
+            let started_ms = node.clock.now_ms();
+            let out = node.handle_frame(&mut delivery.frame, &delivery.from, now);
+            let busy_ms = node.clock.now_ms() - started_ms;
+            // Links lose frames but never refuse them, so every send succeeds.
+            node.sent();
+            for (next_hop, frame) in out {
+                self.send_after(&delivery.to, &next_hop, frame, busy_ms);
+            }
+        } else if let Some(inbox) = self.inboxes.get_mut(&delivery.to) {
+            self.stats.delivered += 1;
+            inbox.push(Received {
+                at_ms: self.now_ms,
+                from: delivery.from,
+                frame: delivery.frame,
+            });
+        } else {
+            self.stats.unroutable += 1;
+        }
+        true
+    }
+
+    /// Runs `Node::tick` on every node at the current time.
+    pub fn tick(&mut self) {
+        let now = self.unix_now();
+        let addresses = self.nodes.keys().cloned().collect::<Vec<_>>();
+        for address in addresses {
+            let out = match self.nodes.get_mut(&address) {
+                Some(node) => {
+                    let out = node.tick(now);
+                    node.sent();
+                    out
+                }
+                None => continue,
+            };
+            for (next_hop, frame) in out {
+                self.send(&address, &next_hop, frame);
+            }
+        }
+    }
+
+    /// Delivers everything due by `now_ms + ms`, ticking the nodes at each
+    /// whole simulated second, then sets the clock there.
+    pub fn run_for(&mut self, ms: u64) {
+        let until = self.now_ms + ms;
+        loop {
+            let next_tick = (self.now_ms / 1000 + 1) * 1000;
+            let next_delivery = self.pending.iter().map(|delivery| delivery.at_ms).min();
+            match next_delivery {
+                Some(at_ms) if at_ms <= until && at_ms < next_tick => {
+                    self.step();
+                }
+                _ if next_tick <= until => {
+                    self.now_ms = next_tick;
+                    self.tick();
+                }
+                _ => break,
+            }
+        }
+        self.now_ms = until;
+    }
+
+    /// Delivers until nothing is in flight.
+    pub fn run_until_idle(&mut self) {
+        while self.step() {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scrap_core_lite::{
+        build_heartbeat, build_task_request, decode_envelope, encode_envelope, Contact, Payload,
+        TaskRequest, Token,
+    };
+    use scrap_edge::DETAIL_REPLAY;
+    use scrap_linux_udp::{StoreForward, StoreForwardConfig};
+
+    /// ORCH (client) - A - C - B, with B executing.
+    fn line(seed: u64) -> Simulator {
+        let mut sim = Simulator::new(seed);
+        sim.add_client("ORCH");
+        sim.add_node(
+            "A",
+            sim_node("A", &[("B", "C"), ("C", "C"), ("ORCH", "ORCH")]),
+        );
+        sim.add_node("C", sim_node("C", &[("B", "B"), ("ORCH", "A")]));
+        sim.add_node("B", sim_node("B", &[("ORCH", "C")]));
+        sim
+    }
+
+    fn task(token_id: u8, hop_limit: u8, expires_at: u64) -> Vec<u8> {
+        command_task(token_id, hop_limit, expires_at, "demo.hash", "7")
+    }
+
+    fn command_task(
+        token_id: u8,
+        hop_limit: u8,
+        expires_at: u64,
+        command: &str,
+        args: &str,
+    ) -> Vec<u8> {
+        let token = Token {
+            token_id: vec![token_id; 16],
+            subject: "DEV-COMMANDER".to_string(),
+            audience: "B".to_string(),
+            capability: command.to_string(),
+            issued_at: START_UNIX,
+            expires_at,
+        };
+        let env = build_task_request(
+            vec![token_id; 16],
+            "ORCH".to_string(),
+            "B".to_string(),
+            hop_limit,
+            TaskRequest {
+                token,
+                command: command.to_string(),
+                args: args.to_string(),
+                reply_to: "ORCH".to_string(),
+                commander_pubkey: "DEV-COMMANDER".to_string(),
+            },
+        );
+        let mut out = Vec::new();
+        encode_envelope(&env, &mut out).unwrap();
+        out
+    }
+
+    /// `line` with store-and-forward on every node and 1s heartbeats.
+    fn store_forward_line(link_timeout_sec: u64) -> Simulator {
+        let mut sim = Simulator::new(1);
+        sim.add_client("ORCH");
+        let nodes = [
+            ("A", vec![("B", "C"), ("C", "C"), ("ORCH", "ORCH")]),
+            ("C", vec![("B", "B"), ("ORCH", "A")]),
+            ("B", vec![("ORCH", "C")]),
+        ];
+        for (address, routes) in nodes {
+            let mut node = sim_node(address, &routes);
+            node.store_forward = Some(
+                StoreForward::open(StoreForwardConfig {
+                    heartbeat_interval_sec: 1,
+                    link_timeout_sec,
+                    ..StoreForwardConfig::default()
+                })
+                .unwrap(),
+            );
+            sim.add_node(address, node);
+        }
+        sim
+    }
+
+    fn contact(dst: &str, next_hop: &str, from_sec: u64, to_sec: u64) -> Contact {
+        Contact {
+            dst: dst.to_string(),
+            next_hop: next_hop.to_string(),
+            start: START_UNIX + from_sec,
+            end: START_UNIX + to_sec,
+            bandwidth: 1_000,
+        }
+    }
+
+    fn queued(sim: &Simulator, address: &str, next_hop: &str) -> usize {
+        sim.node(address)
+            .and_then(|node| node.store_forward.as_ref())
+            .map_or(0, |store| store.queued(next_hop))
+    }
+
+    /// What reached ORCH, without the heartbeats.
+    fn replies(sim: &Simulator) -> Vec<Payload> {
+        payloads(sim)
+            .into_iter()
+            .filter(|payload| !matches!(payload, Payload::Heartbeat(_)))
+            .collect()
+    }
+
+    fn payloads(sim: &Simulator) -> Vec<Payload> {
+        sim.inbox("ORCH")
+            .iter()
+            .map(|received| decode_envelope(&received.frame).unwrap().payload)
+            .collect()
+    }
+
+    #[test]
+    fn forwards_through_relays_and_returns_result() {
+        let mut sim = line(1);
+        sim.send("ORCH", "A", task(1, 8, u64::MAX));
+        sim.run_until_idle();
+
+        let inbox = sim.inbox("ORCH");
+        assert_eq!(inbox.len(), 1);
+        // Three hops out and three back at the default 5ms.
+        assert_eq!(inbox[0].at_ms, 30);
+        match &payloads(&sim)[0] {
+            Payload::TaskResult(result) => {
+                assert_eq!(result.status, 0);
+                assert_eq!(result.telemetry.node_id, "B");
+            }
+            other => panic!("expected result, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn sleeping_tasks_take_simulated_time() {
+        let mut sim = line(1);
+        sim.send(
+            "ORCH",
+            "A",
+            command_task(1, 8, u64::MAX, "demo.sleep", "250"),
+        );
+        sim.run_until_idle();
+
+        let inbox = sim.inbox("ORCH");
+        assert_eq!(inbox.len(), 1);
+        // The six 5ms hops plus the 250ms B spent on the task.
+        assert_eq!(inbox[0].at_ms, 280);
+        match &payloads(&sim)[0] {
+            Payload::TaskResult(result) => assert_eq!(result.telemetry.duration_ms, 250),
+            other => panic!("expected result, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn exhausted_hop_limit_is_rejected_by_the_relay() {
+        let mut sim = line(1);
+        sim.send("ORCH", "A", task(1, 0, u64::MAX));
+        sim.run_until_idle();
+
+        match &payloads(&sim)[..] {
+            [Payload::TaskRejected(rejected)] => {
+                assert_eq!(rejected.reason, "hop_limit_exceeded")
+            }
+            other => panic!("expected one reject, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn duplicated_task_hits_the_replay_cache() {
+        let mut sim = line(1);
+        sim.set_link(
+            "C",
+            "B",
+            LinkConfig {
+                duplicate: 1.0,
+                ..LinkConfig::default()
+            },
+        );
+        sim.send("ORCH", "A", task(1, 8, u64::MAX));
+        sim.run_until_idle();
+
+        let payloads = payloads(&sim);
+        assert!(payloads
+            .iter()
+            .any(|payload| matches!(payload, Payload::TaskResult(_))));
+        assert!(payloads.iter().any(|payload| matches!(
+            payload,
+            Payload::TaskRejected(rejected) if rejected.details == [DETAIL_REPLAY]
+        )));
+    }
+
+    #[test]
+    fn same_seed_reproduces_a_lossy_run() {
+        let run = |seed| {
+            let mut sim = line(seed);
+            sim.set_default_link(LinkConfig {
+                latency_ms: 5,
+                jitter_ms: 40,
+                loss: 0.2,
+                duplicate: 0.1,
+            });
+            for token_id in 0..20 {
+                sim.send("ORCH", "A", task(token_id, 8, u64::MAX));
+                sim.run_for(10);
+            }
+            sim.run_until_idle();
+            (sim.stats(), sim.take_inbox("ORCH"))
+        };
+
+        let (stats, inbox) = run(7);
+        assert!(stats.lost > 0 && stats.duplicated > 0);
+        // Jitter lets later results overtake earlier ones.
+        assert!(inbox
+            .windows(2)
+            .any(|pair| pair[0].frame[..] > pair[1].frame[..]));
+        assert_eq!(run(7), (stats, inbox.clone()));
+        assert_ne!(run(8).1, inbox);
+    }
+
+    #[test]
+    fn partition_times_out_until_healed() {
+        let mut sim = line(1);
+        sim.partition("C", "B");
+        sim.send("ORCH", "A", task(1, 8, u64::MAX));
+        sim.run_for(1_000);
+        assert!(sim.inbox("ORCH").is_empty());
+        assert_eq!(sim.stats().partitioned, 1);
+
+        // B never saw the token, so a retry is not a replay.
+        sim.heal("C", "B");
+        sim.send("ORCH", "A", task(1, 8, u64::MAX));
+        sim.run_until_idle();
+        assert!(matches!(&payloads(&sim)[..], [Payload::TaskResult(_)]));
+        assert_eq!(sim.inbox("ORCH")[0].at_ms, 1_030);
+    }
+
+    #[test]
+    fn slow_link_lets_the_token_expire() {
+        let mut sim = line(1);
+        sim.set_link(
+            "C",
+            "B",
+            LinkConfig {
+                latency_ms: 10_000,
+                ..LinkConfig::default()
+            },
+        );
+        sim.send("ORCH", "A", task(1, 8, START_UNIX + 5));
+        sim.run_until_idle();
+
+        match &payloads(&sim)[..] {
+            [Payload::TaskRejected(rejected)] => {
+                assert!(rejected
+                    .details
+                    .iter()
+                    .any(|detail| detail == "token expired"))
+            }
+            other => panic!("expected one reject, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn store_and_forward_holds_traffic_until_the_link_returns() {
+        let mut sim = store_forward_line(3);
+        sim.partition("C", "B");
+        sim.run_for(2_000);
+        sim.send("ORCH", "A", task(1, 8, u64::MAX));
+        sim.run_for(5_000);
+        assert!(replies(&sim).is_empty());
+        assert_eq!(queued(&sim, "C", "B"), 1);
+
+        // B's next heartbeat releases C's queue. By the time the result is
+        // back at A, ORCH has been silent past the timeout, so it waits there.
+        sim.heal("C", "B");
+        sim.run_for(2_000);
+        assert_eq!(queued(&sim, "C", "B"), 0);
+        assert!(replies(&sim).is_empty());
+        assert_eq!(queued(&sim, "A", "ORCH"), 1);
+
+        let mut heartbeat = Vec::new();
+        encode_envelope(
+            &build_heartbeat("ORCH".to_string(), sim.unix_now()),
+            &mut heartbeat,
+        )
+        .unwrap();
+        sim.send("ORCH", "A", heartbeat);
+        sim.run_for(100);
+        assert!(matches!(&replies(&sim)[..], [Payload::TaskResult(_)]));
+        assert_eq!(queued(&sim, "A", "ORCH"), 0);
+    }
+
+    #[test]
+    fn queued_request_past_expiry_is_rejected_to_the_sender() {
+        let mut sim = store_forward_line(30);
+        sim.partition("C", "B");
+        sim.run_for(2_000);
+        sim.send("ORCH", "A", task(1, 8, START_UNIX + 5));
+        sim.run_for(10_000);
+
+        match &replies(&sim)[..] {
+            [Payload::TaskRejected(rejected)] => {
+                assert_eq!(rejected.reason, "queue_expired");
+                assert_eq!(rejected.details, ["token expired while queued"]);
+            }
+            other => panic!("expected one reject, got {other:?}"),
+        }
+        assert_eq!(queued(&sim, "C", "B"), 0);
+    }
+
+    #[test]
+    fn contact_plan_picks_the_route_open_at_send_time() {
+        let mut sim = Simulator::new(1);
+        sim.add_client("ORCH");
+        sim.add_client("C");
+        sim.add_client("D");
+        let mut node = sim_node("A", &[("B", "C"), ("ORCH", "ORCH")]);
+        node.set_routes(
+            node.routes()
+                .clone()
+                .with_contacts(vec![contact("B", "D", 0, 5)]),
+        );
+        sim.add_node("A", node);
+
+        sim.run_for(1_000);
+        sim.send("ORCH", "A", task(1, 8, u64::MAX));
+        sim.run_for(5_000);
+        sim.send("ORCH", "A", task(2, 8, u64::MAX));
+        sim.run_until_idle();
+
+        assert_eq!(sim.inbox("D").len(), 1);
+        assert_eq!(sim.inbox("C").len(), 1);
+        assert!(sim.inbox("C")[0].at_ms > 6_000);
+    }
+
+    #[test]
+    fn traffic_waits_for_the_next_contact() {
+        let mut sim = store_forward_line(30);
+        // A only reaches C, and through it B, during a window from 10s to 20s.
+        let mut node = sim_node("A", &[("ORCH", "ORCH")]);
+        node.set_routes(
+            node.routes()
+                .clone()
+                .with_contacts(vec![contact("B", "C", 10, 20), contact("C", "C", 10, 20)]),
+        );
+        node.store_forward = Some(
+            StoreForward::open(StoreForwardConfig {
+                heartbeat_interval_sec: 1,
+                link_timeout_sec: 30,
+                ..StoreForwardConfig::default()
+            })
+            .unwrap(),
+        );
+        sim.add_node("A", node);
+
+        sim.run_for(2_000);
+        sim.send("ORCH", "A", task(1, 8, u64::MAX));
+        sim.run_for(7_000);
+        assert!(replies(&sim).is_empty());
+        assert_eq!(queued(&sim, "A", "C"), 1);
+
+        sim.run_for(2_000);
+        assert_eq!(queued(&sim, "A", "C"), 0);
+        match &replies(&sim)[..] {
+            [Payload::TaskResult(result)] => assert_eq!(result.telemetry.node_id, "B"),
+            other => panic!("expected one result, got {other:?}"),
+        }
+        let result = sim
+            .inbox("ORCH")
+            .iter()
+            .find(|received| {
+                !matches!(
+                    decode_envelope(&received.frame).unwrap().payload,
+                    Payload::Heartbeat(_)
+                )
+            })
+            .unwrap();
+        assert!(result.at_ms >= 10_000);
+    }
+}
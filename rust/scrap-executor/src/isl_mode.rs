@@ -0,0 +1,1040 @@
+use crate::settlement_store::{SettlementRecord, SettlementStore};
+use crate::{ensure_parent, load_string_list, replay_check_and_add, to_hex, unix_ts, with_lock};
+use scrap_protocol::{
+    hex_to_bytes, sha256, BoundTaskRequest, DisputeMessage, ExecutionProof, Heartbeat,
+    IslScapMessage, LightningWrapper, MessageType, PeerLiveness, SatCapToken, SatCapVerifier,
+    ScapPayload, SettlementPhase, SpecOperator, TaskAccepted, TaskCompleted, TaskRejected,
+    TaskResponse,
+};
+use serde_json::json;
+use std::collections::HashMap;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::{ErrorKind, Write};
+use std::net::{SocketAddr, UdpSocket};
+use std::time::Duration;
+
+const ISL_VERSION: u64 = 1;
+/// Largest accepted difference between a message `timestamp` and our clock.
+const ISL_MAX_SKEW_SEC: u64 = 300;
+
+pub struct IslExecutor {
+    pub verifier: SatCapVerifier,
+    /// Signs responses and proofs with the executor key.
+    pub signer: SpecOperator,
+    pub node_id: String,
+    /// Shared HMAC-SHA256 key for the inter-satellite link.
+    pub link_key: Vec<u8>,
+    pub replay_cache_path: String,
+    pub revoked_path: String,
+    pub settlement_store_path: String,
+    /// Last accepted sequence per sender, kept across restarts.
+    pub sequence_path: String,
+    /// Seconds between `Accepted` and `Completed`.
+    pub task_duration_sec: u64,
+    /// Seconds without a heartbeat before a peer is considered lost.
+    pub peer_timeout_sec: u64,
+}
+
+/// An accepted task waiting for its completion time.
+struct Pending {
+    due: u64,
+    peer: SocketAddr,
+    sender: String,
+    task_jti: String,
+    request: BoundTaskRequest,
+}
+
+struct Link {
+    /// Highest sequence accepted per sender; anything at or below it is a replay.
+    last_seen: HashMap<String, u64>,
+    /// Seeded from the clock so a restarted executor never reuses a sequence.
+    next_sequence: u64,
+    pending: Vec<Pending>,
+    peers: PeerLiveness,
+    store: SettlementStore,
+}
+
+type Replies = Vec<(SocketAddr, IslScapMessage)>;
+
+impl Link {
+    /// Picks up the sequence state and settlement store left by a previous
+    /// run. Either file being unreadable is an error.
+    fn open(executor: &IslExecutor) -> Result<Self, String> {
+        Ok(Link {
+            last_seen: load_sequences(&executor.sequence_path)?,
+            next_sequence: unix_ts(),
+            pending: Vec::new(),
+            peers: PeerLiveness::new(executor.peer_timeout_sec),
+            store: SettlementStore::open(&executor.settlement_store_path)?,
+        })
+    }
+}
+
+pub fn run(socket: &UdpSocket, executor: &IslExecutor) {
+    let mut link = Link::open(executor).unwrap_or_else(|err| panic!("{err}"));
+    executor.recover(&mut link);
+
+    // Wake up periodically so accepted tasks complete without new traffic.
+    let _ = socket.set_read_timeout(Some(Duration::from_secs(1)));
+    let mut buf = [0u8; 65535];
+    loop {
+        let replies = executor.complete_due(&mut link);
+        send_replies(socket, executor, &mut link, replies);
+        for peer in link.peers.expire(unix_ts()) {
+            let log = json!({
+                "ts": unix_ts(),
+                "event": "peer_lost",
+                "peer": peer
+            });
+            println!("{}", log);
+        }
+
+        let (len, addr) = match socket.recv_from(&mut buf) {
+            Ok(res) => res,
+            Err(_) => continue,
+        };
+
+        let replies = executor.receive(&mut link, &buf[..len], addr);
+        send_replies(socket, executor, &mut link, replies);
+    }
+}
+
+fn send_replies(socket: &UdpSocket, executor: &IslExecutor, link: &mut Link, replies: Replies) {
+    for (peer, mut reply) in replies {
+        reply.sequence = link.next_sequence;
+        link.next_sequence += 1;
+        let encoded = reply
+            .authenticate(&executor.link_key)
+            .and_then(|_| reply.encode_cbor());
+        match encoded {
+            Ok(payload) => {
+                let _ = socket.send_to(&payload, peer);
+            }
+            Err(err) => {
+                let log = json!({
+                    "ts": unix_ts(),
+                    "event": "encode_failed",
+                    "message_type": message_name(reply.msg_type),
+                    "error": err.reason
+                });
+                println!("{}", log);
+            }
+        }
+    }
+}
+
+fn message_name(msg_type: MessageType) -> &'static str {
+    match msg_type {
+        MessageType::TaskRequest => "task_request",
+        MessageType::TaskResponse => "task_response",
+        MessageType::Proof => "proof",
+        MessageType::Heartbeat => "heartbeat",
+        MessageType::Dispute => "dispute",
+        MessageType::Lightning => "lightning",
+    }
+}
+
+/// The executor runs no Lightning node on the link, so wrapped BOLT messages
+/// are logged by type and dropped.
+fn handle_lightning(wrapper: &LightningWrapper, sender: &str) {
+    let now = unix_ts();
+    let log = match wrapper.bolt_payload.get(..2) {
+        Some(bolt_type) => json!({
+            "ts": now,
+            "event": "lightning_ignored",
+            "sender": sender,
+            "bolt_type": u16::from_be_bytes([bolt_type[0], bolt_type[1]]),
+            "bolt_len": wrapper.bolt_payload.len()
+        }),
+        None => json!({
+            "ts": now,
+            "event": "lightning_rejected",
+            "sender": sender,
+            "error": "bolt payload shorter than its type"
+        }),
+    };
+    println!("{}", log);
+}
+
+fn handle_heartbeat(link: &mut Link, heartbeat: &Heartbeat, sender: &str, addr: SocketAddr) {
+    let now = unix_ts();
+    if heartbeat.sender != sender {
+        let log = json!({
+            "ts": now,
+            "event": "heartbeat_rejected",
+            "sender": sender,
+            "heartbeat_sender": heartbeat.sender
+        });
+        println!("{}", log);
+        return;
+    }
+    if link.peers.observe(heartbeat, now) {
+        let log = json!({
+            "ts": now,
+            "event": "peer_alive",
+            "peer": sender,
+            "source": addr.to_string(),
+            "pending_htlcs": heartbeat.pending_htlcs
+        });
+        println!("{}", log);
+    }
+}
+
+/// Moves the task's settlement to `disputed` and stops any pending work on
+/// it. Only the node that sent the request may dispute it, and only while
+/// the settlement is open; anything else is logged and dropped.
+fn handle_dispute(link: &mut Link, dispute: &DisputeMessage, sender: &str) {
+    let now = unix_ts();
+    let mut record = match disputed_record(&link.store, dispute, sender) {
+        Ok(record) => record,
+        Err(err) => {
+            let log = json!({
+                "ts": now,
+                "event": "dispute_rejected",
+                "task_jti": dispute.task_jti,
+                "sender": sender,
+                "error": err
+            });
+            println!("{}", log);
+            return;
+        }
+    };
+    let log = json!({
+        "ts": now,
+        "event": "dispute_recorded",
+        "task_jti": dispute.task_jti,
+        "sender": sender,
+        "reason": dispute.reason,
+        "from_phase": record.phase
+    });
+    println!("{}", log);
+    link.pending
+        .retain(|task| task.task_jti != dispute.task_jti);
+    record.phase = SettlementPhase::Disputed.as_str().to_string();
+    record.dispute = dispute.encode_cbor().ok().map(|bytes| to_hex(&bytes));
+    record.updated_at = now;
+    persist(&mut link.store, record);
+}
+
+fn disputed_record(
+    store: &SettlementStore,
+    dispute: &DisputeMessage,
+    sender: &str,
+) -> Result<SettlementRecord, String> {
+    let record = store
+        .find_task(&dispute.task_jti)
+        .ok_or_else(|| "unknown task".to_string())?;
+    if record.requester.as_deref() != Some(sender) {
+        return Err("sender is not the requester".to_string());
+    }
+    let payment_hash = hex_to_bytes(&record.request)
+        .and_then(|bytes| BoundTaskRequest::decode_cbor_canonical(&bytes))
+        .map(|request| request.payment_hash);
+    if payment_hash.as_ref().ok() != Some(&dispute.payment_hash) {
+        return Err("payment_hash mismatch".to_string());
+    }
+    let phase = SettlementPhase::parse(&record.phase)
+        .ok_or_else(|| format!("unknown phase {}", record.phase))?;
+    if !phase.can_transition_to(SettlementPhase::Disputed) {
+        return Err(format!("settlement is {}", record.phase));
+    }
+    Ok(record.clone())
+}
+
+fn persist(store: &mut SettlementStore, record: SettlementRecord) {
+    let task_id = record.task_id.clone();
+    let phase = record.phase.clone();
+    if !store.put(record) {
+        let log = json!({
+            "ts": unix_ts(),
+            "event": "settlement_store_failed",
+            "task_id": task_id,
+            "phase": phase,
+            "path": store.path()
+        });
+        println!("{}", log);
+    }
+}
+
+/// A missing file means no sender has been seen yet.
+fn load_sequences(path: &str) -> Result<HashMap<String, u64>, String> {
+    match fs::read_to_string(path) {
+        Ok(raw) => serde_json::from_str(&raw)
+            .map_err(|err| format!("isl sequence state {path} is corrupt: {err}")),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(HashMap::new()),
+        Err(err) => Err(format!("isl sequence state {path} unreadable: {err}")),
+    }
+}
+
+fn save_sequences(path: &str, last_seen: &HashMap<String, u64>) -> bool {
+    with_lock(path, || {
+        ensure_parent(path);
+        let tmp_path = format!("{}.tmp", path);
+        let payload = serde_json::to_vec_pretty(last_seen).ok()?;
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp_path)
+            .ok()?;
+        file.write_all(&payload).ok()?;
+        file.sync_all().ok()?;
+        fs::rename(&tmp_path, path).ok()?;
+        Some(true)
+    })
+    .unwrap_or(false)
+}
+
+impl IslExecutor {
+    /// Re-queues tasks that were accepted but never completed, as
+    /// `SpecExecutor::recover` does for spec sessions.
+    fn recover(&self, link: &mut Link) {
+        let now = unix_ts();
+        let accepted: Vec<SettlementRecord> = link
+            .store
+            .records()
+            .filter(|record| record.phase == SettlementPhase::Accepted.as_str())
+            .cloned()
+            .collect();
+        for record in accepted {
+            match self.pending_from_record(&record) {
+                Ok(task) => {
+                    let log = json!({
+                        "ts": now,
+                        "event": "settlement_recovered",
+                        "task_id": record.task_id,
+                        "correlation_id": record.correlation_id,
+                        "phase": record.phase,
+                        "due": task.due
+                    });
+                    println!("{}", log);
+                    link.pending.push(task);
+                }
+                Err(err) => {
+                    let log = json!({
+                        "ts": now,
+                        "event": "settlement_recover_failed",
+                        "task_id": record.task_id,
+                        "correlation_id": record.correlation_id,
+                        "error": err
+                    });
+                    println!("{}", log);
+                }
+            }
+        }
+    }
+
+    fn pending_from_record(&self, record: &SettlementRecord) -> Result<Pending, String> {
+        let request = hex_to_bytes(&record.request)
+            .and_then(|bytes| BoundTaskRequest::decode_cbor_canonical(&bytes))
+            .map_err(|err| err.reason)?;
+        Ok(Pending {
+            due: record.updated_at + self.task_duration_sec,
+            peer: record
+                .peer
+                .parse()
+                .map_err(|_| format!("invalid peer {}", record.peer))?,
+            sender: record
+                .requester
+                .clone()
+                .ok_or_else(|| "requester missing".to_string())?,
+            task_jti: record.task_id.clone(),
+            request,
+        })
+    }
+
+    /// Decodes, authenticates and dispatches one datagram from `addr`.
+    fn receive(&self, link: &mut Link, datagram: &[u8], addr: SocketAddr) -> Replies {
+        let message = match IslScapMessage::decode_cbor_canonical(datagram) {
+            Ok(message) => message,
+            Err(err) => {
+                let log = json!({
+                    "ts": unix_ts(),
+                    "event": "invalid_message",
+                    "source": addr.to_string(),
+                    "error": err.reason
+                });
+                println!("{}", log);
+                return Vec::new();
+            }
+        };
+        if let Err(err) = self.authenticate(link, &message, unix_ts()) {
+            let log = json!({
+                "ts": unix_ts(),
+                "event": "isl_rejected",
+                "source": addr.to_string(),
+                "sender": message.sender,
+                "sequence": message.sequence,
+                "error": err
+            });
+            println!("{}", log);
+            return Vec::new();
+        }
+
+        match message.payload {
+            ScapPayload::TaskRequest(request) => {
+                self.handle_request(link, request, &message.sender, addr)
+            }
+            ScapPayload::Heartbeat(heartbeat) => {
+                handle_heartbeat(link, &heartbeat, &message.sender, addr);
+                Vec::new()
+            }
+            ScapPayload::Dispute(dispute) => {
+                handle_dispute(link, &dispute, &message.sender);
+                Vec::new()
+            }
+            ScapPayload::Lightning(wrapper) => {
+                handle_lightning(&wrapper, &message.sender);
+                Vec::new()
+            }
+            ScapPayload::TaskResponse(_) | ScapPayload::Proof(_) => {
+                let log = json!({
+                    "ts": unix_ts(),
+                    "event": "unexpected_message",
+                    "source": addr.to_string(),
+                    "message_type": message_name(message.msg_type)
+                });
+                println!("{}", log);
+                Vec::new()
+            }
+        }
+    }
+
+    /// Link-layer checks: addressed to us, stamped within `ISL_MAX_SKEW_SEC`
+    /// of `now`, HMAC under the link key and a sequence above the last one
+    /// seen from the sender. The new sequence is persisted before the
+    /// message is accepted.
+    fn authenticate(
+        &self,
+        link: &mut Link,
+        message: &IslScapMessage,
+        now: u64,
+    ) -> Result<(), String> {
+        if message.version != ISL_VERSION {
+            return Err(format!("unsupported isl version {}", message.version));
+        }
+        if message.recipient != self.node_id {
+            return Err("isl recipient mismatch".to_string());
+        }
+        if message.timestamp.abs_diff(now) > ISL_MAX_SKEW_SEC {
+            return Err("isl timestamp outside window".to_string());
+        }
+        message
+            .verify_hmac(&self.link_key)
+            .map_err(|err| err.reason)?;
+        let last = link.last_seen.get(&message.sender).copied();
+        if last.is_some_and(|last| message.sequence <= last) {
+            return Err("isl sequence replayed".to_string());
+        }
+        link.last_seen
+            .insert(message.sender.clone(), message.sequence);
+        if !save_sequences(&self.sequence_path, &link.last_seen) {
+            return Err("isl sequence state unavailable".to_string());
+        }
+        Ok(())
+    }
+
+    fn handle_request(
+        &self,
+        link: &mut Link,
+        request: BoundTaskRequest,
+        sender: &str,
+        addr: SocketAddr,
+    ) -> Replies {
+        let now = unix_ts();
+        let token = match self.verify_request(&request, now) {
+            Ok(token) => token,
+            Err((task_jti, err)) => {
+                return self.reject(&task_jti, sender, addr, "VALIDATION_FAILED", &err);
+            }
+        };
+        let task_jti = token.payload.jti.clone();
+        let mut accepted = TaskAccepted {
+            task_jti: task_jti.clone(),
+            accepted_at: now,
+            estimated_completion: now + self.task_duration_sec,
+            executor_sig: Vec::new(),
+        };
+        if let Err(err) = accepted.sign(&self.signer.operator_key) {
+            return self.reject(&task_jti, sender, addr, "EXECUTION_FAILED", &err.reason);
+        }
+        let log = json!({
+            "ts": now,
+            "event": "task_accepted",
+            "task_jti": task_jti,
+            "sender": sender,
+            "payment_hash": to_hex(&request.payment_hash),
+            "payment_amount_msat": request.payment_amount_msat,
+            "estimated_completion": accepted.estimated_completion
+        });
+        println!("{}", log);
+        let record = SettlementRecord {
+            task_id: task_jti.clone(),
+            correlation_id: to_hex(&request.correlation_id()),
+            phase: SettlementPhase::Accepted.as_str().to_string(),
+            peer: addr.to_string(),
+            request: request
+                .encode_cbor()
+                .map(|bytes| to_hex(&bytes))
+                .unwrap_or_default(),
+            lock: None,
+            proof: None,
+            dispute: None,
+            expires_at: None,
+            requester: Some(sender.to_string()),
+            updated_at: now,
+        };
+        persist(&mut link.store, record);
+        link.pending.push(Pending {
+            due: accepted.estimated_completion,
+            peer: addr,
+            sender: sender.to_string(),
+            task_jti,
+            request,
+        });
+        vec![(
+            addr,
+            self.response(sender, TaskResponse::Accepted(accepted)),
+        )]
+    }
+
+    /// Resolves the token's `prf` ancestors from the request's `proof_chain`.
+    /// On failure returns the task jti (empty if the token did not decode)
+    /// alongside the reason.
+    fn verify_request(
+        &self,
+        request: &BoundTaskRequest,
+        now: u64,
+    ) -> Result<SatCapToken, (String, String)> {
+        let task_jti = SatCapToken::decode_cbor(&request.capability_token)
+            .map(|token| token.payload.jti)
+            .unwrap_or_default();
+        let fail = |reason: &str| (task_jti.clone(), reason.to_string());
+        let known = request
+            .proof_chain
+            .iter()
+            .map(|bytes| SatCapToken::decode_cbor_canonical(bytes))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| fail(&format!("proof_chain: {}", err.reason)))?;
+        let token = self
+            .verifier
+            .verify_bound_request(request, &known, now)
+            .map_err(|err| fail(&err.reason))?;
+        let revoked = load_string_list(&self.revoked_path);
+        if revoked.contains(&token.payload.jti) {
+            return Err(fail("token revoked"));
+        }
+        match replay_check_and_add(&self.replay_cache_path, &token.payload.jti) {
+            Some(true) => Ok(token),
+            Some(false) => Err(fail("replay detected (jti already used)")),
+            None => Err(fail("replay cache unavailable")),
+        }
+    }
+
+    fn complete_due(&self, link: &mut Link) -> Replies {
+        let now = unix_ts();
+        let (due, waiting): (Vec<Pending>, Vec<Pending>) =
+            link.pending.drain(..).partition(|task| task.due <= now);
+        link.pending = waiting;
+        let mut out = Vec::new();
+        for task in due {
+            let completed = match self.execute(&task, now) {
+                Ok(completed) => completed,
+                Err(err) => {
+                    let log = json!({
+                        "ts": now,
+                        "event": "proof_failed",
+                        "task_jti": task.task_jti,
+                        "error": err
+                    });
+                    println!("{}", log);
+                    continue;
+                }
+            };
+            let correlation_id = to_hex(&task.request.correlation_id());
+            if let Some(mut record) = link.store.get(&correlation_id).cloned() {
+                record.phase = SettlementPhase::ProofSent.as_str().to_string();
+                record.proof = completed
+                    .proof
+                    .encode_cbor()
+                    .ok()
+                    .map(|bytes| to_hex(&bytes));
+                record.updated_at = now;
+                persist(&mut link.store, record);
+            }
+            out.push((
+                task.peer,
+                self.response(&task.sender, TaskResponse::Completed(completed)),
+            ));
+        }
+        out
+    }
+
+    fn execute(&self, task: &Pending, now: u64) -> Result<TaskCompleted, String> {
+        let mut output = task.task_jti.as_bytes().to_vec();
+        output.extend_from_slice(&task.request.capability_token);
+        let mut proof = ExecutionProof {
+            task_jti: task.task_jti.clone(),
+            payment_hash: task.request.payment_hash.clone(),
+            output_hash: sha256(&output).to_vec(),
+            execution_timestamp: now,
+            output_metadata: None,
+            executor_sig: Vec::new(),
+        };
+        proof
+            .sign(&self.signer.operator_key)
+            .map_err(|err| err.reason)?;
+        let log = json!({
+            "ts": now,
+            "event": "proof_sent",
+            "task_jti": proof.task_jti,
+            "payment_hash": to_hex(&proof.payment_hash),
+            "output_hash": to_hex(&proof.output_hash)
+        });
+        println!("{}", log);
+        Ok(TaskCompleted {
+            task_jti: task.task_jti.clone(),
+            proof,
+            data_location: None,
+        })
+    }
+
+    fn reject(
+        &self,
+        task_jti: &str,
+        sender: &str,
+        addr: SocketAddr,
+        reason: &str,
+        detail: &str,
+    ) -> Replies {
+        let now = unix_ts();
+        let log = json!({
+            "ts": now,
+            "event": "task_rejected",
+            "task_jti": task_jti,
+            "reason": reason,
+            "details": detail
+        });
+        println!("{}", log);
+        let mut rejected = TaskRejected {
+            task_jti: task_jti.to_string(),
+            rejected_at: now,
+            reason: reason.to_string(),
+            detail: Some(detail.to_string()),
+            executor_sig: Vec::new(),
+        };
+        if rejected.sign(&self.signer.operator_key).is_err() {
+            return Vec::new();
+        }
+        vec![(
+            addr,
+            self.response(sender, TaskResponse::Rejected(rejected)),
+        )]
+    }
+
+    /// Wraps a response for `recipient`; sequence and HMAC are filled in on send.
+    fn response(&self, recipient: &str, response: TaskResponse) -> IslScapMessage {
+        IslScapMessage {
+            version: ISL_VERSION,
+            msg_type: MessageType::TaskResponse,
+            sender: self.node_id.clone(),
+            recipient: recipient.to_string(),
+            sequence: 0,
+            timestamp: unix_ts(),
+            payload: ScapPayload::TaskResponse(response),
+            hmac: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scrap_protocol::{
+        derive_payment_hash, keypair_from_secret, pubkey_from_secret, CapHeader, CapPayload,
+    };
+
+    const OPERATOR_SECRET: &str =
+        "1111111111111111111111111111111111111111111111111111111111111111";
+    const COMMANDER_SECRET: &str =
+        "2222222222222222222222222222222222222222222222222222222222222222";
+    const DELEGATE_SECRET: &str =
+        "3333333333333333333333333333333333333333333333333333333333333333";
+    const EXECUTOR_SECRET: &str =
+        "4444444444444444444444444444444444444444444444444444444444444444";
+    const LINK_KEY: [u8; 32] = [0x55; 32];
+    const NODE_ID: &str = "SAT-EXEC";
+    const COMMANDER_ID: &str = "SAT-CMD";
+
+    fn temp_dir(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("scrap-isl-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.to_string_lossy().into_owned()
+    }
+
+    fn executor(dir: &str, task_duration_sec: u64) -> IslExecutor {
+        let executor_pubkey = pubkey_from_secret(EXECUTOR_SECRET).unwrap();
+        IslExecutor {
+            verifier: SatCapVerifier {
+                operator_pubkey: pubkey_from_secret(OPERATOR_SECRET).unwrap(),
+                executor_pubkey: executor_pubkey.clone(),
+            },
+            signer: SpecOperator {
+                operator_key: keypair_from_secret(EXECUTOR_SECRET).unwrap(),
+                operator_pubkey: executor_pubkey,
+            },
+            node_id: NODE_ID.to_string(),
+            link_key: LINK_KEY.to_vec(),
+            replay_cache_path: format!("{dir}/replay.json"),
+            revoked_path: format!("{dir}/revoked.json"),
+            settlement_store_path: format!("{dir}/settlement.json"),
+            sequence_path: format!("{dir}/sequences.json"),
+            task_duration_sec,
+            peer_timeout_sec: 30,
+        }
+    }
+
+    fn pubkey_hex(secret: &str) -> String {
+        to_hex(&pubkey_from_secret(secret).unwrap())
+    }
+
+    fn root_token(jti: &str) -> SatCapToken {
+        let now = unix_ts();
+        let mut token = SatCapToken {
+            header: CapHeader {
+                alg: "BIP340".to_string(),
+                typ: "SAT-CAP".to_string(),
+                enc: Some("CBOR".to_string()),
+                chn: None,
+            },
+            payload: CapPayload {
+                iss: pubkey_hex(OPERATOR_SECRET),
+                sub: pubkey_hex(COMMANDER_SECRET),
+                aud: pubkey_hex(EXECUTOR_SECRET),
+                iat: now - 60,
+                exp: now + 3_600,
+                jti: jti.to_string(),
+                cap: vec!["cmd:imaging:msi".to_string()],
+                cns: None,
+                prf: None,
+                cmd_pub: None,
+            },
+            signature: Vec::new(),
+        };
+        token
+            .sign(&keypair_from_secret(OPERATOR_SECRET).unwrap())
+            .unwrap();
+        token
+    }
+
+    fn delegated_token(parent: &SatCapToken, jti: &str) -> SatCapToken {
+        let mut token = parent.clone();
+        token.header.chn = Some(1);
+        token.payload.iss = pubkey_hex(COMMANDER_SECRET);
+        token.payload.sub = pubkey_hex(DELEGATE_SECRET);
+        token.payload.jti = jti.to_string();
+        token.payload.prf = Some(parent.payload.jti.clone());
+        token
+            .sign(&keypair_from_secret(COMMANDER_SECRET).unwrap())
+            .unwrap();
+        token
+    }
+
+    fn request(token: &SatCapToken, signer_secret: &str) -> BoundTaskRequest {
+        let capability_token = token.encode_cbor().unwrap();
+        let mut request = BoundTaskRequest {
+            payment_hash: derive_payment_hash(sha256(&capability_token)).to_vec(),
+            capability_token,
+            payment_amount_msat: 1_000,
+            htlc_timeout_blocks: 144,
+            binding_sig: Vec::new(),
+            proof_chain: Vec::new(),
+        };
+        request
+            .sign(&keypair_from_secret(signer_secret).unwrap())
+            .unwrap();
+        request
+    }
+
+    fn datagram(sequence: u64, timestamp: u64, payload: ScapPayload, key: &[u8]) -> Vec<u8> {
+        let msg_type = match &payload {
+            ScapPayload::TaskRequest(_) => MessageType::TaskRequest,
+            ScapPayload::Heartbeat(_) => MessageType::Heartbeat,
+            ScapPayload::Dispute(_) => MessageType::Dispute,
+            ScapPayload::Lightning(_) => MessageType::Lightning,
+            ScapPayload::TaskResponse(_) => MessageType::TaskResponse,
+            ScapPayload::Proof(_) => MessageType::Proof,
+        };
+        let mut message = IslScapMessage {
+            version: ISL_VERSION,
+            msg_type,
+            sender: COMMANDER_ID.to_string(),
+            recipient: NODE_ID.to_string(),
+            sequence,
+            timestamp,
+            payload,
+            hmac: None,
+        };
+        message.authenticate(key).unwrap();
+        message.encode_cbor().unwrap()
+    }
+
+    fn peer() -> SocketAddr {
+        "127.0.0.1:7301".parse().unwrap()
+    }
+
+    fn response(replies: &Replies) -> &TaskResponse {
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].1.recipient, COMMANDER_ID);
+        match &replies[0].1.payload {
+            ScapPayload::TaskResponse(response) => response,
+            other => panic!("expected a task response, got {other:?}"),
+        }
+    }
+
+    fn stored_phase(link: &Link, task_id: &str) -> String {
+        link.store.find_task(task_id).unwrap().phase.clone()
+    }
+
+    #[test]
+    fn request_is_accepted_then_completed_with_its_payment_hash() {
+        let dir = temp_dir("complete");
+        let executor = executor(&dir, 0);
+        let mut link = Link::open(&executor).unwrap();
+        let request = request(&root_token("task-complete"), COMMANDER_SECRET);
+        let payload = ScapPayload::TaskRequest(request.clone());
+
+        let replies = executor.receive(
+            &mut link,
+            &datagram(1, unix_ts(), payload, &LINK_KEY),
+            peer(),
+        );
+        assert!(matches!(response(&replies), TaskResponse::Accepted(_)));
+        assert_eq!(stored_phase(&link, "task-complete"), "accepted");
+
+        let replies = executor.complete_due(&mut link);
+        let TaskResponse::Completed(completed) = response(&replies) else {
+            panic!("expected Completed");
+        };
+        assert_eq!(completed.proof.payment_hash, request.payment_hash);
+        assert_eq!(stored_phase(&link, "task-complete"), "proof_sent");
+    }
+
+    #[test]
+    fn delegated_request_is_verified_against_its_proof_chain() {
+        let dir = temp_dir("chain");
+        let executor = executor(&dir, 60);
+        let mut link = Link::open(&executor).unwrap();
+        let root = root_token("task-root");
+        let child = delegated_token(&root, "task-child");
+
+        let unchained = request(&child, DELEGATE_SECRET);
+        let payload = ScapPayload::TaskRequest(unchained.clone());
+        let replies = executor.receive(
+            &mut link,
+            &datagram(1, unix_ts(), payload, &LINK_KEY),
+            peer(),
+        );
+        let TaskResponse::Rejected(rejected) = response(&replies) else {
+            panic!("expected Rejected");
+        };
+        assert_eq!(
+            rejected.detail.as_deref(),
+            Some("prf parent task-root not found")
+        );
+
+        let chained = BoundTaskRequest {
+            proof_chain: vec![root.encode_cbor().unwrap()],
+            ..unchained
+        };
+        let payload = ScapPayload::TaskRequest(chained);
+        let replies = executor.receive(
+            &mut link,
+            &datagram(2, unix_ts(), payload, &LINK_KEY),
+            peer(),
+        );
+        assert!(matches!(response(&replies), TaskResponse::Accepted(_)));
+    }
+
+    #[test]
+    fn link_drops_bad_hmac_stale_timestamps_and_replayed_sequences() {
+        let dir = temp_dir("link");
+        let executor = executor(&dir, 60);
+        let mut link = Link::open(&executor).unwrap();
+        let now = unix_ts();
+        let message = |sequence, timestamp, key: &[u8]| {
+            let heartbeat = ScapPayload::Heartbeat(Heartbeat {
+                sender: COMMANDER_ID.to_string(),
+                timestamp,
+                pending_htlcs: 0,
+                capabilities: None,
+            });
+            IslScapMessage::decode_cbor(&datagram(sequence, timestamp, heartbeat, key)).unwrap()
+        };
+        assert_eq!(
+            executor.authenticate(&mut link, &message(1, now, &[0x66; 32]), now),
+            Err("isl hmac invalid".to_string())
+        );
+        assert_eq!(
+            executor.authenticate(
+                &mut link,
+                &message(1, now - ISL_MAX_SKEW_SEC - 1, &LINK_KEY),
+                now
+            ),
+            Err("isl timestamp outside window".to_string())
+        );
+        assert!(executor
+            .authenticate(
+                &mut link,
+                &message(1, now + ISL_MAX_SKEW_SEC, &LINK_KEY),
+                now
+            )
+            .is_ok());
+        assert!(executor
+            .authenticate(&mut link, &message(5, now, &LINK_KEY), now)
+            .is_ok());
+
+        // The sequence survives a restart.
+        let mut link = Link::open(&executor).unwrap();
+        assert_eq!(
+            executor.authenticate(&mut link, &message(5, now, &LINK_KEY), now),
+            Err("isl sequence replayed".to_string())
+        );
+        assert!(executor
+            .authenticate(&mut link, &message(6, now, &LINK_KEY), now)
+            .is_ok());
+    }
+
+    #[test]
+    fn corrupt_sequence_state_is_an_error() {
+        let dir = temp_dir("corrupt");
+        let executor = executor(&dir, 60);
+        fs::write(&executor.sequence_path, b"{not json").unwrap();
+        let err = Link::open(&executor).err().unwrap();
+        assert!(err.contains("is corrupt"), "{err}");
+    }
+
+    #[test]
+    fn accepted_task_is_completed_after_a_restart() {
+        let dir = temp_dir("recover");
+        let executor_a = executor(&dir, 3_600);
+        let mut link = Link::open(&executor_a).unwrap();
+        let request = request(&root_token("task-recover"), COMMANDER_SECRET);
+        let payload = ScapPayload::TaskRequest(request.clone());
+        let replies = executor_a.receive(
+            &mut link,
+            &datagram(1, unix_ts(), payload, &LINK_KEY),
+            peer(),
+        );
+        assert!(matches!(response(&replies), TaskResponse::Accepted(_)));
+        assert!(executor_a.complete_due(&mut link).is_empty());
+        drop(link);
+
+        // Restarted with a shorter duration so the recovered task is due.
+        let executor_b = executor(&dir, 0);
+        let mut link = Link::open(&executor_b).unwrap();
+        executor_b.recover(&mut link);
+        assert_eq!(link.pending.len(), 1);
+        assert_eq!(link.pending[0].sender, COMMANDER_ID);
+        assert_eq!(link.pending[0].peer, peer());
+        let replies = executor_b.complete_due(&mut link);
+        let TaskResponse::Completed(completed) = response(&replies) else {
+            panic!("expected Completed");
+        };
+        assert_eq!(completed.proof.payment_hash, request.payment_hash);
+        assert_eq!(stored_phase(&link, "task-recover"), "proof_sent");
+
+        // Completed tasks are not picked up again.
+        let mut link = Link::open(&executor_b).unwrap();
+        executor_b.recover(&mut link);
+        assert!(link.pending.is_empty());
+    }
+
+    #[test]
+    fn only_the_requester_can_dispute_an_open_task() {
+        let dir = temp_dir("dispute");
+        let executor = executor(&dir, 3_600);
+        let mut link = Link::open(&executor).unwrap();
+        let request = request(&root_token("task-dispute"), COMMANDER_SECRET);
+        let payload = ScapPayload::TaskRequest(request.clone());
+        executor.receive(
+            &mut link,
+            &datagram(1, unix_ts(), payload, &LINK_KEY),
+            peer(),
+        );
+        assert_eq!(link.pending.len(), 1);
+        let dispute = DisputeMessage {
+            task_jti: "task-dispute".to_string(),
+            payment_hash: request.payment_hash.clone(),
+            reason: "no imagery delivered".to_string(),
+            timestamp: unix_ts(),
+            evidence: None,
+        };
+
+        handle_dispute(&mut link, &dispute, "SAT-OTHER");
+        let wrong_hash = DisputeMessage {
+            payment_hash: vec![7; 32],
+            ..dispute.clone()
+        };
+        handle_dispute(&mut link, &wrong_hash, COMMANDER_ID);
+        assert_eq!(stored_phase(&link, "task-dispute"), "accepted");
+        assert!(link
+            .store
+            .find_task("task-dispute")
+            .unwrap()
+            .dispute
+            .is_none());
+        assert_eq!(link.pending.len(), 1);
+
+        let payload = ScapPayload::Dispute(dispute.clone());
+        executor.receive(
+            &mut link,
+            &datagram(2, unix_ts(), payload, &LINK_KEY),
+            peer(),
+        );
+        let record = link.store.find_task("task-dispute").unwrap().clone();
+        assert_eq!(record.phase, "disputed");
+        assert_eq!(
+            record.dispute,
+            Some(to_hex(&dispute.encode_cbor().unwrap()))
+        );
+        assert!(link.pending.is_empty());
+
+        // A disputed task does not take a second dispute.
+        let again = DisputeMessage {
+            reason: "still nothing".to_string(),
+            ..dispute
+        };
+        handle_dispute(&mut link, &again, COMMANDER_ID);
+        assert_eq!(
+            link.store.find_task("task-dispute").unwrap().dispute,
+            record.dispute
+        );
+    }
+
+    #[test]
+    fn lightning_and_stray_responses_get_no_reply() {
+        let dir = temp_dir("lightning");
+        let executor = executor(&dir, 60);
+        let mut link = Link::open(&executor).unwrap();
+        let init = ScapPayload::Lightning(LightningWrapper {
+            bolt_payload: vec![0x00, 0x10, 0x00, 0x00],
+        });
+        assert!(executor
+            .receive(&mut link, &datagram(1, unix_ts(), init, &LINK_KEY), peer())
+            .is_empty());
+        let truncated = ScapPayload::Lightning(LightningWrapper {
+            bolt_payload: vec![0x00],
+        });
+        assert!(executor
+            .receive(
+                &mut link,
+                &datagram(2, unix_ts(), truncated, &LINK_KEY),
+                peer()
+            )
+            .is_empty());
+        assert_eq!(link.last_seen[COMMANDER_ID], 2);
+    }
+}
@@ -1,7 +1,17 @@
+mod isl_mode;
+mod settlement_store;
+mod spec_mode;
+
 use clap::Parser;
+use scrap_ledger::{LedgerClient, LedgerRequest};
+use scrap_protocol::{
+    hex_to_bytes, keypair_from_secret, pubkey_from_secret, IntervalClock, SatCapVerifier,
+    SpecOperator, SpecVerifier,
+};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use sha2::{Digest, Sha256};
+use std::cell::Cell;
 use std::fs;
 use std::fs::OpenOptions;
 use std::io::Write;
@@ -27,6 +37,30 @@ struct Args {
 
     #[arg(long, action = clap::ArgAction::SetTrue)]
     allow_mock_signatures: bool,
+
+    /// Wire protocol: `json` demo messages, `spec` TLV with settlement or
+    /// `isl` CBOR `IslScapMessage`s.
+    #[arg(long, default_value = "json", value_parser = ["json", "spec", "isl"])]
+    protocol: String,
+
+    #[arg(long, default_value_t = 600)]
+    accept_expiry_sec: u32,
+
+    /// Seconds per block when converting `timeout_blocks` to wall time.
+    #[arg(long, default_value_t = IntervalClock::BITCOIN_BLOCK_INTERVAL_SEC)]
+    block_interval_sec: u64,
+
+    /// `scrap-ledger` address (HOST:PORT) used to check locks and release HTLCs.
+    #[arg(long)]
+    ledger: Option<String>,
+
+    /// Seconds an accepted ISL task runs before its `Completed` response.
+    #[arg(long, default_value_t = 5)]
+    task_duration_sec: u64,
+
+    /// Seconds without a heartbeat before an ISL peer is reported lost.
+    #[arg(long, default_value_t = 30)]
+    peer_timeout_sec: u64,
 }
 
 #[derive(Debug, Deserialize)]
@@ -34,11 +68,17 @@ struct Policy {
     node_id: Option<String>,
     replay_cache_path: Option<String>,
     revocation_list_path: Option<String>,
+    settlement_store_path: Option<String>,
+    rate_counter_path: Option<String>,
+    isl_sequence_path: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
 struct Keys {
     commander_pubkey: Option<String>,
+    operator_pubkey: Option<String>,
+    executor_privkey: Option<String>,
+    isl_link_key: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -188,6 +228,101 @@ fn replay_check_and_add(path: &str, token_id: &str) -> Option<bool> {
     })
 }
 
+fn spec_executor(
+    args: &Args,
+    keys: &Keys,
+    replay_cache_path: String,
+    revoked_path: String,
+    settlement_store_path: String,
+    rate_counter_path: String,
+) -> spec_mode::SpecExecutor {
+    let secret = keys
+        .executor_privkey
+        .as_deref()
+        .expect("executor_privkey missing in keys (required for --protocol spec)");
+    let operator_pubkey = keys
+        .operator_pubkey
+        .as_deref()
+        .and_then(|hex| hex_to_bytes(hex).ok())
+        .expect("operator_pubkey missing in keys (required for --protocol spec)");
+    let executor_pubkey = pubkey_from_secret(secret).expect("executor_privkey invalid");
+    let ledger = args.ledger.as_deref().map(|server| {
+        LedgerClient::connect(server, Duration::from_secs(2)).expect("ledger client failed")
+    });
+    // Anchor to the ledger's height so blocks line up with its HTLC expiries.
+    let anchor_height = match &ledger {
+        Some(client) => {
+            client
+                .call(&LedgerRequest::Status)
+                .expect("ledger status failed")
+                .height
+        }
+        None => 0,
+    };
+    let clock = IntervalClock::anchored(args.block_interval_sec, anchor_height, unix_ts());
+    spec_mode::SpecExecutor {
+        verifier: SpecVerifier {
+            operator_pubkey,
+            executor_pubkey: executor_pubkey.clone(),
+        },
+        signer: SpecOperator {
+            operator_key: keypair_from_secret(secret).expect("executor_privkey invalid"),
+            operator_pubkey: executor_pubkey,
+        },
+        replay_cache_path,
+        revoked_path,
+        settlement_store_path,
+        rate_counter_path,
+        accept_expiry_sec: args.accept_expiry_sec,
+        clock: Cell::new(clock),
+        ledger,
+    }
+}
+
+fn isl_executor(
+    args: &Args,
+    keys: &Keys,
+    node_id: String,
+    replay_cache_path: String,
+    revoked_path: String,
+    settlement_store_path: String,
+    sequence_path: String,
+) -> isl_mode::IslExecutor {
+    let secret = keys
+        .executor_privkey
+        .as_deref()
+        .expect("executor_privkey missing in keys (required for --protocol isl)");
+    let operator_pubkey = keys
+        .operator_pubkey
+        .as_deref()
+        .and_then(|hex| hex_to_bytes(hex).ok())
+        .expect("operator_pubkey missing in keys (required for --protocol isl)");
+    let link_key = keys
+        .isl_link_key
+        .as_deref()
+        .and_then(|hex| hex_to_bytes(hex).ok())
+        .expect("isl_link_key missing in keys (required for --protocol isl)");
+    let executor_pubkey = pubkey_from_secret(secret).expect("executor_privkey invalid");
+    isl_mode::IslExecutor {
+        verifier: SatCapVerifier {
+            operator_pubkey,
+            executor_pubkey: executor_pubkey.clone(),
+        },
+        signer: SpecOperator {
+            operator_key: keypair_from_secret(secret).expect("executor_privkey invalid"),
+            operator_pubkey: executor_pubkey,
+        },
+        node_id,
+        link_key,
+        replay_cache_path,
+        revoked_path,
+        settlement_store_path,
+        sequence_path,
+        task_duration_sec: args.task_duration_sec,
+        peer_timeout_sec: args.peer_timeout_sec,
+    }
+}
+
 fn main() {
     let args = Args::parse();
 
@@ -195,9 +330,15 @@ fn main() {
         node_id: None,
         replay_cache_path: None,
         revocation_list_path: None,
+        settlement_store_path: None,
+        rate_counter_path: None,
+        isl_sequence_path: None,
     });
     let keys: Keys = read_json_file(&args.keys).unwrap_or(Keys {
         commander_pubkey: None,
+        operator_pubkey: None,
+        executor_privkey: None,
+        isl_link_key: None,
     });
 
     let node_id = policy
@@ -220,10 +361,45 @@ fn main() {
         "port": args.port,
         "policy": args.policy,
         "keys": args.keys,
-        "allow_mock_signatures": args.allow_mock_signatures
+        "allow_mock_signatures": args.allow_mock_signatures,
+        "protocol": args.protocol
     });
     println!("{}", start_log);
 
+    if args.protocol == "spec" {
+        let executor = spec_executor(
+            &args,
+            &keys,
+            replay_cache_path,
+            revoked_path,
+            policy
+                .settlement_store_path
+                .unwrap_or_else(|| "demo/runtime/executor_settlement.json".to_string()),
+            policy
+                .rate_counter_path
+                .unwrap_or_else(|| "demo/runtime/rate_counters.json".to_string()),
+        );
+        spec_mode::run(&socket, &executor);
+        return;
+    }
+    if args.protocol == "isl" {
+        let executor = isl_executor(
+            &args,
+            &keys,
+            node_id,
+            replay_cache_path,
+            revoked_path,
+            policy
+                .settlement_store_path
+                .unwrap_or_else(|| "demo/runtime/isl_settlement.json".to_string()),
+            policy
+                .isl_sequence_path
+                .unwrap_or_else(|| "demo/runtime/isl_sequences.json".to_string()),
+        );
+        isl_mode::run(&socket, &executor);
+        return;
+    }
+
     let mut buf = [0u8; 65535];
     loop {
         let (len, addr) = match socket.recv_from(&mut buf) {
@@ -262,6 +438,13 @@ fn main() {
             notes.push("signature verification skipped (mock mode)".to_string());
         }
 
+        if request.version != 1 {
+            details.push(format!("unsupported version {}", request.version));
+        }
+        if !args.allow_mock_signatures && request.commander_signature.is_empty() {
+            details.push("commander signature missing".to_string());
+        }
+
         let now = unix_ts();
         if request.token.expires_at < now {
             details.push("token expired".to_string());
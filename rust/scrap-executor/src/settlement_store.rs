@@ -0,0 +1,160 @@
+use crate::{ensure_parent, with_lock};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::{ErrorKind, Write};
+
+/// One settlement as persisted on disk. Messages are stored as hex TLV (CBOR
+/// in ISL mode) so the executor can rebuild the session and re-send what it
+/// already signed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SettlementRecord {
+    pub task_id: String,
+    pub correlation_id: String,
+    pub phase: String,
+    pub peer: String,
+    pub request: String,
+    pub lock: Option<String>,
+    pub proof: Option<String>,
+    /// Hex CBOR `DisputeMessage` raised against this task, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dispute: Option<String>,
+    /// Unix time the settlement times out, fixed when the request arrived.
+    /// Records written before this field existed recompute it on load.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    /// ISL node id that sent the request; spec sessions leave it unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub requester: Option<String>,
+    pub updated_at: u64,
+}
+
+/// Settlement records keyed by correlation id, rewritten on every phase
+/// transition.
+pub struct SettlementStore {
+    path: String,
+    records: BTreeMap<String, SettlementRecord>,
+}
+
+impl SettlementStore {
+    /// A missing file is an empty store. A file that cannot be read or parsed
+    /// is an error rather than an empty store the next `put` would overwrite.
+    pub fn open(path: &str) -> Result<Self, String> {
+        let records = match fs::read_to_string(path) {
+            Ok(raw) => serde_json::from_str(&raw)
+                .map_err(|err| format!("settlement store {path} is corrupt: {err}"))?,
+            Err(err) if err.kind() == ErrorKind::NotFound => BTreeMap::new(),
+            Err(err) => return Err(format!("settlement store {path} unreadable: {err}")),
+        };
+        Ok(Self {
+            path: path.to_string(),
+            records,
+        })
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn get(&self, correlation_id: &str) -> Option<&SettlementRecord> {
+        self.records.get(correlation_id)
+    }
+
+    pub fn find_task(&self, task_id: &str) -> Option<&SettlementRecord> {
+        self.records
+            .values()
+            .find(|record| record.task_id == task_id)
+    }
+
+    pub fn records(&self) -> impl Iterator<Item = &SettlementRecord> {
+        self.records.values()
+    }
+
+    pub fn put(&mut self, record: SettlementRecord) -> bool {
+        self.records.insert(record.correlation_id.clone(), record);
+        self.save()
+    }
+
+    fn save(&self) -> bool {
+        let path = self.path.as_str();
+        with_lock(path, || {
+            ensure_parent(path);
+            let tmp_path = format!("{}.tmp", path);
+            let payload = serde_json::to_vec_pretty(&self.records).ok()?;
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&tmp_path)
+                .ok()?;
+            file.write_all(&payload).ok()?;
+            file.sync_all().ok()?;
+            fs::rename(&tmp_path, path).ok()?;
+            Some(true)
+        })
+        .unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn temp_path(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("scrap-store-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("settlement.json").to_string_lossy().into_owned()
+    }
+
+    fn record(task_id: &str, correlation_id: &str, phase: &str) -> SettlementRecord {
+        SettlementRecord {
+            task_id: task_id.to_string(),
+            correlation_id: correlation_id.to_string(),
+            phase: phase.to_string(),
+            peer: "127.0.0.1:7300".to_string(),
+            request: "01".to_string(),
+            lock: None,
+            proof: None,
+            dispute: None,
+            expires_at: None,
+            requester: None,
+            updated_at: 100,
+        }
+    }
+
+    #[test]
+    fn records_survive_a_reopen() {
+        let path = temp_path("roundtrip");
+        let mut store = SettlementStore::open(&path).unwrap();
+        assert_eq!(store.records().count(), 0);
+        assert!(store.put(record("task-a", "aa", "requested")));
+        assert!(store.put(record("task-b", "bb", "locked")));
+        assert!(store.put(record("task-a", "aa", "claimed")));
+
+        let store = SettlementStore::open(&path).unwrap();
+        assert_eq!(store.records().count(), 2);
+        assert_eq!(store.get("aa").unwrap().phase, "claimed");
+        assert_eq!(store.find_task("task-b").unwrap().correlation_id, "bb");
+        assert!(store.find_task("task-c").is_none());
+        assert!(!Path::new(&format!("{path}.tmp")).exists());
+    }
+
+    #[test]
+    fn corrupt_file_is_an_error_and_left_alone() {
+        let path = temp_path("corrupt");
+        fs::write(&path, b"{\"aa\": {\"task_id\": ").unwrap();
+        let err = SettlementStore::open(&path).err().unwrap();
+        assert!(err.contains("corrupt"), "{err}");
+        assert_eq!(fs::read(&path).unwrap(), b"{\"aa\": {\"task_id\": ");
+    }
+
+    #[test]
+    fn unreadable_path_is_an_error() {
+        let path = temp_path("directory");
+        fs::create_dir_all(&path).unwrap();
+        assert!(SettlementStore::open(&path).is_err());
+    }
+}
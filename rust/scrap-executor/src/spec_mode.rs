@@ -0,0 +1,1078 @@
+use crate::settlement_store::{SettlementRecord, SettlementStore};
+use crate::{ensure_parent, load_string_list, replay_check_and_add, to_hex, unix_ts, with_lock};
+use scrap_ledger::{HtlcState, LedgerClient, LedgerRequest};
+use scrap_protocol::{
+    derive_payment_hash, derive_preimage, hex_to_bytes, sha256, ChainClock, IntervalClock,
+    MessageCodec, Operator, RateCounters, SettlementPhase, SettlementState, SpecMessage,
+    SpecMessageCodec, SpecOperator, SpecPaymentClaim, SpecPaymentLock, SpecProofOfExecution,
+    SpecTaskAccept, SpecTaskReject, SpecTaskRequest, SpecToken, SpecVerifier, Verifier,
+};
+use serde_json::json;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fs;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::Duration;
+
+pub struct SpecExecutor {
+    pub verifier: SpecVerifier,
+    pub signer: SpecOperator,
+    pub replay_cache_path: String,
+    pub revoked_path: String,
+    pub settlement_store_path: String,
+    pub rate_counter_path: String,
+    pub accept_expiry_sec: u32,
+    /// Converts `timeout_blocks` to wall time; re-anchored on every ledger
+    /// reply when a ledger is configured.
+    pub clock: Cell<IntervalClock>,
+    /// When set, locks must be backed by an HTLC on this ledger and claims
+    /// release it.
+    pub ledger: Option<LedgerClient>,
+}
+
+struct Session {
+    state: SettlementState,
+    request: SpecTaskRequest,
+    token_id: [u8; 16],
+    peer: SocketAddr,
+    lock: Option<SpecPaymentLock>,
+    proof: Option<SpecProofOfExecution>,
+}
+
+struct Settlements {
+    sessions: HashMap<[u8; 32], Session>,
+    store: SettlementStore,
+}
+
+type Replies = Vec<(SocketAddr, SpecMessage)>;
+
+impl Session {
+    fn record(&self) -> SettlementRecord {
+        SettlementRecord {
+            task_id: self.state.task_id.clone(),
+            correlation_id: to_hex(&self.state.correlation_id),
+            phase: self.state.phase.as_str().to_string(),
+            peer: self.peer.to_string(),
+            request: self
+                .request
+                .encode_tlv()
+                .map(|bytes| to_hex(&bytes))
+                .unwrap_or_default(),
+            lock: self
+                .lock
+                .as_ref()
+                .and_then(|lock| lock.encode_tlv().ok())
+                .map(|bytes| to_hex(&bytes)),
+            proof: self
+                .proof
+                .as_ref()
+                .and_then(|proof| proof.encode_tlv().ok())
+                .map(|bytes| to_hex(&bytes)),
+            dispute: None,
+            expires_at: Some(self.state.expires_at),
+            requester: None,
+            updated_at: unix_ts(),
+        }
+    }
+
+    fn from_record(record: &SettlementRecord, clock: &dyn ChainClock) -> Result<Self, String> {
+        let decode = |hex: &str| hex_to_bytes(hex).map_err(|err| err.reason);
+        let request =
+            SpecTaskRequest::decode_tlv(&decode(&record.request)?).map_err(|err| err.reason)?;
+        let token = SpecToken::decode_tlv(&request.capability_token).map_err(|err| err.reason)?;
+        let lock = match &record.lock {
+            Some(hex) => {
+                Some(SpecPaymentLock::decode_tlv(&decode(hex)?).map_err(|err| err.reason)?)
+            }
+            None => None,
+        };
+        let proof = match &record.proof {
+            Some(hex) => {
+                Some(SpecProofOfExecution::decode_tlv(&decode(hex)?).map_err(|err| err.reason)?)
+            }
+            None => None,
+        };
+        let mut state = SettlementState::new(&request, clock);
+        if to_hex(&state.correlation_id) != record.correlation_id {
+            return Err("correlation_id does not match stored request".to_string());
+        }
+        state.phase = SettlementPhase::parse(&record.phase)
+            .ok_or_else(|| format!("unknown phase {}", record.phase))?;
+        if let Some(expires_at) = record.expires_at {
+            state.expires_at = expires_at;
+        }
+        Ok(Self {
+            state,
+            request,
+            token_id: token.token_id,
+            peer: record
+                .peer
+                .parse()
+                .map_err(|_| format!("invalid peer {}", record.peer))?,
+            lock,
+            proof,
+        })
+    }
+}
+
+/// Counts the request against every rate-limited token in its chain. `None`
+/// means the counter file could not be locked or written.
+fn rate_check_and_record(path: &str, tokens: &[SpecToken], now: u64) -> Option<Result<(), String>> {
+    if tokens.iter().all(|token| token.constraints.rate.is_none()) {
+        return Some(Ok(()));
+    }
+    with_lock(path, || {
+        let mut counters: RateCounters = fs::read_to_string(path)
+            .ok()
+            .and_then(|raw| serde_json::from_str(&raw).ok())
+            .unwrap_or_default();
+        counters.prune(now);
+        let links = tokens
+            .iter()
+            .map(|token| (&token.token_id, &token.constraints));
+        if let Err(err) = counters.record_chain(links, now) {
+            return Some(Err(err.reason));
+        }
+        ensure_parent(path);
+        let tmp_path = format!("{}.tmp", path);
+        let payload = serde_json::to_vec_pretty(&counters).ok()?;
+        fs::write(&tmp_path, payload).ok()?;
+        fs::rename(&tmp_path, path).ok()?;
+        Some(Ok(()))
+    })
+}
+
+fn persist(store: &mut SettlementStore, session: &Session) {
+    if !store.put(session.record()) {
+        let log = json!({
+            "ts": unix_ts(),
+            "event": "settlement_store_failed",
+            "task_id": session.state.task_id,
+            "phase": session.state.phase.as_str(),
+            "path": store.path()
+        });
+        println!("{}", log);
+    }
+}
+
+pub fn run(socket: &UdpSocket, executor: &SpecExecutor) {
+    let mut settlements = Settlements {
+        sessions: HashMap::new(),
+        store: SettlementStore::open(&executor.settlement_store_path)
+            .unwrap_or_else(|err| panic!("{err}")),
+    };
+    let replies = executor.recover(&mut settlements);
+    send_replies(socket, replies);
+
+    // Wake up periodically so requests that never get a lock still expire.
+    let _ = socket.set_read_timeout(Some(Duration::from_secs(1)));
+    let mut buf = [0u8; 65535];
+    loop {
+        let replies = executor.expire_stale(&mut settlements);
+        send_replies(socket, replies);
+
+        let (len, addr) = match socket.recv_from(&mut buf) {
+            Ok(res) => res,
+            Err(_) => continue,
+        };
+
+        let message = match SpecMessageCodec.decode_message(&buf[..len]) {
+            Ok(message) => message,
+            Err(err) => {
+                let log = json!({
+                    "ts": unix_ts(),
+                    "event": "invalid_message",
+                    "source": addr.to_string(),
+                    "error": err.reason
+                });
+                println!("{}", log);
+                continue;
+            }
+        };
+
+        let replies = match message {
+            SpecMessage::TaskRequest(request) => {
+                executor.handle_request(&mut settlements, request, addr)
+            }
+            SpecMessage::PaymentLock(lock) => executor.handle_lock(&mut settlements, lock, addr),
+            other => {
+                let log = json!({
+                    "ts": unix_ts(),
+                    "event": "unexpected_message",
+                    "source": addr.to_string(),
+                    "message_type": message_name(&other)
+                });
+                println!("{}", log);
+                Vec::new()
+            }
+        };
+        send_replies(socket, replies);
+    }
+}
+
+fn send_replies(socket: &UdpSocket, replies: Replies) {
+    for (peer, reply) in replies {
+        match SpecMessageCodec.encode_message(&reply) {
+            Ok(payload) => {
+                let _ = socket.send_to(&payload, peer);
+            }
+            Err(err) => {
+                let log = json!({
+                    "ts": unix_ts(),
+                    "event": "encode_failed",
+                    "message_type": message_name(&reply),
+                    "error": err.reason
+                });
+                println!("{}", log);
+            }
+        }
+    }
+}
+
+fn message_name(message: &SpecMessage) -> &'static str {
+    match message {
+        SpecMessage::TaskRequest(_) => "task_request",
+        SpecMessage::TaskAccept(_) => "task_accept",
+        SpecMessage::ProofOfExecution(_) => "proof_of_execution",
+        SpecMessage::PaymentLock(_) => "payment_lock",
+        SpecMessage::PaymentClaim(_) => "payment_claim",
+        SpecMessage::TaskReject(_) => "task_reject",
+    }
+}
+
+fn reject(task_id: &str, reason: &str, details: &str) -> SpecMessage {
+    let log = json!({
+        "ts": unix_ts(),
+        "event": "task_rejected",
+        "task_id": task_id,
+        "reason": reason,
+        "details": details
+    });
+    println!("{}", log);
+    SpecMessage::TaskReject(SpecTaskReject {
+        task_id: task_id.to_string(),
+        reason: reason.to_string(),
+        details: details.to_string(),
+        timestamp: unix_ts() as u32,
+    })
+}
+
+impl SpecExecutor {
+    fn recover(&self, settlements: &mut Settlements) -> Replies {
+        let pending: Vec<SettlementRecord> = settlements
+            .store
+            .records()
+            .filter(|record| {
+                SettlementPhase::parse(&record.phase).is_none_or(|phase| !phase.is_terminal())
+            })
+            .cloned()
+            .collect();
+        let now = unix_ts();
+        let mut out = Vec::new();
+        for record in pending {
+            let mut session = match Session::from_record(&record, &self.clock.get()) {
+                Ok(session) => session,
+                Err(err) => {
+                    let log = json!({
+                        "ts": now,
+                        "event": "settlement_recover_failed",
+                        "task_id": record.task_id,
+                        "correlation_id": record.correlation_id,
+                        "error": err
+                    });
+                    println!("{}", log);
+                    continue;
+                }
+            };
+            let log = json!({
+                "ts": now,
+                "event": "settlement_recovered",
+                "task_id": session.state.task_id,
+                "correlation_id": record.correlation_id,
+                "phase": session.state.phase.as_str()
+            });
+            println!("{}", log);
+
+            if session.state.is_expired(now) {
+                out.extend(expire(&mut settlements.store, &mut session, now));
+                continue;
+            }
+            if session.state.phase == SettlementPhase::Requested {
+                settlements
+                    .sessions
+                    .insert(session.state.correlation_id, session);
+                continue;
+            }
+            let peer = session.peer;
+            let Some(lock) = session.lock.clone() else {
+                let _ = session.state.reject();
+                persist(&mut settlements.store, &session);
+                out.push((
+                    peer,
+                    reject(
+                        &session.state.task_id,
+                        "settlement_failed",
+                        "lock missing from store",
+                    ),
+                ));
+                continue;
+            };
+            let mut replies = Vec::new();
+            if let Some(proof) = &session.proof {
+                let log = json!({
+                    "ts": now,
+                    "event": "proof_resent",
+                    "task_id": proof.task_id,
+                    "proof_hash": to_hex(&proof.proof_hash()),
+                    "peer": peer.to_string()
+                });
+                println!("{}", log);
+                replies.push(SpecMessage::ProofOfExecution(proof.clone()));
+            }
+            if let Err(err) = self.settle(&mut settlements.store, &mut session, &lock, &mut replies)
+            {
+                let _ = session.state.reject();
+                persist(&mut settlements.store, &session);
+                replies.push(reject(&lock.task_id, "settlement_failed", &err));
+            }
+            out.extend(replies.into_iter().map(|reply| (peer, reply)));
+        }
+        out
+    }
+
+    fn expire_stale(&self, settlements: &mut Settlements) -> Replies {
+        let now = unix_ts();
+        let due: Vec<[u8; 32]> = settlements
+            .sessions
+            .iter()
+            .filter(|(_, session)| session.state.is_expired(now))
+            .map(|(correlation_id, _)| *correlation_id)
+            .collect();
+        let mut out = Vec::new();
+        for correlation_id in due {
+            if let Some(mut session) = settlements.sessions.remove(&correlation_id) {
+                out.extend(expire(&mut settlements.store, &mut session, now));
+            }
+        }
+        out
+    }
+
+    fn handle_request(
+        &self,
+        settlements: &mut Settlements,
+        request: SpecTaskRequest,
+        addr: SocketAddr,
+    ) -> Replies {
+        let correlation_id = request.request_hash();
+        if settlements.sessions.contains_key(&correlation_id)
+            || settlements.store.get(&to_hex(&correlation_id)).is_some()
+        {
+            let log = json!({
+                "ts": unix_ts(),
+                "event": "duplicate_request",
+                "task_id": request.task_id,
+                "correlation_id": to_hex(&correlation_id)
+            });
+            println!("{}", log);
+            return Vec::new();
+        }
+
+        if let Err(err) = self.verifier.verify_request(&request, unix_ts()) {
+            return vec![(
+                addr,
+                reject(&request.task_id, "validation_failed", &err.reason),
+            )];
+        }
+        let token = match SpecToken::decode_tlv(&request.capability_token) {
+            Ok(token) => token,
+            Err(err) => {
+                return vec![(
+                    addr,
+                    reject(&request.task_id, "validation_failed", &err.reason),
+                )]
+            }
+        };
+
+        let mut tokens: Vec<SpecToken> = request
+            .delegation_chain
+            .iter()
+            .filter_map(|raw| SpecToken::decode_tlv(raw).ok())
+            .collect();
+        tokens.push(token.clone());
+
+        let revoked = load_string_list(&self.revoked_path);
+        if tokens
+            .iter()
+            .any(|link| revoked.contains(&to_hex(&link.token_id)))
+        {
+            return vec![(
+                addr,
+                reject(&request.task_id, "validation_failed", "token revoked"),
+            )];
+        }
+        // Replays are turned away before they count against any rate limit.
+        match replay_check_and_add(&self.replay_cache_path, &to_hex(&token.token_id)) {
+            Some(true) => {}
+            Some(false) => {
+                return vec![(
+                    addr,
+                    reject(
+                        &request.task_id,
+                        "validation_failed",
+                        "replay detected (token_id already used)",
+                    ),
+                )]
+            }
+            None => {
+                return vec![(
+                    addr,
+                    reject(
+                        &request.task_id,
+                        "validation_failed",
+                        "replay cache unavailable",
+                    ),
+                )]
+            }
+        }
+        match rate_check_and_record(&self.rate_counter_path, &tokens, unix_ts()) {
+            Some(Ok(())) => {}
+            Some(Err(err)) => {
+                return vec![(addr, reject(&request.task_id, "validation_failed", &err))]
+            }
+            None => {
+                return vec![(
+                    addr,
+                    reject(
+                        &request.task_id,
+                        "validation_failed",
+                        "rate counters unavailable",
+                    ),
+                )]
+            }
+        }
+
+        let session = Session {
+            state: SettlementState::new(&request, &self.clock.get()),
+            request,
+            token_id: token.token_id,
+            peer: addr,
+            lock: None,
+            proof: None,
+        };
+        persist(&mut settlements.store, &session);
+        let log = json!({
+            "ts": unix_ts(),
+            "event": "task_requested",
+            "task_id": session.state.task_id,
+            "correlation_id": to_hex(&correlation_id),
+            "payment_hash": to_hex(&derive_payment_hash(correlation_id)),
+            "payment_max_sats": session.request.payment_max_sats,
+            "timeout_blocks": session.request.timeout_blocks,
+            "phase": session.state.phase.as_str()
+        });
+        println!("{}", log);
+        settlements.sessions.insert(correlation_id, session);
+        Vec::new()
+    }
+
+    fn handle_lock(
+        &self,
+        settlements: &mut Settlements,
+        lock: SpecPaymentLock,
+        addr: SocketAddr,
+    ) -> Replies {
+        let Some(session) = settlements.sessions.get_mut(&lock.correlation_id) else {
+            let details = match settlements.store.get(&to_hex(&lock.correlation_id)) {
+                Some(record) => format!("settlement already {}", record.phase),
+                None if settlements.store.find_task(&lock.task_id).is_some() => {
+                    "lock correlation_id does not match task".to_string()
+                }
+                None => "no pending request for lock".to_string(),
+            };
+            return vec![(addr, reject(&lock.task_id, "unknown_task", &details))];
+        };
+        session.peer = addr;
+        let peer = addr;
+        if let Err(err) = session.state.lock(&lock, unix_ts()) {
+            let _ = session.state.reject();
+            persist(&mut settlements.store, session);
+            settlements.sessions.remove(&lock.correlation_id);
+            return vec![(
+                peer,
+                reject(&lock.task_id, "lock_invalid", &err.to_string()),
+            )];
+        }
+        if let Err(err) = self.check_ledger_lock(&lock, &session.state) {
+            let _ = session.state.reject();
+            persist(&mut settlements.store, session);
+            settlements.sessions.remove(&lock.correlation_id);
+            return vec![(peer, reject(&lock.task_id, "payment_not_locked", &err))];
+        }
+        session.lock = Some(lock.clone());
+        persist(&mut settlements.store, session);
+        let log = json!({
+            "ts": unix_ts(),
+            "event": "payment_locked",
+            "task_id": lock.task_id,
+            "correlation_id": to_hex(&lock.correlation_id),
+            "amount_sats": lock.amount_sats,
+            "phase": session.state.phase.as_str()
+        });
+        println!("{}", log);
+
+        let mut replies = Vec::new();
+        if let Err(err) = self.settle(&mut settlements.store, session, &lock, &mut replies) {
+            let _ = session.state.reject();
+            persist(&mut settlements.store, session);
+            replies.push(reject(&lock.task_id, "settlement_failed", &err));
+        }
+        settlements.sessions.remove(&lock.correlation_id);
+        replies.into_iter().map(|reply| (peer, reply)).collect()
+    }
+
+    fn settle(
+        &self,
+        store: &mut SettlementStore,
+        session: &mut Session,
+        lock: &SpecPaymentLock,
+        replies: &mut Vec<SpecMessage>,
+    ) -> Result<(), String> {
+        if session.state.can_emit_accept() {
+            session
+                .state
+                .accept(unix_ts())
+                .map_err(|err| err.to_string())?;
+            let accept = self.accept(session, lock)?;
+            persist(store, session);
+            replies.push(SpecMessage::TaskAccept(accept));
+        }
+        if session.state.can_emit_proof() {
+            session
+                .state
+                .record_proof(unix_ts())
+                .map_err(|err| err.to_string())?;
+            let proof = self.execute(session, lock)?;
+            session.proof = Some(proof.clone());
+            persist(store, session);
+            replies.push(SpecMessage::ProofOfExecution(proof));
+        }
+        let claim = SpecPaymentClaim {
+            task_id: lock.task_id.clone(),
+            correlation_id: lock.correlation_id,
+            payment_hash: lock.payment_hash,
+            preimage: derive_preimage(lock.correlation_id),
+            timestamp: unix_ts() as u32,
+        };
+        session
+            .state
+            .validate_claim(&claim)
+            .map_err(|err| err.to_string())?;
+        self.release_on_ledger(&claim)?;
+        session
+            .state
+            .claim(&claim, unix_ts())
+            .map_err(|err| err.to_string())?;
+        persist(store, session);
+        let log = json!({
+            "ts": unix_ts(),
+            "event": "payment_claimed",
+            "task_id": lock.task_id,
+            "payment_hash": to_hex(&lock.payment_hash),
+            "phase": session.state.phase.as_str()
+        });
+        println!("{}", log);
+        replies.push(SpecMessage::PaymentClaim(claim));
+        Ok(())
+    }
+
+    /// The lock message alone proves nothing; the ledger must hold a live HTLC
+    /// paying this executor at least the locked amount.
+    fn check_ledger_lock(
+        &self,
+        lock: &SpecPaymentLock,
+        state: &SettlementState,
+    ) -> Result<(), String> {
+        let Some(ledger) = &self.ledger else {
+            return Ok(());
+        };
+        let response = ledger.call(&LedgerRequest::Get {
+            payment_hash: to_hex(&lock.payment_hash),
+        })?;
+        self.anchor_clock(response.height);
+        let htlc = response.htlc.ok_or("ledger returned no htlc")?;
+        if htlc.state != HtlcState::Locked {
+            return Err(format!("htlc not locked ({:?})", htlc.state));
+        }
+        if htlc.amount_sats < lock.amount_sats {
+            return Err(format!(
+                "htlc holds {} sats, lock claims {}",
+                htlc.amount_sats, lock.amount_sats
+            ));
+        }
+        if htlc.payee != to_hex(&self.signer.operator_pubkey) {
+            return Err("htlc payee is not this executor".to_string());
+        }
+        if self.clock.get().time_at(htlc.expiry_height) < state.expires_at {
+            return Err(format!(
+                "htlc expires at height {} before the settlement timeout",
+                htlc.expiry_height
+            ));
+        }
+        Ok(())
+    }
+
+    fn anchor_clock(&self, height: u64) {
+        let clock = self.clock.get();
+        self.clock.set(IntervalClock::anchored(
+            clock.block_interval_sec,
+            height,
+            unix_ts(),
+        ));
+    }
+
+    fn release_on_ledger(&self, claim: &SpecPaymentClaim) -> Result<(), String> {
+        let Some(ledger) = &self.ledger else {
+            return Ok(());
+        };
+        let response = ledger
+            .call(&LedgerRequest::Release {
+                payment_hash: to_hex(&claim.payment_hash),
+                preimage: to_hex(&claim.preimage),
+            })
+            .map_err(|err| format!("ledger release failed: {err}"))?;
+        self.anchor_clock(response.height);
+        let log = json!({
+            "ts": unix_ts(),
+            "event": "ledger_released",
+            "task_id": claim.task_id,
+            "payment_hash": to_hex(&claim.payment_hash),
+            "height": response.height
+        });
+        println!("{}", log);
+        Ok(())
+    }
+
+    fn accept(&self, session: &Session, lock: &SpecPaymentLock) -> Result<SpecTaskAccept, String> {
+        let now = unix_ts() as u32;
+        let mut accept = SpecTaskAccept {
+            task_id: session.state.task_id.clone(),
+            timestamp: now,
+            in_reply_to: session.state.correlation_id,
+            payment_hash: lock.payment_hash,
+            amount_sats: lock.amount_sats,
+            expiry_sec: self
+                .accept_expiry_sec
+                .min(u32::try_from(session.state.remaining_secs(now as u64)).unwrap_or(u32::MAX)),
+            description: session.request.task_type.clone(),
+            estimated_duration_sec: 0,
+            earliest_start: now,
+            data_volume_mb: 0,
+            quality_estimate: 0,
+            executor_signature: [0u8; 64],
+        };
+        accept.executor_signature = self.signer.sign_accept(&accept).map_err(|err| err.reason)?;
+        let log = json!({
+            "ts": unix_ts(),
+            "event": "task_accepted",
+            "task_id": accept.task_id,
+            "payment_hash": to_hex(&accept.payment_hash),
+            "amount_sats": accept.amount_sats,
+            "phase": session.state.phase.as_str()
+        });
+        println!("{}", log);
+        Ok(accept)
+    }
+
+    fn execute(
+        &self,
+        session: &Session,
+        lock: &SpecPaymentLock,
+    ) -> Result<SpecProofOfExecution, String> {
+        let mut output = session.request.task_type.as_bytes().to_vec();
+        output.extend_from_slice(session.request.parameters_json.as_bytes());
+        let mut proof = SpecProofOfExecution {
+            task_id: session.state.task_id.clone(),
+            task_token_id: session.token_id,
+            payment_hash: lock.payment_hash,
+            output_hash: sha256(&output),
+            execution_timestamp: unix_ts() as u32,
+            executor_pubkey: self.signer.operator_pubkey.clone(),
+            executor_signature: [0u8; 64],
+        };
+        proof.executor_signature = self
+            .signer
+            .sign_proof(proof.proof_hash())
+            .map_err(|err| err.reason)?;
+        let log = json!({
+            "ts": unix_ts(),
+            "event": "proof_sent",
+            "task_id": proof.task_id,
+            "output_hash": to_hex(&proof.output_hash),
+            "proof_hash": to_hex(&proof.proof_hash()),
+            "phase": session.state.phase.as_str()
+        });
+        println!("{}", log);
+        Ok(proof)
+    }
+}
+
+fn expire(store: &mut SettlementStore, session: &mut Session, now: u64) -> Replies {
+    if session.state.expire(now).is_err() {
+        return Vec::new();
+    }
+    persist(store, session);
+    let log = json!({
+        "ts": now,
+        "event": "settlement_expired",
+        "task_id": session.state.task_id,
+        "correlation_id": to_hex(&session.state.correlation_id),
+        "expires_at": session.state.expires_at
+    });
+    println!("{}", log);
+    vec![(
+        session.peer,
+        reject(
+            &session.state.task_id,
+            "settlement_expired",
+            "timeout_blocks elapsed before settlement completed",
+        ),
+    )]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scrap_protocol::{
+        keypair_from_secret, pubkey_from_secret, sign_message_hash, sign_tagged, DelegationRequest,
+        TokenIssueRequest,
+    };
+
+    const OPERATOR_SECRET: &str =
+        "1111111111111111111111111111111111111111111111111111111111111111";
+    const COMMANDER_SECRET: &str =
+        "2222222222222222222222222222222222222222222222222222222222222222";
+    const DELEGATE_SECRET: &str =
+        "3333333333333333333333333333333333333333333333333333333333333333";
+    const EXECUTOR_SECRET: &str =
+        "4444444444444444444444444444444444444444444444444444444444444444";
+
+    fn temp_dir(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("scrap-spec-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.to_string_lossy().into_owned()
+    }
+
+    fn executor(dir: &str) -> SpecExecutor {
+        let executor_pubkey = pubkey_from_secret(EXECUTOR_SECRET).unwrap();
+        SpecExecutor {
+            verifier: SpecVerifier {
+                operator_pubkey: pubkey_from_secret(OPERATOR_SECRET).unwrap(),
+                executor_pubkey: executor_pubkey.clone(),
+            },
+            signer: SpecOperator {
+                operator_key: keypair_from_secret(EXECUTOR_SECRET).unwrap(),
+                operator_pubkey: executor_pubkey,
+            },
+            replay_cache_path: format!("{dir}/replay.json"),
+            revoked_path: format!("{dir}/revoked.json"),
+            settlement_store_path: format!("{dir}/settlement.json"),
+            rate_counter_path: format!("{dir}/rate.json"),
+            accept_expiry_sec: 600,
+            clock: Cell::new(IntervalClock::anchored(60, 0, unix_ts())),
+            ledger: None,
+        }
+    }
+
+    fn settlements(executor: &SpecExecutor) -> Settlements {
+        Settlements {
+            sessions: HashMap::new(),
+            store: SettlementStore::open(&executor.settlement_store_path).unwrap(),
+        }
+    }
+
+    fn root_token(rate: Option<(u32, u32)>) -> SpecToken {
+        let now = unix_ts() as u32;
+        let operator = SpecOperator {
+            operator_key: keypair_from_secret(OPERATOR_SECRET).unwrap(),
+            operator_pubkey: pubkey_from_secret(OPERATOR_SECRET).unwrap(),
+        };
+        let mut token = operator
+            .issue_token(&TokenIssueRequest {
+                subject: pubkey_from_secret(COMMANDER_SECRET).unwrap(),
+                audience: pubkey_from_secret(EXECUTOR_SECRET).unwrap(),
+                capability: vec!["cmd:imaging:msi".to_string()],
+                issued_at: now - 10,
+                expires_at: now + 3600,
+                token_id: None,
+            })
+            .unwrap();
+        if rate.is_some() {
+            token.constraints.rate = rate;
+            token.signature = sign_tagged(
+                "SCRAP/token/v1",
+                &token.encode_tlv_without_signature().unwrap(),
+                &operator.operator_key,
+            )
+            .unwrap();
+        }
+        token
+    }
+
+    fn signed_request(
+        task_id: &str,
+        token: &SpecToken,
+        chain: &[SpecToken],
+        signer_secret: &str,
+    ) -> SpecTaskRequest {
+        let mut request = SpecTaskRequest {
+            task_id: task_id.to_string(),
+            timestamp: unix_ts() as u32,
+            capability_token: token.encode_tlv().unwrap(),
+            delegation_chain: chain
+                .iter()
+                .map(|link| link.encode_tlv().unwrap())
+                .collect(),
+            task_type: "cmd:imaging:msi".to_string(),
+            target_json: "{}".to_string(),
+            parameters_json: "{}".to_string(),
+            constraints_json: "{}".to_string(),
+            payment_max_sats: 5000,
+            timeout_blocks: 10,
+            commander_signature: [0u8; 64],
+        };
+        let signer = keypair_from_secret(signer_secret).unwrap();
+        request.commander_signature =
+            sign_message_hash(request.commander_signing_hash(), &signer).unwrap();
+        request
+    }
+
+    fn request(task_id: &str) -> SpecTaskRequest {
+        signed_request(task_id, &root_token(None), &[], COMMANDER_SECRET)
+    }
+
+    fn lock_for(request: &SpecTaskRequest, amount_sats: u64) -> SpecPaymentLock {
+        let correlation_id = request.request_hash();
+        SpecPaymentLock {
+            task_id: request.task_id.clone(),
+            correlation_id,
+            payment_hash: derive_payment_hash(correlation_id),
+            amount_sats,
+            timeout_blocks: request.timeout_blocks,
+            timestamp: unix_ts() as u32,
+        }
+    }
+
+    fn peer() -> SocketAddr {
+        "127.0.0.1:7300".parse().unwrap()
+    }
+
+    fn stored_phase(settlements: &Settlements, request: &SpecTaskRequest) -> String {
+        settlements
+            .store
+            .get(&to_hex(&request.request_hash()))
+            .map(|record| record.phase.clone())
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn lock_runs_the_session_through_proof_to_claim() {
+        let dir = temp_dir("flow");
+        let executor = executor(&dir);
+        let mut settlements = settlements(&executor);
+        let request = request("task-flow");
+
+        let replies = executor.handle_request(&mut settlements, request.clone(), peer());
+        assert!(replies.is_empty());
+        assert_eq!(stored_phase(&settlements, &request), "requested");
+
+        let lock = lock_for(&request, 4000);
+        let replies = executor.handle_lock(&mut settlements, lock.clone(), peer());
+        assert!(replies.iter().all(|(to, _)| *to == peer()));
+        let messages: Vec<&SpecMessage> = replies.iter().map(|(_, message)| message).collect();
+        let [SpecMessage::TaskAccept(accept), SpecMessage::ProofOfExecution(proof), SpecMessage::PaymentClaim(claim)] =
+            messages.as_slice()
+        else {
+            panic!("unexpected replies {messages:?}");
+        };
+        executor
+            .verifier
+            .verify_accept(accept, request.request_hash())
+            .unwrap();
+        assert_eq!(accept.amount_sats, 4000);
+        executor.verifier.verify_proof(proof).unwrap();
+        assert_eq!(proof.payment_hash, lock.payment_hash);
+        assert_eq!(sha256(&claim.preimage), lock.payment_hash);
+        assert_eq!(stored_phase(&settlements, &request), "claimed");
+        assert!(settlements.sessions.is_empty());
+
+        let replies = executor.handle_lock(&mut settlements, lock, peer());
+        let [(_, SpecMessage::TaskReject(reject))] = replies.as_slice() else {
+            panic!("unexpected replies {replies:?}");
+        };
+        assert_eq!(reject.reason, "unknown_task");
+        assert_eq!(reject.details, "settlement already claimed");
+    }
+
+    #[test]
+    fn invalid_lock_rejects_the_session() {
+        let dir = temp_dir("overpay");
+        let executor = executor(&dir);
+        let mut settlements = settlements(&executor);
+        let request = request("task-overpay");
+        executor.handle_request(&mut settlements, request.clone(), peer());
+
+        let replies = executor.handle_lock(&mut settlements, lock_for(&request, 9000), peer());
+        let [(_, SpecMessage::TaskReject(reject))] = replies.as_slice() else {
+            panic!("unexpected replies {replies:?}");
+        };
+        assert_eq!(reject.reason, "lock_invalid");
+        assert_eq!(stored_phase(&settlements, &request), "rejected");
+        assert!(settlements.sessions.is_empty());
+    }
+
+    #[test]
+    fn lock_without_a_request_is_unknown() {
+        let dir = temp_dir("unknown");
+        let executor = executor(&dir);
+        let mut settlements = settlements(&executor);
+        let request = request("task-unknown");
+
+        let replies = executor.handle_lock(&mut settlements, lock_for(&request, 1000), peer());
+        let [(_, SpecMessage::TaskReject(reject))] = replies.as_slice() else {
+            panic!("unexpected replies {replies:?}");
+        };
+        assert_eq!(reject.reason, "unknown_task");
+        assert_eq!(reject.details, "no pending request for lock");
+    }
+
+    #[test]
+    fn duplicate_request_is_ignored() {
+        let dir = temp_dir("duplicate");
+        let executor = executor(&dir);
+        let mut settlements = settlements(&executor);
+        let request = request("task-duplicate");
+
+        assert!(executor
+            .handle_request(&mut settlements, request.clone(), peer())
+            .is_empty());
+        assert!(executor
+            .handle_request(&mut settlements, request.clone(), peer())
+            .is_empty());
+        assert_eq!(settlements.sessions.len(), 1);
+    }
+
+    #[test]
+    fn restart_resumes_a_locked_session() {
+        let dir = temp_dir("restart-locked");
+        let executor = executor(&dir);
+        let mut before = settlements(&executor);
+        let request = request("task-restart");
+        executor.handle_request(&mut before, request.clone(), peer());
+        let lock = lock_for(&request, 3000);
+        let mut session = before.sessions.remove(&request.request_hash()).unwrap();
+        session.state.lock(&lock, unix_ts()).unwrap();
+        session.lock = Some(lock.clone());
+        persist(&mut before.store, &session);
+        drop(before);
+
+        let mut after = settlements(&executor);
+        assert_eq!(stored_phase(&after, &request), "locked");
+        let replies = executor.recover(&mut after);
+        assert!(replies.iter().all(|(to, _)| *to == peer()));
+        let messages: Vec<&SpecMessage> = replies.iter().map(|(_, message)| message).collect();
+        let [SpecMessage::TaskAccept(accept), SpecMessage::ProofOfExecution(_), SpecMessage::PaymentClaim(claim)] =
+            messages.as_slice()
+        else {
+            panic!("unexpected replies {messages:?}");
+        };
+        assert_eq!(accept.amount_sats, 3000);
+        assert_eq!(claim.payment_hash, lock.payment_hash);
+        assert_eq!(stored_phase(&after, &request), "claimed");
+    }
+
+    #[test]
+    fn restart_keeps_a_requested_session_waiting_for_its_lock() {
+        let dir = temp_dir("restart-requested");
+        let executor = executor(&dir);
+        let mut before = settlements(&executor);
+        let request = request("task-waiting");
+        executor.handle_request(&mut before, request.clone(), peer());
+        drop(before);
+
+        let mut after = settlements(&executor);
+        assert!(executor.recover(&mut after).is_empty());
+        assert!(after.sessions.contains_key(&request.request_hash()));
+        let replies = executor.handle_lock(&mut after, lock_for(&request, 1000), peer());
+        assert_eq!(replies.len(), 3);
+        assert_eq!(stored_phase(&after, &request), "claimed");
+    }
+
+    #[test]
+    fn restored_session_keeps_its_original_deadline() {
+        let dir = temp_dir("deadline");
+        let executor = executor(&dir);
+        let mut settlements = settlements(&executor);
+        let request = request("task-deadline");
+        executor.handle_request(&mut settlements, request.clone(), peer());
+        let session = &settlements.sessions[&request.request_hash()];
+        let record = session.record();
+        assert_eq!(record.expires_at, Some(session.state.expires_at));
+
+        // A clock re-anchored after the restart would put the deadline elsewhere.
+        let moved = IntervalClock::anchored(1, 500, unix_ts());
+        let restored = Session::from_record(&record, &moved).unwrap();
+        assert_eq!(restored.state.expires_at, session.state.expires_at);
+
+        let legacy = SettlementRecord {
+            expires_at: None,
+            ..record
+        };
+        let recomputed = Session::from_record(&legacy, &moved).unwrap();
+        assert_ne!(recomputed.state.expires_at, session.state.expires_at);
+    }
+
+    #[test]
+    fn replays_do_not_use_up_the_rate_limit() {
+        let dir = temp_dir("replay-rate");
+        let executor = executor(&dir);
+        let mut settlements = settlements(&executor);
+        let parent = root_token(Some((2, 3600)));
+        let commander = keypair_from_secret(COMMANDER_SECRET).unwrap();
+        let now = unix_ts() as u32;
+        let child = || {
+            parent
+                .delegate(
+                    &commander,
+                    &DelegationRequest {
+                        subject: pubkey_from_secret(DELEGATE_SECRET).unwrap(),
+                        capability: vec!["cmd:imaging:msi".to_string()],
+                        issued_at: now - 5,
+                        expires_at: now + 600,
+                        token_id: None,
+                    },
+                )
+                .unwrap()
+        };
+        let (first, second) = (child(), child());
+        let chain = std::slice::from_ref(&parent);
+        let send = |settlements: &mut Settlements, task_id: &str, token: &SpecToken| {
+            let request = signed_request(task_id, token, chain, DELEGATE_SECRET);
+            executor.handle_request(settlements, request, peer())
+        };
+
+        assert!(send(&mut settlements, "task-rate-1", &first).is_empty());
+        let replies = send(&mut settlements, "task-rate-2", &first);
+        let [(_, SpecMessage::TaskReject(reject))] = replies.as_slice() else {
+            panic!("unexpected replies {replies:?}");
+        };
+        assert_eq!(reject.details, "replay detected (token_id already used)");
+        assert!(send(&mut settlements, "task-rate-3", &second).is_empty());
+
+        let replies = send(&mut settlements, "task-rate-4", &child());
+        let [(_, SpecMessage::TaskReject(reject))] = replies.as_slice() else {
+            panic!("unexpected replies {replies:?}");
+        };
+        assert!(reject.details.contains("rate"), "{}", reject.details);
+    }
+}
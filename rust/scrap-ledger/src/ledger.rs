@@ -0,0 +1,447 @@
+use scrap_protocol::{bytes_to_hex, sha256};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HtlcState {
+    Locked,
+    Released,
+    Refunded,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Htlc {
+    pub payment_hash: String,
+    pub payer: String,
+    pub payee: String,
+    pub amount_sats: u64,
+    pub locked_height: u64,
+    pub expiry_height: u64,
+    pub state: HtlcState,
+    pub preimage: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LedgerError {
+    ZeroAmount,
+    ZeroTimeout,
+    InsufficientFunds {
+        account: String,
+        balance: u64,
+        needed: u64,
+    },
+    DuplicateHtlc,
+    UnknownHtlc,
+    NotLocked(HtlcState),
+    PreimageMismatch,
+    Expired {
+        expiry_height: u64,
+        height: u64,
+    },
+    NotExpired {
+        expiry_height: u64,
+        height: u64,
+    },
+}
+
+impl fmt::Display for LedgerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LedgerError::ZeroAmount => write!(f, "amount must be non-zero"),
+            LedgerError::ZeroTimeout => write!(f, "timeout_blocks must be non-zero"),
+            LedgerError::InsufficientFunds {
+                account,
+                balance,
+                needed,
+            } => write!(
+                f,
+                "insufficient funds for {account}: balance {balance}, needed {needed}"
+            ),
+            LedgerError::DuplicateHtlc => write!(f, "htlc already exists for payment_hash"),
+            LedgerError::UnknownHtlc => write!(f, "no htlc for payment_hash"),
+            LedgerError::NotLocked(state) => write!(f, "htlc not locked ({state:?})"),
+            LedgerError::PreimageMismatch => write!(f, "preimage does not hash to payment_hash"),
+            LedgerError::Expired {
+                expiry_height,
+                height,
+            } => write!(
+                f,
+                "htlc expired at height {expiry_height} (height {height})"
+            ),
+            LedgerError::NotExpired {
+                expiry_height,
+                height,
+            } => write!(
+                f,
+                "htlc not expired until height {expiry_height} (height {height})"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LedgerError {}
+
+/// Simulated payment network: account balances plus hash-locked, height-timed
+/// contracts. `height` only moves when blocks are mined.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Ledger {
+    pub height: u64,
+    pub balances: BTreeMap<String, u64>,
+    pub htlcs: BTreeMap<String, Htlc>,
+}
+
+impl Ledger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn balance(&self, account: &str) -> u64 {
+        self.balances.get(account).copied().unwrap_or(0)
+    }
+
+    pub fn fund(&mut self, account: &str, amount_sats: u64) -> u64 {
+        let balance = self.balances.entry(account.to_string()).or_insert(0);
+        *balance = balance.saturating_add(amount_sats);
+        *balance
+    }
+
+    pub fn htlc(&self, payment_hash: [u8; 32]) -> Option<&Htlc> {
+        self.htlcs.get(&bytes_to_hex(&payment_hash))
+    }
+
+    pub fn lock(
+        &mut self,
+        payer: &str,
+        payee: &str,
+        payment_hash: [u8; 32],
+        amount_sats: u64,
+        timeout_blocks: u64,
+    ) -> Result<&Htlc, LedgerError> {
+        if amount_sats == 0 {
+            return Err(LedgerError::ZeroAmount);
+        }
+        if timeout_blocks == 0 {
+            return Err(LedgerError::ZeroTimeout);
+        }
+        let key = bytes_to_hex(&payment_hash);
+        if self.htlcs.contains_key(&key) {
+            return Err(LedgerError::DuplicateHtlc);
+        }
+        let balance = self.balance(payer);
+        if balance < amount_sats {
+            return Err(LedgerError::InsufficientFunds {
+                account: payer.to_string(),
+                balance,
+                needed: amount_sats,
+            });
+        }
+        self.balances
+            .insert(payer.to_string(), balance - amount_sats);
+        let htlc = Htlc {
+            payment_hash: key.clone(),
+            payer: payer.to_string(),
+            payee: payee.to_string(),
+            amount_sats,
+            locked_height: self.height,
+            expiry_height: self.height.saturating_add(timeout_blocks),
+            state: HtlcState::Locked,
+            preimage: None,
+        };
+        Ok(self.htlcs.entry(key).or_insert(htlc))
+    }
+
+    /// Pays the payee. Only possible below the expiry height.
+    pub fn release(
+        &mut self,
+        payment_hash: [u8; 32],
+        preimage: [u8; 32],
+    ) -> Result<&Htlc, LedgerError> {
+        let height = self.height;
+        let htlc = self
+            .htlcs
+            .get_mut(&bytes_to_hex(&payment_hash))
+            .ok_or(LedgerError::UnknownHtlc)?;
+        if htlc.state != HtlcState::Locked {
+            return Err(LedgerError::NotLocked(htlc.state));
+        }
+        if height >= htlc.expiry_height {
+            return Err(LedgerError::Expired {
+                expiry_height: htlc.expiry_height,
+                height,
+            });
+        }
+        if sha256(&preimage) != payment_hash {
+            return Err(LedgerError::PreimageMismatch);
+        }
+        htlc.state = HtlcState::Released;
+        htlc.preimage = Some(bytes_to_hex(&preimage));
+        let payee = htlc.payee.clone();
+        let amount = htlc.amount_sats;
+        self.fund(&payee, amount);
+        Ok(&self.htlcs[&bytes_to_hex(&payment_hash)])
+    }
+
+    /// Returns the funds to the payer once the expiry height is reached.
+    pub fn refund(&mut self, payment_hash: [u8; 32]) -> Result<&Htlc, LedgerError> {
+        let height = self.height;
+        let htlc = self
+            .htlcs
+            .get_mut(&bytes_to_hex(&payment_hash))
+            .ok_or(LedgerError::UnknownHtlc)?;
+        if htlc.state != HtlcState::Locked {
+            return Err(LedgerError::NotLocked(htlc.state));
+        }
+        if height < htlc.expiry_height {
+            return Err(LedgerError::NotExpired {
+                expiry_height: htlc.expiry_height,
+                height,
+            });
+        }
+        htlc.state = HtlcState::Refunded;
+        let payer = htlc.payer.clone();
+        let amount = htlc.amount_sats;
+        self.fund(&payer, amount);
+        Ok(&self.htlcs[&bytes_to_hex(&payment_hash)])
+    }
+
+    /// Advances the block clock and refunds every HTLC that has reached its
+    /// expiry height. Returns the payment hashes that were refunded.
+    pub fn mine(&mut self, blocks: u64) -> Vec<String> {
+        self.height = self.height.saturating_add(blocks);
+        let due: Vec<String> = self
+            .htlcs
+            .values()
+            .filter(|htlc| htlc.state == HtlcState::Locked && self.height >= htlc.expiry_height)
+            .map(|htlc| htlc.payment_hash.clone())
+            .collect();
+        for key in &due {
+            if let Some(htlc) = self.htlcs.get_mut(key) {
+                htlc.state = HtlcState::Refunded;
+                let payer = htlc.payer.clone();
+                let amount = htlc.amount_sats;
+                self.fund(&payer, amount);
+            }
+        }
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scrap_protocol::{
+        derive_payment_hash, derive_preimage, keypair_from_secret, pubkey_from_secret,
+        sign_message_hash, ChainClock, IntervalClock, Operator, SettlementPhase, SettlementState,
+        SpecOperator, SpecPaymentClaim, SpecPaymentLock, SpecProofOfExecution, SpecTaskAccept,
+        SpecTaskRequest, SpecVerifier, TokenIssueRequest, Verifier,
+    };
+
+    const OPERATOR: &str = "0101010101010101010101010101010101010101010101010101010101010101";
+    const COMMANDER: &str = "0202020202020202020202020202020202020202020202020202020202020202";
+    const EXECUTOR: &str = "0303030303030303030303030303030303030303030303030303030303030303";
+
+    #[test]
+    fn lock_release_pays_payee() {
+        let mut ledger = Ledger::new();
+        ledger.fund("alice", 1_000);
+        let preimage = derive_preimage([7u8; 32]);
+        let payment_hash = sha256(&preimage);
+        ledger
+            .lock("alice", "bob", payment_hash, 600, 10)
+            .expect("lock");
+        assert_eq!(ledger.balance("alice"), 400);
+        assert_eq!(
+            ledger.lock("alice", "bob", payment_hash, 1, 10),
+            Err(LedgerError::DuplicateHtlc)
+        );
+        assert_eq!(
+            ledger.release(payment_hash, [0u8; 32]),
+            Err(LedgerError::PreimageMismatch)
+        );
+        assert!(matches!(
+            ledger.refund(payment_hash),
+            Err(LedgerError::NotExpired { .. })
+        ));
+        let htlc = ledger.release(payment_hash, preimage).expect("release");
+        assert_eq!(htlc.state, HtlcState::Released);
+        assert_eq!(ledger.balance("bob"), 600);
+        assert_eq!(
+            ledger.release(payment_hash, preimage),
+            Err(LedgerError::NotLocked(HtlcState::Released))
+        );
+    }
+
+    #[test]
+    fn timeout_refunds_payer() {
+        let mut ledger = Ledger::new();
+        ledger.fund("alice", 500);
+        assert!(matches!(
+            ledger.lock("alice", "bob", [1u8; 32], 600, 10),
+            Err(LedgerError::InsufficientFunds { .. })
+        ));
+        let preimage = [9u8; 32];
+        let payment_hash = sha256(&preimage);
+        ledger
+            .lock("alice", "bob", payment_hash, 500, 3)
+            .expect("lock");
+        assert!(ledger.mine(2).is_empty());
+        let refunded = ledger.mine(1);
+        assert_eq!(refunded, vec![bytes_to_hex(&payment_hash)]);
+        assert_eq!(ledger.balance("alice"), 500);
+        assert!(matches!(
+            ledger.release(payment_hash, preimage),
+            Err(LedgerError::NotLocked(HtlcState::Refunded))
+        ));
+
+        let second = sha256(&[8u8; 32]);
+        ledger.lock("alice", "bob", second, 100, 1).expect("lock");
+        ledger.height += 1;
+        assert!(matches!(
+            ledger.release(second, [8u8; 32]),
+            Err(LedgerError::Expired { .. })
+        ));
+        ledger.refund(second).expect("refund");
+        assert_eq!(ledger.balance("alice"), 500);
+    }
+
+    #[test]
+    fn pay_gated_spec_flow_settles_on_ledger() {
+        let operator = SpecOperator {
+            operator_key: keypair_from_secret(OPERATOR).expect("operator key"),
+            operator_pubkey: pubkey_from_secret(OPERATOR).expect("operator pubkey"),
+        };
+        let commander_pub = pubkey_from_secret(COMMANDER).expect("commander pubkey");
+        let executor = SpecOperator {
+            operator_key: keypair_from_secret(EXECUTOR).expect("executor key"),
+            operator_pubkey: pubkey_from_secret(EXECUTOR).expect("executor pubkey"),
+        };
+        let verifier = SpecVerifier {
+            operator_pubkey: operator.operator_pubkey.clone(),
+            executor_pubkey: executor.operator_pubkey.clone(),
+        };
+        let token = operator
+            .issue_token(&TokenIssueRequest {
+                subject: commander_pub.clone(),
+                audience: executor.operator_pubkey.clone(),
+                capability: vec!["cmd:imaging:msi".to_string()],
+                issued_at: 100,
+                expires_at: 10_000,
+                token_id: Some([4u8; 16]),
+            })
+            .expect("issue token");
+
+        let mut request = SpecTaskRequest {
+            task_id: "task-ledger".to_string(),
+            timestamp: 200,
+            capability_token: token.encode_tlv().expect("encode token"),
+            delegation_chain: vec![],
+            task_type: "cmd:imaging:msi".to_string(),
+            target_json: "{}".to_string(),
+            parameters_json: "{}".to_string(),
+            constraints_json: "{}".to_string(),
+            payment_max_sats: 1_000,
+            timeout_blocks: 144,
+            commander_signature: [0u8; 64],
+        };
+        request.commander_signature = sign_message_hash(
+            request.commander_signing_hash(),
+            &keypair_from_secret(COMMANDER).expect("commander key"),
+        )
+        .expect("sign request");
+        verifier
+            .verify_request(&request, 210)
+            .expect("verify request");
+
+        // Commander funds an HTLC for the request's payment hash.
+        let mut ledger = Ledger::new();
+        let commander = bytes_to_hex(&commander_pub);
+        let payee = bytes_to_hex(&executor.operator_pubkey);
+        ledger.fund(&commander, 5_000);
+        let correlation_id = request.request_hash();
+        let payment_hash = derive_payment_hash(correlation_id);
+        ledger
+            .lock(
+                &commander,
+                &payee,
+                payment_hash,
+                800,
+                request.timeout_blocks as u64,
+            )
+            .expect("ledger lock");
+
+        // Ledger height 0 is the request time; both sides count 600s blocks.
+        let clock = IntervalClock::anchored(
+            IntervalClock::BITCOIN_BLOCK_INTERVAL_SEC,
+            ledger.height,
+            200,
+        );
+        let mut state = SettlementState::new(&request, &clock);
+        let lock = SpecPaymentLock {
+            task_id: request.task_id.clone(),
+            correlation_id,
+            payment_hash,
+            amount_sats: 800,
+            timeout_blocks: request.timeout_blocks,
+            timestamp: 211,
+        };
+        let htlc = ledger.htlc(lock.payment_hash).expect("htlc present");
+        assert_eq!(htlc.state, HtlcState::Locked);
+        assert_eq!(htlc.payee, payee);
+        assert!(htlc.amount_sats >= lock.amount_sats);
+        assert!(clock.time_at(htlc.expiry_height) >= state.expires_at);
+        state.lock(&lock, 211).expect("state lock");
+
+        state.accept(212).expect("state accept");
+        let mut accept = SpecTaskAccept {
+            task_id: request.task_id.clone(),
+            timestamp: 212,
+            in_reply_to: correlation_id,
+            payment_hash,
+            amount_sats: lock.amount_sats,
+            expiry_sec: 600,
+            description: request.task_type.clone(),
+            estimated_duration_sec: 1,
+            earliest_start: 212,
+            data_volume_mb: 0,
+            quality_estimate: 0,
+            executor_signature: [0u8; 64],
+        };
+        accept.executor_signature = executor.sign_accept(&accept).expect("sign accept");
+        verifier
+            .verify_accept(&accept, correlation_id)
+            .expect("verify accept");
+
+        ledger.mine(3);
+        state.record_proof(213).expect("state proof");
+        let mut proof = SpecProofOfExecution {
+            task_id: request.task_id.clone(),
+            task_token_id: token.token_id,
+            payment_hash,
+            output_hash: sha256(b"image"),
+            execution_timestamp: 213,
+            executor_pubkey: executor.operator_pubkey.clone(),
+            executor_signature: [0u8; 64],
+        };
+        proof.executor_signature = executor.sign_proof(proof.proof_hash()).expect("sign proof");
+        verifier.verify_proof(&proof).expect("verify proof");
+
+        let claim = SpecPaymentClaim {
+            task_id: request.task_id.clone(),
+            correlation_id,
+            payment_hash,
+            preimage: derive_preimage(correlation_id),
+            timestamp: 214,
+        };
+        state.validate_claim(&claim).expect("claim valid");
+        ledger
+            .release(claim.payment_hash, claim.preimage)
+            .expect("ledger release");
+        state.claim(&claim, 214).expect("state claim");
+
+        assert_eq!(state.phase, SettlementPhase::Claimed);
+        assert_eq!(ledger.balance(&payee), 800);
+        assert_eq!(ledger.balance(&commander), 4_200);
+    }
+}
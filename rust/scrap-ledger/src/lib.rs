@@ -0,0 +1,5 @@
+mod ledger;
+mod service;
+
+pub use ledger::*;
+pub use service::*;
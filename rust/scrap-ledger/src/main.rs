@@ -0,0 +1,215 @@
+use clap::Parser;
+use scrap_ledger::{Ledger, LedgerRequest, LedgerResponse};
+use scrap_protocol::{ChainClock, IntervalClock};
+use serde_json::json;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::{ErrorKind, Write};
+use std::net::UdpSocket;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Parser, Debug)]
+#[command(name = "scrap-ledger", about = "Simulated HTLC payment ledger")]
+struct Args {
+    #[arg(long, default_value = "127.0.0.1")]
+    bind: String,
+
+    #[arg(long, default_value_t = 7400)]
+    port: u16,
+
+    /// JSON file holding heights, balances and HTLCs across restarts.
+    #[arg(long)]
+    state: Option<String>,
+
+    /// Mine one block every N seconds; 0 leaves the clock to `mine` requests.
+    #[arg(long, default_value_t = 0)]
+    block_interval_sec: u64,
+
+    /// Credit an account at startup, `account=sats`. Repeatable.
+    #[arg(long = "fund")]
+    fund: Vec<String>,
+}
+
+fn unix_ts() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// A missing state file starts a fresh ledger; anything else that fails to
+/// load is an error so balances and HTLCs are never silently reset.
+fn load_ledger(path: &str) -> Result<Ledger, String> {
+    match fs::read_to_string(path) {
+        Ok(raw) => serde_json::from_str(&raw)
+            .map_err(|err| format!("ledger state {path} is corrupt: {err}")),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(Ledger::default()),
+        Err(err) => Err(format!("ledger state {path} unreadable: {err}")),
+    }
+}
+
+fn save_ledger(path: &str, ledger: &Ledger) -> bool {
+    if let Some(parent) = Path::new(path).parent() {
+        if !parent.as_os_str().is_empty() {
+            let _ = fs::create_dir_all(parent);
+        }
+    }
+    let tmp_path = format!("{}.tmp", path);
+    if let Ok(payload) = serde_json::to_vec_pretty(ledger) {
+        if let Ok(mut file) = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp_path)
+        {
+            if file.write_all(&payload).is_ok() {
+                return fs::rename(&tmp_path, path).is_ok();
+            }
+        }
+    }
+    false
+}
+
+fn persist(path: &Option<String>, ledger: &Ledger) {
+    if let Some(path) = path {
+        if !save_ledger(path, ledger) {
+            let log = json!({
+                "ts": unix_ts(),
+                "event": "ledger_save_failed",
+                "path": path
+            });
+            println!("{}", log);
+        }
+    }
+}
+
+fn main() {
+    let args = Args::parse();
+    let mut ledger = match args.state.as_deref().map(load_ledger) {
+        Some(Ok(ledger)) => ledger,
+        Some(Err(err)) => {
+            eprintln!("{err}");
+            std::process::exit(2);
+        }
+        None => Ledger::default(),
+    };
+    for entry in &args.fund {
+        let Some((account, amount)) = entry.split_once('=') else {
+            eprintln!("--fund expects account=sats, got {entry}");
+            std::process::exit(2);
+        };
+        let Ok(amount) = amount.parse::<u64>() else {
+            eprintln!("--fund amount is not a number: {entry}");
+            std::process::exit(2);
+        };
+        ledger.fund(account, amount);
+    }
+    persist(&args.state, &ledger);
+
+    let bind_addr = format!("{}:{}", args.bind, args.port);
+    let socket = UdpSocket::bind(&bind_addr).expect("bind failed");
+    socket
+        .set_read_timeout(Some(Duration::from_secs(1)))
+        .expect("set read timeout failed");
+
+    let start_log = json!({
+        "ts": unix_ts(),
+        "event": "ledger_started",
+        "bind": args.bind,
+        "port": args.port,
+        "height": ledger.height,
+        "block_interval_sec": args.block_interval_sec,
+        "state": args.state
+    });
+    println!("{}", start_log);
+
+    // Heights follow wall time from startup; `mine` requests re-anchor it.
+    let mut clock = IntervalClock::anchored(args.block_interval_sec, ledger.height, unix_ts());
+    let mut buf = [0u8; 65535];
+    loop {
+        let due = clock.height_at(unix_ts());
+        if args.block_interval_sec > 0 && due > ledger.height {
+            let refunded = ledger.mine(due - ledger.height);
+            persist(&args.state, &ledger);
+            let log = json!({
+                "ts": unix_ts(),
+                "event": "block_mined",
+                "height": ledger.height,
+                "refunded": refunded
+            });
+            println!("{}", log);
+        }
+
+        let (len, addr) = match socket.recv_from(&mut buf) {
+            Ok(res) => res,
+            Err(_) => continue,
+        };
+        let response = match serde_json::from_slice::<LedgerRequest>(&buf[..len]) {
+            Ok(request) => {
+                let response = ledger.apply(&request);
+                if response.ok && request.is_mutating() {
+                    persist(&args.state, &ledger);
+                }
+                if let LedgerRequest::Mine { .. } = request {
+                    clock =
+                        IntervalClock::anchored(args.block_interval_sec, ledger.height, unix_ts());
+                }
+                let log = json!({
+                    "ts": unix_ts(),
+                    "event": "ledger_request",
+                    "source": addr.to_string(),
+                    "request": request,
+                    "ok": response.ok,
+                    "error": response.error,
+                    "height": response.height
+                });
+                println!("{}", log);
+                response
+            }
+            Err(err) => LedgerResponse {
+                ok: false,
+                height: ledger.height,
+                error: Some(format!("invalid request: {err}")),
+                ..LedgerResponse::default()
+            },
+        };
+        if let Ok(payload) = serde_json::to_vec(&response) {
+            let _ = socket.send_to(&payload, addr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("scrap-ledger-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir.join("ledger.json").to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn state_survives_a_reload_and_missing_state_is_fresh() {
+        let path = temp_path("reload");
+        assert_eq!(load_ledger(&path).unwrap().height, 0);
+
+        let mut ledger = Ledger::new();
+        ledger.fund("commander", 5000);
+        ledger.mine(3);
+        assert!(save_ledger(&path, &ledger));
+        let loaded = load_ledger(&path).unwrap();
+        assert_eq!(loaded.height, 3);
+        assert_eq!(loaded.balance("commander"), 5000);
+    }
+
+    #[test]
+    fn corrupt_state_is_an_error() {
+        let path = temp_path("corrupt");
+        fs::create_dir_all(Path::new(&path).parent().unwrap()).unwrap();
+        fs::write(&path, b"{\"height\": 3, \"balances\": ").unwrap();
+        let err = load_ledger(&path).err().unwrap();
+        assert!(err.contains("corrupt"), "{err}");
+    }
+}
@@ -0,0 +1,180 @@
+use crate::{Htlc, Ledger};
+use scrap_protocol::hex_to_bytes;
+use serde::{Deserialize, Serialize};
+use std::net::UdpSocket;
+use std::time::Duration;
+
+/// One JSON datagram sent to `scrap-ledger`; each gets one `LedgerResponse`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum LedgerRequest {
+    Status,
+    Fund {
+        account: String,
+        amount_sats: u64,
+    },
+    Lock {
+        payer: String,
+        payee: String,
+        payment_hash: String,
+        amount_sats: u64,
+        timeout_blocks: u64,
+    },
+    Release {
+        payment_hash: String,
+        preimage: String,
+    },
+    Refund {
+        payment_hash: String,
+    },
+    Get {
+        payment_hash: String,
+    },
+    Mine {
+        blocks: u64,
+    },
+}
+
+impl LedgerRequest {
+    pub fn is_mutating(&self) -> bool {
+        !matches!(self, LedgerRequest::Status | LedgerRequest::Get { .. })
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LedgerResponse {
+    pub ok: bool,
+    pub height: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub htlc: Option<Htlc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub balance: Option<u64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub refunded: Vec<String>,
+}
+
+fn parse_hash(hex: &str, what: &str) -> Result<[u8; 32], String> {
+    hex_to_bytes(hex)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| format!("{what} must be 32-byte hex"))
+}
+
+impl Ledger {
+    pub fn apply(&mut self, request: &LedgerRequest) -> LedgerResponse {
+        let result: Result<LedgerResponse, String> = match request {
+            LedgerRequest::Status => Ok(LedgerResponse::default()),
+            LedgerRequest::Fund {
+                account,
+                amount_sats,
+            } => Ok(LedgerResponse {
+                balance: Some(self.fund(account, *amount_sats)),
+                ..LedgerResponse::default()
+            }),
+            LedgerRequest::Lock {
+                payer,
+                payee,
+                payment_hash,
+                amount_sats,
+                timeout_blocks,
+            } => parse_hash(payment_hash, "payment_hash").and_then(|hash| {
+                self.lock(payer, payee, hash, *amount_sats, *timeout_blocks)
+                    .map(|htlc| LedgerResponse {
+                        htlc: Some(htlc.clone()),
+                        ..LedgerResponse::default()
+                    })
+                    .map_err(|err| err.to_string())
+            }),
+            LedgerRequest::Release {
+                payment_hash,
+                preimage,
+            } => parse_hash(payment_hash, "payment_hash").and_then(|hash| {
+                let preimage = parse_hash(preimage, "preimage")?;
+                self.release(hash, preimage)
+                    .map(|htlc| LedgerResponse {
+                        htlc: Some(htlc.clone()),
+                        ..LedgerResponse::default()
+                    })
+                    .map_err(|err| err.to_string())
+            }),
+            LedgerRequest::Refund { payment_hash } => parse_hash(payment_hash, "payment_hash")
+                .and_then(|hash| {
+                    self.refund(hash)
+                        .map(|htlc| LedgerResponse {
+                            htlc: Some(htlc.clone()),
+                            ..LedgerResponse::default()
+                        })
+                        .map_err(|err| err.to_string())
+                }),
+            LedgerRequest::Get { payment_hash } => parse_hash(payment_hash, "payment_hash")
+                .and_then(|hash| {
+                    self.htlc(hash)
+                        .map(|htlc| LedgerResponse {
+                            htlc: Some(htlc.clone()),
+                            ..LedgerResponse::default()
+                        })
+                        .ok_or_else(|| "no htlc for payment_hash".to_string())
+                }),
+            LedgerRequest::Mine { blocks } => Ok(LedgerResponse {
+                refunded: self.mine(*blocks),
+                ..LedgerResponse::default()
+            }),
+        };
+        match result {
+            Ok(response) => LedgerResponse {
+                ok: true,
+                height: self.height,
+                ..response
+            },
+            Err(error) => LedgerResponse {
+                ok: false,
+                height: self.height,
+                error: Some(error),
+                ..LedgerResponse::default()
+            },
+        }
+    }
+}
+
+/// Blocking UDP client for a running `scrap-ledger`.
+pub struct LedgerClient {
+    socket: UdpSocket,
+    server: String,
+}
+
+impl LedgerClient {
+    pub fn connect(server: &str, timeout: Duration) -> Result<Self, String> {
+        let socket =
+            UdpSocket::bind("0.0.0.0:0").map_err(|err| format!("ledger bind failed: {err}"))?;
+        socket
+            .set_read_timeout(Some(timeout))
+            .map_err(|err| format!("ledger socket: {err}"))?;
+        Ok(Self {
+            socket,
+            server: server.to_string(),
+        })
+    }
+
+    pub fn call(&self, request: &LedgerRequest) -> Result<LedgerResponse, String> {
+        let payload = serde_json::to_vec(request).map_err(|err| err.to_string())?;
+        self.socket
+            .send_to(&payload, &self.server)
+            .map_err(|err| format!("ledger send failed: {err}"))?;
+        let mut buf = [0u8; 65535];
+        let (len, _) = self
+            .socket
+            .recv_from(&mut buf)
+            .map_err(|err| format!("ledger did not answer: {err}"))?;
+        let response: LedgerResponse =
+            serde_json::from_slice(&buf[..len]).map_err(|err| err.to_string())?;
+        if response.ok {
+            Ok(response)
+        } else {
+            Err(response
+                .error
+                .unwrap_or_else(|| "ledger request failed".to_string()))
+        }
+    }
+}
@@ -1,4 +1,9 @@
 use clap::{Parser, Subcommand};
+use scrap_protocol::{
+    bytes_to_hex, demo_id_bytes, demo_token_id, generate_secret_hex, hex_to_bytes,
+    keypair_from_secret, normalize_pubkey, pubkey_from_secret, CapHeader, CapPayload,
+    DelegationRequest, Operator, SatCapToken, SpecOperator, SpecToken, TokenIssueRequest,
+};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use sha2::{Digest, Sha256};
@@ -16,6 +21,39 @@ struct Args {
 
 #[derive(Subcommand, Debug)]
 enum Command {
+    #[command(name = "generate-keys")]
+    GenerateKeys {
+        #[arg(long)]
+        keys: String,
+
+        #[arg(long, default_value = "operator", value_parser = ["operator", "commander", "executor"])]
+        role: String,
+
+        #[arg(long, action = clap::ArgAction::SetTrue)]
+        force: bool,
+    },
+
+    #[command(name = "show-pubkey")]
+    ShowPubkey {
+        #[arg(long)]
+        keys: String,
+
+        #[arg(long, default_value = "operator", value_parser = ["operator", "commander", "executor"])]
+        role: String,
+
+        #[arg(long, action = clap::ArgAction::SetTrue)]
+        xonly: bool,
+    },
+
+    #[command(name = "rotate-keys")]
+    RotateKeys {
+        #[arg(long)]
+        keys: String,
+
+        #[arg(long, default_value = "operator", value_parser = ["operator", "commander", "executor"])]
+        role: String,
+    },
+
     #[command(name = "issue-token")]
     IssueToken {
         #[arg(long)]
@@ -27,6 +65,12 @@ enum Command {
         #[arg(long)]
         meta_out: Option<String>,
 
+        #[arg(long)]
+        tlv_out: Option<String>,
+
+        #[arg(long)]
+        cbor_out: Option<String>,
+
         #[arg(long)]
         subject: String,
 
@@ -42,9 +86,41 @@ enum Command {
         #[arg(long)]
         token_id: Option<String>,
 
-        #[arg(long, action = clap::ArgAction::SetTrue)]
+        /// Writes an unsigned JSON token. The TLV and CBOR forms are always
+        /// signed, so they cannot be requested alongside.
+        #[arg(long, action = clap::ArgAction::SetTrue, conflicts_with_all = ["tlv_out", "cbor_out"])]
         allow_mock_signature: bool,
     },
+
+    #[command(name = "delegate")]
+    Delegate {
+        #[arg(long)]
+        keys: String,
+
+        #[arg(long, default_value = "commander", value_parser = ["operator", "commander", "executor"])]
+        role: String,
+
+        #[arg(long)]
+        parent: String,
+
+        #[arg(long)]
+        out: String,
+
+        #[arg(long)]
+        meta_out: Option<String>,
+
+        #[arg(long)]
+        subject: String,
+
+        #[arg(long = "capability")]
+        capabilities: Vec<String>,
+
+        #[arg(long)]
+        expires_in: Option<u64>,
+
+        #[arg(long)]
+        token_id: Option<String>,
+    },
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -56,7 +132,13 @@ struct Token {
     capability: String,
     issued_at: u64,
     expires_at: u64,
+    /// Hex BIP340 signature over the `SCRAP/token/v1` tagged hash of
+    /// `signed_tlv`, not over these JSON fields; `"mock"` when unsigned.
     signature: String,
+    /// Hex spec TLV token without its signature record: the bytes `signature`
+    /// covers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    signed_tlv: Option<String>,
 }
 
 fn unix_ts() -> u64 {
@@ -102,13 +184,169 @@ fn write_json<T: Serialize>(path: &str, value: &T) {
     let _ = file.write_all(&payload);
 }
 
+fn write_bytes(path: &str, bytes: &[u8]) {
+    ensure_parent(path);
+    fs::write(path, bytes).expect("write failed");
+}
+
+fn write_secret_json<T: Serialize>(path: &str, value: &T) {
+    ensure_parent(path);
+    let payload = serde_json::to_vec_pretty(value).expect("serialize failed");
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path).expect("open failed");
+    // mode() only applies on create; tighten files that already existed.
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let _ = fs::set_permissions(path, fs::Permissions::from_mode(0o600));
+    }
+    file.write_all(&payload).expect("write failed");
+}
+
+/// Lifetime of a delegated token when `--expires-in` is not given.
+const DEFAULT_DELEGATION_TTL_SECS: u64 = 3600;
+
+/// A delegated token must expire strictly before its parent; without an
+/// explicit lifetime it gets the default TTL, cut short by the parent.
+fn delegated_expiry(
+    issued_at: u64,
+    parent_expires_at: u64,
+    expires_in: Option<u64>,
+) -> Result<u64, String> {
+    if parent_expires_at <= issued_at + 1 {
+        return Err("parent token expired or about to expire".to_string());
+    }
+    let expires_at = match expires_in {
+        Some(0) => return Err("--expires-in must be positive".to_string()),
+        Some(secs) => issued_at.saturating_add(secs),
+        None => (issued_at + DEFAULT_DELEGATION_TTL_SECS).min(parent_expires_at - 1),
+    };
+    if expires_at >= parent_expires_at {
+        return Err(format!(
+            "--expires-in {} would expire at {expires_at}, not before the parent's {parent_expires_at}",
+            expires_in.unwrap_or_default()
+        ));
+    }
+    Ok(expires_at)
+}
+
+fn fail(msg: &str) -> ! {
+    eprintln!("scrap-operator: {msg}");
+    std::process::exit(2);
+}
+
+fn load_keys(path: &str) -> serde_json::Map<String, serde_json::Value> {
+    match fs::read_to_string(path) {
+        Ok(raw) => match serde_json::from_str::<serde_json::Value>(&raw) {
+            Ok(serde_json::Value::Object(map)) => map,
+            _ => fail(&format!("keys file is not a JSON object: {path}")),
+        },
+        Err(_) => serde_json::Map::new(),
+    }
+}
+
+fn role_secret(keys: &serde_json::Map<String, serde_json::Value>, role: &str) -> Option<String> {
+    keys.get(&format!("{role}_privkey"))
+        .and_then(|v| v.as_str())
+        .map(|v| v.to_string())
+}
+
+fn set_role_keypair(
+    keys: &mut serde_json::Map<String, serde_json::Value>,
+    role: &str,
+    secret_hex: &str,
+) -> String {
+    let pubkey = pubkey_from_secret(secret_hex).expect("derive pubkey failed");
+    let pubkey_hex = bytes_to_hex(&pubkey);
+    keys.insert(format!("{role}_privkey"), json!(secret_hex));
+    keys.insert(format!("{role}_pubkey"), json!(pubkey_hex));
+    pubkey_hex
+}
+
+fn load_spec_token(path: &str) -> SpecToken {
+    let raw = fs::read(path).unwrap_or_else(|err| fail(&format!("read {path} failed: {err}")));
+    if let Ok(token) = SpecToken::decode_tlv(&raw) {
+        return token;
+    }
+    let text = String::from_utf8_lossy(&raw);
+    let bytes = hex_to_bytes(text.trim())
+        .unwrap_or_else(|_| fail(&format!("{path} is neither TLV nor hex TLV")));
+    SpecToken::decode_tlv(&bytes)
+        .unwrap_or_else(|err| fail(&format!("decode parent token failed: {err}")))
+}
+
 fn main() {
     let args = Args::parse();
     match args.command {
+        Command::GenerateKeys { keys, role, force } => {
+            let mut map = load_keys(&keys);
+            if role_secret(&map, &role).is_some() && !force {
+                fail(&format!(
+                    "{role} key already present in {keys}; use rotate-keys or --force"
+                ));
+            }
+            let pubkey = set_role_keypair(&mut map, &role, &generate_secret_hex());
+            write_secret_json(&keys, &serde_json::Value::Object(map));
+            println!(
+                "{}",
+                json!({
+                    "ts": unix_ts(),
+                    "event": "keys_generated",
+                    "role": role,
+                    "keys": keys,
+                    "pubkey": pubkey
+                })
+            );
+        }
+        Command::ShowPubkey { keys, role, xonly } => {
+            let map = load_keys(&keys);
+            let secret = role_secret(&map, &role)
+                .unwrap_or_else(|| fail(&format!("{role}_privkey missing in {keys}")));
+            let pubkey = pubkey_from_secret(&secret)
+                .unwrap_or_else(|err| fail(&format!("{role}_privkey invalid: {err}")));
+            if xonly {
+                let norm = normalize_pubkey(&pubkey).expect("normalize pubkey failed");
+                println!("{}", bytes_to_hex(&norm));
+            } else {
+                println!("{}", bytes_to_hex(&pubkey));
+            }
+        }
+        Command::RotateKeys { keys, role } => {
+            let mut map = load_keys(&keys);
+            let previous = role_secret(&map, &role)
+                .unwrap_or_else(|| fail(&format!("{role}_privkey missing in {keys}")));
+            let previous_pubkey = pubkey_from_secret(&previous)
+                .map(|pk| bytes_to_hex(&pk))
+                .unwrap_or_default();
+            let backup = format!("{keys}.{}.bak", unix_ts());
+            write_secret_json(&backup, &serde_json::Value::Object(map.clone()));
+            let pubkey = set_role_keypair(&mut map, &role, &generate_secret_hex());
+            write_secret_json(&keys, &serde_json::Value::Object(map));
+            println!(
+                "{}",
+                json!({
+                    "ts": unix_ts(),
+                    "event": "keys_rotated",
+                    "role": role,
+                    "keys": keys,
+                    "backup": backup,
+                    "previous_pubkey": previous_pubkey,
+                    "pubkey": pubkey
+                })
+            );
+        }
         Command::IssueToken {
-            keys: _keys,
+            keys,
             out,
             meta_out,
+            tlv_out,
+            cbor_out,
             subject,
             audience,
             capability,
@@ -124,7 +362,7 @@ fn main() {
                 hash[..32].to_string()
             });
 
-            let token = Token {
+            let mut token = Token {
                 version: 1,
                 token_id: token_id.clone(),
                 subject: subject.clone(),
@@ -132,8 +370,79 @@ fn main() {
                 capability: capability.clone(),
                 issued_at,
                 expires_at,
-                signature: if allow_mock_signature { "mock".to_string() } else { "".to_string() },
+                signature: "mock".to_string(),
+                signed_tlv: None,
             };
+            let mut issuer: Option<String> = None;
+
+            if !allow_mock_signature {
+                let map = load_keys(&keys);
+                let secret = role_secret(&map, "operator")
+                    .unwrap_or_else(|| fail(&format!("operator_privkey missing in {keys}")));
+                let operator = SpecOperator {
+                    operator_key: keypair_from_secret(&secret)
+                        .unwrap_or_else(|err| fail(&format!("operator_privkey invalid: {err}"))),
+                    operator_pubkey: pubkey_from_secret(&secret)
+                        .unwrap_or_else(|err| fail(&format!("operator_privkey invalid: {err}"))),
+                };
+                let to_u32 = |value: u64| {
+                    u32::try_from(value).unwrap_or_else(|_| fail("timestamp exceeds u32"))
+                };
+                let issue = TokenIssueRequest {
+                    subject: demo_id_bytes(&subject),
+                    audience: demo_id_bytes(&audience),
+                    capability: vec![capability.clone()],
+                    issued_at: to_u32(issued_at),
+                    expires_at: to_u32(expires_at),
+                    token_id: Some(demo_token_id(&token_id)),
+                };
+                let spec_token = operator
+                    .issue_token(&issue)
+                    .unwrap_or_else(|err| fail(&format!("issue token failed: {err}")));
+                token.signature = bytes_to_hex(&spec_token.signature);
+                let signed = spec_token
+                    .encode_tlv_without_signature()
+                    .expect("encode tlv failed");
+                token.signed_tlv = Some(bytes_to_hex(&signed));
+                issuer = Some(bytes_to_hex(&operator.operator_pubkey));
+
+                if let Some(path) = &tlv_out {
+                    let encoded = spec_token.encode_tlv().expect("encode tlv failed");
+                    write_bytes(path, &encoded);
+                }
+
+                if let Some(path) = &cbor_out {
+                    let cmd_pub = hex_to_bytes(&subject)
+                        .ok()
+                        .filter(|bytes| normalize_pubkey(bytes).is_ok());
+                    let mut cap_token = SatCapToken {
+                        header: CapHeader {
+                            alg: "BIP340".to_string(),
+                            typ: "SAT-CAP".to_string(),
+                            enc: Some("CBOR".to_string()),
+                            chn: None,
+                        },
+                        payload: CapPayload {
+                            iss: bytes_to_hex(&operator.operator_pubkey),
+                            sub: subject.clone(),
+                            aud: audience.clone(),
+                            iat: issued_at,
+                            exp: expires_at,
+                            jti: token_id.clone(),
+                            cap: vec![capability.clone()],
+                            cns: None,
+                            prf: None,
+                            cmd_pub,
+                        },
+                        signature: Vec::new(),
+                    };
+                    cap_token
+                        .sign(&operator.operator_key)
+                        .expect("sign cbor token failed");
+                    let encoded = cap_token.encode_cbor().expect("encode cbor failed");
+                    write_bytes(path, &encoded);
+                }
+            }
 
             write_json(&out, &token);
 
@@ -145,10 +454,161 @@ fn main() {
                     "audience": audience,
                     "subject": subject,
                     "capability": capability,
+                    "issuer": issuer,
+                    "tlv_out": tlv_out,
+                    "cbor_out": cbor_out,
                     "signature_mocked": allow_mock_signature
                 });
                 write_json(&meta_out, &meta);
             }
         }
+        Command::Delegate {
+            keys,
+            role,
+            parent,
+            out,
+            meta_out,
+            subject,
+            capabilities,
+            expires_in,
+            token_id,
+        } => {
+            let parent_token = load_spec_token(&parent);
+            let map = load_keys(&keys);
+            let secret = role_secret(&map, &role)
+                .unwrap_or_else(|| fail(&format!("{role}_privkey missing in {keys}")));
+            let holder_key = keypair_from_secret(&secret)
+                .unwrap_or_else(|err| fail(&format!("{role}_privkey invalid: {err}")));
+
+            let issued_at = unix_ts();
+            let expires_at =
+                delegated_expiry(issued_at, parent_token.expires_at as u64, expires_in)
+                    .unwrap_or_else(|err| fail(&err));
+            let capabilities = if capabilities.is_empty() {
+                parent_token.capabilities.clone()
+            } else {
+                capabilities
+            };
+            let subject_bytes = hex_to_bytes(&subject)
+                .ok()
+                .filter(|bytes| normalize_pubkey(bytes).is_ok())
+                .unwrap_or_else(|| fail("delegate subject must be a pubkey hex"));
+            let token_id = token_id.unwrap_or_else(|| {
+                let seed = format!(
+                    "{}:{}:{}",
+                    bytes_to_hex(&parent_token.token_id),
+                    subject,
+                    issued_at
+                );
+                sha256_hex(&[&seed])[..32].to_string()
+            });
+
+            let request = DelegationRequest {
+                subject: subject_bytes,
+                capability: capabilities,
+                issued_at: u32::try_from(issued_at)
+                    .unwrap_or_else(|_| fail("timestamp exceeds u32")),
+                expires_at: u32::try_from(expires_at)
+                    .unwrap_or_else(|_| fail("timestamp exceeds u32")),
+                token_id: Some(demo_token_id(&token_id)),
+            };
+            let child = parent_token
+                .delegate(&holder_key, &request)
+                .unwrap_or_else(|err| fail(&format!("delegation refused: {err}")));
+            let encoded = child.encode_tlv().expect("encode tlv failed");
+            write_bytes(&out, &encoded);
+
+            let meta = json!({
+                "token_id": bytes_to_hex(&child.token_id),
+                "parent_token_id": bytes_to_hex(&parent_token.token_id),
+                "root_token_id": child.delegation.root_token_id.map(|id| bytes_to_hex(&id)),
+                "chain_depth": child.delegation.chain_depth,
+                "issuer": bytes_to_hex(&child.issuer),
+                "subject": subject,
+                "capabilities": child.capabilities,
+                "issued_at": child.issued_at,
+                "expires_at": child.expires_at,
+                "tlv_out": out
+            });
+            if let Some(meta_out) = meta_out {
+                write_json(&meta_out, &meta);
+            }
+            println!(
+                "{}",
+                json!({
+                    "ts": unix_ts(),
+                    "event": "token_delegated",
+                    "token": meta
+                })
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mock_signatures_cannot_come_with_signed_outputs() {
+        let issue = |extra: &[&str]| {
+            let mut argv = vec![
+                "scrap-operator",
+                "issue-token",
+                "--keys",
+                "keys.json",
+                "--out",
+                "token.json",
+                "--subject",
+                "commander",
+                "--audience",
+                "sat-1",
+                "--capability",
+                "cmd:imaging:msi",
+            ];
+            argv.extend_from_slice(extra);
+            Args::try_parse_from(argv)
+        };
+        assert!(issue(&["--allow-mock-signature"]).is_ok());
+        assert!(issue(&["--tlv-out", "token.tlv", "--cbor-out", "token.cbor"]).is_ok());
+        assert!(issue(&["--allow-mock-signature", "--tlv-out", "token.tlv"]).is_err());
+        assert!(issue(&["--allow-mock-signature", "--cbor-out", "token.cbor"]).is_err());
+    }
+
+    #[test]
+    fn delegation_must_expire_before_its_parent() {
+        let args = Args::try_parse_from([
+            "scrap-operator",
+            "delegate",
+            "--keys",
+            "keys.json",
+            "--parent",
+            "parent.tlv",
+            "--out",
+            "child.tlv",
+            "--subject",
+            "02ab",
+            "--expires-in",
+            "900",
+        ])
+        .expect("parse delegate");
+        let Command::Delegate { expires_in, .. } = args.command else {
+            panic!("expected delegate");
+        };
+        assert_eq!(delegated_expiry(1_000, 2_000, expires_in), Ok(1_900));
+
+        // Equal to the parent's expiry is not a shortened lifetime.
+        assert!(delegated_expiry(1_000, 2_000, Some(1_000)).is_err());
+        assert!(delegated_expiry(1_000, 2_000, Some(5_000)).is_err());
+        assert!(delegated_expiry(1_000, 2_000, Some(u64::MAX)).is_err());
+        assert!(delegated_expiry(1_000, 2_000, Some(0)).is_err());
+        assert!(delegated_expiry(2_000, 2_000, None).is_err());
+
+        // The default TTL is bounded and never reaches the parent's expiry.
+        assert_eq!(
+            delegated_expiry(1_000, 100_000, None),
+            Ok(1_000 + DEFAULT_DELEGATION_TTL_SECS)
+        );
+        assert_eq!(delegated_expiry(1_000, 2_000, None), Ok(1_999));
     }
 }
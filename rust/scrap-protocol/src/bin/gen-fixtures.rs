@@ -0,0 +1,47 @@
+//! Regenerates the CBOR/JSON fixture pairs in `fixtures/` (or the directory
+//! given as the only argument) from `scrap_protocol::fixtures::corpus()`.
+//! Signatures use fixed aux randomness, so an unchanged corpus regenerates
+//! byte for byte. Needs the `fixtures` feature.
+
+use scrap_protocol::fixtures::corpus;
+use std::path::PathBuf;
+use std::process::exit;
+
+fn fail(msg: &str) -> ! {
+    eprintln!("gen-fixtures: {msg}");
+    exit(1);
+}
+
+fn main() {
+    let out = std::env::args()
+        .nth(1)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("fixtures"));
+    std::fs::create_dir_all(&out)
+        .unwrap_or_else(|err| fail(&format!("create {} failed: {err}", out.display())));
+
+    let fixtures = corpus().unwrap_or_else(|err| fail(&err.reason));
+    for (name, fixture) in &fixtures {
+        let cbor = fixture
+            .encode_cbor()
+            .unwrap_or_else(|err| fail(&format!("{name}: {}", err.reason)));
+        let json = fixture
+            .to_json()
+            .and_then(|value| {
+                serde_json::to_string_pretty(&value)
+                    .map_err(|err| scrap_protocol::ProtocolError::new(err.to_string()))
+            })
+            .unwrap_or_else(|err| fail(&format!("{name}: {}", err.reason)));
+        for (path, bytes) in [
+            (out.join(format!("{name}.cbor")), cbor),
+            (
+                out.join(format!("{name}.json")),
+                format!("{json}\n").into_bytes(),
+            ),
+        ] {
+            std::fs::write(&path, bytes)
+                .unwrap_or_else(|err| fail(&format!("write {} failed: {err}", path.display())));
+        }
+    }
+    println!("wrote {} fixtures to {}", fixtures.len(), out.display());
+}
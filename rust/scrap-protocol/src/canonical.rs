@@ -0,0 +1,89 @@
+use crate::ProtocolError;
+use scrap_core_lite::{check_canonical, DecodeError};
+
+/// Checks that `bytes` is exactly one CBOR data item in deterministic
+/// encoding (RFC 8949 §4.2.1), by the same rules as the envelope decoder in
+/// `scrap_core_lite::check_canonical`. The encoders always write f64, whose
+/// width is not checked.
+pub fn check_canonical_cbor(bytes: &[u8]) -> Result<(), ProtocolError> {
+    check_canonical(bytes).map_err(|err| match err {
+        DecodeError::NonCanonical(what) => ProtocolError::new(format!("cbor {what}")),
+        other => ProtocolError::new(other.to_string()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{corpus, Fixture};
+    use crate::SatCapToken;
+
+    fn reason(bytes: &[u8]) -> String {
+        check_canonical_cbor(bytes).unwrap_err().reason
+    }
+
+    #[test]
+    fn encoders_emit_canonical_cbor() {
+        for (name, fixture) in corpus().unwrap() {
+            check_canonical_cbor(&fixture.encode_cbor().unwrap()).expect(name);
+        }
+    }
+
+    #[test]
+    fn rejects_non_deterministic_encodings() {
+        // {"a": 1, "b": 2}
+        assert!(check_canonical_cbor(&[0xa2, 0x61, 0x61, 0x01, 0x61, 0x62, 0x02]).is_ok());
+        assert_eq!(
+            reason(&[0xa2, 0x61, 0x62, 0x02, 0x61, 0x61, 0x01]),
+            "cbor map keys out of order"
+        );
+        assert_eq!(
+            reason(&[0xa2, 0x61, 0x61, 0x01, 0x61, 0x61, 0x02]),
+            "cbor duplicate map key"
+        );
+        // Shorter keys sort first: {"b": 1, "aa": 2}
+        assert!(check_canonical_cbor(&[0xa2, 0x61, 0x62, 0x01, 0x62, 0x61, 0x61, 0x02]).is_ok());
+        assert_eq!(
+            reason(&[0xbf, 0x61, 0x61, 0x01, 0xff]),
+            "cbor indefinite length"
+        );
+        assert_eq!(reason(&[0x18, 0x17]), "cbor non-minimal integer");
+        assert_eq!(reason(&[0x19, 0x00, 0xff]), "cbor non-minimal integer");
+        assert_eq!(reason(&[0x41, 0x00, 0x00]), "cbor trailing bytes");
+        assert_eq!(reason(&[0x42, 0x00]), "cbor truncated");
+        // Floats keep their width.
+        assert!(check_canonical_cbor(&[0xfb, 0x40, 0x24, 0, 0, 0, 0, 0, 0]).is_ok());
+    }
+
+    #[test]
+    fn strict_decoding_rejects_reordered_tokens() {
+        let token = match &corpus().unwrap()[0].1 {
+            Fixture::SatCapToken(token) => token.clone(),
+            _ => panic!("capability_token fixture"),
+        };
+        let bytes = token.encode_cbor().unwrap();
+        assert_eq!(SatCapToken::decode_cbor_canonical(&bytes).unwrap(), token);
+
+        // The same entries with `signature` moved first.
+        let mut dec = minicbor::Decoder::new(&bytes);
+        dec.set_position(1);
+        let mut entries = Vec::new();
+        while dec.position() < bytes.len() {
+            let start = dec.position();
+            dec.skip().unwrap();
+            dec.skip().unwrap();
+            entries.push(&bytes[start..dec.position()]);
+        }
+        let mut reordered = vec![bytes[0]];
+        for index in [2, 0, 1] {
+            reordered.extend_from_slice(entries[index]);
+        }
+        assert_eq!(SatCapToken::decode_cbor(&reordered).unwrap(), token);
+        assert_eq!(
+            SatCapToken::decode_cbor_canonical(&reordered)
+                .unwrap_err()
+                .reason,
+            "cbor map keys out of order"
+        );
+    }
+}
@@ -0,0 +1,104 @@
+/// Maps between chain height and unix time. Settlement timeouts are counted in
+/// blocks; everything that compares them with wall time goes through a clock.
+pub trait ChainClock {
+    /// Height of the chain at `unix_ts`.
+    fn height_at(&self, unix_ts: u64) -> u64;
+
+    /// Unix time at which `height` is reached.
+    fn time_at(&self, height: u64) -> u64;
+
+    /// Unix time at which `blocks` blocks past the height at `start` have been
+    /// mined.
+    fn deadline(&self, start: u64, blocks: u64) -> u64 {
+        self.time_at(self.height_at(start).saturating_add(blocks))
+    }
+}
+
+/// Fixed block interval anchored at a known `(height, unix time)` pair. Anchor
+/// it at a ledger's reported height to follow that ledger.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IntervalClock {
+    pub block_interval_sec: u64,
+    pub anchor_height: u64,
+    pub anchor_time: u64,
+}
+
+impl IntervalClock {
+    pub const BITCOIN_BLOCK_INTERVAL_SEC: u64 = 600;
+
+    pub const fn new(block_interval_sec: u64) -> Self {
+        Self::anchored(block_interval_sec, 0, 0)
+    }
+
+    pub const fn anchored(block_interval_sec: u64, height: u64, unix_ts: u64) -> Self {
+        Self {
+            block_interval_sec: if block_interval_sec == 0 {
+                1
+            } else {
+                block_interval_sec
+            },
+            anchor_height: height,
+            anchor_time: unix_ts,
+        }
+    }
+}
+
+impl Default for IntervalClock {
+    fn default() -> Self {
+        Self::new(Self::BITCOIN_BLOCK_INTERVAL_SEC)
+    }
+}
+
+impl ChainClock for IntervalClock {
+    fn height_at(&self, unix_ts: u64) -> u64 {
+        if unix_ts >= self.anchor_time {
+            let blocks = (unix_ts - self.anchor_time) / self.block_interval_sec;
+            self.anchor_height.saturating_add(blocks)
+        } else {
+            let blocks = (self.anchor_time - unix_ts).div_ceil(self.block_interval_sec);
+            self.anchor_height.saturating_sub(blocks)
+        }
+    }
+
+    fn time_at(&self, height: u64) -> u64 {
+        if height >= self.anchor_height {
+            let secs = (height - self.anchor_height).saturating_mul(self.block_interval_sec);
+            self.anchor_time.saturating_add(secs)
+        } else {
+            let secs = (self.anchor_height - height).saturating_mul(self.block_interval_sec);
+            self.anchor_time.saturating_sub(secs)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interval_clock_round_trips_heights() {
+        let clock = IntervalClock::anchored(600, 100, 60_000);
+        assert_eq!(clock.height_at(60_000), 100);
+        assert_eq!(clock.height_at(60_599), 100);
+        assert_eq!(clock.height_at(60_600), 101);
+        assert_eq!(clock.height_at(59_999), 99);
+        assert_eq!(clock.height_at(0), 0);
+        assert_eq!(clock.time_at(101), 60_600);
+        assert_eq!(clock.time_at(99), 59_400);
+        for ts in [0, 1, 59_401, 60_000, 61_234, 1_000_000] {
+            let height = clock.height_at(ts);
+            assert!(clock.time_at(height) <= ts);
+            assert!(clock.time_at(height + 1) > ts);
+        }
+    }
+
+    #[test]
+    fn deadline_counts_whole_blocks() {
+        let clock = IntervalClock::anchored(600, 100, 60_000);
+        // Mid-block starts expire when the height is reached, not a full
+        // interval later.
+        assert_eq!(clock.deadline(60_300, 2), 61_200);
+        assert_eq!(IntervalClock::new(1).deadline(10, 10), 20);
+        assert_eq!(IntervalClock::new(0).block_interval_sec, 1);
+    }
+}
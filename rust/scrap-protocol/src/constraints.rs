@@ -0,0 +1,805 @@
+use crate::{
+    bytes_to_hex, Constraints, GeoBounds, SatCapToken, SpecConstraints, SpecTaskRequest,
+    TimeWindow, VerifyError,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+/// Latitude/longitude box in decimal degrees. The TLV `constraint_geo` record
+/// carries it as `lat_min,lon_min,lat_max,lon_max`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GeoBox {
+    pub lat_min: f64,
+    pub lon_min: f64,
+    pub lat_max: f64,
+    pub lon_max: f64,
+}
+
+impl GeoBox {
+    pub fn parse(value: &str) -> Result<Self, VerifyError> {
+        let invalid = || VerifyError::new("constraint_geo invalid");
+        let parts = value
+            .split(',')
+            .map(|part| part.trim().parse::<f64>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| invalid())?;
+        let [lat_min, lon_min, lat_max, lon_max] = parts[..] else {
+            return Err(invalid());
+        };
+        let geo = GeoBox {
+            lat_min,
+            lon_min,
+            lat_max,
+            lon_max,
+        };
+        let in_range = (-90.0..=90.0).contains(&lat_min)
+            && (-90.0..=90.0).contains(&lat_max)
+            && (-180.0..=180.0).contains(&lon_min)
+            && (-180.0..=180.0).contains(&lon_max);
+        if !in_range || lat_min > lat_max || lon_min > lon_max {
+            return Err(invalid());
+        }
+        Ok(geo)
+    }
+
+    pub fn contains(&self, lat: f64, lon: f64) -> bool {
+        (self.lat_min..=self.lat_max).contains(&lat) && (self.lon_min..=self.lon_max).contains(&lon)
+    }
+
+    pub fn within(&self, outer: &GeoBox) -> bool {
+        outer.contains(self.lat_min, self.lon_min) && outer.contains(self.lat_max, self.lon_max)
+    }
+}
+
+impl std::fmt::Display for GeoBox {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{},{},{},{}",
+            self.lat_min, self.lon_min, self.lat_max, self.lon_max
+        )
+    }
+}
+
+/// Reads `{"lat": .., "lon": ..}` from a request's `target_json`, either at the
+/// top level or under `"location"`.
+pub fn target_location(target_json: &str) -> Option<(f64, f64)> {
+    let value: Value = serde_json::from_str(target_json).ok()?;
+    let point = value.get("location").unwrap_or(&value);
+    let lat = point.get("lat")?.as_f64()?;
+    let lon = point.get("lon")?.as_f64()?;
+    Some((lat, lon))
+}
+
+impl SpecConstraints {
+    pub fn check_time(&self, now: u64) -> Result<(), VerifyError> {
+        match self.not_before {
+            Some(not_before) if now < not_before as u64 => {
+                Err(VerifyError::new("token not yet valid"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks the stateless constraints against a request. `rate` needs
+    /// counters that outlive a single request; see `RateCounters`.
+    pub fn check_request(&self, request: &SpecTaskRequest) -> Result<(), VerifyError> {
+        if let Some(amount) = self.amount {
+            if request.payment_max_sats > amount {
+                return Err(VerifyError::new(
+                    "payment_max_sats exceeds amount constraint",
+                ));
+            }
+        }
+        if let Some(geo) = &self.geo {
+            let geo = GeoBox::parse(geo)?;
+            let (lat, lon) = target_location(&request.target_json)
+                .ok_or_else(|| VerifyError::new("target location missing for geo constraint"))?;
+            if !geo.contains(lat, lon) {
+                return Err(VerifyError::new("target outside geo constraint"));
+            }
+        }
+        Ok(())
+    }
+
+    /// A delegated token must keep every constraint of its parent, at the same
+    /// value or tighter.
+    pub fn check_narrows(&self, parent: &SpecConstraints) -> Result<(), VerifyError> {
+        if let Some(parent_geo) = &parent.geo {
+            let parent_geo = GeoBox::parse(parent_geo)?;
+            let geo = self
+                .geo
+                .as_deref()
+                .ok_or_else(|| VerifyError::new("delegation drops geo constraint"))
+                .and_then(GeoBox::parse)?;
+            if !geo.within(&parent_geo) {
+                return Err(VerifyError::new("delegation widens geo constraint"));
+            }
+        }
+        if let Some((parent_count, parent_window)) = parent.rate {
+            let (count, window) = self
+                .rate
+                .ok_or_else(|| VerifyError::new("delegation drops rate constraint"))?;
+            if count > parent_count || window < parent_window {
+                return Err(VerifyError::new("delegation widens rate constraint"));
+            }
+        }
+        if let Some(parent_amount) = parent.amount {
+            match self.amount {
+                None => return Err(VerifyError::new("delegation drops amount constraint")),
+                Some(amount) if amount > parent_amount => {
+                    return Err(VerifyError::new("delegation widens amount constraint"))
+                }
+                _ => {}
+            }
+        }
+        if let Some(parent_after) = parent.not_before {
+            match self.not_before {
+                None => return Err(VerifyError::new("delegation drops not_before constraint")),
+                Some(after) if after < parent_after => {
+                    return Err(VerifyError::new("delegation widens not_before constraint"))
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RateWindow {
+    pub start: u64,
+    pub window_sec: u32,
+    pub count: u32,
+}
+
+/// Uses per token id within fixed windows, for `rate = (count, window_sec)`.
+/// Serializes to JSON so the executor can keep it across restarts.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct RateCounters {
+    windows: BTreeMap<String, RateWindow>,
+}
+
+impl RateCounters {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, key: &str) -> Option<&RateWindow> {
+        self.windows.get(key)
+    }
+
+    /// Counts one use of `key` at `now`, or fails without counting once the
+    /// window's budget is spent.
+    pub fn record(&mut self, key: &str, rate: (u32, u32), now: u64) -> Result<(), VerifyError> {
+        let (count, window_sec) = rate;
+        let window = self.windows.entry(key.to_string()).or_default();
+        if window.count == 0 || now >= window.start.saturating_add(window_sec as u64) {
+            *window = RateWindow {
+                start: now,
+                window_sec,
+                count: 0,
+            };
+        }
+        if window.count >= count {
+            return Err(VerifyError::new("rate limit exceeded"));
+        }
+        window.count += 1;
+        Ok(())
+    }
+
+    /// Records a request against every rate-limited token in its chain. Either
+    /// all counters advance or none do.
+    pub fn record_chain<'a>(
+        &mut self,
+        tokens: impl IntoIterator<Item = (&'a [u8; 16], &'a SpecConstraints)>,
+        now: u64,
+    ) -> Result<(), VerifyError> {
+        let mut next = self.clone();
+        for (token_id, constraints) in tokens {
+            if let Some(rate) = constraints.rate {
+                next.record(&bytes_to_hex(token_id), rate, now)?;
+            }
+        }
+        *self = next;
+        Ok(())
+    }
+
+    /// Drops windows that have ended by `now`.
+    pub fn prune(&mut self, now: u64) {
+        self.windows
+            .retain(|_, window| window.start.saturating_add(window.window_sec as u64) > now);
+    }
+}
+
+/// What the executor knows about a task when admitting it against a
+/// `SatCapToken`'s `Constraints`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TaskContext {
+    pub now: u64,
+    /// Links the task crossed to get here (ISL relays).
+    pub hop_count: u32,
+    /// Requested target as `(lat, lon)`.
+    pub target: Option<(f64, f64)>,
+    /// Executor position as `(lat, lon)`, for `max_range_km`.
+    pub origin: Option<(f64, f64)>,
+    /// Conditions currently raised on the executor, matched against
+    /// `abort_triggers`.
+    pub active_conditions: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConstraintViolation {
+    TooManyHops { max: u32, hops: u32 },
+    BeforeWindow { start: u64, now: u64 },
+    AfterWindow { end: u64, now: u64 },
+    TargetMissing,
+    InvalidBounds(String),
+    OutsideBounds { lat: f64, lon: f64 },
+    OutsidePolygon { lat: f64, lon: f64 },
+    RangeUnknown,
+    OutOfRange { max_km: f64, distance_km: f64 },
+    AbortTriggered(String),
+}
+
+impl ConstraintViolation {
+    /// Stable snake_case name for logs and reject reasons.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ConstraintViolation::TooManyHops { .. } => "too_many_hops",
+            ConstraintViolation::BeforeWindow { .. } => "before_time_window",
+            ConstraintViolation::AfterWindow { .. } => "after_time_window",
+            ConstraintViolation::TargetMissing => "target_missing",
+            ConstraintViolation::InvalidBounds(_) => "invalid_bounds",
+            ConstraintViolation::OutsideBounds { .. } => "outside_bounds",
+            ConstraintViolation::OutsidePolygon { .. } => "outside_polygon",
+            ConstraintViolation::RangeUnknown => "range_unknown",
+            ConstraintViolation::OutOfRange { .. } => "out_of_range",
+            ConstraintViolation::AbortTriggered(_) => "abort_triggered",
+        }
+    }
+}
+
+impl std::fmt::Display for ConstraintViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConstraintViolation::TooManyHops { max, hops } => {
+                write!(f, "{hops} hops exceeds max_hops {max}")
+            }
+            ConstraintViolation::BeforeWindow { start, now } => {
+                write!(f, "time window opens at {start}, now {now}")
+            }
+            ConstraintViolation::AfterWindow { end, now } => {
+                write!(f, "time window closed at {end}, now {now}")
+            }
+            ConstraintViolation::TargetMissing => write!(f, "target location missing"),
+            ConstraintViolation::InvalidBounds(reason) => {
+                write!(f, "geographic_bounds invalid: {reason}")
+            }
+            ConstraintViolation::OutsideBounds { lat, lon } => {
+                write!(f, "target ({lat}, {lon}) outside geographic bounds")
+            }
+            ConstraintViolation::OutsidePolygon { lat, lon } => {
+                write!(f, "target ({lat}, {lon}) outside bounds polygon")
+            }
+            ConstraintViolation::RangeUnknown => {
+                write!(f, "max_range_km set but target or origin unknown")
+            }
+            ConstraintViolation::OutOfRange {
+                max_km,
+                distance_km,
+            } => write!(
+                f,
+                "target {distance_km:.1} km away exceeds max_range_km {max_km}"
+            ),
+            ConstraintViolation::AbortTriggered(trigger) => {
+                write!(f, "abort trigger {trigger} active")
+            }
+        }
+    }
+}
+
+/// Evaluates CBOR `Constraints`. Each check returns every violation it finds
+/// rather than stopping at the first. `max_area_km2`, `min_approach_distance_m`,
+/// `max_relative_velocity_m_s` and `fuel_budget_kg` describe execution, not
+/// admission, and are left to the task itself. `max_payment_msat` is checked by
+/// `BoundTaskRequest::verify`.
+pub struct ConstraintChecker;
+
+impl ConstraintChecker {
+    pub fn check_token(token: &SatCapToken, ctx: &TaskContext) -> Vec<ConstraintViolation> {
+        match &token.payload.cns {
+            Some(cns) => Self::check(cns, ctx),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn check(cns: &Constraints, ctx: &TaskContext) -> Vec<ConstraintViolation> {
+        let mut violations = Vec::new();
+        if let Some(max) = cns.max_hops {
+            if ctx.hop_count > max {
+                violations.push(ConstraintViolation::TooManyHops {
+                    max,
+                    hops: ctx.hop_count,
+                });
+            }
+        }
+        if let Some(window) = &cns.time_window {
+            violations.extend(Self::check_time_window(window, ctx.now));
+        }
+        if let Some(bounds) = &cns.geographic_bounds {
+            match ctx.target {
+                Some((lat, lon)) => violations.extend(Self::check_bounds(bounds, lat, lon)),
+                None => violations.push(ConstraintViolation::TargetMissing),
+            }
+        }
+        if let Some(max_km) = cns.max_range_km {
+            match (ctx.origin, ctx.target) {
+                (Some(origin), Some(target)) => {
+                    let distance_km = haversine_km(origin, target);
+                    if distance_km > max_km {
+                        violations.push(ConstraintViolation::OutOfRange {
+                            max_km,
+                            distance_km,
+                        });
+                    }
+                }
+                _ => violations.push(ConstraintViolation::RangeUnknown),
+            }
+        }
+        if let Some(triggers) = &cns.abort_triggers {
+            for trigger in triggers {
+                if ctx.active_conditions.contains(trigger) {
+                    violations.push(ConstraintViolation::AbortTriggered(trigger.clone()));
+                }
+            }
+        }
+        violations
+    }
+
+    /// Both ends of the window are inclusive.
+    pub fn check_time_window(window: &TimeWindow, now: u64) -> Option<ConstraintViolation> {
+        if now < window.start {
+            Some(ConstraintViolation::BeforeWindow {
+                start: window.start,
+                now,
+            })
+        } else if now > window.end {
+            Some(ConstraintViolation::AfterWindow {
+                end: window.end,
+                now,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Each bound that is set must hold; a polygon is checked on top of the
+    /// lat/lon ranges.
+    pub fn check_bounds(bounds: &GeoBounds, lat: f64, lon: f64) -> Vec<ConstraintViolation> {
+        let mut violations = Vec::new();
+        let above = |min: Option<f64>, value: f64| min.is_none_or(|min| value >= min);
+        let below = |max: Option<f64>, value: f64| max.is_none_or(|max| value <= max);
+        if !(above(bounds.lat_min, lat)
+            && below(bounds.lat_max, lat)
+            && above(bounds.lon_min, lon)
+            && below(bounds.lon_max, lon))
+        {
+            violations.push(ConstraintViolation::OutsideBounds { lat, lon });
+        }
+        if let Some(polygon) = &bounds.polygon {
+            if polygon.len() < 3 {
+                violations.push(ConstraintViolation::InvalidBounds(
+                    "polygon needs at least 3 points".to_string(),
+                ));
+            } else if !point_in_polygon(polygon, lat, lon) {
+                violations.push(ConstraintViolation::OutsidePolygon { lat, lon });
+            }
+        }
+        violations
+    }
+}
+
+impl Constraints {
+    /// A delegated token may only tighten its parent's constraints: upper
+    /// limits can shrink, `min_approach_distance_m` can grow, the time window
+    /// and bounds must sit inside the parent's, and abort triggers are kept.
+    /// A child polygon must keep its whole boundary inside the parent's, so
+    /// concave parents cannot be escaped through a notch.
+    pub fn check_narrows(&self, parent: &Constraints) -> Result<(), VerifyError> {
+        narrows("max_hops", self.max_hops, parent.max_hops, |c, p| c > p)?;
+        narrows("max_area_km2", self.max_area_km2, parent.max_area_km2, |c, p| c > p)?;
+        narrows("max_range_km", self.max_range_km, parent.max_range_km, |c, p| c > p)?;
+        narrows("fuel_budget_kg", self.fuel_budget_kg, parent.fuel_budget_kg, |c, p| c > p)?;
+        narrows(
+            "max_payment_msat",
+            self.max_payment_msat,
+            parent.max_payment_msat,
+            |c, p| c > p,
+        )?;
+        narrows(
+            "max_relative_velocity_m_s",
+            self.max_relative_velocity_m_s,
+            parent.max_relative_velocity_m_s,
+            |c, p| c > p,
+        )?;
+        narrows(
+            "min_approach_distance_m",
+            self.min_approach_distance_m,
+            parent.min_approach_distance_m,
+            |c, p| c < p,
+        )?;
+        narrows(
+            "time_window",
+            self.time_window.as_ref(),
+            parent.time_window.as_ref(),
+            |c, p| c.start < p.start || c.end > p.end,
+        )?;
+        if let Some(parent_bounds) = &parent.geographic_bounds {
+            let bounds = self
+                .geographic_bounds
+                .as_ref()
+                .ok_or_else(|| VerifyError::new("delegation drops geographic_bounds constraint"))?;
+            narrows("lat_min", bounds.lat_min, parent_bounds.lat_min, |c, p| c < p)?;
+            narrows("lat_max", bounds.lat_max, parent_bounds.lat_max, |c, p| c > p)?;
+            narrows("lon_min", bounds.lon_min, parent_bounds.lon_min, |c, p| c < p)?;
+            narrows("lon_max", bounds.lon_max, parent_bounds.lon_max, |c, p| c > p)?;
+            narrows(
+                "polygon",
+                bounds.polygon.as_deref(),
+                parent_bounds.polygon.as_deref(),
+                |c, p| !polygon_within(c, p),
+            )?;
+        }
+        narrows(
+            "abort_triggers",
+            self.abort_triggers.as_deref(),
+            parent.abort_triggers.as_deref(),
+            |c, p| !p.iter().all(|trigger| c.contains(trigger)),
+        )?;
+        Ok(())
+    }
+}
+
+fn narrows<T>(
+    name: &str,
+    child: Option<T>,
+    parent: Option<T>,
+    widens: impl Fn(&T, &T) -> bool,
+) -> Result<(), VerifyError> {
+    match (child, parent) {
+        (_, None) => Ok(()),
+        (None, Some(_)) => Err(VerifyError::new(format!(
+            "delegation drops {name} constraint"
+        ))),
+        (Some(child), Some(parent)) if widens(&child, &parent) => Err(VerifyError::new(format!(
+            "delegation widens {name} constraint"
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Ray casting over `[lat, lon]` vertices; the polygon closes implicitly and
+/// points on an edge count as inside.
+pub fn point_in_polygon(polygon: &[[f64; 2]], lat: f64, lon: f64) -> bool {
+    let mut inside = false;
+    let mut j = polygon.len() - 1;
+    for i in 0..polygon.len() {
+        let [lat_i, lon_i] = polygon[i];
+        let [lat_j, lon_j] = polygon[j];
+        if on_segment([lat_i, lon_i], [lat_j, lon_j], lat, lon) {
+            return true;
+        }
+        if (lon_i > lon) != (lon_j > lon) {
+            let lat_cross = lat_i + (lon - lon_i) / (lon_j - lon_i) * (lat_j - lat_i);
+            if lat < lat_cross {
+                inside = !inside;
+            }
+        }
+        j = i;
+    }
+    inside
+}
+
+/// True when every edge of `inner` stays inside `outer`. Each edge is split
+/// wherever it meets an edge of `outer` and every piece is tested at its
+/// midpoint, so an edge that leaves and re-enters a concave `outer` fails.
+pub fn polygon_within(inner: &[[f64; 2]], outer: &[[f64; 2]]) -> bool {
+    if inner.is_empty() {
+        return true;
+    }
+    if outer.is_empty() {
+        return false;
+    }
+    let mut j = inner.len() - 1;
+    for i in 0..inner.len() {
+        if !segment_within(inner[j], inner[i], outer) {
+            return false;
+        }
+        j = i;
+    }
+    true
+}
+
+fn segment_within(a: [f64; 2], b: [f64; 2], polygon: &[[f64; 2]]) -> bool {
+    let d = [b[0] - a[0], b[1] - a[1]];
+    let len2 = d[0] * d[0] + d[1] * d[1];
+    let mut cuts = vec![0.0, 1.0];
+    let mut j = polygon.len() - 1;
+    for i in 0..polygon.len() {
+        let (p, q) = (polygon[j], polygon[i]);
+        let e = [q[0] - p[0], q[1] - p[1]];
+        let denom = d[0] * e[1] - d[1] * e[0];
+        let ap = [p[0] - a[0], p[1] - a[1]];
+        if denom.abs() > 1e-12 {
+            let t = (ap[0] * e[1] - ap[1] * e[0]) / denom;
+            let u = (ap[0] * d[1] - ap[1] * d[0]) / denom;
+            if (0.0..=1.0).contains(&t) && (0.0..=1.0).contains(&u) {
+                cuts.push(t);
+            }
+        } else if len2 > 0.0 && (ap[0] * d[1] - ap[1] * d[0]).abs() <= 1e-12 {
+            // Collinear edges: the parent's endpoints split the child edge.
+            for v in [p, q] {
+                let t = ((v[0] - a[0]) * d[0] + (v[1] - a[1]) * d[1]) / len2;
+                if (0.0..=1.0).contains(&t) {
+                    cuts.push(t);
+                }
+            }
+        }
+        j = i;
+    }
+    cuts.sort_by(f64::total_cmp);
+    let at = |t: f64| [a[0] + t * d[0], a[1] + t * d[1]];
+    let [lat, lon] = a;
+    point_in_polygon(polygon, lat, lon)
+        && cuts.windows(2).all(|w| {
+            let [lat, lon] = at((w[0] + w[1]) / 2.0);
+            point_in_polygon(polygon, lat, lon)
+        })
+}
+
+fn on_segment(a: [f64; 2], b: [f64; 2], lat: f64, lon: f64) -> bool {
+    let cross = (b[0] - a[0]) * (lon - a[1]) - (b[1] - a[1]) * (lat - a[0]);
+    cross.abs() <= 1e-12
+        && lat >= a[0].min(b[0])
+        && lat <= a[0].max(b[0])
+        && lon >= a[1].min(b[1])
+        && lon <= a[1].max(b[1])
+}
+
+/// Great-circle distance on a spherical Earth.
+pub fn haversine_km(from: (f64, f64), to: (f64, f64)) -> f64 {
+    const EARTH_RADIUS_KM: f64 = 6371.0;
+    let (lat1, lon1) = (from.0.to_radians(), from.1.to_radians());
+    let (lat2, lon2) = (to.0.to_radians(), to.1.to_radians());
+    let a = ((lat2 - lat1) / 2.0).sin().powi(2)
+        + lat1.cos() * lat2.cos() * ((lon2 - lon1) / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_KM * a.sqrt().asin()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(target_json: &str, payment_max_sats: u64) -> SpecTaskRequest {
+        SpecTaskRequest {
+            task_id: "task-cns".to_string(),
+            timestamp: 10,
+            capability_token: vec![],
+            delegation_chain: vec![],
+            task_type: "cmd:imaging:msi".to_string(),
+            target_json: target_json.to_string(),
+            parameters_json: "{}".to_string(),
+            constraints_json: "{}".to_string(),
+            payment_max_sats,
+            timeout_blocks: 10,
+            commander_signature: [0u8; 64],
+        }
+    }
+
+    #[test]
+    fn request_constraints_checked() {
+        let constraints = SpecConstraints {
+            geo: Some("45,-10,50,5".to_string()),
+            rate: None,
+            amount: Some(1_000),
+            not_before: Some(100),
+        };
+        assert!(constraints.check_time(99).is_err());
+        assert!(constraints.check_time(100).is_ok());
+
+        let inside = r#"{"lat": 48.1, "lon": 2.3}"#;
+        assert!(constraints.check_request(&request(inside, 1_000)).is_ok());
+        assert!(constraints.check_request(&request(inside, 1_001)).is_err());
+        let nested = r#"{"location": {"lat": 46, "lon": -9.5}, "band": "msi"}"#;
+        assert!(constraints.check_request(&request(nested, 10)).is_ok());
+        let outside = r#"{"lat": 40.0, "lon": 2.3}"#;
+        assert!(constraints.check_request(&request(outside, 10)).is_err());
+        assert!(constraints.check_request(&request("{}", 10)).is_err());
+
+        let bad_geo = SpecConstraints {
+            geo: Some("50,5,45,-10".to_string()),
+            ..SpecConstraints::default()
+        };
+        assert!(bad_geo.check_request(&request(inside, 10)).is_err());
+    }
+
+    #[test]
+    fn delegation_must_narrow() {
+        let parent = SpecConstraints {
+            geo: Some("45,-10,50,5".to_string()),
+            rate: Some((10, 3600)),
+            amount: Some(1_000),
+            not_before: Some(100),
+        };
+        assert!(parent.check_narrows(&parent).is_ok());
+        let narrower = SpecConstraints {
+            geo: Some("46,-5,49,0".to_string()),
+            rate: Some((5, 7200)),
+            amount: Some(500),
+            not_before: Some(200),
+        };
+        assert!(narrower.check_narrows(&parent).is_ok());
+        assert!(parent.check_narrows(&narrower).is_err());
+        assert!(SpecConstraints::default().check_narrows(&parent).is_err());
+        assert!(narrower.check_narrows(&SpecConstraints::default()).is_ok());
+
+        let wider_rate = SpecConstraints {
+            rate: Some((10, 60)),
+            ..parent.clone()
+        };
+        assert!(wider_rate.check_narrows(&parent).is_err());
+    }
+
+    #[test]
+    fn rate_counters_limit_uses_per_window() {
+        let mut counters = RateCounters::new();
+        let constraints = SpecConstraints {
+            rate: Some((2, 60)),
+            ..SpecConstraints::default()
+        };
+        let root = [1u8; 16];
+        let leaf = [2u8; 16];
+        let unlimited = SpecConstraints::default();
+        let chain = || [(&root, &constraints), (&leaf, &unlimited)];
+
+        counters.record_chain(chain(), 1_000).expect("first use");
+        counters.record_chain(chain(), 1_030).expect("second use");
+        assert!(counters.record_chain(chain(), 1_059).is_err());
+        counters.record_chain(chain(), 1_060).expect("new window");
+        assert_eq!(
+            counters.get(&bytes_to_hex(&root)),
+            Some(&RateWindow {
+                start: 1_060,
+                window_sec: 60,
+                count: 1
+            })
+        );
+        assert!(counters.get(&bytes_to_hex(&leaf)).is_none());
+
+        let json = serde_json::to_string(&counters).expect("encode");
+        let restored: RateCounters = serde_json::from_str(&json).expect("decode");
+        assert_eq!(restored, counters);
+
+        counters.prune(1_119);
+        assert_ne!(counters, RateCounters::new());
+        counters.prune(1_120);
+        assert_eq!(counters, RateCounters::new());
+    }
+
+    fn cbor_constraints() -> Constraints {
+        Constraints {
+            max_area_km2: None,
+            max_range_km: Some(1_000.0),
+            max_hops: Some(2),
+            geographic_bounds: Some(GeoBounds {
+                lat_min: Some(40.0),
+                lat_max: Some(55.0),
+                lon_min: None,
+                lon_max: None,
+                // Triangle around northern France.
+                polygon: Some(vec![[42.0, -5.0], [52.0, 2.0], [44.0, 8.0]]),
+            }),
+            time_window: Some(TimeWindow {
+                start: 100,
+                end: 200,
+            }),
+            min_approach_distance_m: None,
+            max_relative_velocity_m_s: None,
+            fuel_budget_kg: None,
+            abort_triggers: Some(vec!["low_power".to_string()]),
+            max_payment_msat: None,
+        }
+    }
+
+    #[test]
+    fn constraint_checker_reports_each_violation() {
+        let cns = cbor_constraints();
+        let ok = TaskContext {
+            now: 150,
+            hop_count: 2,
+            target: Some((46.0, 2.0)),
+            origin: Some((48.8, 2.3)),
+            active_conditions: vec!["eclipse".to_string()],
+        };
+        assert_eq!(ConstraintChecker::check(&cns, &ok), Vec::new());
+
+        let bad = TaskContext {
+            now: 201,
+            hop_count: 3,
+            target: Some((54.0, 2.0)),
+            origin: Some((30.0, 2.0)),
+            active_conditions: vec!["low_power".to_string()],
+        };
+        let codes: Vec<&str> = ConstraintChecker::check(&cns, &bad)
+            .iter()
+            .map(ConstraintViolation::code)
+            .collect();
+        assert_eq!(
+            codes,
+            [
+                "too_many_hops",
+                "after_time_window",
+                "outside_polygon",
+                "out_of_range",
+                "abort_triggered"
+            ]
+        );
+
+        let blind = TaskContext {
+            now: 99,
+            ..TaskContext::default()
+        };
+        let codes: Vec<&str> = ConstraintChecker::check(&cns, &blind)
+            .iter()
+            .map(ConstraintViolation::code)
+            .collect();
+        assert_eq!(
+            codes,
+            ["before_time_window", "target_missing", "range_unknown"]
+        );
+    }
+
+    #[test]
+    fn polygon_and_bounds_checks() {
+        let square = [[0.0, 0.0], [0.0, 10.0], [10.0, 10.0], [10.0, 0.0]];
+        assert!(point_in_polygon(&square, 5.0, 5.0));
+        assert!(point_in_polygon(&square, 0.0, 5.0));
+        assert!(point_in_polygon(&square, 10.0, 10.0));
+        assert!(!point_in_polygon(&square, 10.5, 5.0));
+        assert!(!point_in_polygon(&square, -0.1, -0.1));
+
+        // Concave "U": the notch between the arms is outside.
+        let u_shape = [
+            [0.0, 0.0],
+            [0.0, 9.0],
+            [9.0, 9.0],
+            [9.0, 6.0],
+            [3.0, 6.0],
+            [3.0, 3.0],
+            [9.0, 3.0],
+            [9.0, 0.0],
+        ];
+        assert!(point_in_polygon(&u_shape, 6.0, 1.5));
+        assert!(point_in_polygon(&u_shape, 6.0, 7.5));
+        assert!(!point_in_polygon(&u_shape, 6.0, 4.5));
+
+        let bounds = GeoBounds {
+            lat_min: Some(0.0),
+            lat_max: None,
+            lon_min: None,
+            lon_max: Some(5.0),
+            polygon: Some(vec![[0.0, 0.0], [1.0, 1.0]]),
+        };
+        let codes: Vec<&str> = ConstraintChecker::check_bounds(&bounds, -1.0, 2.0)
+            .iter()
+            .map(ConstraintViolation::code)
+            .collect();
+        assert_eq!(codes, ["outside_bounds", "invalid_bounds"]);
+
+        let paris_london = haversine_km((48.8566, 2.3522), (51.5074, -0.1278));
+        assert!((paris_london - 343.5).abs() < 1.0, "{paris_london}");
+    }
+}
@@ -1,4 +1,7 @@
-use crate::{MessageCodec, ProtocolError, TokenCodec};
+use crate::{
+    hex_to_bytes, sha256, MessageCodec, ProtocolError, SpecConstraints, SpecDelegation, SpecToken,
+    TokenCodec,
+};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
@@ -14,6 +17,50 @@ pub struct DemoToken {
     pub signature: String,
 }
 
+impl DemoToken {
+    /// The spec TLV token whose signature is carried in `signature` when the
+    /// demo token was issued by `scrap-operator issue-token`.
+    pub fn to_spec_token(&self, issuer: &[u8]) -> Result<SpecToken, ProtocolError> {
+        let mut signature = [0u8; 64];
+        if let Ok(bytes) = hex_to_bytes(&self.signature) {
+            if bytes.len() == 64 {
+                signature.copy_from_slice(&bytes);
+            }
+        }
+        let timestamp = |value: u64| {
+            u32::try_from(value).map_err(|_| ProtocolError::new("demo timestamp exceeds u32"))
+        };
+        Ok(SpecToken {
+            version: self.version,
+            issuer: issuer.to_vec(),
+            subject: demo_id_bytes(&self.subject),
+            audience: demo_id_bytes(&self.audience),
+            issued_at: timestamp(self.issued_at)?,
+            expires_at: timestamp(self.expires_at)?,
+            token_id: demo_token_id(&self.token_id),
+            capabilities: vec![self.capability.clone()],
+            constraints: SpecConstraints::default(),
+            delegation: SpecDelegation::default(),
+            signature,
+        })
+    }
+}
+
+/// Hex identifiers (pubkeys, key-ids) are carried as raw bytes in the spec
+/// token; anything else, such as a node name, is carried as UTF-8.
+pub fn demo_id_bytes(value: &str) -> Vec<u8> {
+    hex_to_bytes(value).unwrap_or_else(|_| value.as_bytes().to_vec())
+}
+
+pub fn demo_token_id(token_id: &str) -> [u8; 16] {
+    let mut out = [0u8; 16];
+    match hex_to_bytes(token_id) {
+        Ok(bytes) if bytes.len() == 16 => out.copy_from_slice(&bytes),
+        _ => out.copy_from_slice(&sha256(token_id.as_bytes())[..16]),
+    }
+    out
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct DemoTaskRequest {
     pub version: u8,
@@ -0,0 +1,465 @@
+//! The sample messages behind the checked-in corpus in `fixtures/`. The
+//! `gen-fixtures` binary writes each one as a CBOR/JSON pair; the tests below
+//! decode every pair through both decoders and check they agree. Only built
+//! with the `fixtures` feature: the secret keys here are public knowledge.
+//!
+//! `fixtures/external/` holds messages authored outside this crate, which
+//! the tests check our decoders and verifiers against.
+
+use crate::spec::FIXED_AUX_RAND;
+use crate::{
+    keypair_from_secret, pubkey_from_secret, sha256, BoundTaskRequest, CapHeader, CapPayload,
+    Constraints, DataLocation, DisputeMessage, ExecutionProof, GeoBounds, Heartbeat,
+    IslScapMessage, LightningWrapper, MessageType, OutputMetadata, ProtocolError, SatCapToken,
+    ScapPayload, TaskAccepted, TaskCompleted, TaskFailed, TaskRejected, TaskResponse, TimeWindow,
+};
+use serde_json::Value;
+
+pub const OPERATOR_SECRET: &str =
+    "1111111111111111111111111111111111111111111111111111111111111111";
+pub const COMMANDER_SECRET: &str =
+    "2222222222222222222222222222222222222222222222222222222222222222";
+pub const DELEGATE_SECRET: &str =
+    "3333333333333333333333333333333333333333333333333333333333333333";
+pub const EXECUTOR_SECRET: &str =
+    "4444444444444444444444444444444444444444444444444444444444444444";
+/// HMAC key of the ISL fixture.
+pub const LINK_KEY: [u8; 32] = [0x55; 32];
+
+/// Fixture timestamps fall inside the tokens' validity from here on.
+pub const ISSUED_AT: u64 = 1_710_000_000;
+/// BIP340 aux randomness for every fixture signature, so regenerating the
+/// corpus reproduces it byte for byte.
+const AUX_RAND: [u8; 32] = [0x5a; 32];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Fixture {
+    SatCapToken(SatCapToken),
+    BoundTaskRequest(BoundTaskRequest),
+    ExecutionProof(ExecutionProof),
+    TaskResponse(TaskResponse),
+    IslScapMessage(IslScapMessage),
+    Heartbeat(Heartbeat),
+    Dispute(DisputeMessage),
+    Lightning(LightningWrapper),
+}
+
+macro_rules! for_each_fixture {
+    ($fixture:expr, $value:ident => $body:expr) => {
+        match $fixture {
+            Fixture::SatCapToken($value) => $body,
+            Fixture::BoundTaskRequest($value) => $body,
+            Fixture::ExecutionProof($value) => $body,
+            Fixture::TaskResponse($value) => $body,
+            Fixture::IslScapMessage($value) => $body,
+            Fixture::Heartbeat($value) => $body,
+            Fixture::Dispute($value) => $body,
+            Fixture::Lightning($value) => $body,
+        }
+    };
+}
+
+/// Decodes with `$decode` into the variant `$fixture` already holds.
+macro_rules! decode_like {
+    ($fixture:expr, $decode:ident($input:expr)) => {
+        match $fixture {
+            Fixture::SatCapToken(_) => SatCapToken::$decode($input).map(Fixture::SatCapToken),
+            Fixture::BoundTaskRequest(_) => {
+                BoundTaskRequest::$decode($input).map(Fixture::BoundTaskRequest)
+            }
+            Fixture::ExecutionProof(_) => {
+                ExecutionProof::$decode($input).map(Fixture::ExecutionProof)
+            }
+            Fixture::TaskResponse(_) => TaskResponse::$decode($input).map(Fixture::TaskResponse),
+            Fixture::IslScapMessage(_) => {
+                IslScapMessage::$decode($input).map(Fixture::IslScapMessage)
+            }
+            Fixture::Heartbeat(_) => Heartbeat::$decode($input).map(Fixture::Heartbeat),
+            Fixture::Dispute(_) => DisputeMessage::$decode($input).map(Fixture::Dispute),
+            Fixture::Lightning(_) => LightningWrapper::$decode($input).map(Fixture::Lightning),
+        }
+    };
+}
+
+/// JSON decoding under the same name as the CBOR one, for `decode_like!`.
+trait FromJson: Sized {
+    fn from_json(value: &Value) -> Result<Self, ProtocolError>;
+}
+
+impl<T: serde::de::DeserializeOwned> FromJson for T {
+    fn from_json(value: &Value) -> Result<Self, ProtocolError> {
+        serde_json::from_value(value.clone()).map_err(|err| ProtocolError::new(err.to_string()))
+    }
+}
+
+impl Fixture {
+    pub fn encode_cbor(&self) -> Result<Vec<u8>, ProtocolError> {
+        for_each_fixture!(self, value => value.encode_cbor())
+    }
+
+    pub fn to_json(&self) -> Result<Value, ProtocolError> {
+        for_each_fixture!(self, value => serde_json::to_value(value))
+            .map_err(|err| ProtocolError::new(err.to_string()))
+    }
+
+    /// Decodes `bytes` as the same message type as `self`.
+    pub fn decode_cbor_like(&self, bytes: &[u8]) -> Result<Fixture, ProtocolError> {
+        decode_like!(self, decode_cbor(bytes))
+    }
+
+    /// Decodes `value` as the same message type as `self`.
+    pub fn decode_json_like(&self, value: &Value) -> Result<Fixture, ProtocolError> {
+        decode_like!(self, from_json(value))
+    }
+}
+
+fn pubkey_hex(secret: &str) -> Result<String, ProtocolError> {
+    let pubkey = pubkey_from_secret(secret)?;
+    Ok(pubkey.iter().map(|b| format!("{b:02x}")).collect())
+}
+
+/// Every fixture by file stem. The same on every call.
+pub fn corpus() -> Result<Vec<(&'static str, Fixture)>, ProtocolError> {
+    FIXED_AUX_RAND.with(|aux| aux.set(Some(AUX_RAND)));
+    let fixtures = build_corpus();
+    FIXED_AUX_RAND.with(|aux| aux.set(None));
+    fixtures
+}
+
+fn build_corpus() -> Result<Vec<(&'static str, Fixture)>, ProtocolError> {
+    let operator = keypair_from_secret(OPERATOR_SECRET)?;
+    let commander = keypair_from_secret(COMMANDER_SECRET)?;
+    let executor = keypair_from_secret(EXECUTOR_SECRET)?;
+
+    let mut root = SatCapToken {
+        header: CapHeader {
+            alg: "BIP340".to_string(),
+            typ: "SAT-CAP".to_string(),
+            enc: Some("CBOR".to_string()),
+            chn: None,
+        },
+        payload: CapPayload {
+            iss: pubkey_hex(OPERATOR_SECRET)?,
+            sub: pubkey_hex(COMMANDER_SECRET)?,
+            aud: pubkey_hex(EXECUTOR_SECRET)?,
+            iat: ISSUED_AT,
+            exp: ISSUED_AT + 86_400,
+            jti: "cap-root-0001".to_string(),
+            cap: vec!["cmd:imaging:msi".to_string(), "cmd:imaging:sar".to_string()],
+            cns: Some(Constraints {
+                max_hops: Some(2),
+                time_window: Some(TimeWindow {
+                    start: ISSUED_AT,
+                    end: ISSUED_AT + 86_400,
+                }),
+                geographic_bounds: Some(GeoBounds {
+                    lat_min: Some(-10.0),
+                    lat_max: Some(10.0),
+                    lon_min: Some(30.0),
+                    lon_max: Some(50.0),
+                    polygon: None,
+                }),
+                max_payment_msat: Some(5_000_000),
+                ..Constraints::default()
+            }),
+            prf: None,
+            cmd_pub: None,
+        },
+        signature: Vec::new(),
+    };
+    root.sign(&operator)?;
+
+    let mut delegated = root.clone();
+    delegated.header.chn = Some(1);
+    delegated.payload.iss = pubkey_hex(COMMANDER_SECRET)?;
+    delegated.payload.sub = pubkey_hex(DELEGATE_SECRET)?;
+    delegated.payload.iat = ISSUED_AT + 60;
+    delegated.payload.exp = ISSUED_AT + 43_200;
+    delegated.payload.jti = "cap-delegated-0001".to_string();
+    delegated.payload.cap = vec!["cmd:imaging:msi".to_string()];
+    delegated.payload.prf = Some(root.payload.jti.clone());
+    if let Some(cns) = delegated.payload.cns.as_mut() {
+        cns.max_hops = Some(1);
+        cns.max_payment_msat = Some(2_000_000);
+        cns.geographic_bounds = Some(GeoBounds {
+            lat_min: Some(-5.0),
+            lat_max: Some(5.0),
+            lon_min: Some(35.0),
+            lon_max: Some(45.0),
+            polygon: None,
+        });
+    }
+    delegated.sign(&commander)?;
+
+    let capability_token = root.encode_cbor()?;
+    let mut request = BoundTaskRequest {
+        payment_hash: Vec::new(),
+        capability_token,
+        payment_amount_msat: 1_500_000,
+        htlc_timeout_blocks: 144,
+        binding_sig: Vec::new(),
+        proof_chain: Vec::new(),
+    };
+    request.payment_hash = crate::derive_payment_hash(request.correlation_id()).to_vec();
+    request.sign(&commander)?;
+
+    let task_jti = root.payload.jti.clone();
+    let mut proof = ExecutionProof {
+        task_jti: task_jti.clone(),
+        payment_hash: request.payment_hash.clone(),
+        output_hash: sha256(b"scrap fixture output").to_vec(),
+        execution_timestamp: ISSUED_AT + 3_600,
+        output_metadata: Some(OutputMetadata {
+            data_size_bytes: Some(52_428_800),
+            data_format: Some("GeoTIFF".to_string()),
+            coverage_km2: Some(125.5),
+            acquisition_start: Some(ISSUED_AT + 3_000),
+            acquisition_end: Some(ISSUED_AT + 3_300),
+            sensor_mode: Some("msi".to_string()),
+            content_type: None,
+            size_bytes: None,
+            storage_location: Some("onboard://ssd0/cap-root-0001.tif".to_string()),
+        }),
+        executor_sig: Vec::new(),
+    };
+    proof.sign(&executor)?;
+
+    let mut accepted = TaskAccepted {
+        task_jti: task_jti.clone(),
+        accepted_at: ISSUED_AT + 120,
+        estimated_completion: ISSUED_AT + 3_600,
+        executor_sig: Vec::new(),
+    };
+    accepted.sign(&executor)?;
+
+    let mut rejected = TaskRejected {
+        task_jti: task_jti.clone(),
+        rejected_at: ISSUED_AT + 120,
+        reason: "CONSTRAINT_VIOLATION".to_string(),
+        detail: Some("target outside geographic_bounds".to_string()),
+        executor_sig: Vec::new(),
+    };
+    rejected.sign(&executor)?;
+
+    let completed = TaskCompleted {
+        task_jti: task_jti.clone(),
+        proof: proof.clone(),
+        data_location: Some(DataLocation {
+            method: "ISL_RELAY".to_string(),
+            relay_satellite: Some("SAT-RELAY-2".to_string()),
+            ground_station: None,
+            estimated_delivery: Some(ISSUED_AT + 7_200),
+        }),
+    };
+
+    let mut partial_proof = proof.clone();
+    partial_proof.output_metadata = None;
+    partial_proof.sign(&executor)?;
+    let mut failed = TaskFailed {
+        task_jti: task_jti.clone(),
+        failed_at: ISSUED_AT + 3_400,
+        reason: "SENSOR_FAULT".to_string(),
+        detail: Some("detector temperature out of range".to_string()),
+        partial_proof: Some(partial_proof),
+        executor_sig: Vec::new(),
+    };
+    failed.sign(&executor)?;
+
+    let mut isl = IslScapMessage {
+        version: 1,
+        msg_type: MessageType::TaskRequest,
+        sender: "TASKLIB-GS-1".to_string(),
+        recipient: "SAT-EXEC-1".to_string(),
+        sequence: 42,
+        timestamp: ISSUED_AT + 90,
+        payload: ScapPayload::TaskRequest(request.clone()),
+        hmac: None,
+    };
+    isl.authenticate(&LINK_KEY)?;
+
+    let dispute = DisputeMessage {
+        task_jti,
+        payment_hash: request.payment_hash.clone(),
+        reason: "PROOF_INVALID".to_string(),
+        timestamp: ISSUED_AT + 7_200,
+        evidence: Some(proof.output_hash.clone()),
+    };
+
+    let heartbeat = Heartbeat {
+        sender: "SAT-EXEC-1".to_string(),
+        timestamp: ISSUED_AT + 30,
+        pending_htlcs: 1,
+        capabilities: Some(vec!["cmd:imaging:msi".to_string()]),
+    };
+
+    // BOLT #1 ping: type 18, num_pong_bytes 16, no padding.
+    let lightning = LightningWrapper {
+        bolt_payload: vec![0x00, 0x12, 0x00, 0x10, 0x00, 0x00],
+    };
+
+    Ok(vec![
+        ("capability_token", Fixture::SatCapToken(root)),
+        ("delegation_token", Fixture::SatCapToken(delegated)),
+        ("bound_task_request", Fixture::BoundTaskRequest(request)),
+        ("execution_proof", Fixture::ExecutionProof(proof)),
+        (
+            "task_accepted",
+            Fixture::TaskResponse(TaskResponse::Accepted(accepted)),
+        ),
+        (
+            "task_rejected",
+            Fixture::TaskResponse(TaskResponse::Rejected(rejected)),
+        ),
+        (
+            "task_completed",
+            Fixture::TaskResponse(TaskResponse::Completed(completed)),
+        ),
+        (
+            "task_failed",
+            Fixture::TaskResponse(TaskResponse::Failed(failed)),
+        ),
+        ("isl_tasklib_message", Fixture::IslScapMessage(isl)),
+        ("dispute_message", Fixture::Dispute(dispute)),
+        ("heartbeat", Fixture::Heartbeat(heartbeat)),
+        ("lightning_wrapper", Fixture::Lightning(lightning)),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SatCapVerifier;
+    use std::path::PathBuf;
+
+    fn fixtures_dir() -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("fixtures")
+    }
+
+    fn read_pair(name: &str) -> (Vec<u8>, Value) {
+        let dir = fixtures_dir();
+        let cbor = std::fs::read(dir.join(format!("{name}.cbor"))).expect(name);
+        let raw = std::fs::read_to_string(dir.join(format!("{name}.json"))).expect(name);
+        (cbor, serde_json::from_str(&raw).expect(name))
+    }
+
+    #[test]
+    fn fixtures_agree_across_decoders() {
+        for (name, sample) in corpus().unwrap() {
+            let (cbor, json) = read_pair(name);
+            let from_cbor = sample.decode_cbor_like(&cbor).expect(name);
+            let from_json = sample.decode_json_like(&json).expect(name);
+            assert_eq!(from_cbor, from_json, "{name}");
+            assert_eq!(from_cbor.encode_cbor().unwrap(), cbor, "{name} cbor");
+            assert_eq!(from_json.to_json().unwrap(), json, "{name} json");
+        }
+    }
+
+    #[test]
+    fn fixture_directory_matches_corpus() {
+        let mut expected: Vec<String> = corpus()
+            .unwrap()
+            .iter()
+            .flat_map(|(name, _)| [format!("{name}.cbor"), format!("{name}.json")])
+            .collect();
+        expected.sort();
+        let mut found: Vec<String> = std::fs::read_dir(fixtures_dir())
+            .unwrap()
+            .map(|entry| entry.unwrap())
+            .filter(|entry| entry.file_type().unwrap().is_file())
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .collect();
+        found.sort();
+        assert_eq!(found, expected);
+    }
+
+    #[test]
+    fn fixture_signatures_verify() {
+        let verifier = SatCapVerifier {
+            operator_pubkey: pubkey_from_secret(OPERATOR_SECRET).unwrap(),
+            executor_pubkey: pubkey_from_secret(EXECUTOR_SECRET).unwrap(),
+        };
+        let now = ISSUED_AT + 300;
+        let decode = |name: &str| {
+            let (cbor, _) = read_pair(name);
+            let sample = corpus()
+                .unwrap()
+                .into_iter()
+                .find(|(stem, _)| *stem == name)
+                .unwrap()
+                .1;
+            sample.decode_cbor_like(&cbor).unwrap()
+        };
+
+        let Fixture::SatCapToken(root) = decode("capability_token") else {
+            panic!("capability_token");
+        };
+        verifier.verify_token(&root, now).unwrap();
+        let Fixture::SatCapToken(delegated) = decode("delegation_token") else {
+            panic!("delegation_token");
+        };
+        verifier
+            .verify_with_proofs(&delegated, std::slice::from_ref(&root), now)
+            .unwrap();
+        let Fixture::BoundTaskRequest(request) = decode("bound_task_request") else {
+            panic!("bound_task_request");
+        };
+        verifier.verify_bound_request(&request, &[], now).unwrap();
+
+        for name in [
+            "task_accepted",
+            "task_rejected",
+            "task_completed",
+            "task_failed",
+        ] {
+            let Fixture::TaskResponse(response) = decode(name) else {
+                panic!("{name}");
+            };
+            verifier.verify_response(&response, &request).expect(name);
+        }
+        let Fixture::ExecutionProof(proof) = decode("execution_proof") else {
+            panic!("execution_proof");
+        };
+        proof.verify(&verifier.executor_pubkey).unwrap();
+
+        let Fixture::IslScapMessage(isl) = decode("isl_tasklib_message") else {
+            panic!("isl_tasklib_message");
+        };
+        isl.verify_hmac(&LINK_KEY).unwrap();
+        assert_eq!(isl.payload, ScapPayload::TaskRequest(request));
+    }
+
+    #[test]
+    fn regenerating_reproduces_the_checked_in_bytes() {
+        for (name, sample) in corpus().unwrap() {
+            let (cbor, json) = read_pair(name);
+            assert_eq!(sample.encode_cbor().unwrap(), cbor, "{name} cbor");
+            assert_eq!(sample.to_json().unwrap(), json, "{name} json");
+        }
+    }
+
+    #[test]
+    fn externally_authored_token_decodes_and_verifies() {
+        let dir = fixtures_dir().join("external");
+        let cbor = std::fs::read(dir.join("capability_token.cbor")).unwrap();
+        let raw = std::fs::read_to_string(dir.join("capability_token.json")).unwrap();
+        let from_cbor = SatCapToken::decode_cbor_canonical(&cbor).unwrap();
+        let from_json: SatCapToken = serde_json::from_str(&raw).unwrap();
+        assert_eq!(from_cbor, from_json);
+        assert_eq!(from_cbor.encode_cbor().unwrap(), cbor);
+        assert_eq!(from_cbor.payload.jti, "bip340-ref-0001");
+
+        let issuer = crate::hex_to_bytes(&from_cbor.payload.iss).unwrap();
+        let verifier = SatCapVerifier {
+            operator_pubkey: issuer,
+            executor_pubkey: crate::hex_to_bytes(&from_cbor.payload.aud).unwrap(),
+        };
+        verifier
+            .verify_token(&from_cbor, from_cbor.payload.iat)
+            .unwrap();
+        // Secret key 3, the first BIP340 test vector's.
+        assert_eq!(
+            from_cbor.payload.iss,
+            "02f9308a019258c31049344f85f89d5229b531c845836f99b08601f113bce036f9"
+        );
+    }
+}
@@ -1,10 +1,23 @@
+mod canonical;
+mod clock;
+mod constraints;
 mod demo;
+mod liveness;
+mod sat_cap;
 mod spec;
 mod spec_cbor;
 mod tlv;
 mod traits;
 
+#[cfg(any(test, feature = "fixtures"))]
+pub mod fixtures;
+
+pub use canonical::*;
+pub use clock::*;
+pub use constraints::*;
 pub use demo::*;
+pub use liveness::*;
+pub use sat_cap::*;
 pub use spec::*;
 pub use spec_cbor::*;
 pub use tlv::*;
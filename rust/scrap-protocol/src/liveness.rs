@@ -0,0 +1,97 @@
+use crate::Heartbeat;
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerStatus {
+    /// Local receive time of the last heartbeat.
+    pub last_heard: u64,
+    pub pending_htlcs: u64,
+    pub alive: bool,
+}
+
+/// Peer liveness fed by heartbeats. A peer is alive from its first heartbeat
+/// until `timeout_sec` pass without another.
+#[derive(Debug, Clone)]
+pub struct PeerLiveness {
+    timeout_sec: u64,
+    peers: BTreeMap<String, PeerStatus>,
+}
+
+impl PeerLiveness {
+    pub fn new(timeout_sec: u64) -> Self {
+        Self {
+            timeout_sec,
+            peers: BTreeMap::new(),
+        }
+    }
+
+    /// Records a heartbeat received at `now`. Returns true when the sender was
+    /// unknown or had been marked lost.
+    pub fn observe(&mut self, heartbeat: &Heartbeat, now: u64) -> bool {
+        let status = PeerStatus {
+            last_heard: now,
+            pending_htlcs: heartbeat.pending_htlcs,
+            alive: true,
+        };
+        let previous = self.peers.insert(heartbeat.sender.clone(), status);
+        !previous.is_some_and(|peer| peer.alive)
+    }
+
+    pub fn is_alive(&self, peer: &str, now: u64) -> bool {
+        self.peers.get(peer).is_some_and(|status| {
+            status.alive && now < status.last_heard.saturating_add(self.timeout_sec)
+        })
+    }
+
+    pub fn get(&self, peer: &str) -> Option<&PeerStatus> {
+        self.peers.get(peer)
+    }
+
+    /// Marks peers silent for `timeout_sec` or longer as lost and returns
+    /// the ones that changed.
+    pub fn expire(&mut self, now: u64) -> Vec<String> {
+        let mut lost = Vec::new();
+        for (peer, status) in self.peers.iter_mut() {
+            if status.alive && now >= status.last_heard.saturating_add(self.timeout_sec) {
+                status.alive = false;
+                lost.push(peer.clone());
+            }
+        }
+        lost
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn heartbeat(sender: &str, pending_htlcs: u64) -> Heartbeat {
+        Heartbeat {
+            sender: sender.to_string(),
+            timestamp: 0,
+            pending_htlcs,
+            capabilities: None,
+        }
+    }
+
+    #[test]
+    fn heartbeats_keep_peers_alive() {
+        let mut peers = PeerLiveness::new(30);
+        assert!(!peers.is_alive("SAT-B", 100));
+        assert!(peers.observe(&heartbeat("SAT-B", 1), 100));
+        assert!(!peers.observe(&heartbeat("SAT-B", 2), 110));
+        assert!(peers.is_alive("SAT-B", 139));
+        assert_eq!(peers.get("SAT-B").unwrap().pending_htlcs, 2);
+
+        assert!(peers.expire(139).is_empty());
+        assert!(peers.observe(&heartbeat("SAT-C", 0), 120));
+        assert_eq!(peers.expire(140), vec!["SAT-B".to_string()]);
+        assert!(!peers.is_alive("SAT-B", 140));
+        assert!(peers.is_alive("SAT-C", 140));
+        assert!(peers.expire(141).is_empty());
+
+        // A lost peer that heartbeats again comes back.
+        assert!(peers.observe(&heartbeat("SAT-B", 0), 150));
+        assert!(peers.is_alive("SAT-B", 150));
+    }
+}
@@ -0,0 +1,616 @@
+use crate::{
+    audience_matches, capabilities_subset, hex_to_bytes, normalize_pubkey, BoundTaskRequest,
+    ConstraintChecker, SatCapToken, TaskResponse, VerifyError,
+};
+
+/// Verifies CBOR `SatCapToken`s with the same guarantees `SpecVerifier` gives
+/// TLV tokens: operator-signed root, executor audience, validity window and
+/// delegations that only narrow. Delegation links are tied together by `prf`
+/// and signed by the parent subject's commander key.
+#[derive(Debug, Clone)]
+pub struct SatCapVerifier {
+    pub operator_pubkey: Vec<u8>,
+    pub executor_pubkey: Vec<u8>,
+}
+
+impl SatCapVerifier {
+    pub fn verify_token(&self, token: &SatCapToken, now: u64) -> Result<(), VerifyError> {
+        if token.payload.prf.is_some() || token.header.chn.unwrap_or(0) != 0 {
+            return Err(VerifyError::new("root token carries a delegation"));
+        }
+        token
+            .verify_signature(&self.operator_pubkey)
+            .map_err(|err| VerifyError::new(format!("token {}", err.reason)))?;
+        let issuer_norm = hex_to_bytes(&token.payload.iss)
+            .ok()
+            .and_then(|iss| normalize_pubkey(&iss).ok())
+            .ok_or_else(|| VerifyError::new("issuer not pubkey"))?;
+        let operator_norm = normalize_pubkey(&self.operator_pubkey)
+            .map_err(|_| VerifyError::new("operator pubkey invalid"))?;
+        if issuer_norm != operator_norm {
+            return Err(VerifyError::new("token issuer mismatch"));
+        }
+        let audience = hex_to_bytes(&token.payload.aud).unwrap_or_default();
+        if !audience_matches(&audience, &self.executor_pubkey)? {
+            return Err(VerifyError::new("token audience mismatch"));
+        }
+        check_validity(token, now)
+    }
+
+    /// `chain` holds the ancestors of `token`, root first, as returned by
+    /// `SatCapToken::resolve_proof_chain`.
+    pub fn verify_token_chain(
+        &self,
+        token: &SatCapToken,
+        chain: &[SatCapToken],
+        now: u64,
+    ) -> Result<(), VerifyError> {
+        let Some(root) = chain.first() else {
+            return self.verify_token(token, now);
+        };
+        self.verify_token(root, now)?;
+        let links = chain.iter().skip(1).chain(std::iter::once(token));
+        for (parent, child) in chain.iter().zip(links) {
+            verify_delegation(parent, child, now)?;
+        }
+        Ok(())
+    }
+
+    /// Resolves `token`'s `prf` chain against `known` and verifies it.
+    pub fn verify_with_proofs(
+        &self,
+        token: &SatCapToken,
+        known: &[SatCapToken],
+        now: u64,
+    ) -> Result<Vec<SatCapToken>, VerifyError> {
+        let chain = token.resolve_proof_chain(known)?;
+        self.verify_token_chain(token, &chain, now)?;
+        Ok(chain)
+    }
+
+    /// Verifies the token a bound request spends, resolving its `prf`
+    /// ancestors from `known`, then the request's binding signature.
+    pub fn verify_bound_request(
+        &self,
+        request: &BoundTaskRequest,
+        known: &[SatCapToken],
+        now: u64,
+    ) -> Result<SatCapToken, VerifyError> {
+        let token = SatCapToken::decode_cbor_canonical(&request.capability_token)
+            .map_err(|err| VerifyError::new(err.reason))?;
+        self.verify_with_proofs(&token, known, now)?;
+        request.verify(&token.commander_pubkey()?)?;
+        Ok(token)
+    }
+
+    /// Commander-side check of an executor's response to `request`: the
+    /// executor's signature, the task it names and, for proofs, the payment.
+    pub fn verify_response(
+        &self,
+        response: &TaskResponse,
+        request: &BoundTaskRequest,
+    ) -> Result<(), VerifyError> {
+        let token = SatCapToken::decode_cbor_canonical(&request.capability_token)
+            .map_err(|err| VerifyError::new(err.reason))?;
+        if response.task_jti() != token.payload.jti {
+            return Err(VerifyError::new("response task_jti mismatch"));
+        }
+        response.verify(&self.executor_pubkey)?;
+        let proof = match response {
+            TaskResponse::Completed(completed) => Some(&completed.proof),
+            TaskResponse::Failed(failed) => failed.partial_proof.as_ref(),
+            _ => None,
+        };
+        if let Some(proof) = proof {
+            if proof.payment_hash != request.payment_hash {
+                return Err(VerifyError::new("proof payment_hash mismatch"));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn verify_delegation(
+    parent: &SatCapToken,
+    child: &SatCapToken,
+    now: u64,
+) -> Result<(), VerifyError> {
+    if child.payload.prf.as_deref() != Some(parent.payload.jti.as_str()) {
+        return Err(VerifyError::new("delegation prf mismatch"));
+    }
+    if child.payload.iss != parent.payload.sub {
+        return Err(VerifyError::new("delegation issuer mismatch"));
+    }
+    let parent_key = parent.commander_pubkey()?;
+    child
+        .verify_signature(&parent_key)
+        .map_err(|err| VerifyError::new(format!("delegation {}", err.reason)))?;
+    if child.payload.aud != parent.payload.aud {
+        return Err(VerifyError::new("delegation audience mismatch"));
+    }
+    if child.payload.exp > parent.payload.exp {
+        return Err(VerifyError::new("delegation extends expiration"));
+    }
+    if !capabilities_subset(&child.payload.cap, &parent.payload.cap) {
+        return Err(VerifyError::new("delegation capability not subset"));
+    }
+    if child.header.chn != Some(parent.header.chn.unwrap_or(0) + 1) {
+        return Err(VerifyError::new("delegation depth mismatch"));
+    }
+    if let Some(parent_cns) = &parent.payload.cns {
+        child
+            .payload
+            .cns
+            .clone()
+            .unwrap_or_default()
+            .check_narrows(parent_cns)?;
+    }
+    check_validity(child, now)
+}
+
+fn check_validity(token: &SatCapToken, now: u64) -> Result<(), VerifyError> {
+    if token.payload.exp <= now {
+        return Err(VerifyError::new("token expired"));
+    }
+    if token.payload.iat > now {
+        return Err(VerifyError::new("token not yet valid"));
+    }
+    if let Some(window) = token
+        .payload
+        .cns
+        .as_ref()
+        .and_then(|cns| cns.time_window.as_ref())
+    {
+        if let Some(violation) = ConstraintChecker::check_time_window(window, now) {
+            return Err(VerifyError::new(violation.to_string()));
+        }
+    }
+    if token.payload.cmd_pub.is_some() {
+        token.commander_pubkey()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        bytes_to_hex, derive_payment_hash, keypair_from_secret, pubkey_from_secret, sha256,
+        CapHeader, CapPayload, Constraints, ExecutionProof, TaskAccepted, TaskCompleted,
+        TimeWindow,
+    };
+
+    const OPERATOR: &str = "1111111111111111111111111111111111111111111111111111111111111111";
+    const COMMANDER: &str = "2222222222222222222222222222222222222222222222222222222222222222";
+    const DELEGATE: &str = "3333333333333333333333333333333333333333333333333333333333333333";
+    const EXECUTOR: &str = "4444444444444444444444444444444444444444444444444444444444444444";
+
+    fn pubkey_hex(secret: &str) -> String {
+        bytes_to_hex(&pubkey_from_secret(secret).unwrap())
+    }
+
+    fn verifier() -> SatCapVerifier {
+        SatCapVerifier {
+            operator_pubkey: pubkey_from_secret(OPERATOR).unwrap(),
+            executor_pubkey: pubkey_from_secret(EXECUTOR).unwrap(),
+        }
+    }
+
+    fn token(issuer: &str, subject: &str, jti: &str, alg: &str) -> SatCapToken {
+        let mut token = SatCapToken {
+            header: CapHeader {
+                alg: alg.to_string(),
+                typ: "SAT-CAP".to_string(),
+                enc: Some("CBOR".to_string()),
+                chn: None,
+            },
+            payload: CapPayload {
+                iss: pubkey_hex(issuer),
+                sub: pubkey_hex(subject),
+                aud: pubkey_hex(EXECUTOR),
+                iat: 100,
+                exp: 1_000,
+                jti: jti.to_string(),
+                cap: vec!["cmd:imaging:*".to_string()],
+                cns: Some(Constraints {
+                    max_hops: Some(3),
+                    time_window: Some(TimeWindow {
+                        start: 100,
+                        end: 900,
+                    }),
+                    ..Constraints::default()
+                }),
+                prf: None,
+                cmd_pub: None,
+            },
+            signature: Vec::new(),
+        };
+        token.sign(&keypair_from_secret(issuer).unwrap()).unwrap();
+        token
+    }
+
+    fn delegate(parent: &SatCapToken, jti: &str, edit: impl Fn(&mut SatCapToken)) -> SatCapToken {
+        let mut child = token(COMMANDER, DELEGATE, jti, "BIP340");
+        child.header.chn = Some(parent.header.chn.unwrap_or(0) + 1);
+        child.payload.prf = Some(parent.payload.jti.clone());
+        child.payload.exp = 800;
+        child.payload.cap = vec!["cmd:imaging:msi".to_string()];
+        edit(&mut child);
+        child
+            .sign(&keypair_from_secret(COMMANDER).unwrap())
+            .unwrap();
+        child
+    }
+
+    fn reason(result: Result<(), VerifyError>) -> String {
+        result.expect_err("expected rejection").reason
+    }
+
+    #[test]
+    fn signature_dispatches_on_alg() {
+        let operator = pubkey_from_secret(OPERATOR).unwrap();
+        for alg in ["BIP340", "ES256K"] {
+            let token = token(OPERATOR, COMMANDER, "root", alg);
+            assert!(token.verify_signature(&operator).is_ok(), "{alg}");
+            let decoded = SatCapToken::decode_cbor(&token.encode_cbor().unwrap()).unwrap();
+            assert!(decoded.verify_signature(&operator).is_ok(), "{alg}");
+
+            let mut tampered = token.clone();
+            tampered.payload.exp += 1;
+            assert!(tampered.verify_signature(&operator).is_err(), "{alg}");
+        }
+
+        // The alg is signed, so relabelling a signature fails either way.
+        let mut relabelled = token(OPERATOR, COMMANDER, "root", "BIP340");
+        relabelled.header.alg = "ES256K".to_string();
+        assert!(relabelled.verify_signature(&operator).is_err());
+        relabelled.header.alg = "none".to_string();
+        assert_eq!(
+            reason(relabelled.verify_signature(&operator)),
+            "unsupported alg none"
+        );
+        assert!(relabelled
+            .sign(&keypair_from_secret(OPERATOR).unwrap())
+            .is_err());
+    }
+
+    #[test]
+    fn signing_input_orders_keys_canonically() {
+        let token = token(OPERATOR, COMMANDER, "root", "BIP340");
+        let input = token.signing_input().unwrap();
+        let mut dec = minicbor::Decoder::new(&input);
+        assert_eq!(dec.map().unwrap(), Some(2));
+        assert_eq!(dec.str().unwrap(), "header");
+        dec.skip().unwrap();
+        assert_eq!(dec.str().unwrap(), "payload");
+        let len = dec.map().unwrap().unwrap();
+        let mut keys = Vec::new();
+        for _ in 0..len {
+            keys.push(dec.str().unwrap().to_string());
+            dec.skip().unwrap();
+        }
+        let mut sorted = keys.clone();
+        sorted.sort_by(|a, b| a.len().cmp(&b.len()).then(a.cmp(b)));
+        assert_eq!(keys, sorted);
+    }
+
+    #[test]
+    fn root_token_checks() {
+        let verifier = verifier();
+        let root = token(OPERATOR, COMMANDER, "root", "BIP340");
+        assert!(verifier.verify_token(&root, 500).is_ok());
+        assert_eq!(reason(verifier.verify_token(&root, 1_000)), "token expired");
+        assert_eq!(
+            reason(verifier.verify_token(&root, 50)),
+            "token not yet valid"
+        );
+        assert!(reason(verifier.verify_token(&root, 950)).contains("window"));
+
+        let forged = token(COMMANDER, COMMANDER, "root", "BIP340");
+        assert_eq!(
+            reason(verifier.verify_token(&forged, 500)),
+            "token sat_cap signature invalid"
+        );
+
+        let mut other_audience = root.clone();
+        other_audience.payload.aud = pubkey_hex(DELEGATE);
+        other_audience
+            .sign(&keypair_from_secret(OPERATOR).unwrap())
+            .unwrap();
+        assert_eq!(
+            reason(verifier.verify_token(&other_audience, 500)),
+            "token audience mismatch"
+        );
+    }
+
+    #[test]
+    fn cmd_pub_binds_commander_key() {
+        let verifier = verifier();
+        let mut root = token(OPERATOR, COMMANDER, "root", "BIP340");
+        root.payload.sub = "commander-7".to_string();
+        root.payload.cmd_pub = Some(pubkey_from_secret(COMMANDER).unwrap());
+        root.sign(&keypair_from_secret(OPERATOR).unwrap()).unwrap();
+        assert!(verifier.verify_token(&root, 500).is_ok());
+        assert_eq!(
+            root.commander_pubkey().unwrap(),
+            pubkey_from_secret(COMMANDER).unwrap()
+        );
+
+        let mut mismatched = token(OPERATOR, COMMANDER, "root", "BIP340");
+        mismatched.payload.cmd_pub = Some(pubkey_from_secret(DELEGATE).unwrap());
+        mismatched
+            .sign(&keypair_from_secret(OPERATOR).unwrap())
+            .unwrap();
+        assert_eq!(
+            reason(verifier.verify_token(&mismatched, 500)),
+            "cmd_pub does not match sub"
+        );
+    }
+
+    #[test]
+    fn prf_chain_resolves_and_narrows() {
+        let verifier = verifier();
+        let root = token(OPERATOR, COMMANDER, "root", "BIP340");
+        let child = delegate(&root, "child", |_| {});
+        let known = vec![child.clone(), root.clone()];
+
+        let chain = verifier.verify_with_proofs(&child, &known, 500).unwrap();
+        assert_eq!(chain, vec![root.clone()]);
+        assert!(child.allows("cmd:imaging:msi"));
+
+        let orphan = delegate(&root, "orphan", |t| {
+            t.payload.prf = Some("missing".to_string())
+        });
+        assert_eq!(
+            reason(
+                verifier
+                    .verify_with_proofs(&orphan, &known, 500)
+                    .map(|_| ())
+            ),
+            "prf parent missing not found"
+        );
+
+        let wrong_signer = {
+            let mut t = delegate(&root, "c2", |_| {});
+            t.sign(&keypair_from_secret(DELEGATE).unwrap()).unwrap();
+            t
+        };
+        assert_eq!(
+            reason(verifier.verify_token_chain(&wrong_signer, std::slice::from_ref(&root), 500)),
+            "delegation sat_cap signature invalid"
+        );
+
+        type Edit = fn(&mut SatCapToken);
+        let cases: Vec<(&str, Edit)> = vec![
+            ("delegation extends expiration", |t| t.payload.exp = 2_000),
+            ("delegation capability not subset", |t| {
+                t.payload.cap = vec!["cmd:comms:*".to_string()]
+            }),
+            ("delegation depth mismatch", |t| t.header.chn = Some(5)),
+            ("delegation issuer mismatch", |t| {
+                t.payload.iss = pubkey_hex(DELEGATE)
+            }),
+            ("delegation widens max_hops constraint", |t| {
+                t.payload.cns.as_mut().unwrap().max_hops = Some(9)
+            }),
+            ("delegation drops max_hops constraint", |t| {
+                t.payload.cns = None
+            }),
+        ];
+        for (expected, edit) in cases {
+            let bad = delegate(&root, "bad", edit);
+            assert_eq!(
+                reason(verifier.verify_token_chain(&bad, std::slice::from_ref(&root), 500)),
+                expected
+            );
+        }
+    }
+
+    fn bound_request(token: &SatCapToken, amount_msat: u64) -> BoundTaskRequest {
+        let capability_token = token.encode_cbor().unwrap();
+        let mut request = BoundTaskRequest {
+            payment_hash: derive_payment_hash(sha256(&capability_token)).to_vec(),
+            capability_token,
+            payment_amount_msat: amount_msat,
+            htlc_timeout_blocks: 144,
+            binding_sig: Vec::new(),
+            proof_chain: Vec::new(),
+        };
+        request
+            .sign(&keypair_from_secret(COMMANDER).unwrap())
+            .unwrap();
+        request
+    }
+
+    #[test]
+    fn bound_request_binding_signature() {
+        let verifier = verifier();
+        let mut root = token(OPERATOR, COMMANDER, "root", "BIP340");
+        root.payload.cns.as_mut().unwrap().max_payment_msat = Some(1_000_000);
+        root.sign(&keypair_from_secret(OPERATOR).unwrap()).unwrap();
+        let commander = pubkey_from_secret(COMMANDER).unwrap();
+
+        let request = bound_request(&root, 900_000);
+        assert_eq!(request.correlation_id(), sha256(&request.capability_token));
+        assert!(request.verify(&commander).is_ok());
+        let decoded = BoundTaskRequest::decode_cbor(&request.encode_cbor().unwrap()).unwrap();
+        assert_eq!(
+            verifier.verify_bound_request(&decoded, &[], 500).unwrap(),
+            root
+        );
+
+        assert_eq!(
+            reason(request.verify(&pubkey_from_secret(DELEGATE).unwrap())),
+            "cmd_pub is not the token's commander key"
+        );
+        let mut tampered = request.clone();
+        tampered.htlc_timeout_blocks = 10;
+        assert_eq!(
+            reason(tampered.verify(&commander)),
+            "binding signature invalid"
+        );
+        assert_eq!(
+            reason(bound_request(&root, 1_000_001).verify(&commander)),
+            "payment amount exceeds token constraint"
+        );
+        let mut wrong_hash = request.clone();
+        wrong_hash.payment_hash = [7u8; 32].to_vec();
+        wrong_hash
+            .sign(&keypair_from_secret(COMMANDER).unwrap())
+            .unwrap();
+        assert_eq!(
+            reason(wrong_hash.verify(&commander)),
+            "payment_hash does not match correlation"
+        );
+
+        // The delegate signs requests spending the delegated token.
+        let child = delegate(&root, "child", |t| t.payload.cns = root.payload.cns.clone());
+        let mut delegated = bound_request(&child, 500_000);
+        assert!(verifier
+            .verify_bound_request(&delegated, std::slice::from_ref(&root), 500)
+            .is_err());
+        delegated
+            .sign(&keypair_from_secret(DELEGATE).unwrap())
+            .unwrap();
+        assert!(verifier
+            .verify_bound_request(&delegated, std::slice::from_ref(&root), 500)
+            .is_ok());
+
+        // The chain travels with the request without touching the binding.
+        delegated.proof_chain = vec![root.encode_cbor().unwrap()];
+        delegated
+            .verify(&pubkey_from_secret(DELEGATE).unwrap())
+            .unwrap();
+        let encoded = delegated.encode_cbor().unwrap();
+        assert_eq!(
+            BoundTaskRequest::decode_cbor_canonical(&encoded).unwrap(),
+            delegated
+        );
+    }
+
+    #[test]
+    fn commander_verifies_responses() {
+        let verifier = verifier();
+        let root = token(OPERATOR, COMMANDER, "root", "BIP340");
+        let request = bound_request(&root, 1_000);
+        let executor = keypair_from_secret(EXECUTOR).unwrap();
+
+        let mut accepted = TaskAccepted {
+            task_jti: "root".to_string(),
+            accepted_at: 200,
+            estimated_completion: 260,
+            executor_sig: Vec::new(),
+        };
+        accepted.sign(&executor).unwrap();
+        let response = TaskResponse::Accepted(accepted.clone());
+        assert!(verifier.verify_response(&response, &request).is_ok());
+
+        accepted.task_jti = "other".to_string();
+        accepted.sign(&executor).unwrap();
+        assert_eq!(
+            reason(verifier.verify_response(&TaskResponse::Accepted(accepted), &request)),
+            "response task_jti mismatch"
+        );
+
+        let mut proof = ExecutionProof {
+            task_jti: "root".to_string(),
+            payment_hash: request.payment_hash.clone(),
+            output_hash: vec![9u8; 32],
+            execution_timestamp: 250,
+            output_metadata: None,
+            executor_sig: Vec::new(),
+        };
+        proof.sign(&executor).unwrap();
+        let completed = TaskResponse::Completed(TaskCompleted {
+            task_jti: "root".to_string(),
+            proof: proof.clone(),
+            data_location: None,
+        });
+        assert!(verifier.verify_response(&completed, &request).is_ok());
+
+        proof.payment_hash = vec![0u8; 32];
+        proof.sign(&executor).unwrap();
+        let completed = TaskResponse::Completed(TaskCompleted {
+            task_jti: "root".to_string(),
+            proof,
+            data_location: None,
+        });
+        assert_eq!(
+            reason(verifier.verify_response(&completed, &request)),
+            "proof payment_hash mismatch"
+        );
+    }
+
+    #[test]
+    fn constraints_only_narrow() {
+        let parent = Constraints {
+            max_range_km: Some(500.0),
+            min_approach_distance_m: Some(100),
+            time_window: Some(TimeWindow { start: 10, end: 20 }),
+            geographic_bounds: Some(crate::GeoBounds {
+                lat_min: Some(40.0),
+                lat_max: Some(50.0),
+                polygon: Some(vec![[40.0, 0.0], [50.0, 0.0], [50.0, 10.0], [40.0, 10.0]]),
+                ..Default::default()
+            }),
+            abort_triggers: Some(vec!["solar_flare".to_string()]),
+            ..Constraints::default()
+        };
+        assert!(parent.check_narrows(&parent).is_ok());
+        assert!(parent.check_narrows(&Constraints::default()).is_ok());
+
+        let mut child = parent.clone();
+        child.min_approach_distance_m = Some(50);
+        assert!(child.check_narrows(&parent).is_err());
+
+        let mut child = parent.clone();
+        child.time_window = Some(TimeWindow { start: 12, end: 21 });
+        assert!(child.check_narrows(&parent).is_err());
+
+        let mut child = parent.clone();
+        child.geographic_bounds.as_mut().unwrap().polygon =
+            Some(vec![[41.0, 1.0], [49.0, 1.0], [49.0, 11.0]]);
+        assert!(child.check_narrows(&parent).is_err());
+
+        let mut child = parent.clone();
+        child.abort_triggers = Some(vec!["low_fuel".to_string()]);
+        assert!(child.check_narrows(&parent).is_err());
+    }
+
+    #[test]
+    fn concave_parent_polygon_cannot_be_escaped_through_its_notch() {
+        // An "L": the square [40,50]x[0,10] minus its [45,50]x[5,10] corner.
+        let l_shape = vec![
+            [40.0, 0.0],
+            [50.0, 0.0],
+            [50.0, 5.0],
+            [45.0, 5.0],
+            [45.0, 10.0],
+            [40.0, 10.0],
+        ];
+        let with_polygon = |polygon: Vec<[f64; 2]>| Constraints {
+            geographic_bounds: Some(crate::GeoBounds {
+                polygon: Some(polygon),
+                ..Default::default()
+            }),
+            ..Constraints::default()
+        };
+        let parent = with_polygon(l_shape.clone());
+        assert!(parent.check_narrows(&parent).is_ok());
+
+        // Every vertex sits inside the L, but the edge from the bottom arm's
+        // tip to the left arm's tip runs across the missing corner.
+        let spanning = with_polygon(vec![[49.0, 1.0], [49.0, 4.0], [44.0, 9.0], [41.0, 9.0]]);
+        assert!(spanning.check_narrows(&parent).is_err());
+
+        let inside = with_polygon(vec![
+            [41.0, 1.0],
+            [49.0, 1.0],
+            [49.0, 4.0],
+            [44.0, 4.0],
+            [44.0, 9.0],
+            [41.0, 9.0],
+        ]);
+        assert!(inside.check_narrows(&parent).is_ok());
+    }
+}
@@ -1,11 +1,14 @@
-use crate::tlv::{decode_records, encode_records, TlvRecord};
+use crate::tlv::{
+    check_unknown_type, decode_records, decode_records_repeating, encode_records, TlvRecord,
+};
 use crate::{
-    MessageCodec, Operator, ProtocolError, TokenCodec, TokenIssueRequest, Verifier, VerifyError,
+    ChainClock, MessageCodec, Operator, ProtocolError, TokenCodec, TokenIssueRequest, Verifier,
+    VerifyError,
 };
 use rand::rngs::OsRng;
 use rand::RngCore;
 use secp256k1::schnorr::Signature;
-use secp256k1::{Keypair as KeyPair, Message, PublicKey, Secp256k1, SecretKey, XOnlyPublicKey};
+use secp256k1::{Keypair, Message, PublicKey, Secp256k1, SecretKey, XOnlyPublicKey};
 use sha2::{Digest, Sha256};
 
 pub const MSG_TASK_REQUEST: u8 = 0x01;
@@ -102,6 +105,15 @@ pub struct SpecDelegation {
     pub chain_depth: Option<u8>,
 }
 
+#[derive(Debug, Clone)]
+pub struct DelegationRequest {
+    pub subject: Vec<u8>,
+    pub capability: Vec<String>,
+    pub issued_at: u32,
+    pub expires_at: u32,
+    pub token_id: Option<[u8; 16]>,
+}
+
 #[derive(Debug, Clone)]
 pub struct SpecToken {
     pub version: u8,
@@ -232,9 +244,7 @@ impl MessageCodec for SpecMessageCodec {
     fn decode_message(&self, bytes: &[u8]) -> Result<Self::Message, ProtocolError> {
         let (msg_type, body) = decode_envelope(bytes)?;
         match msg_type {
-            MSG_TASK_REQUEST => Ok(SpecMessage::TaskRequest(SpecTaskRequest::decode_tlv(
-                body,
-            )?)),
+            MSG_TASK_REQUEST => Ok(SpecMessage::TaskRequest(SpecTaskRequest::decode_tlv(body)?)),
             MSG_TASK_ACCEPT => Ok(SpecMessage::TaskAccept(SpecTaskAccept::decode_tlv(body)?)),
             MSG_PROOF_OF_EXECUTION => Ok(SpecMessage::ProofOfExecution(
                 SpecProofOfExecution::decode_tlv(body)?,
@@ -251,7 +261,7 @@ impl MessageCodec for SpecMessageCodec {
 
 #[derive(Debug, Clone)]
 pub struct SpecOperator {
-    pub operator_key: KeyPair,
+    pub operator_key: Keypair,
     pub operator_pubkey: Vec<u8>,
 }
 
@@ -323,29 +333,25 @@ impl Verifier for SpecVerifier {
     fn verify_request(&self, request: &Self::Request, now: u64) -> Result<(), VerifyError> {
         let token = SpecToken::decode_tlv(&request.capability_token)
             .map_err(|err| VerifyError::new(err.reason))?;
-        if !request.delegation_chain.is_empty() {
-            let mut chain = Vec::new();
-            for raw in &request.delegation_chain {
-                let parsed =
-                    SpecToken::decode_tlv(raw).map_err(|err| VerifyError::new(err.reason))?;
-                chain.push(parsed);
-            }
-            verify_delegation_chain(
-                &token,
-                &chain,
-                &self.operator_pubkey,
-                &self.executor_pubkey,
-                now,
-            )?;
-        } else {
-            verify_token_root(&token, &self.operator_pubkey, &self.executor_pubkey, now)?;
+        let mut chain = Vec::new();
+        for raw in &request.delegation_chain {
+            let parsed = SpecToken::decode_tlv(raw).map_err(|err| VerifyError::new(err.reason))?;
+            chain.push(parsed);
+        }
+        self.verify_token_chain(&token, &chain, now)?;
+        for link in chain.iter().chain(std::iter::once(&token)) {
+            link.constraints.check_request(request)?;
         }
-        let commander_pubkey = parse_xonly(&token.subject).map_err(|err| VerifyError::new(err))?;
+        let commander_pubkey = parse_xonly(&token.subject).map_err(VerifyError::new)?;
         let signing_hash = request.commander_signing_hash();
-        if !verify_schnorr(&signing_hash, &request.commander_signature, &commander_pubkey) {
+        if !verify_schnorr(
+            &signing_hash,
+            &request.commander_signature,
+            &commander_pubkey,
+        ) {
             return Err(VerifyError::new("commander signature invalid"));
         }
-        if !capabilities_subset(&[request.task_type.clone()], &token.capabilities) {
+        if !token.allows(&request.task_type) {
             return Err(VerifyError::new("capability not authorized"));
         }
         if request.task_type.is_empty() {
@@ -362,8 +368,7 @@ impl Verifier for SpecVerifier {
         if accept.in_reply_to != expected_request_hash {
             return Err(VerifyError::new("task_accept in_reply_to mismatch"));
         }
-        let executor_pubkey =
-            parse_xonly(&self.executor_pubkey).map_err(|err| VerifyError::new(err))?;
+        let executor_pubkey = parse_xonly(&self.executor_pubkey).map_err(VerifyError::new)?;
         let signing_hash = accept.executor_signing_hash();
         if !verify_schnorr(&signing_hash, &accept.executor_signature, &executor_pubkey) {
             return Err(VerifyError::new("executor signature invalid"));
@@ -372,8 +377,7 @@ impl Verifier for SpecVerifier {
     }
 
     fn verify_proof(&self, proof: &Self::Proof) -> Result<(), VerifyError> {
-        let executor_pubkey =
-            parse_xonly(&proof.executor_pubkey).map_err(|err| VerifyError::new(err))?;
+        let executor_pubkey = parse_xonly(&proof.executor_pubkey).map_err(VerifyError::new)?;
         let proof_hash = proof.proof_hash();
         if !verify_schnorr(&proof_hash, &proof.executor_signature, &executor_pubkey) {
             return Err(VerifyError::new("proof signature invalid"));
@@ -382,7 +386,34 @@ impl Verifier for SpecVerifier {
     }
 }
 
+impl SpecVerifier {
+    pub fn verify_token_chain(
+        &self,
+        token: &SpecToken,
+        chain: &[SpecToken],
+        now: u64,
+    ) -> Result<(), VerifyError> {
+        if chain.is_empty() {
+            verify_token_root(token, &self.operator_pubkey, &self.executor_pubkey, now)
+        } else {
+            verify_delegation_chain(
+                token,
+                chain,
+                &self.operator_pubkey,
+                &self.executor_pubkey,
+                now,
+            )
+        }
+    }
+}
+
 impl SpecToken {
+    pub fn allows(&self, capability: &str) -> bool {
+        self.capabilities
+            .iter()
+            .any(|granted| capability_allows(granted, capability))
+    }
+
     pub fn encode_tlv(&self) -> Result<Vec<u8>, ProtocolError> {
         let mut records = self.base_records()?;
         records.push(TlvRecord {
@@ -398,47 +429,50 @@ impl SpecToken {
     }
 
     fn base_records(&self) -> Result<Vec<TlvRecord>, ProtocolError> {
-        let mut records = Vec::new();
-        records.push(TlvRecord {
-            t: TLV_TOKEN_VERSION,
-            v: vec![self.version],
-        });
-        records.push(TlvRecord {
-            t: TLV_TOKEN_ISSUER,
-            v: self.issuer.clone(),
-        });
-        records.push(TlvRecord {
-            t: TLV_TOKEN_SUBJECT,
-            v: self.subject.clone(),
-        });
-        records.push(TlvRecord {
-            t: TLV_TOKEN_AUDIENCE,
-            v: self.audience.clone(),
-        });
-        records.push(TlvRecord {
-            t: TLV_TOKEN_ISSUED_AT,
-            v: self.issued_at.to_be_bytes().to_vec(),
-        });
-        records.push(TlvRecord {
-            t: TLV_TOKEN_EXPIRES_AT,
-            v: self.expires_at.to_be_bytes().to_vec(),
-        });
-        records.push(TlvRecord {
-            t: TLV_TOKEN_ID,
-            v: self.token_id.to_vec(),
-        });
-        for cap in &self.capabilities {
-            records.push(TlvRecord {
-                t: TLV_TOKEN_CAPABILITY,
-                v: cap.as_bytes().to_vec(),
-            });
-        }
+        let mut records = vec![
+            TlvRecord {
+                t: TLV_TOKEN_VERSION,
+                v: vec![self.version],
+            },
+            TlvRecord {
+                t: TLV_TOKEN_ISSUER,
+                v: self.issuer.clone(),
+            },
+            TlvRecord {
+                t: TLV_TOKEN_SUBJECT,
+                v: self.subject.clone(),
+            },
+            TlvRecord {
+                t: TLV_TOKEN_AUDIENCE,
+                v: self.audience.clone(),
+            },
+            TlvRecord {
+                t: TLV_TOKEN_ISSUED_AT,
+                v: self.issued_at.to_be_bytes().to_vec(),
+            },
+            TlvRecord {
+                t: TLV_TOKEN_EXPIRES_AT,
+                v: self.expires_at.to_be_bytes().to_vec(),
+            },
+            TlvRecord {
+                t: TLV_TOKEN_ID,
+                v: self.token_id.to_vec(),
+            },
+        ];
+        // Records stay in ascending type order: geo (13) sorts before the
+        // capabilities (14), the other constraints after them.
         if let Some(geo) = &self.constraints.geo {
             records.push(TlvRecord {
                 t: TLV_TOKEN_CONSTRAINT_GEO,
                 v: geo.as_bytes().to_vec(),
             });
         }
+        for cap in &self.capabilities {
+            records.push(TlvRecord {
+                t: TLV_TOKEN_CAPABILITY,
+                v: cap.as_bytes().to_vec(),
+            });
+        }
         if let Some((count, period)) = &self.constraints.rate {
             let mut bytes = Vec::with_capacity(8);
             bytes.extend_from_slice(&count.to_be_bytes());
@@ -488,7 +522,8 @@ impl SpecToken {
     }
 
     pub fn decode_tlv(bytes: &[u8]) -> Result<Self, ProtocolError> {
-        let records = decode_records(bytes)?;
+        let records = decode_records_repeating(bytes, &[TLV_TOKEN_CAPABILITY])
+            .map_err(|err| in_message("token", err))?;
         let mut version: Option<u8> = None;
         let mut issuer: Option<Vec<u8>> = None;
         let mut subject: Option<Vec<u8>> = None;
@@ -553,8 +588,14 @@ impl SpecToken {
                         return Err(ProtocolError::new("constraint_amount length invalid"));
                     }
                     let amount = u64::from_be_bytes([
-                        record.v[0], record.v[1], record.v[2], record.v[3], record.v[4],
-                        record.v[5], record.v[6], record.v[7],
+                        record.v[0],
+                        record.v[1],
+                        record.v[2],
+                        record.v[3],
+                        record.v[4],
+                        record.v[5],
+                        record.v[6],
+                        record.v[7],
                     ]);
                     constraints.amount = Some(amount);
                 }
@@ -573,11 +614,7 @@ impl SpecToken {
                 TLV_TOKEN_CHAIN_DEPTH => {
                     delegation.chain_depth = record.v.first().copied();
                 }
-                _ => {
-                    if record.t % 2 == 0 {
-                        return Err(ProtocolError::new("token: unknown even tlv type"));
-                    }
-                }
+                _ => check_unknown_type(record.t).map_err(|err| in_message("token", err))?,
             }
         }
 
@@ -612,19 +649,20 @@ impl SpecTaskRequest {
     }
 
     fn base_records(&self) -> Result<Vec<TlvRecord>, ProtocolError> {
-        let mut records = Vec::new();
-        records.push(TlvRecord {
-            t: TLV_REQ_TASK_ID,
-            v: self.task_id.as_bytes().to_vec(),
-        });
-        records.push(TlvRecord {
-            t: TLV_REQ_TIMESTAMP,
-            v: self.timestamp.to_be_bytes().to_vec(),
-        });
-        records.push(TlvRecord {
-            t: TLV_REQ_CAPABILITY_TOKEN,
-            v: self.capability_token.clone(),
-        });
+        let mut records = vec![
+            TlvRecord {
+                t: TLV_REQ_TASK_ID,
+                v: self.task_id.as_bytes().to_vec(),
+            },
+            TlvRecord {
+                t: TLV_REQ_TIMESTAMP,
+                v: self.timestamp.to_be_bytes().to_vec(),
+            },
+            TlvRecord {
+                t: TLV_REQ_CAPABILITY_TOKEN,
+                v: self.capability_token.clone(),
+            },
+        ];
         for token in &self.delegation_chain {
             records.push(TlvRecord {
                 t: TLV_REQ_DELEGATION_TOKEN,
@@ -659,7 +697,8 @@ impl SpecTaskRequest {
     }
 
     pub fn decode_tlv(bytes: &[u8]) -> Result<Self, ProtocolError> {
-        let records = decode_records(bytes)?;
+        let records = decode_records_repeating(bytes, &[TLV_REQ_DELEGATION_TOKEN])
+            .map_err(|err| in_message("request", err))?;
         let mut task_id: Option<String> = None;
         let mut timestamp: Option<u32> = None;
         let mut capability_token: Option<Vec<u8>> = None;
@@ -675,43 +714,44 @@ impl SpecTaskRequest {
         for record in records {
             match record.t {
                 TLV_REQ_TASK_ID => {
-                    task_id = Some(String::from_utf8(record.v).map_err(|_| {
-                        ProtocolError::new("task_id not utf-8")
-                    })?);
+                    task_id = Some(
+                        String::from_utf8(record.v)
+                            .map_err(|_| ProtocolError::new("task_id not utf-8"))?,
+                    );
                 }
                 TLV_REQ_TIMESTAMP => timestamp = Some(read_u32(&record.v)?),
                 TLV_REQ_CAPABILITY_TOKEN => capability_token = Some(record.v),
                 TLV_REQ_DELEGATION_TOKEN => delegation_chain.push(record.v),
                 TLV_REQ_TASK_TYPE => {
-                    task_type = Some(String::from_utf8(record.v).map_err(|_| {
-                        ProtocolError::new("task_type not utf-8")
-                    })?);
+                    task_type = Some(
+                        String::from_utf8(record.v)
+                            .map_err(|_| ProtocolError::new("task_type not utf-8"))?,
+                    );
                 }
                 TLV_REQ_TARGET => {
-                    target_json = Some(String::from_utf8(record.v).map_err(|_| {
-                        ProtocolError::new("target not utf-8")
-                    })?);
+                    target_json = Some(
+                        String::from_utf8(record.v)
+                            .map_err(|_| ProtocolError::new("target not utf-8"))?,
+                    );
                 }
                 TLV_REQ_PARAMETERS => {
-                    parameters_json = Some(String::from_utf8(record.v).map_err(|_| {
-                        ProtocolError::new("parameters not utf-8")
-                    })?);
+                    parameters_json = Some(
+                        String::from_utf8(record.v)
+                            .map_err(|_| ProtocolError::new("parameters not utf-8"))?,
+                    );
                 }
                 TLV_REQ_CONSTRAINTS => {
-                    constraints_json = Some(String::from_utf8(record.v).map_err(|_| {
-                        ProtocolError::new("constraints not utf-8")
-                    })?);
+                    constraints_json = Some(
+                        String::from_utf8(record.v)
+                            .map_err(|_| ProtocolError::new("constraints not utf-8"))?,
+                    );
                 }
                 TLV_REQ_MAX_AMOUNT_SATS => payment_max_sats = Some(read_u64(&record.v)?),
                 TLV_REQ_TIMEOUT_BLOCKS => timeout_blocks = Some(read_u32(&record.v)?),
                 TLV_REQ_COMMANDER_SIGNATURE => {
                     commander_signature = Some(read_fixed(&record.v)?);
                 }
-                _ => {
-                    if record.t % 2 == 0 {
-                        return Err(ProtocolError::new("request: unknown even tlv type"));
-                    }
-                }
+                _ => check_unknown_type(record.t).map_err(|err| in_message("request", err))?,
             }
         }
 
@@ -763,56 +803,57 @@ impl SpecTaskAccept {
     }
 
     fn base_records(&self) -> Result<Vec<TlvRecord>, ProtocolError> {
-        let mut records = Vec::new();
-        records.push(TlvRecord {
-            t: TLV_ACCEPT_TASK_ID,
-            v: self.task_id.as_bytes().to_vec(),
-        });
-        records.push(TlvRecord {
-            t: TLV_ACCEPT_TIMESTAMP,
-            v: self.timestamp.to_be_bytes().to_vec(),
-        });
-        records.push(TlvRecord {
-            t: TLV_ACCEPT_IN_REPLY_TO,
-            v: self.in_reply_to.to_vec(),
-        });
-        records.push(TlvRecord {
-            t: TLV_ACCEPT_PAYMENT_HASH,
-            v: self.payment_hash.to_vec(),
-        });
-        records.push(TlvRecord {
-            t: TLV_ACCEPT_AMOUNT_SATS,
-            v: self.amount_sats.to_be_bytes().to_vec(),
-        });
-        records.push(TlvRecord {
-            t: TLV_ACCEPT_EXPIRY_SEC,
-            v: self.expiry_sec.to_be_bytes().to_vec(),
-        });
-        records.push(TlvRecord {
-            t: TLV_ACCEPT_DESCRIPTION,
-            v: self.description.as_bytes().to_vec(),
-        });
-        records.push(TlvRecord {
-            t: TLV_ACCEPT_EST_DURATION_SEC,
-            v: self.estimated_duration_sec.to_be_bytes().to_vec(),
-        });
-        records.push(TlvRecord {
-            t: TLV_ACCEPT_EARLIEST_START,
-            v: self.earliest_start.to_be_bytes().to_vec(),
-        });
-        records.push(TlvRecord {
-            t: TLV_ACCEPT_DATA_VOLUME_MB,
-            v: self.data_volume_mb.to_be_bytes().to_vec(),
-        });
-        records.push(TlvRecord {
-            t: TLV_ACCEPT_QUALITY_ESTIMATE,
-            v: self.quality_estimate.to_be_bytes().to_vec(),
-        });
+        let records = vec![
+            TlvRecord {
+                t: TLV_ACCEPT_TASK_ID,
+                v: self.task_id.as_bytes().to_vec(),
+            },
+            TlvRecord {
+                t: TLV_ACCEPT_TIMESTAMP,
+                v: self.timestamp.to_be_bytes().to_vec(),
+            },
+            TlvRecord {
+                t: TLV_ACCEPT_IN_REPLY_TO,
+                v: self.in_reply_to.to_vec(),
+            },
+            TlvRecord {
+                t: TLV_ACCEPT_PAYMENT_HASH,
+                v: self.payment_hash.to_vec(),
+            },
+            TlvRecord {
+                t: TLV_ACCEPT_AMOUNT_SATS,
+                v: self.amount_sats.to_be_bytes().to_vec(),
+            },
+            TlvRecord {
+                t: TLV_ACCEPT_EXPIRY_SEC,
+                v: self.expiry_sec.to_be_bytes().to_vec(),
+            },
+            TlvRecord {
+                t: TLV_ACCEPT_DESCRIPTION,
+                v: self.description.as_bytes().to_vec(),
+            },
+            TlvRecord {
+                t: TLV_ACCEPT_EST_DURATION_SEC,
+                v: self.estimated_duration_sec.to_be_bytes().to_vec(),
+            },
+            TlvRecord {
+                t: TLV_ACCEPT_EARLIEST_START,
+                v: self.earliest_start.to_be_bytes().to_vec(),
+            },
+            TlvRecord {
+                t: TLV_ACCEPT_DATA_VOLUME_MB,
+                v: self.data_volume_mb.to_be_bytes().to_vec(),
+            },
+            TlvRecord {
+                t: TLV_ACCEPT_QUALITY_ESTIMATE,
+                v: self.quality_estimate.to_be_bytes().to_vec(),
+            },
+        ];
         Ok(records)
     }
 
     pub fn decode_tlv(bytes: &[u8]) -> Result<Self, ProtocolError> {
-        let records = decode_records(bytes)?;
+        let records = decode_records(bytes).map_err(|err| in_message("accept", err))?;
         let mut task_id: Option<String> = None;
         let mut timestamp: Option<u32> = None;
         let mut in_reply_to: Option<[u8; 32]> = None;
@@ -829,9 +870,10 @@ impl SpecTaskAccept {
         for record in records {
             match record.t {
                 TLV_ACCEPT_TASK_ID => {
-                    task_id = Some(String::from_utf8(record.v).map_err(|_| {
-                        ProtocolError::new("task_id not utf-8")
-                    })?);
+                    task_id = Some(
+                        String::from_utf8(record.v)
+                            .map_err(|_| ProtocolError::new("task_id not utf-8"))?,
+                    );
                 }
                 TLV_ACCEPT_TIMESTAMP => timestamp = Some(read_u32(&record.v)?),
                 TLV_ACCEPT_IN_REPLY_TO => in_reply_to = Some(read_fixed(&record.v)?),
@@ -839,24 +881,17 @@ impl SpecTaskAccept {
                 TLV_ACCEPT_AMOUNT_SATS => amount_sats = Some(read_u64(&record.v)?),
                 TLV_ACCEPT_EXPIRY_SEC => expiry_sec = Some(read_u32(&record.v)?),
                 TLV_ACCEPT_DESCRIPTION => {
-                    description = Some(String::from_utf8(record.v).map_err(|_| {
-                        ProtocolError::new("description not utf-8")
-                    })?);
-                }
-                TLV_ACCEPT_EST_DURATION_SEC => {
-                    estimated_duration_sec = Some(read_u32(&record.v)?)
+                    description = Some(
+                        String::from_utf8(record.v)
+                            .map_err(|_| ProtocolError::new("description not utf-8"))?,
+                    );
                 }
+                TLV_ACCEPT_EST_DURATION_SEC => estimated_duration_sec = Some(read_u32(&record.v)?),
                 TLV_ACCEPT_EARLIEST_START => earliest_start = Some(read_u32(&record.v)?),
                 TLV_ACCEPT_DATA_VOLUME_MB => data_volume_mb = Some(read_u32(&record.v)?),
                 TLV_ACCEPT_QUALITY_ESTIMATE => quality_estimate = Some(read_u32(&record.v)?),
-                TLV_ACCEPT_EXECUTOR_SIGNATURE => {
-                    executor_signature = Some(read_fixed(&record.v)?)
-                }
-                _ => {
-                    if record.t % 2 == 0 {
-                        return Err(ProtocolError::new("accept: unknown even tlv type"));
-                    }
-                }
+                TLV_ACCEPT_EXECUTOR_SIGNATURE => executor_signature = Some(read_fixed(&record.v)?),
+                _ => check_unknown_type(record.t).map_err(|err| in_message("accept", err))?,
             }
         }
 
@@ -869,7 +904,8 @@ impl SpecTaskAccept {
                 .ok_or_else(|| ProtocolError::new("accept missing payment_hash"))?,
             amount_sats: amount_sats
                 .ok_or_else(|| ProtocolError::new("accept missing amount_sats"))?,
-            expiry_sec: expiry_sec.ok_or_else(|| ProtocolError::new("accept missing expiry_sec"))?,
+            expiry_sec: expiry_sec
+                .ok_or_else(|| ProtocolError::new("accept missing expiry_sec"))?,
             description: description
                 .ok_or_else(|| ProtocolError::new("accept missing description"))?,
             estimated_duration_sec: estimated_duration_sec
@@ -895,40 +931,41 @@ impl SpecTaskAccept {
 
 impl SpecProofOfExecution {
     pub fn encode_tlv(&self) -> Result<Vec<u8>, ProtocolError> {
-        let mut records = Vec::new();
-        records.push(TlvRecord {
-            t: TLV_PROOF_TASK_ID,
-            v: self.task_id.as_bytes().to_vec(),
-        });
-        records.push(TlvRecord {
-            t: TLV_PROOF_TOKEN_ID,
-            v: self.task_token_id.to_vec(),
-        });
-        records.push(TlvRecord {
-            t: TLV_PROOF_PAYMENT_HASH,
-            v: self.payment_hash.to_vec(),
-        });
-        records.push(TlvRecord {
-            t: TLV_PROOF_OUTPUT_HASH,
-            v: self.output_hash.to_vec(),
-        });
-        records.push(TlvRecord {
-            t: TLV_PROOF_EXECUTION_TS,
-            v: self.execution_timestamp.to_be_bytes().to_vec(),
-        });
-        records.push(TlvRecord {
-            t: TLV_PROOF_EXECUTOR_PUBKEY,
-            v: self.executor_pubkey.clone(),
-        });
-        records.push(TlvRecord {
-            t: TLV_PROOF_SIGNATURE,
-            v: self.executor_signature.to_vec(),
-        });
+        let records = vec![
+            TlvRecord {
+                t: TLV_PROOF_TASK_ID,
+                v: self.task_id.as_bytes().to_vec(),
+            },
+            TlvRecord {
+                t: TLV_PROOF_TOKEN_ID,
+                v: self.task_token_id.to_vec(),
+            },
+            TlvRecord {
+                t: TLV_PROOF_PAYMENT_HASH,
+                v: self.payment_hash.to_vec(),
+            },
+            TlvRecord {
+                t: TLV_PROOF_OUTPUT_HASH,
+                v: self.output_hash.to_vec(),
+            },
+            TlvRecord {
+                t: TLV_PROOF_EXECUTION_TS,
+                v: self.execution_timestamp.to_be_bytes().to_vec(),
+            },
+            TlvRecord {
+                t: TLV_PROOF_EXECUTOR_PUBKEY,
+                v: self.executor_pubkey.clone(),
+            },
+            TlvRecord {
+                t: TLV_PROOF_SIGNATURE,
+                v: self.executor_signature.to_vec(),
+            },
+        ];
         encode_records(&records)
     }
 
     pub fn decode_tlv(bytes: &[u8]) -> Result<Self, ProtocolError> {
-        let records = decode_records(bytes)?;
+        let records = decode_records(bytes).map_err(|err| in_message("proof", err))?;
         let mut task_id: Option<String> = None;
         let mut task_token_id: Option<[u8; 16]> = None;
         let mut payment_hash: Option<[u8; 32]> = None;
@@ -940,9 +977,10 @@ impl SpecProofOfExecution {
         for record in records {
             match record.t {
                 TLV_PROOF_TASK_ID => {
-                    task_id = Some(String::from_utf8(record.v).map_err(|_| {
-                        ProtocolError::new("task_id not utf-8")
-                    })?);
+                    task_id = Some(
+                        String::from_utf8(record.v)
+                            .map_err(|_| ProtocolError::new("task_id not utf-8"))?,
+                    );
                 }
                 TLV_PROOF_TOKEN_ID => task_token_id = Some(read_fixed(&record.v)?),
                 TLV_PROOF_PAYMENT_HASH => payment_hash = Some(read_fixed(&record.v)?),
@@ -950,11 +988,7 @@ impl SpecProofOfExecution {
                 TLV_PROOF_EXECUTION_TS => execution_timestamp = Some(read_u32(&record.v)?),
                 TLV_PROOF_EXECUTOR_PUBKEY => executor_pubkey = Some(record.v),
                 TLV_PROOF_SIGNATURE => executor_signature = Some(read_fixed(&record.v)?),
-                _ => {
-                    if record.t % 2 == 0 {
-                        return Err(ProtocolError::new("proof: unknown even tlv type"));
-                    }
-                }
+                _ => check_unknown_type(record.t).map_err(|err| in_message("proof", err))?,
             }
         }
 
@@ -1017,7 +1051,7 @@ impl SpecPaymentLock {
     }
 
     pub fn decode_tlv(bytes: &[u8]) -> Result<Self, ProtocolError> {
-        let records = decode_records(bytes)?;
+        let records = decode_records(bytes).map_err(|err| in_message("lock", err))?;
         let mut task_id: Option<String> = None;
         let mut correlation_id: Option<[u8; 32]> = None;
         let mut payment_hash: Option<[u8; 32]> = None;
@@ -1028,20 +1062,17 @@ impl SpecPaymentLock {
         for record in records {
             match record.t {
                 TLV_LOCK_TASK_ID => {
-                    task_id = Some(String::from_utf8(record.v).map_err(|_| {
-                        ProtocolError::new("task_id not utf-8")
-                    })?);
+                    task_id = Some(
+                        String::from_utf8(record.v)
+                            .map_err(|_| ProtocolError::new("task_id not utf-8"))?,
+                    );
                 }
                 TLV_LOCK_CORRELATION_ID => correlation_id = Some(read_fixed(&record.v)?),
                 TLV_LOCK_PAYMENT_HASH => payment_hash = Some(read_fixed(&record.v)?),
                 TLV_LOCK_AMOUNT_SATS => amount_sats = Some(read_u64(&record.v)?),
                 TLV_LOCK_TIMEOUT_BLOCKS => timeout_blocks = Some(read_u32(&record.v)?),
                 TLV_LOCK_TIMESTAMP => timestamp = Some(read_u32(&record.v)?),
-                _ => {
-                    if record.t % 2 == 0 {
-                        return Err(ProtocolError::new("lock: unknown even tlv type"));
-                    }
-                }
+                _ => check_unknown_type(record.t).map_err(|err| in_message("lock", err))?,
             }
         }
 
@@ -1088,7 +1119,7 @@ impl SpecPaymentClaim {
     }
 
     pub fn decode_tlv(bytes: &[u8]) -> Result<Self, ProtocolError> {
-        let records = decode_records(bytes)?;
+        let records = decode_records(bytes).map_err(|err| in_message("claim", err))?;
         let mut task_id: Option<String> = None;
         let mut correlation_id: Option<[u8; 32]> = None;
         let mut payment_hash: Option<[u8; 32]> = None;
@@ -1098,19 +1129,16 @@ impl SpecPaymentClaim {
         for record in records {
             match record.t {
                 TLV_CLAIM_TASK_ID => {
-                    task_id = Some(String::from_utf8(record.v).map_err(|_| {
-                        ProtocolError::new("task_id not utf-8")
-                    })?);
+                    task_id = Some(
+                        String::from_utf8(record.v)
+                            .map_err(|_| ProtocolError::new("task_id not utf-8"))?,
+                    );
                 }
                 TLV_CLAIM_CORRELATION_ID => correlation_id = Some(read_fixed(&record.v)?),
                 TLV_CLAIM_PAYMENT_HASH => payment_hash = Some(read_fixed(&record.v)?),
                 TLV_CLAIM_PREIMAGE => preimage = Some(read_fixed(&record.v)?),
                 TLV_CLAIM_TIMESTAMP => timestamp = Some(read_u32(&record.v)?),
-                _ => {
-                    if record.t % 2 == 0 {
-                        return Err(ProtocolError::new("claim: unknown even tlv type"));
-                    }
-                }
+                _ => check_unknown_type(record.t).map_err(|err| in_message("claim", err))?,
             }
         }
 
@@ -1150,7 +1178,7 @@ impl SpecTaskReject {
     }
 
     pub fn decode_tlv(bytes: &[u8]) -> Result<Self, ProtocolError> {
-        let records = decode_records(bytes)?;
+        let records = decode_records(bytes).map_err(|err| in_message("reject", err))?;
         let mut task_id: Option<String> = None;
         let mut reason: Option<String> = None;
         let mut details: Option<String> = None;
@@ -1159,26 +1187,25 @@ impl SpecTaskReject {
         for record in records {
             match record.t {
                 TLV_REJECT_TASK_ID => {
-                    task_id = Some(String::from_utf8(record.v).map_err(|_| {
-                        ProtocolError::new("task_id not utf-8")
-                    })?);
+                    task_id = Some(
+                        String::from_utf8(record.v)
+                            .map_err(|_| ProtocolError::new("task_id not utf-8"))?,
+                    );
                 }
                 TLV_REJECT_REASON => {
-                    reason = Some(String::from_utf8(record.v).map_err(|_| {
-                        ProtocolError::new("reason not utf-8")
-                    })?);
+                    reason = Some(
+                        String::from_utf8(record.v)
+                            .map_err(|_| ProtocolError::new("reason not utf-8"))?,
+                    );
                 }
                 TLV_REJECT_DETAILS => {
-                    details = Some(String::from_utf8(record.v).map_err(|_| {
-                        ProtocolError::new("details not utf-8")
-                    })?);
+                    details = Some(
+                        String::from_utf8(record.v)
+                            .map_err(|_| ProtocolError::new("details not utf-8"))?,
+                    );
                 }
                 TLV_REJECT_TIMESTAMP => timestamp = Some(read_u32(&record.v)?),
-                _ => {
-                    if record.t % 2 == 0 {
-                        return Err(ProtocolError::new("reject: unknown even tlv type"));
-                    }
-                }
+                _ => check_unknown_type(record.t).map_err(|err| in_message("reject", err))?,
             }
         }
 
@@ -1232,6 +1259,26 @@ pub fn tagged_hash(tag: &str, msg: &[u8]) -> [u8; 32] {
     sha256(&buf)
 }
 
+/// HMAC-SHA256 (RFC 2104).
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    const BLOCK: usize = 64;
+    let mut block_key = [0u8; BLOCK];
+    if key.len() > BLOCK {
+        block_key[..32].copy_from_slice(&sha256(key));
+    } else {
+        block_key[..key.len()].copy_from_slice(key);
+    }
+    let mut inner = Sha256::new();
+    inner.update(block_key.map(|b| b ^ 0x36));
+    inner.update(data);
+    let mut outer = Sha256::new();
+    outer.update(block_key.map(|b| b ^ 0x5c));
+    outer.update(inner.finalize());
+    let mut out = [0u8; 32];
+    out.copy_from_slice(&outer.finalize()[..]);
+    out
+}
+
 pub fn derive_preimage(correlation_id: [u8; 32]) -> [u8; 32] {
     tagged_hash("SCRAP/preimage/v1", &correlation_id)
 }
@@ -1248,6 +1295,115 @@ pub enum SettlementPhase {
     ProofSent,
     Claimed,
     Rejected,
+    Expired,
+    /// The requester contested execution or settlement; nothing further is
+    /// done for the task until it is rejected or expires.
+    Disputed,
+}
+
+impl SettlementPhase {
+    pub const ALL: [SettlementPhase; 8] = [
+        SettlementPhase::Requested,
+        SettlementPhase::Locked,
+        SettlementPhase::Accepted,
+        SettlementPhase::ProofSent,
+        SettlementPhase::Claimed,
+        SettlementPhase::Rejected,
+        SettlementPhase::Expired,
+        SettlementPhase::Disputed,
+    ];
+
+    pub fn is_terminal(self) -> bool {
+        matches!(
+            self,
+            SettlementPhase::Claimed | SettlementPhase::Rejected | SettlementPhase::Expired
+        )
+    }
+
+    pub fn can_transition_to(self, to: SettlementPhase) -> bool {
+        use SettlementPhase::*;
+        match (self, to) {
+            (Requested, Locked) => true,
+            (Locked, Accepted) => true,
+            (Accepted, ProofSent) => true,
+            (ProofSent, Claimed) => true,
+            (Locked, Disputed) | (Accepted, Disputed) | (ProofSent, Disputed) => true,
+            (from, Rejected) | (from, Expired) => !from.is_terminal(),
+            _ => false,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SettlementPhase::Requested => "requested",
+            SettlementPhase::Locked => "locked",
+            SettlementPhase::Accepted => "accepted",
+            SettlementPhase::ProofSent => "proof_sent",
+            SettlementPhase::Claimed => "claimed",
+            SettlementPhase::Rejected => "rejected",
+            SettlementPhase::Expired => "expired",
+            SettlementPhase::Disputed => "disputed",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|phase| phase.as_str() == value)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SettlementError {
+    IllegalTransition {
+        from: SettlementPhase,
+        to: SettlementPhase,
+    },
+    Expired {
+        expires_at: u64,
+        now: u64,
+    },
+    NotExpired {
+        expires_at: u64,
+        now: u64,
+    },
+    InvalidLock(String),
+    InvalidClaim(String),
+}
+
+impl std::fmt::Display for SettlementError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SettlementError::IllegalTransition { from, to } => {
+                write!(
+                    f,
+                    "illegal settlement transition {} -> {}",
+                    from.as_str(),
+                    to.as_str()
+                )
+            }
+            SettlementError::Expired { expires_at, now } => {
+                write!(
+                    f,
+                    "settlement timeout elapsed (expires_at {expires_at}, now {now})"
+                )
+            }
+            SettlementError::NotExpired { expires_at, now } => {
+                write!(
+                    f,
+                    "settlement not expired (expires_at {expires_at}, now {now})"
+                )
+            }
+            SettlementError::InvalidLock(reason) => write!(f, "invalid lock: {reason}"),
+            SettlementError::InvalidClaim(reason) => write!(f, "invalid claim: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for SettlementError {}
+
+impl From<SettlementError> for VerifyError {
+    fn from(err: SettlementError) -> Self {
+        VerifyError::new(err.to_string())
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -1257,21 +1413,38 @@ pub struct SettlementState {
     pub request_timestamp: u32,
     pub max_amount_sats: u64,
     pub timeout_blocks: u32,
+    /// Unix time at which `timeout_blocks` blocks past the request have been
+    /// mined, per the clock the state was created with.
+    pub expires_at: u64,
     pub phase: SettlementPhase,
 }
 
 impl SettlementState {
-    pub fn new(request: &SpecTaskRequest) -> Self {
+    pub fn new(request: &SpecTaskRequest, clock: &dyn ChainClock) -> Self {
         Self {
             task_id: request.task_id.clone(),
             correlation_id: request.request_hash(),
             request_timestamp: request.timestamp,
             max_amount_sats: request.payment_max_sats,
             timeout_blocks: request.timeout_blocks,
+            expires_at: clock.deadline(request.timestamp as u64, request.timeout_blocks as u64),
             phase: SettlementPhase::Requested,
         }
     }
 
+    pub fn is_expired(&self, now: u64) -> bool {
+        now > self.expires_at
+    }
+
+    /// Seconds left before the settlement times out; caps accept expiry.
+    pub fn remaining_secs(&self, now: u64) -> u64 {
+        self.expires_at.saturating_sub(now)
+    }
+
+    pub fn payment_hash(&self) -> [u8; 32] {
+        derive_payment_hash(self.correlation_id)
+    }
+
     pub fn validate_lock(&self, lock: &SpecPaymentLock, now: u64) -> Result<(), VerifyError> {
         if lock.task_id != self.task_id {
             return Err(VerifyError::new("lock task_id mismatch"));
@@ -1285,39 +1458,135 @@ impl SettlementState {
         if lock.timeout_blocks != self.timeout_blocks {
             return Err(VerifyError::new("lock timeout mismatch"));
         }
-        let expires_at = self.request_timestamp as u64 + self.timeout_blocks as u64;
-        if now > expires_at {
+        if self.is_expired(now) {
             return Err(VerifyError::new("lock timeout elapsed"));
         }
-        let expected_hash = derive_payment_hash(self.correlation_id);
-        if lock.payment_hash != expected_hash {
+        if lock.payment_hash != self.payment_hash() {
             return Err(VerifyError::new("lock payment_hash mismatch"));
         }
         Ok(())
     }
 
-    pub fn mark_locked(&mut self) {
+    pub fn validate_claim(&self, claim: &SpecPaymentClaim) -> Result<(), SettlementError> {
+        if claim.task_id != self.task_id {
+            return Err(SettlementError::InvalidClaim(
+                "task_id mismatch".to_string(),
+            ));
+        }
+        if claim.correlation_id != self.correlation_id {
+            return Err(SettlementError::InvalidClaim(
+                "correlation_id mismatch".to_string(),
+            ));
+        }
+        if claim.payment_hash != self.payment_hash() {
+            return Err(SettlementError::InvalidClaim(
+                "payment_hash mismatch".to_string(),
+            ));
+        }
+        if sha256(&claim.preimage) != claim.payment_hash {
+            return Err(SettlementError::InvalidClaim(
+                "preimage does not hash to payment_hash".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn lock(&mut self, lock: &SpecPaymentLock, now: u64) -> Result<(), SettlementError> {
+        self.check_transition(SettlementPhase::Locked, now)?;
+        self.validate_lock(lock, now)
+            .map_err(|err| SettlementError::InvalidLock(err.reason))?;
         self.phase = SettlementPhase::Locked;
+        Ok(())
+    }
+
+    pub fn accept(&mut self, now: u64) -> Result<(), SettlementError> {
+        self.check_transition(SettlementPhase::Accepted, now)?;
+        self.phase = SettlementPhase::Accepted;
+        Ok(())
+    }
+
+    pub fn record_proof(&mut self, now: u64) -> Result<(), SettlementError> {
+        self.check_transition(SettlementPhase::ProofSent, now)?;
+        self.phase = SettlementPhase::ProofSent;
+        Ok(())
+    }
+
+    pub fn claim(&mut self, claim: &SpecPaymentClaim, now: u64) -> Result<(), SettlementError> {
+        self.check_transition(SettlementPhase::Claimed, now)?;
+        self.validate_claim(claim)?;
+        self.phase = SettlementPhase::Claimed;
+        Ok(())
+    }
+
+    pub fn dispute(&mut self) -> Result<(), SettlementError> {
+        self.check_edge(SettlementPhase::Disputed)?;
+        self.phase = SettlementPhase::Disputed;
+        Ok(())
+    }
+
+    pub fn reject(&mut self) -> Result<(), SettlementError> {
+        self.check_edge(SettlementPhase::Rejected)?;
+        self.phase = SettlementPhase::Rejected;
+        Ok(())
+    }
+
+    pub fn expire(&mut self, now: u64) -> Result<(), SettlementError> {
+        self.check_edge(SettlementPhase::Expired)?;
+        if !self.is_expired(now) {
+            return Err(SettlementError::NotExpired {
+                expires_at: self.expires_at,
+                now,
+            });
+        }
+        self.phase = SettlementPhase::Expired;
+        Ok(())
+    }
+
+    fn check_edge(&self, to: SettlementPhase) -> Result<(), SettlementError> {
+        if self.phase.can_transition_to(to) {
+            Ok(())
+        } else {
+            Err(SettlementError::IllegalTransition {
+                from: self.phase,
+                to,
+            })
+        }
+    }
+
+    fn check_transition(&self, to: SettlementPhase, now: u64) -> Result<(), SettlementError> {
+        self.check_edge(to)?;
+        if self.is_expired(now) {
+            return Err(SettlementError::Expired {
+                expires_at: self.expires_at,
+                now,
+            });
+        }
+        Ok(())
     }
 
     pub fn can_emit_accept(&self) -> bool {
-        self.phase == SettlementPhase::Locked
+        self.phase.can_transition_to(SettlementPhase::Accepted)
     }
 
     pub fn can_emit_proof(&self) -> bool {
-        matches!(self.phase, SettlementPhase::Accepted | SettlementPhase::Locked)
+        self.phase.can_transition_to(SettlementPhase::ProofSent)
     }
 }
 
+pub fn generate_secret_hex() -> String {
+    let secret = SecretKey::new(&mut OsRng);
+    bytes_to_hex(&secret.secret_bytes())
+}
+
 pub fn parse_secret_key(hex: &str) -> Result<SecretKey, ProtocolError> {
     let bytes = hex_to_bytes(hex)?;
     SecretKey::from_slice(&bytes).map_err(|_| ProtocolError::new("invalid secret key"))
 }
 
-pub fn keypair_from_secret(hex: &str) -> Result<KeyPair, ProtocolError> {
+pub fn keypair_from_secret(hex: &str) -> Result<Keypair, ProtocolError> {
     let secp = Secp256k1::new();
     let secret = parse_secret_key(hex)?;
-    Ok(KeyPair::from_secret_key(&secp, &secret))
+    Ok(Keypair::from_secret_key(&secp, &secret))
 }
 
 pub fn pubkey_from_secret(hex: &str) -> Result<Vec<u8>, ProtocolError> {
@@ -1330,7 +1599,7 @@ pub fn pubkey_from_secret(hex: &str) -> Result<Vec<u8>, ProtocolError> {
 pub fn xonly_from_secret(hex: &str) -> Result<XOnlyPublicKey, ProtocolError> {
     let secp = Secp256k1::new();
     let secret = parse_secret_key(hex)?;
-    let keypair = KeyPair::from_secret_key(&secp, &secret);
+    let keypair = Keypair::from_secret_key(&secp, &secret);
     Ok(XOnlyPublicKey::from_keypair(&keypair).0)
 }
 
@@ -1338,8 +1607,8 @@ pub fn parse_xonly(bytes: &[u8]) -> Result<XOnlyPublicKey, String> {
     match bytes.len() {
         32 => XOnlyPublicKey::from_slice(bytes).map_err(|_| "invalid x-only pubkey".to_string()),
         33 => {
-            let pubkey =
-                PublicKey::from_slice(bytes).map_err(|_| "invalid compressed pubkey".to_string())?;
+            let pubkey = PublicKey::from_slice(bytes)
+                .map_err(|_| "invalid compressed pubkey".to_string())?;
             Ok(pubkey.x_only_public_key().0)
         }
         _ => Err("invalid pubkey length".to_string()),
@@ -1351,21 +1620,35 @@ pub fn normalize_pubkey(bytes: &[u8]) -> Result<[u8; 32], ProtocolError> {
     Ok(xonly.serialize())
 }
 
-pub fn sign_tagged(tag: &str, data: &[u8], keypair: &KeyPair) -> Result<[u8; 64], ProtocolError> {
+pub fn sign_tagged(tag: &str, data: &[u8], keypair: &Keypair) -> Result<[u8; 64], ProtocolError> {
     let hash = tagged_hash(tag, data);
     sign_message_hash(hash, keypair)
 }
 
-pub fn sign_message_hash(hash: [u8; 32], keypair: &KeyPair) -> Result<[u8; 64], ProtocolError> {
+#[cfg(any(test, feature = "fixtures"))]
+thread_local! {
+    /// While set, BIP340 signatures on this thread use it as aux randomness
+    /// instead of fresh bytes; see `fixtures::corpus`.
+    pub(crate) static FIXED_AUX_RAND: std::cell::Cell<Option<[u8; 32]>> =
+        const { std::cell::Cell::new(None) };
+}
+
+pub fn sign_message_hash(hash: [u8; 32], keypair: &Keypair) -> Result<[u8; 64], ProtocolError> {
     let secp = Secp256k1::new();
-    let msg = Message::from_slice(&hash).map_err(|_| ProtocolError::new("invalid message hash"))?;
+    let msg = Message::from_digest_slice(&hash)
+        .map_err(|_| ProtocolError::new("invalid message hash"))?;
+    #[cfg(any(test, feature = "fixtures"))]
+    if let Some(aux) = FIXED_AUX_RAND.with(|aux| aux.get()) {
+        let sig = secp.sign_schnorr_with_aux_rand(&msg, keypair, &aux);
+        return Ok(*sig.as_ref());
+    }
     let sig = secp.sign_schnorr(&msg, keypair);
     Ok(*sig.as_ref())
 }
 
 pub fn verify_schnorr(hash: &[u8; 32], signature: &[u8; 64], pubkey: &XOnlyPublicKey) -> bool {
     let secp = Secp256k1::verification_only();
-    let msg = match Message::from_slice(hash) {
+    let msg = match Message::from_digest_slice(hash) {
         Ok(msg) => msg,
         Err(_) => return false,
     };
@@ -1378,13 +1661,15 @@ pub fn verify_schnorr(hash: &[u8; 32], signature: &[u8; 64], pubkey: &XOnlyPubli
 
 pub fn hex_to_bytes(hex: &str) -> Result<Vec<u8>, ProtocolError> {
     let hex = hex.trim_start_matches("0x");
-    if hex.len() % 2 != 0 {
+    if !hex.len().is_multiple_of(2) {
         return Err(ProtocolError::new("hex string has odd length"));
     }
     let mut out = Vec::with_capacity(hex.len() / 2);
     let chars: Vec<char> = hex.chars().collect();
     for i in (0..chars.len()).step_by(2) {
-        let hi = chars[i].to_digit(16).ok_or_else(|| ProtocolError::new("invalid hex"))?;
+        let hi = chars[i]
+            .to_digit(16)
+            .ok_or_else(|| ProtocolError::new("invalid hex"))?;
         let lo = chars[i + 1]
             .to_digit(16)
             .ok_or_else(|| ProtocolError::new("invalid hex"))?;
@@ -1410,6 +1695,11 @@ fn read_fixed<const N: usize>(bytes: &[u8]) -> Result<[u8; N], ProtocolError> {
     Ok(out)
 }
 
+/// Prefixes a record-level error with the message it came from.
+fn in_message(what: &str, err: ProtocolError) -> ProtocolError {
+    ProtocolError::new(format!("{what}: {}", err.reason))
+}
+
 fn read_u32(bytes: &[u8]) -> Result<u32, ProtocolError> {
     if bytes.len() != 4 {
         return Err(ProtocolError::new("invalid u32 field"));
@@ -1442,8 +1732,8 @@ fn verify_token_root(
     }
     let issuer_norm =
         normalize_pubkey(&token.issuer).map_err(|_| VerifyError::new("issuer not pubkey"))?;
-    let operator_norm =
-        normalize_pubkey(operator_pubkey).map_err(|_| VerifyError::new("operator pubkey invalid"))?;
+    let operator_norm = normalize_pubkey(operator_pubkey)
+        .map_err(|_| VerifyError::new("operator pubkey invalid"))?;
     if issuer_norm != operator_norm {
         return Err(VerifyError::new("token issuer mismatch"));
     }
@@ -1453,6 +1743,7 @@ fn verify_token_root(
     if token.expires_at as u64 <= now {
         return Err(VerifyError::new("token expired"));
     }
+    token.constraints.check_time(now)?;
     Ok(())
 }
 
@@ -1478,8 +1769,8 @@ fn verify_delegation_chain(
     }
     let root_issuer_norm =
         normalize_pubkey(&root.issuer).map_err(|_| VerifyError::new("root issuer not pubkey"))?;
-    let operator_norm =
-        normalize_pubkey(operator_pubkey).map_err(|_| VerifyError::new("operator pubkey invalid"))?;
+    let operator_norm = normalize_pubkey(operator_pubkey)
+        .map_err(|_| VerifyError::new("operator pubkey invalid"))?;
     if root_issuer_norm != operator_norm {
         return Err(VerifyError::new("root issuer mismatch"));
     }
@@ -1492,6 +1783,7 @@ fn verify_delegation_chain(
     if root.expires_at as u64 <= now {
         return Err(VerifyError::new("root token expired"));
     }
+    root.constraints.check_time(now)?;
 
     for i in 1..full_chain.len() {
         let parent = &full_chain[i - 1];
@@ -1526,17 +1818,91 @@ fn verify_delegation_chain(
         if child.expires_at as u64 <= now {
             return Err(VerifyError::new("delegation token expired"));
         }
+        child.constraints.check_narrows(&parent.constraints)?;
+        child.constraints.check_time(now)?;
     }
     Ok(())
 }
 
 impl SpecToken {
+    /// Issues a child token signed by the holder of `self`. Capabilities and
+    /// expiry may only narrow; constraints are inherited unchanged.
+    pub fn delegate(
+        &self,
+        holder_key: &Keypair,
+        req: &DelegationRequest,
+    ) -> Result<SpecToken, ProtocolError> {
+        let holder = XOnlyPublicKey::from_keypair(holder_key).0.serialize();
+        if normalize_pubkey(&self.subject)? != holder {
+            return Err(ProtocolError::new(
+                "holder key does not match parent subject",
+            ));
+        }
+        if req.capability.is_empty() {
+            return Err(ProtocolError::new("delegation capability missing"));
+        }
+        if !capabilities_subset(&req.capability, &self.capabilities) {
+            return Err(ProtocolError::new("delegation capability not subset"));
+        }
+        if req.expires_at > self.expires_at {
+            return Err(ProtocolError::new("delegation extends expiration"));
+        }
+        if req.expires_at <= req.issued_at {
+            return Err(ProtocolError::new("delegation expires before issuance"));
+        }
+        parse_xonly(&req.subject).map_err(ProtocolError::new)?;
+        let chain_depth = self
+            .delegation
+            .chain_depth
+            .unwrap_or(0)
+            .checked_add(1)
+            .ok_or_else(|| ProtocolError::new("delegation chain depth overflow"))?;
+        let token_id = match req.token_id {
+            Some(id) => id,
+            None => {
+                let mut bytes = [0u8; 16];
+                OsRng.fill_bytes(&mut bytes);
+                bytes
+            }
+        };
+        let delegation = SpecDelegation {
+            root_issuer: Some(
+                self.delegation
+                    .root_issuer
+                    .clone()
+                    .unwrap_or_else(|| self.issuer.clone()),
+            ),
+            root_token_id: Some(self.delegation.root_token_id.unwrap_or(self.token_id)),
+            parent_token_id: Some(self.token_id),
+            chain_depth: Some(chain_depth),
+        };
+        let mut child = SpecToken {
+            version: self.version,
+            issuer: self.subject.clone(),
+            subject: req.subject.clone(),
+            audience: self.audience.clone(),
+            issued_at: req.issued_at,
+            expires_at: req.expires_at,
+            token_id,
+            capabilities: req.capability.clone(),
+            constraints: self.constraints.clone(),
+            delegation,
+            signature: [0u8; 64],
+        };
+        child.signature = sign_tagged(
+            "SCRAP/delegation/v1",
+            &child.encode_tlv_without_signature()?,
+            holder_key,
+        )?;
+        Ok(child)
+    }
+
     fn chain_depth_mismatch(&self) -> bool {
         matches!(self.delegation.chain_depth, Some(depth) if depth != 0)
     }
 }
 
-fn audience_matches(audience: &[u8], executor_pubkey: &[u8]) -> Result<bool, VerifyError> {
+pub fn audience_matches(audience: &[u8], executor_pubkey: &[u8]) -> Result<bool, VerifyError> {
     let executor_norm = normalize_pubkey(executor_pubkey)
         .map_err(|_| VerifyError::new("executor pubkey invalid"))?;
     if let Ok(aud_norm) = normalize_pubkey(audience) {
@@ -1548,11 +1914,13 @@ fn audience_matches(audience: &[u8], executor_pubkey: &[u8]) -> Result<bool, Ver
     Ok(audience == &key_id[..])
 }
 
-fn capabilities_subset(child: &[String], parent: &[String]) -> bool {
-    child.iter().all(|cap| parent.iter().any(|p| capability_allows(p, cap)))
+pub(crate) fn capabilities_subset(child: &[String], parent: &[String]) -> bool {
+    child
+        .iter()
+        .all(|cap| parent.iter().any(|p| capability_allows(p, cap)))
 }
 
-fn capability_allows(parent: &str, child: &str) -> bool {
+pub(crate) fn capability_allows(parent: &str, child: &str) -> bool {
     if parent == child {
         return true;
     }
@@ -1565,11 +1933,39 @@ fn capability_allows(parent: &str, child: &str) -> bool {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{write_bigsize, IntervalClock};
+
+    // One-second blocks keep the fixture timestamps small.
+    const SECONDS: IntervalClock = IntervalClock::new(1);
 
-    fn keypair() -> KeyPair {
+    fn keypair() -> Keypair {
         let secp = Secp256k1::new();
         let secret = SecretKey::new(&mut OsRng);
-        KeyPair::from_secret_key(&secp, &secret)
+        Keypair::from_secret_key(&secp, &secret)
+    }
+
+    #[test]
+    fn hmac_sha256_matches_rfc4231() {
+        assert_eq!(
+            hmac_sha256(&[0x0b; 20], b"Hi There").to_vec(),
+            hex_to_bytes("b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7")
+                .unwrap()
+        );
+        assert_eq!(
+            hmac_sha256(b"Jefe", b"what do ya want for nothing?").to_vec(),
+            hex_to_bytes("5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843")
+                .unwrap()
+        );
+        // Keys longer than the block size are hashed first.
+        assert_eq!(
+            hmac_sha256(
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            )
+            .to_vec(),
+            hex_to_bytes("60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54")
+                .unwrap()
+        );
     }
 
     #[test]
@@ -1604,6 +2000,76 @@ mod tests {
         verifier.verify_token(&decoded, 10).expect("verify token");
     }
 
+    #[test]
+    fn tlv_record_rules_name_the_record() {
+        let operator = SpecOperator {
+            operator_key: keypair(),
+            operator_pubkey: vec![2; 33],
+        };
+        let issue = TokenIssueRequest {
+            subject: vec![3; 33],
+            audience: vec![4; 33],
+            capability: vec!["cmd:imaging:msi".to_string(), "cmd:imaging:sar".to_string()],
+            issued_at: 1,
+            expires_at: 100,
+            token_id: None,
+        };
+        let token = operator.issue_token(&issue).expect("issue token");
+        let encoded = token.encode_tlv().expect("encode token");
+        // Repeated capability records are fine.
+        assert_eq!(
+            SpecToken::decode_tlv(&encoded).unwrap().capabilities.len(),
+            2
+        );
+
+        let record = |t: u64, v: &[u8]| {
+            let mut out = Vec::new();
+            write_bigsize(t, &mut out);
+            write_bigsize(v.len() as u64, &mut out);
+            out.extend_from_slice(v);
+            out
+        };
+        let mut twice = encoded.clone();
+        twice.extend(record(TLV_TOKEN_SIGNATURE, &token.signature));
+        assert_eq!(
+            SpecToken::decode_tlv(&twice).unwrap_err().reason,
+            "token: tlv: type 240 repeated"
+        );
+        let mut odd = encoded.clone();
+        odd.extend(record(241, b"extension"));
+        let decoded = SpecToken::decode_tlv(&odd).unwrap();
+        assert_eq!(decoded.encode_tlv().unwrap(), token.encode_tlv().unwrap());
+        let mut even = encoded;
+        even.extend(record(242, b"critical"));
+        assert_eq!(
+            SpecToken::decode_tlv(&even).unwrap_err().reason,
+            "token: unknown even tlv type 242"
+        );
+
+        let request = SpecTaskRequest {
+            task_id: "task-1".to_string(),
+            timestamp: 10,
+            capability_token: vec![1],
+            delegation_chain: vec![vec![2], vec![3]],
+            task_type: "cmd:imaging:msi".to_string(),
+            target_json: "{}".to_string(),
+            parameters_json: "{}".to_string(),
+            constraints_json: "{}".to_string(),
+            payment_max_sats: 1000,
+            timeout_blocks: 144,
+            commander_signature: [0u8; 64],
+        };
+        let encoded = request.encode_tlv().expect("encode request");
+        let decoded = SpecTaskRequest::decode_tlv(&encoded).unwrap();
+        assert_eq!(decoded.delegation_chain.len(), 2);
+        let mut reordered = record(TLV_REQ_TIMESTAMP, &10u32.to_be_bytes());
+        reordered.extend(record(TLV_REQ_TASK_ID, b"task-1"));
+        assert_eq!(
+            SpecTaskRequest::decode_tlv(&reordered).unwrap_err().reason,
+            "request: tlv: type 0 after 2, types must be ascending"
+        );
+    }
+
     #[test]
     fn token_audience_mismatch_rejected() {
         let operator = keypair();
@@ -1710,7 +2176,7 @@ mod tests {
             timeout_blocks: 5,
             commander_signature: [0u8; 64],
         };
-        let settlement = SettlementState::new(&request);
+        let settlement = SettlementState::new(&request, &SECONDS);
         assert!(!settlement.can_emit_proof());
     }
 
@@ -1729,7 +2195,7 @@ mod tests {
             timeout_blocks: 5,
             commander_signature: [0u8; 64],
         };
-        let settlement = SettlementState::new(&request);
+        let settlement = SettlementState::new(&request, &SECONDS);
         let correlation_id = request.request_hash();
         let lock = SpecPaymentLock {
             task_id: request.task_id.clone(),
@@ -1758,7 +2224,7 @@ mod tests {
             timeout_blocks: 10,
             commander_signature: [0u8; 64],
         };
-        let mut settlement = SettlementState::new(&request);
+        let mut settlement = SettlementState::new(&request, &SECONDS);
         let correlation_id = request.request_hash();
         let payment_hash = derive_payment_hash(correlation_id);
         let lock = SpecPaymentLock {
@@ -1769,9 +2235,10 @@ mod tests {
             timeout_blocks: 10,
             timestamp: 12,
         };
-        settlement.validate_lock(&lock, 15).expect("lock valid");
-        settlement.mark_locked();
+        settlement.lock(&lock, 15).expect("lock valid");
         assert!(settlement.can_emit_accept());
+        assert!(!settlement.can_emit_proof());
+        settlement.accept(16).expect("accept");
         assert!(settlement.can_emit_proof());
     }
 
@@ -1893,7 +2360,9 @@ mod tests {
             operator_pubkey: operator_pub.clone(),
             executor_pubkey: executor_pub.clone(),
         };
-        verifier.verify_request(&request, 50).expect("verify request");
+        verifier
+            .verify_request(&request, 50)
+            .expect("verify request");
 
         let correlation_id = request.request_hash();
         let payment_hash = derive_payment_hash(correlation_id);
@@ -1925,11 +2394,8 @@ mod tests {
             timeout_blocks: request.timeout_blocks,
             timestamp: 12,
         };
-        let mut settlement = SettlementState::new(&request);
-        settlement
-            .validate_lock(&lock, 12)
-            .expect("validate lock");
-        settlement.mark_locked();
+        let mut settlement = SettlementState::new(&request, &SECONDS);
+        settlement.lock(&lock, 12).expect("lock");
 
         let mut proof = SpecProofOfExecution {
             task_id: request.task_id.clone(),
@@ -1957,4 +2423,461 @@ mod tests {
             _ => panic!("unexpected message"),
         }
     }
+
+    #[test]
+    fn delegated_token_verifies_in_request() {
+        let operator = keypair();
+        let commander = keypair();
+        let delegate = keypair();
+        let executor = keypair();
+
+        let operator_pub = PublicKey::from_keypair(&operator).serialize().to_vec();
+        let executor_pub = PublicKey::from_keypair(&executor).serialize().to_vec();
+        let commander_pub = PublicKey::from_keypair(&commander).serialize().to_vec();
+        let delegate_pub = PublicKey::from_keypair(&delegate).serialize().to_vec();
+
+        let operator_impl = SpecOperator {
+            operator_key: operator,
+            operator_pubkey: operator_pub.clone(),
+        };
+        let root = operator_impl
+            .issue_token(&TokenIssueRequest {
+                subject: commander_pub,
+                audience: executor_pub.clone(),
+                capability: vec!["cmd:imaging:*".to_string()],
+                issued_at: 1,
+                expires_at: 100,
+                token_id: None,
+            })
+            .expect("issue token");
+        let child = root
+            .delegate(
+                &commander,
+                &DelegationRequest {
+                    subject: delegate_pub,
+                    capability: vec!["cmd:imaging:msi".to_string()],
+                    issued_at: 5,
+                    expires_at: 80,
+                    token_id: None,
+                },
+            )
+            .expect("delegate");
+        assert_eq!(child.delegation.chain_depth, Some(1));
+        assert_eq!(child.delegation.root_token_id, Some(root.token_id));
+        assert_eq!(child.delegation.parent_token_id, Some(root.token_id));
+
+        let mut request = SpecTaskRequest {
+            task_id: "task-delegated".to_string(),
+            timestamp: 10,
+            capability_token: child.encode_tlv().expect("encode child"),
+            delegation_chain: vec![root.encode_tlv().expect("encode root")],
+            task_type: "cmd:imaging:msi".to_string(),
+            target_json: "{}".to_string(),
+            parameters_json: "{}".to_string(),
+            constraints_json: "{}".to_string(),
+            payment_max_sats: 1000,
+            timeout_blocks: 144,
+            commander_signature: [0u8; 64],
+        };
+        request.commander_signature =
+            sign_message_hash(request.commander_signing_hash(), &delegate).expect("sign request");
+        let verifier = SpecVerifier {
+            operator_pubkey: operator_pub,
+            executor_pubkey: executor_pub,
+        };
+        verifier
+            .verify_request(&request, 50)
+            .expect("verify delegated request");
+    }
+
+    #[test]
+    fn delegation_widening_refused() {
+        let operator = keypair();
+        let commander = keypair();
+        let other = keypair();
+        let operator_pub = PublicKey::from_keypair(&operator).serialize().to_vec();
+        let commander_pub = PublicKey::from_keypair(&commander).serialize().to_vec();
+        let other_pub = PublicKey::from_keypair(&other).serialize().to_vec();
+
+        let operator_impl = SpecOperator {
+            operator_key: operator,
+            operator_pubkey: operator_pub,
+        };
+        let root = operator_impl
+            .issue_token(&TokenIssueRequest {
+                subject: commander_pub,
+                audience: other_pub.clone(),
+                capability: vec!["cmd:imaging:msi".to_string()],
+                issued_at: 1,
+                expires_at: 100,
+                token_id: None,
+            })
+            .expect("issue token");
+        let narrow = DelegationRequest {
+            subject: other_pub,
+            capability: vec!["cmd:imaging:msi".to_string()],
+            issued_at: 5,
+            expires_at: 80,
+            token_id: None,
+        };
+
+        let mut wider_caps = narrow.clone();
+        wider_caps.capability = vec!["cmd:imaging:*".to_string()];
+        assert!(root.delegate(&commander, &wider_caps).is_err());
+
+        let mut longer = narrow.clone();
+        longer.expires_at = 200;
+        assert!(root.delegate(&commander, &longer).is_err());
+
+        assert!(root.delegate(&other, &narrow).is_err());
+        assert!(root.delegate(&commander, &narrow).is_ok());
+    }
+
+    #[test]
+    fn token_constraints_enforced_in_request() {
+        let operator = keypair();
+        let commander = keypair();
+        let delegate = keypair();
+        let executor = keypair();
+        let operator_pub = PublicKey::from_keypair(&operator).serialize().to_vec();
+        let executor_pub = PublicKey::from_keypair(&executor).serialize().to_vec();
+        let commander_pub = PublicKey::from_keypair(&commander).serialize().to_vec();
+        let delegate_pub = PublicKey::from_keypair(&delegate).serialize().to_vec();
+
+        let operator_impl = SpecOperator {
+            operator_key: operator,
+            operator_pubkey: operator_pub.clone(),
+        };
+        let mut root = operator_impl
+            .issue_token(&TokenIssueRequest {
+                subject: commander_pub,
+                audience: executor_pub.clone(),
+                capability: vec!["cmd:imaging:msi".to_string()],
+                issued_at: 1,
+                expires_at: 100,
+                token_id: None,
+            })
+            .expect("issue token");
+        root.constraints = SpecConstraints {
+            geo: Some("45,-10,50,5".to_string()),
+            rate: Some((3, 60)),
+            amount: Some(1_000),
+            not_before: Some(20),
+        };
+        root.signature = sign_tagged(
+            "SCRAP/token/v1",
+            &root.encode_tlv_without_signature().expect("encode root"),
+            &operator,
+        )
+        .expect("sign root");
+        let decoded =
+            SpecToken::decode_tlv(&root.encode_tlv().expect("encode root")).expect("decode root");
+        assert_eq!(decoded.constraints.geo, root.constraints.geo);
+        assert_eq!(decoded.constraints.rate, Some((3, 60)));
+        assert_eq!(decoded.constraints.amount, Some(1_000));
+        assert_eq!(decoded.constraints.not_before, Some(20));
+
+        let child = root
+            .delegate(
+                &commander,
+                &DelegationRequest {
+                    subject: delegate_pub,
+                    capability: vec!["cmd:imaging:msi".to_string()],
+                    issued_at: 5,
+                    expires_at: 80,
+                    token_id: None,
+                },
+            )
+            .expect("delegate");
+        let verifier = SpecVerifier {
+            operator_pubkey: operator_pub,
+            executor_pubkey: executor_pub,
+        };
+        let request_for = |child: &SpecToken, target: &str, amount: u64| {
+            let mut request = SpecTaskRequest {
+                task_id: "task-constrained".to_string(),
+                timestamp: 30,
+                capability_token: child.encode_tlv().expect("encode child"),
+                delegation_chain: vec![root.encode_tlv().expect("encode root")],
+                task_type: "cmd:imaging:msi".to_string(),
+                target_json: target.to_string(),
+                parameters_json: "{}".to_string(),
+                constraints_json: "{}".to_string(),
+                payment_max_sats: amount,
+                timeout_blocks: 144,
+                commander_signature: [0u8; 64],
+            };
+            request.commander_signature =
+                sign_message_hash(request.commander_signing_hash(), &delegate)
+                    .expect("sign request");
+            request
+        };
+        let inside = r#"{"lat": 48.8, "lon": 2.3}"#;
+        verifier
+            .verify_request(&request_for(&child, inside, 1_000), 50)
+            .expect("verify constrained request");
+        let err = verifier
+            .verify_request(&request_for(&child, inside, 1_000), 10)
+            .expect_err("not_before");
+        assert_eq!(err.reason, "token not yet valid");
+        assert!(verifier
+            .verify_request(&request_for(&child, inside, 1_001), 50)
+            .is_err());
+        assert!(verifier
+            .verify_request(&request_for(&child, r#"{"lat": 10, "lon": 2.3}"#, 500), 50)
+            .is_err());
+
+        let mut widened = child.clone();
+        widened.constraints.amount = Some(5_000);
+        widened.signature = sign_tagged(
+            "SCRAP/delegation/v1",
+            &widened
+                .encode_tlv_without_signature()
+                .expect("encode child"),
+            &commander,
+        )
+        .expect("sign child");
+        let err = verifier
+            .verify_request(&request_for(&widened, inside, 1_000), 50)
+            .expect_err("widened");
+        assert_eq!(err.reason, "delegation widens amount constraint");
+    }
+
+    fn settlement_fixture() -> (SpecTaskRequest, SpecPaymentLock, SpecPaymentClaim) {
+        let request = SpecTaskRequest {
+            task_id: "task-settle".to_string(),
+            timestamp: 10,
+            capability_token: vec![1, 2, 3],
+            delegation_chain: vec![],
+            task_type: "cmd:imaging:msi".to_string(),
+            target_json: "{}".to_string(),
+            parameters_json: "{}".to_string(),
+            constraints_json: "{}".to_string(),
+            payment_max_sats: 1000,
+            timeout_blocks: 10,
+            commander_signature: [0u8; 64],
+        };
+        let correlation_id = request.request_hash();
+        let lock = SpecPaymentLock {
+            task_id: request.task_id.clone(),
+            correlation_id,
+            payment_hash: derive_payment_hash(correlation_id),
+            amount_sats: 900,
+            timeout_blocks: 10,
+            timestamp: 12,
+        };
+        let claim = SpecPaymentClaim {
+            task_id: request.task_id.clone(),
+            correlation_id,
+            payment_hash: derive_payment_hash(correlation_id),
+            preimage: derive_preimage(correlation_id),
+            timestamp: 14,
+        };
+        (request, lock, claim)
+    }
+
+    fn apply_settlement_op(
+        state: &mut SettlementState,
+        to: SettlementPhase,
+        lock: &SpecPaymentLock,
+        claim: &SpecPaymentClaim,
+        now: u64,
+    ) -> Result<(), SettlementError> {
+        match to {
+            SettlementPhase::Requested => Err(SettlementError::IllegalTransition {
+                from: state.phase,
+                to,
+            }),
+            SettlementPhase::Locked => state.lock(lock, now),
+            SettlementPhase::Accepted => state.accept(now),
+            SettlementPhase::ProofSent => state.record_proof(now),
+            SettlementPhase::Claimed => state.claim(claim, now),
+            SettlementPhase::Rejected => state.reject(),
+            SettlementPhase::Expired => state.expire(now),
+            SettlementPhase::Disputed => state.dispute(),
+        }
+    }
+
+    #[test]
+    fn settlement_every_illegal_edge_rejected() {
+        let (request, lock, claim) = settlement_fixture();
+        let legal = [
+            (SettlementPhase::Requested, SettlementPhase::Locked),
+            (SettlementPhase::Locked, SettlementPhase::Accepted),
+            (SettlementPhase::Accepted, SettlementPhase::ProofSent),
+            (SettlementPhase::ProofSent, SettlementPhase::Claimed),
+            (SettlementPhase::Locked, SettlementPhase::Disputed),
+            (SettlementPhase::Accepted, SettlementPhase::Disputed),
+            (SettlementPhase::ProofSent, SettlementPhase::Disputed),
+        ];
+        // Shortcuts that skip a step of Requested -> Locked -> Accepted ->
+        // ProofSent -> Claimed.
+        let skipped = [
+            (SettlementPhase::Requested, SettlementPhase::Accepted),
+            (SettlementPhase::Requested, SettlementPhase::ProofSent),
+            (SettlementPhase::Locked, SettlementPhase::ProofSent),
+            (SettlementPhase::Locked, SettlementPhase::Claimed),
+            (SettlementPhase::Accepted, SettlementPhase::Claimed),
+        ];
+        for (from, to) in skipped {
+            assert!(!legal.contains(&(from, to)), "{from:?} -> {to:?}");
+            let mut state = SettlementState::new(&request, &SECONDS);
+            state.phase = from;
+            assert_eq!(
+                apply_settlement_op(&mut state, to, &lock, &claim, 15),
+                Err(SettlementError::IllegalTransition { from, to })
+            );
+        }
+        for from in SettlementPhase::ALL {
+            for to in SettlementPhase::ALL {
+                let expected = legal.contains(&(from, to))
+                    || (!from.is_terminal()
+                        && matches!(to, SettlementPhase::Rejected | SettlementPhase::Expired));
+                assert_eq!(from.can_transition_to(to), expected, "{from:?} -> {to:?}");
+
+                let mut state = SettlementState::new(&request, &SECONDS);
+                state.phase = from;
+                let now = if to == SettlementPhase::Expired {
+                    21
+                } else {
+                    15
+                };
+                let result = apply_settlement_op(&mut state, to, &lock, &claim, now);
+                if expected {
+                    assert_eq!(result, Ok(()), "{from:?} -> {to:?}");
+                    assert_eq!(state.phase, to);
+                } else {
+                    assert_eq!(
+                        result,
+                        Err(SettlementError::IllegalTransition { from, to }),
+                        "{from:?} -> {to:?}"
+                    );
+                    assert_eq!(state.phase, from);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn settlement_full_flow_and_claim_validation() {
+        let (request, lock, claim) = settlement_fixture();
+        let mut state = SettlementState::new(&request, &SECONDS);
+        state.lock(&lock, 12).expect("lock");
+        assert!(state.can_emit_accept());
+        state.accept(13).expect("accept");
+        assert!(!state.can_emit_accept());
+        assert!(state.can_emit_proof());
+        state.record_proof(14).expect("proof");
+
+        let mut bad = claim.clone();
+        bad.preimage[0] ^= 1;
+        assert!(matches!(
+            state.validate_claim(&bad),
+            Err(SettlementError::InvalidClaim(_))
+        ));
+        let mut bad = claim.clone();
+        bad.task_id = "other".to_string();
+        assert!(matches!(
+            state.claim(&bad, 15),
+            Err(SettlementError::InvalidClaim(_))
+        ));
+        let mut bad = claim.clone();
+        bad.correlation_id[0] ^= 1;
+        assert!(matches!(
+            state.claim(&bad, 15),
+            Err(SettlementError::InvalidClaim(_))
+        ));
+        assert_eq!(state.phase, SettlementPhase::ProofSent);
+
+        state.claim(&claim, 15).expect("claim");
+        assert_eq!(state.phase, SettlementPhase::Claimed);
+        assert_eq!(
+            SettlementPhase::parse(state.phase.as_str()),
+            Some(SettlementPhase::Claimed)
+        );
+        assert!(state.reject().is_err());
+    }
+
+    #[test]
+    fn settlement_timeout_expiry() {
+        let (request, lock, _) = settlement_fixture();
+        let mut state = SettlementState::new(&request, &SECONDS);
+        state.lock(&lock, 12).expect("lock");
+        assert_eq!(state.expires_at, 20);
+        assert_eq!(
+            state.accept(21),
+            Err(SettlementError::Expired {
+                expires_at: 20,
+                now: 21
+            })
+        );
+        assert_eq!(state.phase, SettlementPhase::Locked);
+        assert_eq!(
+            state.expire(20),
+            Err(SettlementError::NotExpired {
+                expires_at: 20,
+                now: 20
+            })
+        );
+        state.expire(21).expect("expire");
+        assert_eq!(state.phase, SettlementPhase::Expired);
+        assert!(state.lock(&lock, 12).is_err());
+
+        let mut late = SettlementState::new(&request, &SECONDS);
+        assert!(matches!(
+            late.lock(&lock, 25),
+            Err(SettlementError::Expired { .. })
+        ));
+    }
+
+    #[test]
+    fn settlement_deadline_follows_chain_clock() {
+        let (mut request, mut lock, _) = settlement_fixture();
+        request.timestamp = 60_300;
+        request.timeout_blocks = 2;
+        lock.correlation_id = request.request_hash();
+        lock.payment_hash = derive_payment_hash(lock.correlation_id);
+        lock.timeout_blocks = 2;
+        let clock = IntervalClock::anchored(600, 100, 60_000);
+        let mut state = SettlementState::new(&request, &clock);
+        assert_eq!(state.expires_at, 61_200);
+        assert_eq!(state.remaining_secs(60_900), 300);
+        assert_eq!(state.remaining_secs(62_000), 0);
+        // Seconds-as-blocks would have expired this lock long ago.
+        state.lock(&lock, 61_000).expect("lock");
+        assert!(matches!(
+            state.accept(61_201),
+            Err(SettlementError::Expired {
+                expires_at: 61_200,
+                ..
+            })
+        ));
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn settlement_random_walk_respects_edges(
+            ops in proptest::collection::vec((0usize..8, 0u64..30), 0..24)
+        ) {
+            let (request, lock, claim) = settlement_fixture();
+            let mut state = SettlementState::new(&request, &SECONDS);
+            for (index, now) in ops {
+                let from = state.phase;
+                let to = SettlementPhase::ALL[index];
+                match apply_settlement_op(&mut state, to, &lock, &claim, now) {
+                    Ok(()) => {
+                        proptest::prop_assert!(from.can_transition_to(to));
+                        proptest::prop_assert_eq!(state.phase, to);
+                        if to == SettlementPhase::Claimed {
+                            proptest::prop_assert!(now <= state.expires_at);
+                        }
+                    }
+                    Err(_) => proptest::prop_assert_eq!(state.phase, from),
+                }
+                if from.is_terminal() {
+                    proptest::prop_assert_eq!(state.phase, from);
+                }
+            }
+        }
+    }
 }
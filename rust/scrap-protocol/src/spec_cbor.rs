@@ -1,6 +1,19 @@
-use crate::ProtocolError;
+use crate::{
+    capability_allows, check_canonical_cbor, derive_payment_hash, hex_to_bytes, hmac_sha256,
+    normalize_pubkey, parse_xonly, sha256, sign_tagged, tagged_hash, verify_schnorr, ProtocolError,
+    VerifyError,
+};
 use minicbor::data::Type;
 use minicbor::{Decoder, Encoder};
+use secp256k1::{ecdsa, Keypair, Message, PublicKey, Secp256k1};
+use serde::{Deserialize, Serialize};
+
+const SAT_CAP_TAG: &str = "SCRAP/sat_cap/v1";
+const BOUND_TASK_TAG: &str = "SCRAP/bound_task/v1";
+const PROOF_TAG: &str = "SCRAP/execution_proof/v1";
+const ACCEPTED_TAG: &str = "SCRAP/task_accepted/v1";
+const REJECTED_TAG: &str = "SCRAP/task_rejected/v1";
+const FAILED_TAG: &str = "SCRAP/task_failed/v1";
 
 impl From<minicbor::decode::Error> for ProtocolError {
     fn from(err: minicbor::decode::Error) -> Self {
@@ -14,43 +27,62 @@ impl<E: core::fmt::Display> From<minicbor::encode::Error<E>> for ProtocolError {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct CapHeader {
     pub alg: String,
     pub typ: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub enc: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub chn: Option<u32>,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct GeoBounds {
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub lat_min: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub lat_max: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub lon_min: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub lon_max: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub polygon: Option<Vec<[f64; 2]>>,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct TimeWindow {
     pub start: u64,
     pub end: u64,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct Constraints {
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub max_area_km2: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub max_range_km: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub max_hops: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub geographic_bounds: Option<GeoBounds>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub time_window: Option<TimeWindow>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub min_approach_distance_m: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub max_relative_velocity_m_s: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub fuel_budget_kg: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub abort_triggers: Option<Vec<String>>,
+    /// Ceiling on `BoundTaskRequest.payment_amount_msat`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_payment_msat: Option<u64>,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct CapPayload {
     pub iss: String,
     pub sub: String,
@@ -59,93 +91,159 @@ pub struct CapPayload {
     pub exp: u64,
     pub jti: String,
     pub cap: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub cns: Option<Constraints>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub prf: Option<String>,
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "hex_bytes_opt"
+    )]
     pub cmd_pub: Option<Vec<u8>>,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct SatCapToken {
     pub header: CapHeader,
     pub payload: CapPayload,
+    #[serde(with = "hex_bytes")]
     pub signature: Vec<u8>,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+/// Signature algorithms `CapHeader.alg` may name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CapAlg {
+    /// BIP340 Schnorr over the `SCRAP/sat_cap/v1` tagged hash.
+    Bip340,
+    /// ECDSA over SHA-256, compact `r || s` signature.
+    Es256k,
+}
+
+impl CapAlg {
+    pub fn parse(value: &str) -> Result<Self, VerifyError> {
+        match value {
+            "BIP340" => Ok(CapAlg::Bip340),
+            "ES256K" => Ok(CapAlg::Es256k),
+            other => Err(VerifyError::new(format!("unsupported alg {other}"))),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CapAlg::Bip340 => "BIP340",
+            CapAlg::Es256k => "ES256K",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct BoundTaskRequest {
+    #[serde(with = "hex_bytes")]
     pub capability_token: Vec<u8>,
+    #[serde(with = "hex_bytes")]
     pub payment_hash: Vec<u8>,
     pub payment_amount_msat: u64,
     pub htlc_timeout_blocks: u32,
+    #[serde(with = "hex_bytes")]
     pub binding_sig: Vec<u8>,
+    /// Encoded ancestors of `capability_token`, root first, for the `prf`
+    /// chain to resolve against. Not covered by `binding_sig`: each token
+    /// carries its own signature.
+    #[serde(default, skip_serializing_if = "Vec::is_empty", with = "hex_bytes_list")]
+    pub proof_chain: Vec<Vec<u8>>,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct OutputMetadata {
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub data_size_bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub data_format: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub coverage_km2: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub acquisition_start: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub acquisition_end: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub sensor_mode: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub content_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub size_bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub storage_location: Option<String>,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ExecutionProof {
     pub task_jti: String,
+    #[serde(with = "hex_bytes")]
     pub payment_hash: Vec<u8>,
+    #[serde(with = "hex_bytes")]
     pub output_hash: Vec<u8>,
     pub execution_timestamp: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub output_metadata: Option<OutputMetadata>,
+    #[serde(with = "hex_bytes")]
     pub executor_sig: Vec<u8>,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct TaskAccepted {
     pub task_jti: String,
     pub accepted_at: u64,
     pub estimated_completion: u64,
+    #[serde(with = "hex_bytes")]
     pub executor_sig: Vec<u8>,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct TaskRejected {
     pub task_jti: String,
     pub rejected_at: u64,
     pub reason: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub detail: Option<String>,
+    #[serde(with = "hex_bytes")]
     pub executor_sig: Vec<u8>,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct DataLocation {
     pub method: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub relay_satellite: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub ground_station: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub estimated_delivery: Option<u64>,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct TaskCompleted {
     pub task_jti: String,
     pub proof: ExecutionProof,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub data_location: Option<DataLocation>,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct TaskFailed {
     pub task_jti: String,
     pub failed_at: u64,
     pub reason: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub detail: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub partial_proof: Option<ExecutionProof>,
+    #[serde(with = "hex_bytes")]
     pub executor_sig: Vec<u8>,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "UPPERCASE")]
 pub enum TaskResponse {
     Accepted(TaskAccepted),
     Rejected(TaskRejected),
@@ -153,18 +251,60 @@ pub enum TaskResponse {
     Failed(TaskFailed),
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Periodic liveness beacon from a peer on the link.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Heartbeat {
+    pub sender: String,
+    pub timestamp: u64,
+    pub pending_htlcs: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub capabilities: Option<Vec<String>>,
+}
+
+/// Raised by a commander that contests a task's execution or settlement.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DisputeMessage {
+    pub task_jti: String,
+    #[serde(with = "hex_bytes")]
+    pub payment_hash: Vec<u8>,
+    pub reason: String,
+    pub timestamp: u64,
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "hex_bytes_opt"
+    )]
+    pub evidence: Option<Vec<u8>>,
+}
+
+/// A Lightning peer message (BOLT #1 framing: 2-byte type, then payload)
+/// tunnelled over the ISL.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LightningWrapper {
+    #[serde(with = "hex_bytes")]
+    pub bolt_payload: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum MessageType {
     TaskRequest,
     TaskResponse,
     Proof,
+    Heartbeat,
+    Dispute,
+    Lightning,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
 pub enum ScapPayload {
     TaskRequest(BoundTaskRequest),
     TaskResponse(TaskResponse),
     Proof(ExecutionProof),
+    Heartbeat(Heartbeat),
+    Dispute(DisputeMessage),
+    Lightning(LightningWrapper),
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -191,16 +331,16 @@ impl CapHeader {
         enc.map(len)?;
         enc.str("alg")?;
         enc.str(&self.alg)?;
-        enc.str("typ")?;
-        enc.str(&self.typ)?;
-        if let Some(enc_val) = &self.enc {
-            enc.str("enc")?;
-            enc.str(enc_val)?;
-        }
         if let Some(chn) = self.chn {
             enc.str("chn")?;
             enc.u32(chn)?;
         }
+        if let Some(enc_val) = &self.enc {
+            enc.str("enc")?;
+            enc.str(enc_val)?;
+        }
+        enc.str("typ")?;
+        enc.str(&self.typ)?;
         Ok(())
     }
 
@@ -263,22 +403,22 @@ impl GeoBounds {
             len += 1;
         }
         enc.map(len)?;
-        if let Some(val) = self.lat_min {
-            enc.str("lat_min")?;
-            enc.f64(val)?;
-        }
         if let Some(val) = self.lat_max {
             enc.str("lat_max")?;
             enc.f64(val)?;
         }
-        if let Some(val) = self.lon_min {
-            enc.str("lon_min")?;
+        if let Some(val) = self.lat_min {
+            enc.str("lat_min")?;
             enc.f64(val)?;
         }
         if let Some(val) = self.lon_max {
             enc.str("lon_max")?;
             enc.f64(val)?;
         }
+        if let Some(val) = self.lon_min {
+            enc.str("lon_min")?;
+            enc.f64(val)?;
+        }
         if let Some(poly) = &self.polygon {
             enc.str("polygon")?;
             enc.array(poly.len() as u64)?;
@@ -335,10 +475,10 @@ impl GeoBounds {
 impl TimeWindow {
     fn encode_into(&self, enc: &mut Encoder<&mut Vec<u8>>) -> Result<(), ProtocolError> {
         enc.map(2)?;
-        enc.str("start")?;
-        enc.u64(self.start)?;
         enc.str("end")?;
         enc.u64(self.end)?;
+        enc.str("start")?;
+        enc.u64(self.start)?;
         Ok(())
     }
 
@@ -392,7 +532,18 @@ impl Constraints {
         if self.abort_triggers.is_some() {
             len += 1;
         }
+        if self.max_payment_msat.is_some() {
+            len += 1;
+        }
         enc.map(len)?;
+        if let Some(val) = self.max_hops {
+            enc.str("max_hops")?;
+            enc.u32(val)?;
+        }
+        if let Some(window) = &self.time_window {
+            enc.str("time_window")?;
+            window.encode_into(enc)?;
+        }
         if let Some(val) = self.max_area_km2 {
             enc.str("max_area_km2")?;
             enc.u64(val)?;
@@ -401,18 +552,25 @@ impl Constraints {
             enc.str("max_range_km")?;
             enc.f64(val)?;
         }
-        if let Some(val) = self.max_hops {
-            enc.str("max_hops")?;
-            enc.u32(val)?;
+        if let Some(triggers) = &self.abort_triggers {
+            enc.str("abort_triggers")?;
+            enc.array(triggers.len() as u64)?;
+            for trigger in triggers {
+                enc.str(trigger)?;
+            }
+        }
+        if let Some(val) = self.fuel_budget_kg {
+            enc.str("fuel_budget_kg")?;
+            enc.f64(val)?;
+        }
+        if let Some(val) = self.max_payment_msat {
+            enc.str("max_payment_msat")?;
+            enc.u64(val)?;
         }
         if let Some(bounds) = &self.geographic_bounds {
             enc.str("geographic_bounds")?;
             bounds.encode_into(enc)?;
         }
-        if let Some(window) = &self.time_window {
-            enc.str("time_window")?;
-            window.encode_into(enc)?;
-        }
         if let Some(val) = self.min_approach_distance_m {
             enc.str("min_approach_distance_m")?;
             enc.u64(val)?;
@@ -421,17 +579,6 @@ impl Constraints {
             enc.str("max_relative_velocity_m_s")?;
             enc.f64(val)?;
         }
-        if let Some(val) = self.fuel_budget_kg {
-            enc.str("fuel_budget_kg")?;
-            enc.f64(val)?;
-        }
-        if let Some(triggers) = &self.abort_triggers {
-            enc.str("abort_triggers")?;
-            enc.array(triggers.len() as u64)?;
-            for trigger in triggers {
-                enc.str(trigger)?;
-            }
-        }
         Ok(())
     }
 
@@ -445,6 +592,7 @@ impl Constraints {
         let mut max_relative_velocity_m_s = None;
         let mut fuel_budget_kg = None;
         let mut abort_triggers = None;
+        let mut max_payment_msat = None;
 
         decode_map(dec, |key, dec| {
             match key {
@@ -464,6 +612,7 @@ impl Constraints {
                     }
                     abort_triggers = Some(items);
                 }
+                "max_payment_msat" => max_payment_msat = Some(dec.u64()?),
                 _ => {
                     dec.skip()?;
                 }
@@ -481,6 +630,7 @@ impl Constraints {
             max_relative_velocity_m_s,
             fuel_budget_kg,
             abort_triggers,
+            max_payment_msat,
         })
     }
 }
@@ -498,18 +648,8 @@ impl CapPayload {
             len += 1;
         }
         enc.map(len)?;
-        enc.str("iss")?;
-        enc.str(&self.iss)?;
-        enc.str("sub")?;
-        enc.str(&self.sub)?;
         enc.str("aud")?;
         enc.str(&self.aud)?;
-        enc.str("iat")?;
-        enc.u64(self.iat)?;
-        enc.str("exp")?;
-        enc.u64(self.exp)?;
-        enc.str("jti")?;
-        enc.str(&self.jti)?;
         enc.str("cap")?;
         enc.array(self.cap.len() as u64)?;
         for cap in &self.cap {
@@ -519,10 +659,20 @@ impl CapPayload {
             enc.str("cns")?;
             constraints.encode_into(enc)?;
         }
+        enc.str("exp")?;
+        enc.u64(self.exp)?;
+        enc.str("iat")?;
+        enc.u64(self.iat)?;
+        enc.str("iss")?;
+        enc.str(&self.iss)?;
+        enc.str("jti")?;
+        enc.str(&self.jti)?;
         if let Some(prf) = &self.prf {
             enc.str("prf")?;
             enc.str(prf)?;
         }
+        enc.str("sub")?;
+        enc.str(&self.sub)?;
         if let Some(cmd_pub) = &self.cmd_pub {
             enc.str("cmd_pub")?;
             enc.bytes(cmd_pub)?;
@@ -597,6 +747,133 @@ impl SatCapToken {
         Ok(buf)
     }
 
+    /// Deterministic CBOR (RFC 8949 §4.2.1) of `{header, payload}`: definite
+    /// lengths, shortest-form integers and map keys in encoded-byte order.
+    /// The encoders in this file emit their keys in that order.
+    pub fn signing_input(&self) -> Result<Vec<u8>, ProtocolError> {
+        let mut buf = Vec::new();
+        let mut enc = Encoder::new(&mut buf);
+        enc.map(2)?;
+        enc.str("header")?;
+        self.header.encode_into(&mut enc)?;
+        enc.str("payload")?;
+        self.payload.encode_into(&mut enc)?;
+        Ok(buf)
+    }
+
+    /// Signs with the algorithm named in `header.alg`.
+    pub fn sign(&mut self, keypair: &Keypair) -> Result<(), ProtocolError> {
+        let alg = CapAlg::parse(&self.header.alg).map_err(|err| ProtocolError::new(err.reason))?;
+        let input = self.signing_input()?;
+        self.signature = match alg {
+            CapAlg::Bip340 => sign_tagged(SAT_CAP_TAG, &input, keypair)?.to_vec(),
+            CapAlg::Es256k => {
+                let secp = Secp256k1::signing_only();
+                let msg = Message::from_digest(sha256(&input));
+                secp.sign_ecdsa(&msg, &keypair.secret_key())
+                    .serialize_compact()
+                    .to_vec()
+            }
+        };
+        Ok(())
+    }
+
+    /// Checks `signature` against `pubkey` using the algorithm named in
+    /// `header.alg`. ES256K needs the full compressed key.
+    pub fn verify_signature(&self, pubkey: &[u8]) -> Result<(), VerifyError> {
+        let alg = CapAlg::parse(&self.header.alg)?;
+        let input = self
+            .signing_input()
+            .map_err(|err| VerifyError::new(err.reason))?;
+        let valid = match alg {
+            CapAlg::Bip340 => {
+                let xonly = parse_xonly(pubkey).map_err(VerifyError::new)?;
+                let signature: [u8; 64] = self
+                    .signature
+                    .as_slice()
+                    .try_into()
+                    .map_err(|_| VerifyError::new("sat_cap signature length invalid"))?;
+                verify_schnorr(&tagged_hash(SAT_CAP_TAG, &input), &signature, &xonly)
+            }
+            CapAlg::Es256k => {
+                let pubkey = PublicKey::from_slice(pubkey)
+                    .map_err(|_| VerifyError::new("ES256K needs a compressed pubkey"))?;
+                let signature = ecdsa::Signature::from_compact(&self.signature)
+                    .map_err(|_| VerifyError::new("sat_cap signature length invalid"))?;
+                let msg = Message::from_digest(sha256(&input));
+                Secp256k1::verification_only()
+                    .verify_ecdsa(&msg, &signature, &pubkey)
+                    .is_ok()
+            }
+        };
+        if !valid {
+            return Err(VerifyError::new("sat_cap signature invalid"));
+        }
+        Ok(())
+    }
+
+    /// The key the subject signs requests and delegations with: `cmd_pub`
+    /// when present, otherwise `sub` read as a pubkey hex. When both are keys
+    /// they must agree.
+    pub fn commander_pubkey(&self) -> Result<Vec<u8>, VerifyError> {
+        let sub = hex_to_bytes(&self.payload.sub)
+            .ok()
+            .filter(|bytes| normalize_pubkey(bytes).is_ok());
+        match (&self.payload.cmd_pub, sub) {
+            (Some(cmd_pub), sub) => {
+                let cmd_norm =
+                    normalize_pubkey(cmd_pub).map_err(|_| VerifyError::new("cmd_pub invalid"))?;
+                if let Some(sub) = sub {
+                    if normalize_pubkey(&sub).ok() != Some(cmd_norm) {
+                        return Err(VerifyError::new("cmd_pub does not match sub"));
+                    }
+                }
+                Ok(cmd_pub.clone())
+            }
+            (None, Some(sub)) => Ok(sub),
+            (None, None) => Err(VerifyError::new("token subject has no commander key")),
+        }
+    }
+
+    pub fn allows(&self, capability: &str) -> bool {
+        self.payload
+            .cap
+            .iter()
+            .any(|granted| capability_allows(granted, capability))
+    }
+
+    /// Follows `prf` references through `known` and returns the ancestors of
+    /// this token, root first.
+    pub fn resolve_proof_chain(
+        &self,
+        known: &[SatCapToken],
+    ) -> Result<Vec<SatCapToken>, VerifyError> {
+        let mut chain: Vec<SatCapToken> = Vec::new();
+        let mut prf = self.payload.prf.as_deref();
+        while let Some(parent_jti) = prf {
+            if parent_jti == self.payload.jti
+                || chain.iter().any(|token| token.payload.jti == parent_jti)
+            {
+                return Err(VerifyError::new("prf chain loops"));
+            }
+            let parent = known
+                .iter()
+                .find(|token| token.payload.jti == parent_jti)
+                .ok_or_else(|| VerifyError::new(format!("prf parent {parent_jti} not found")))?;
+            chain.push(parent.clone());
+            prf = parent.payload.prf.as_deref();
+        }
+        chain.reverse();
+        Ok(chain)
+    }
+
+    /// `decode_cbor` that first requires deterministic encoding; see
+    /// `check_canonical_cbor`.
+    pub fn decode_cbor_canonical(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        check_canonical_cbor(bytes)?;
+        Self::decode_cbor(bytes)
+    }
+
     pub fn decode_cbor(bytes: &[u8]) -> Result<Self, ProtocolError> {
         let mut dec = Decoder::new(bytes);
         let mut header: Option<CapHeader> = None;
@@ -625,61 +902,110 @@ impl SatCapToken {
 
 impl BoundTaskRequest {
     fn encode_into(&self, enc: &mut Encoder<&mut Vec<u8>>) -> Result<(), ProtocolError> {
-        enc.map(5)?;
-        enc.str("capability_token")?;
-        enc.bytes(&self.capability_token)?;
+        let chained = !self.proof_chain.is_empty();
+        enc.map(if chained { 6 } else { 5 })?;
+        enc.str("binding_sig")?;
+        enc.bytes(&self.binding_sig)?;
+        if chained {
+            enc.str("proof_chain")?;
+            enc.array(self.proof_chain.len() as u64)?;
+            for token in &self.proof_chain {
+                enc.bytes(token)?;
+            }
+        }
+        self.encode_bound_fields(enc)
+    }
+
+    fn encode_bound_fields(&self, enc: &mut Encoder<&mut Vec<u8>>) -> Result<(), ProtocolError> {
         enc.str("payment_hash")?;
         enc.bytes(&self.payment_hash)?;
-        enc.str("payment_amount_msat")?;
-        enc.u64(self.payment_amount_msat)?;
+        enc.str("capability_token")?;
+        enc.bytes(&self.capability_token)?;
         enc.str("htlc_timeout_blocks")?;
         enc.u32(self.htlc_timeout_blocks)?;
-        enc.str("binding_sig")?;
-        enc.bytes(&self.binding_sig)?;
+        enc.str("payment_amount_msat")?;
+        enc.u64(self.payment_amount_msat)?;
         Ok(())
     }
 
-    pub fn encode_cbor(&self) -> Result<Vec<u8>, ProtocolError> {
+    /// Deterministic CBOR of every field except `binding_sig`.
+    pub fn signing_input(&self) -> Result<Vec<u8>, ProtocolError> {
         let mut buf = Vec::new();
         let mut enc = Encoder::new(&mut buf);
-        self.encode_into(&mut enc)?;
+        enc.map(4)?;
+        self.encode_bound_fields(&mut enc)?;
         Ok(buf)
     }
 
-    pub fn decode_cbor(bytes: &[u8]) -> Result<Self, ProtocolError> {
-        let mut dec = Decoder::new(bytes);
-        let mut capability_token = None;
-        let mut payment_hash = None;
-        let mut payment_amount_msat = None;
-        let mut htlc_timeout_blocks = None;
-        let mut binding_sig = None;
+    pub fn signing_hash(&self) -> Result<[u8; 32], ProtocolError> {
+        Ok(tagged_hash(BOUND_TASK_TAG, &self.signing_input()?))
+    }
 
-        decode_map(&mut dec, |key, dec| {
-            match key {
-                "capability_token" => capability_token = Some(dec.bytes()?.to_vec()),
-                "payment_hash" => payment_hash = Some(dec.bytes()?.to_vec()),
-                "payment_amount_msat" => payment_amount_msat = Some(dec.u64()?),
-                "htlc_timeout_blocks" => htlc_timeout_blocks = Some(decode_u32(dec)?),
-                "binding_sig" => binding_sig = Some(dec.bytes()?.to_vec()),
-                _ => {
-                    dec.skip()?;
-                }
+    /// Settlement correlation for a bound request: the hash of the capability
+    /// token it spends. `payment_hash` must be `derive_payment_hash` of it.
+    pub fn correlation_id(&self) -> [u8; 32] {
+        sha256(&self.capability_token)
+    }
+
+    /// Sets `binding_sig` to a BIP340 signature by the commander key.
+    pub fn sign(&mut self, commander_key: &Keypair) -> Result<(), ProtocolError> {
+        let signature = sign_tagged(BOUND_TASK_TAG, &self.signing_input()?, commander_key)?;
+        self.binding_sig = signature.to_vec();
+        Ok(())
+    }
+
+    /// Checks `binding_sig` against `cmd_pub`, which must be the commander key
+    /// the capability token binds, then the amount against the token's
+    /// `max_payment_msat` and `payment_hash` against the settlement
+    /// correlation. The token itself is verified by `SatCapVerifier`.
+    pub fn verify(&self, cmd_pub: &[u8]) -> Result<(), VerifyError> {
+        let token = SatCapToken::decode_cbor_canonical(&self.capability_token)
+            .map_err(|err| VerifyError::new(err.reason))?;
+        let commander = normalize_pubkey(&token.commander_pubkey()?)
+            .map_err(|_| VerifyError::new("commander key invalid"))?;
+        if normalize_pubkey(cmd_pub).ok() != Some(commander) {
+            return Err(VerifyError::new("cmd_pub is not the token's commander key"));
+        }
+        let xonly = parse_xonly(cmd_pub).map_err(VerifyError::new)?;
+        let signature: [u8; 64] = self
+            .binding_sig
+            .as_slice()
+            .try_into()
+            .map_err(|_| VerifyError::new("binding signature length invalid"))?;
+        let hash = self
+            .signing_hash()
+            .map_err(|err| VerifyError::new(err.reason))?;
+        if !verify_schnorr(&hash, &signature, &xonly) {
+            return Err(VerifyError::new("binding signature invalid"));
+        }
+        let max_payment = token.payload.cns.and_then(|cns| cns.max_payment_msat);
+        if let Some(max) = max_payment {
+            if self.payment_amount_msat > max {
+                return Err(VerifyError::new("payment amount exceeds token constraint"));
             }
-            Ok(())
-        })?;
+        }
+        if self.payment_hash != derive_payment_hash(self.correlation_id()) {
+            return Err(VerifyError::new("payment_hash does not match correlation"));
+        }
+        Ok(())
+    }
 
-        Ok(BoundTaskRequest {
-            capability_token: capability_token
-                .ok_or_else(|| ProtocolError::new("bound task missing capability_token"))?,
-            payment_hash: payment_hash
-                .ok_or_else(|| ProtocolError::new("bound task missing payment_hash"))?,
-            payment_amount_msat: payment_amount_msat
-                .ok_or_else(|| ProtocolError::new("bound task missing payment_amount_msat"))?,
-            htlc_timeout_blocks: htlc_timeout_blocks
-                .ok_or_else(|| ProtocolError::new("bound task missing htlc_timeout_blocks"))?,
-            binding_sig: binding_sig
-                .ok_or_else(|| ProtocolError::new("bound task missing binding_sig"))?,
-        })
+    pub fn encode_cbor(&self) -> Result<Vec<u8>, ProtocolError> {
+        let mut buf = Vec::new();
+        let mut enc = Encoder::new(&mut buf);
+        self.encode_into(&mut enc)?;
+        Ok(buf)
+    }
+
+    /// `decode_cbor` that first requires deterministic encoding; see
+    /// `check_canonical_cbor`.
+    pub fn decode_cbor_canonical(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        check_canonical_cbor(bytes)?;
+        Self::decode_cbor(bytes)
+    }
+
+    pub fn decode_cbor(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        Self::decode_cbor_from(&mut Decoder::new(bytes))
     }
 }
 
@@ -714,26 +1040,14 @@ impl OutputMetadata {
             len += 1;
         }
         enc.map(len)?;
-        if let Some(val) = self.data_size_bytes {
-            enc.str("data_size_bytes")?;
+        if let Some(val) = self.size_bytes {
+            enc.str("size_bytes")?;
             enc.u64(val)?;
         }
         if let Some(val) = &self.data_format {
             enc.str("data_format")?;
             enc.str(val)?;
         }
-        if let Some(val) = self.coverage_km2 {
-            enc.str("coverage_km2")?;
-            enc.f64(val)?;
-        }
-        if let Some(val) = self.acquisition_start {
-            enc.str("acquisition_start")?;
-            enc.u64(val)?;
-        }
-        if let Some(val) = self.acquisition_end {
-            enc.str("acquisition_end")?;
-            enc.u64(val)?;
-        }
         if let Some(val) = &self.sensor_mode {
             enc.str("sensor_mode")?;
             enc.str(val)?;
@@ -742,14 +1056,26 @@ impl OutputMetadata {
             enc.str("content_type")?;
             enc.str(val)?;
         }
-        if let Some(val) = self.size_bytes {
-            enc.str("size_bytes")?;
+        if let Some(val) = self.coverage_km2 {
+            enc.str("coverage_km2")?;
+            enc.f64(val)?;
+        }
+        if let Some(val) = self.acquisition_end {
+            enc.str("acquisition_end")?;
+            enc.u64(val)?;
+        }
+        if let Some(val) = self.data_size_bytes {
+            enc.str("data_size_bytes")?;
             enc.u64(val)?;
         }
         if let Some(val) = &self.storage_location {
             enc.str("storage_location")?;
             enc.str(val)?;
         }
+        if let Some(val) = self.acquisition_start {
+            enc.str("acquisition_start")?;
+            enc.u64(val)?;
+        }
         Ok(())
     }
 
@@ -798,28 +1124,63 @@ impl OutputMetadata {
 
 impl ExecutionProof {
     fn encode_into(&self, enc: &mut Encoder<&mut Vec<u8>>) -> Result<(), ProtocolError> {
-        let mut len = 5;
+        self.encode_fields(enc, Some(&self.executor_sig))
+    }
+
+    fn encode_fields(
+        &self,
+        enc: &mut Encoder<&mut Vec<u8>>,
+        executor_sig: Option<&[u8]>,
+    ) -> Result<(), ProtocolError> {
+        let mut len = 4;
         if self.output_metadata.is_some() {
             len += 1;
         }
+        if executor_sig.is_some() {
+            len += 1;
+        }
         enc.map(len)?;
         enc.str("task_jti")?;
         enc.str(&self.task_jti)?;
-        enc.str("payment_hash")?;
-        enc.bytes(&self.payment_hash)?;
         enc.str("output_hash")?;
         enc.bytes(&self.output_hash)?;
-        enc.str("execution_timestamp")?;
-        enc.u64(self.execution_timestamp)?;
+        if let Some(sig) = executor_sig {
+            enc.str("executor_sig")?;
+            enc.bytes(sig)?;
+        }
+        enc.str("payment_hash")?;
+        enc.bytes(&self.payment_hash)?;
         if let Some(meta) = &self.output_metadata {
             enc.str("output_metadata")?;
             meta.encode_into(enc)?;
         }
-        enc.str("executor_sig")?;
-        enc.bytes(&self.executor_sig)?;
+        enc.str("execution_timestamp")?;
+        enc.u64(self.execution_timestamp)?;
         Ok(())
     }
 
+    /// Deterministic CBOR of every field except `executor_sig`.
+    pub fn signing_input(&self) -> Result<Vec<u8>, ProtocolError> {
+        let mut buf = Vec::new();
+        self.encode_fields(&mut Encoder::new(&mut buf), None)?;
+        Ok(buf)
+    }
+
+    pub fn sign(&mut self, executor_key: &Keypair) -> Result<(), ProtocolError> {
+        self.executor_sig = sign_executor(PROOF_TAG, self.signing_input(), executor_key)?;
+        Ok(())
+    }
+
+    pub fn verify(&self, executor_pubkey: &[u8]) -> Result<(), VerifyError> {
+        verify_executor(
+            PROOF_TAG,
+            self.signing_input(),
+            &self.executor_sig,
+            executor_pubkey,
+        )
+        .map_err(|reason| VerifyError::new(format!("proof {reason}")))
+    }
+
     pub fn encode_cbor(&self) -> Result<Vec<u8>, ProtocolError> {
         let mut buf = Vec::new();
         let mut enc = Encoder::new(&mut buf);
@@ -827,6 +1188,13 @@ impl ExecutionProof {
         Ok(buf)
     }
 
+    /// `decode_cbor` that first requires deterministic encoding; see
+    /// `check_canonical_cbor`.
+    pub fn decode_cbor_canonical(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        check_canonical_cbor(bytes)?;
+        Self::decode_cbor(bytes)
+    }
+
     pub fn decode_cbor(bytes: &[u8]) -> Result<Self, ProtocolError> {
         let mut dec = Decoder::new(bytes);
         let mut task_jti = None;
@@ -867,6 +1235,24 @@ impl ExecutionProof {
 }
 
 impl TaskResponse {
+    pub fn task_jti(&self) -> &str {
+        match self {
+            TaskResponse::Accepted(accepted) => &accepted.task_jti,
+            TaskResponse::Rejected(rejected) => &rejected.task_jti,
+            TaskResponse::Completed(completed) => &completed.task_jti,
+            TaskResponse::Failed(failed) => &failed.task_jti,
+        }
+    }
+
+    pub fn verify(&self, executor_pubkey: &[u8]) -> Result<(), VerifyError> {
+        match self {
+            TaskResponse::Accepted(accepted) => accepted.verify(executor_pubkey),
+            TaskResponse::Rejected(rejected) => rejected.verify(executor_pubkey),
+            TaskResponse::Completed(completed) => completed.verify(executor_pubkey),
+            TaskResponse::Failed(failed) => failed.verify(executor_pubkey),
+        }
+    }
+
     pub fn encode_cbor(&self) -> Result<Vec<u8>, ProtocolError> {
         match self {
             TaskResponse::Accepted(accepted) => accepted.encode_cbor(),
@@ -876,6 +1262,13 @@ impl TaskResponse {
         }
     }
 
+    /// `decode_cbor` that first requires deterministic encoding; see
+    /// `check_canonical_cbor`.
+    pub fn decode_cbor_canonical(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        check_canonical_cbor(bytes)?;
+        Self::decode_cbor(bytes)
+    }
+
     pub fn decode_cbor(bytes: &[u8]) -> Result<Self, ProtocolError> {
         let mut dec = Decoder::new(bytes);
         Self::decode_from(&mut dec)
@@ -958,20 +1351,52 @@ impl TaskResponse {
 
 impl TaskAccepted {
     fn encode_into(&self, enc: &mut Encoder<&mut Vec<u8>>) -> Result<(), ProtocolError> {
-        enc.map(5)?;
+        self.encode_fields(enc, Some(&self.executor_sig))
+    }
+
+    fn encode_fields(
+        &self,
+        enc: &mut Encoder<&mut Vec<u8>>,
+        executor_sig: Option<&[u8]>,
+    ) -> Result<(), ProtocolError> {
+        enc.map(if executor_sig.is_some() { 5 } else { 4 })?;
         enc.str("type")?;
         enc.str("ACCEPTED")?;
         enc.str("task_jti")?;
         enc.str(&self.task_jti)?;
         enc.str("accepted_at")?;
         enc.u64(self.accepted_at)?;
+        if let Some(sig) = executor_sig {
+            enc.str("executor_sig")?;
+            enc.bytes(sig)?;
+        }
         enc.str("estimated_completion")?;
         enc.u64(self.estimated_completion)?;
-        enc.str("executor_sig")?;
-        enc.bytes(&self.executor_sig)?;
         Ok(())
     }
 
+    /// Deterministic CBOR of every field except `executor_sig`.
+    pub fn signing_input(&self) -> Result<Vec<u8>, ProtocolError> {
+        let mut buf = Vec::new();
+        self.encode_fields(&mut Encoder::new(&mut buf), None)?;
+        Ok(buf)
+    }
+
+    pub fn sign(&mut self, executor_key: &Keypair) -> Result<(), ProtocolError> {
+        self.executor_sig = sign_executor(ACCEPTED_TAG, self.signing_input(), executor_key)?;
+        Ok(())
+    }
+
+    pub fn verify(&self, executor_pubkey: &[u8]) -> Result<(), VerifyError> {
+        verify_executor(
+            ACCEPTED_TAG,
+            self.signing_input(),
+            &self.executor_sig,
+            executor_pubkey,
+        )
+        .map_err(|reason| VerifyError::new(format!("accepted {reason}")))
+    }
+
     fn encode_cbor(&self) -> Result<Vec<u8>, ProtocolError> {
         let mut buf = Vec::new();
         let mut enc = Encoder::new(&mut buf);
@@ -982,28 +1407,63 @@ impl TaskAccepted {
 
 impl TaskRejected {
     fn encode_into(&self, enc: &mut Encoder<&mut Vec<u8>>) -> Result<(), ProtocolError> {
-        let mut len = 5;
+        self.encode_fields(enc, Some(&self.executor_sig))
+    }
+
+    fn encode_fields(
+        &self,
+        enc: &mut Encoder<&mut Vec<u8>>,
+        executor_sig: Option<&[u8]>,
+    ) -> Result<(), ProtocolError> {
+        let mut len = 4;
         if self.detail.is_some() {
             len += 1;
         }
+        if executor_sig.is_some() {
+            len += 1;
+        }
         enc.map(len)?;
         enc.str("type")?;
         enc.str("REJECTED")?;
+        if let Some(detail) = &self.detail {
+            enc.str("detail")?;
+            enc.str(detail)?;
+        }
+        enc.str("reason")?;
+        enc.str(&self.reason)?;
         enc.str("task_jti")?;
         enc.str(&self.task_jti)?;
         enc.str("rejected_at")?;
         enc.u64(self.rejected_at)?;
-        enc.str("reason")?;
-        enc.str(&self.reason)?;
-        if let Some(detail) = &self.detail {
-            enc.str("detail")?;
-            enc.str(detail)?;
+        if let Some(sig) = executor_sig {
+            enc.str("executor_sig")?;
+            enc.bytes(sig)?;
         }
-        enc.str("executor_sig")?;
-        enc.bytes(&self.executor_sig)?;
         Ok(())
     }
 
+    /// Deterministic CBOR of every field except `executor_sig`.
+    pub fn signing_input(&self) -> Result<Vec<u8>, ProtocolError> {
+        let mut buf = Vec::new();
+        self.encode_fields(&mut Encoder::new(&mut buf), None)?;
+        Ok(buf)
+    }
+
+    pub fn sign(&mut self, executor_key: &Keypair) -> Result<(), ProtocolError> {
+        self.executor_sig = sign_executor(REJECTED_TAG, self.signing_input(), executor_key)?;
+        Ok(())
+    }
+
+    pub fn verify(&self, executor_pubkey: &[u8]) -> Result<(), VerifyError> {
+        verify_executor(
+            REJECTED_TAG,
+            self.signing_input(),
+            &self.executor_sig,
+            executor_pubkey,
+        )
+        .map_err(|reason| VerifyError::new(format!("rejected {reason}")))
+    }
+
     fn encode_cbor(&self) -> Result<Vec<u8>, ProtocolError> {
         let mut buf = Vec::new();
         let mut enc = Encoder::new(&mut buf);
@@ -1027,14 +1487,14 @@ impl DataLocation {
         enc.map(len)?;
         enc.str("method")?;
         enc.str(&self.method)?;
-        if let Some(val) = &self.relay_satellite {
-            enc.str("relay_satellite")?;
-            enc.str(val)?;
-        }
         if let Some(val) = &self.ground_station {
             enc.str("ground_station")?;
             enc.str(val)?;
         }
+        if let Some(val) = &self.relay_satellite {
+            enc.str("relay_satellite")?;
+            enc.str(val)?;
+        }
         if let Some(val) = self.estimated_delivery {
             enc.str("estimated_delivery")?;
             enc.u64(val)?;
@@ -1077,10 +1537,10 @@ impl TaskCompleted {
         enc.map(len)?;
         enc.str("type")?;
         enc.str("COMPLETED")?;
-        enc.str("task_jti")?;
-        enc.str(&self.task_jti)?;
         enc.str("proof")?;
         self.proof.encode_into(enc)?;
+        enc.str("task_jti")?;
+        enc.str(&self.task_jti)?;
         if let Some(loc) = &self.data_location {
             enc.str("data_location")?;
             loc.encode_into(enc)?;
@@ -1088,6 +1548,14 @@ impl TaskCompleted {
         Ok(())
     }
 
+    /// A completion carries no signature of its own; the proof's covers it.
+    pub fn verify(&self, executor_pubkey: &[u8]) -> Result<(), VerifyError> {
+        if self.proof.task_jti != self.task_jti {
+            return Err(VerifyError::new("completed proof task_jti mismatch"));
+        }
+        self.proof.verify(executor_pubkey)
+    }
+
     fn encode_cbor(&self) -> Result<Vec<u8>, ProtocolError> {
         let mut buf = Vec::new();
         let mut enc = Encoder::new(&mut buf);
@@ -1098,32 +1566,76 @@ impl TaskCompleted {
 
 impl TaskFailed {
     fn encode_into(&self, enc: &mut Encoder<&mut Vec<u8>>) -> Result<(), ProtocolError> {
-        let mut len = 5;
+        self.encode_fields(enc, Some(&self.executor_sig))
+    }
+
+    fn encode_fields(
+        &self,
+        enc: &mut Encoder<&mut Vec<u8>>,
+        executor_sig: Option<&[u8]>,
+    ) -> Result<(), ProtocolError> {
+        let mut len = 4;
         if self.detail.is_some() {
             len += 1;
         }
         if self.partial_proof.is_some() {
             len += 1;
         }
+        if executor_sig.is_some() {
+            len += 1;
+        }
         enc.map(len)?;
         enc.str("type")?;
         enc.str("FAILED")?;
+        if let Some(detail) = &self.detail {
+            enc.str("detail")?;
+            enc.str(detail)?;
+        }
+        enc.str("reason")?;
+        enc.str(&self.reason)?;
         enc.str("task_jti")?;
         enc.str(&self.task_jti)?;
         enc.str("failed_at")?;
         enc.u64(self.failed_at)?;
-        enc.str("reason")?;
-        enc.str(&self.reason)?;
-        if let Some(detail) = &self.detail {
-            enc.str("detail")?;
-            enc.str(detail)?;
+        if let Some(sig) = executor_sig {
+            enc.str("executor_sig")?;
+            enc.bytes(sig)?;
         }
         if let Some(proof) = &self.partial_proof {
             enc.str("partial_proof")?;
             proof.encode_into(enc)?;
         }
-        enc.str("executor_sig")?;
-        enc.bytes(&self.executor_sig)?;
+        Ok(())
+    }
+
+    /// Deterministic CBOR of every field except `executor_sig`. A partial
+    /// proof is included with its own signature.
+    pub fn signing_input(&self) -> Result<Vec<u8>, ProtocolError> {
+        let mut buf = Vec::new();
+        self.encode_fields(&mut Encoder::new(&mut buf), None)?;
+        Ok(buf)
+    }
+
+    /// Sign the partial proof first; its signature is part of this input.
+    pub fn sign(&mut self, executor_key: &Keypair) -> Result<(), ProtocolError> {
+        self.executor_sig = sign_executor(FAILED_TAG, self.signing_input(), executor_key)?;
+        Ok(())
+    }
+
+    pub fn verify(&self, executor_pubkey: &[u8]) -> Result<(), VerifyError> {
+        verify_executor(
+            FAILED_TAG,
+            self.signing_input(),
+            &self.executor_sig,
+            executor_pubkey,
+        )
+        .map_err(|reason| VerifyError::new(format!("failed {reason}")))?;
+        if let Some(proof) = &self.partial_proof {
+            if proof.task_jti != self.task_jti {
+                return Err(VerifyError::new("partial proof task_jti mismatch"));
+            }
+            proof.verify(executor_pubkey)?;
+        }
         Ok(())
     }
 
@@ -1135,12 +1647,193 @@ impl TaskFailed {
     }
 }
 
+impl Heartbeat {
+    pub fn encode_cbor(&self) -> Result<Vec<u8>, ProtocolError> {
+        let mut buf = Vec::new();
+        let mut enc = Encoder::new(&mut buf);
+        self.encode_into(&mut enc)?;
+        Ok(buf)
+    }
+
+    pub fn decode_cbor(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        let mut dec = Decoder::new(bytes);
+        Self::decode_from(&mut dec)
+    }
+
+    fn encode_into(&self, enc: &mut Encoder<&mut Vec<u8>>) -> Result<(), ProtocolError> {
+        let mut len = 3;
+        if self.capabilities.is_some() {
+            len += 1;
+        }
+        enc.map(len)?;
+        enc.str("sender")?;
+        enc.str(&self.sender)?;
+        enc.str("timestamp")?;
+        enc.u64(self.timestamp)?;
+        if let Some(capabilities) = &self.capabilities {
+            enc.str("capabilities")?;
+            enc.array(capabilities.len() as u64)?;
+            for cap in capabilities {
+                enc.str(cap)?;
+            }
+        }
+        enc.str("pending_htlcs")?;
+        enc.u64(self.pending_htlcs)?;
+        Ok(())
+    }
+
+    fn decode_from(dec: &mut Decoder<'_>) -> Result<Self, ProtocolError> {
+        let mut sender = None;
+        let mut timestamp = None;
+        let mut pending_htlcs = None;
+        let mut capabilities = None;
+        decode_map(dec, |key, dec| {
+            match key {
+                "sender" => sender = Some(dec.str()?.to_string()),
+                "timestamp" => timestamp = Some(dec.u64()?),
+                "pending_htlcs" => pending_htlcs = Some(dec.u64()?),
+                "capabilities" => {
+                    let len = dec.array()?.unwrap_or(0);
+                    let mut caps = Vec::new();
+                    for _ in 0..len {
+                        caps.push(dec.str()?.to_string());
+                    }
+                    capabilities = Some(caps);
+                }
+                _ => {
+                    dec.skip()?;
+                }
+            }
+            Ok(())
+        })?;
+        Ok(Heartbeat {
+            sender: sender.ok_or_else(|| ProtocolError::new("heartbeat missing sender"))?,
+            timestamp: timestamp
+                .ok_or_else(|| ProtocolError::new("heartbeat missing timestamp"))?,
+            pending_htlcs: pending_htlcs
+                .ok_or_else(|| ProtocolError::new("heartbeat missing pending_htlcs"))?,
+            capabilities,
+        })
+    }
+}
+
+impl DisputeMessage {
+    pub fn encode_cbor(&self) -> Result<Vec<u8>, ProtocolError> {
+        let mut buf = Vec::new();
+        let mut enc = Encoder::new(&mut buf);
+        self.encode_into(&mut enc)?;
+        Ok(buf)
+    }
+
+    pub fn decode_cbor(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        let mut dec = Decoder::new(bytes);
+        Self::decode_from(&mut dec)
+    }
+
+    fn encode_into(&self, enc: &mut Encoder<&mut Vec<u8>>) -> Result<(), ProtocolError> {
+        let mut len = 4;
+        if self.evidence.is_some() {
+            len += 1;
+        }
+        enc.map(len)?;
+        enc.str("reason")?;
+        enc.str(&self.reason)?;
+        if let Some(evidence) = &self.evidence {
+            enc.str("evidence")?;
+            enc.bytes(evidence)?;
+        }
+        enc.str("task_jti")?;
+        enc.str(&self.task_jti)?;
+        enc.str("timestamp")?;
+        enc.u64(self.timestamp)?;
+        enc.str("payment_hash")?;
+        enc.bytes(&self.payment_hash)?;
+        Ok(())
+    }
+
+    fn decode_from(dec: &mut Decoder<'_>) -> Result<Self, ProtocolError> {
+        let mut task_jti = None;
+        let mut payment_hash = None;
+        let mut reason = None;
+        let mut timestamp = None;
+        let mut evidence = None;
+        decode_map(dec, |key, dec| {
+            match key {
+                "task_jti" => task_jti = Some(dec.str()?.to_string()),
+                "payment_hash" => payment_hash = Some(dec.bytes()?.to_vec()),
+                "reason" => reason = Some(dec.str()?.to_string()),
+                "timestamp" => timestamp = Some(dec.u64()?),
+                "evidence" => evidence = Some(dec.bytes()?.to_vec()),
+                _ => {
+                    dec.skip()?;
+                }
+            }
+            Ok(())
+        })?;
+        Ok(DisputeMessage {
+            task_jti: task_jti.ok_or_else(|| ProtocolError::new("dispute missing task_jti"))?,
+            payment_hash: payment_hash
+                .ok_or_else(|| ProtocolError::new("dispute missing payment_hash"))?,
+            reason: reason.ok_or_else(|| ProtocolError::new("dispute missing reason"))?,
+            timestamp: timestamp.ok_or_else(|| ProtocolError::new("dispute missing timestamp"))?,
+            evidence,
+        })
+    }
+}
+
+impl LightningWrapper {
+    pub fn encode_cbor(&self) -> Result<Vec<u8>, ProtocolError> {
+        let mut buf = Vec::new();
+        let mut enc = Encoder::new(&mut buf);
+        self.encode_into(&mut enc)?;
+        Ok(buf)
+    }
+
+    pub fn decode_cbor(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        let mut dec = Decoder::new(bytes);
+        Self::decode_from(&mut dec)
+    }
+
+    /// The BOLT #1 message type, if the payload is long enough to carry one.
+    pub fn bolt_type(&self) -> Option<u16> {
+        let prefix = self.bolt_payload.get(..2)?;
+        Some(u16::from_be_bytes([prefix[0], prefix[1]]))
+    }
+
+    fn encode_into(&self, enc: &mut Encoder<&mut Vec<u8>>) -> Result<(), ProtocolError> {
+        enc.map(1)?;
+        enc.str("bolt_payload")?;
+        enc.bytes(&self.bolt_payload)?;
+        Ok(())
+    }
+
+    fn decode_from(dec: &mut Decoder<'_>) -> Result<Self, ProtocolError> {
+        let mut bolt_payload = None;
+        decode_map(dec, |key, dec| {
+            match key {
+                "bolt_payload" => bolt_payload = Some(dec.bytes()?.to_vec()),
+                _ => {
+                    dec.skip()?;
+                }
+            }
+            Ok(())
+        })?;
+        Ok(LightningWrapper {
+            bolt_payload: bolt_payload
+                .ok_or_else(|| ProtocolError::new("lightning missing bolt_payload"))?,
+        })
+    }
+}
+
 impl MessageType {
     fn as_str(&self) -> &'static str {
         match self {
             MessageType::TaskRequest => "TASK_REQUEST",
             MessageType::TaskResponse => "TASK_RESPONSE",
             MessageType::Proof => "PROOF",
+            MessageType::Heartbeat => "HEARTBEAT",
+            MessageType::Dispute => "DISPUTE",
+            MessageType::Lightning => "LIGHTNING",
         }
     }
 
@@ -1149,6 +1842,9 @@ impl MessageType {
             "TASK_REQUEST" => Some(MessageType::TaskRequest),
             "TASK_RESPONSE" => Some(MessageType::TaskResponse),
             "PROOF" => Some(MessageType::Proof),
+            "HEARTBEAT" => Some(MessageType::Heartbeat),
+            "DISPUTE" => Some(MessageType::Dispute),
+            "LIGHTNING" => Some(MessageType::Lightning),
             _ => None,
         }
     }
@@ -1156,34 +1852,77 @@ impl MessageType {
 
 impl IslScapMessage {
     pub fn encode_cbor(&self) -> Result<Vec<u8>, ProtocolError> {
+        self.encode_with(self.hmac.as_deref())
+    }
+
+    /// Canonical encoding without the `hmac` field; the bytes the link HMAC covers.
+    pub fn hmac_input(&self) -> Result<Vec<u8>, ProtocolError> {
+        self.encode_with(None)
+    }
+
+    /// Sets `hmac` to HMAC-SHA256 of [`Self::hmac_input`] under the link key.
+    pub fn authenticate(&mut self, link_key: &[u8]) -> Result<(), ProtocolError> {
+        let input = self.hmac_input()?;
+        self.hmac = Some(hmac_sha256(link_key, &input).to_vec());
+        Ok(())
+    }
+
+    pub fn verify_hmac(&self, link_key: &[u8]) -> Result<(), VerifyError> {
+        let hmac = self
+            .hmac
+            .as_deref()
+            .ok_or_else(|| VerifyError::new("isl hmac missing"))?;
+        let input = self
+            .hmac_input()
+            .map_err(|err| VerifyError::new(err.reason))?;
+        let expected = hmac_sha256(link_key, &input);
+        let diff = hmac.len() ^ expected.len()
+            | hmac
+                .iter()
+                .zip(expected.iter())
+                .fold(0usize, |acc, (a, b)| acc | (a ^ b) as usize);
+        if diff != 0 {
+            return Err(VerifyError::new("isl hmac invalid"));
+        }
+        Ok(())
+    }
+
+    fn encode_with(&self, hmac: Option<&[u8]>) -> Result<Vec<u8>, ProtocolError> {
         let mut buf = Vec::new();
         let mut enc = Encoder::new(&mut buf);
         let mut len = 7;
-        if self.hmac.is_some() {
+        if hmac.is_some() {
             len += 1;
         }
         enc.map(len)?;
+        if let Some(hmac) = hmac {
+            enc.str("hmac")?;
+            enc.bytes(hmac)?;
+        }
+        enc.str("sender")?;
+        enc.str(&self.sender)?;
+        enc.str("payload")?;
+        encode_payload(&self.payload, &mut enc)?;
         enc.str("version")?;
         enc.u64(self.version)?;
         enc.str("msg_type")?;
         enc.str(self.msg_type.as_str())?;
-        enc.str("sender")?;
-        enc.str(&self.sender)?;
-        enc.str("recipient")?;
-        enc.str(&self.recipient)?;
         enc.str("sequence")?;
         enc.u64(self.sequence)?;
+        enc.str("recipient")?;
+        enc.str(&self.recipient)?;
         enc.str("timestamp")?;
         enc.u64(self.timestamp)?;
-        enc.str("payload")?;
-        encode_payload(&self.payload, &mut enc)?;
-        if let Some(hmac) = &self.hmac {
-            enc.str("hmac")?;
-            enc.bytes(hmac)?;
-        }
         Ok(buf)
     }
 
+    /// `decode_cbor` that first requires deterministic encoding; see
+    /// `check_canonical_cbor`.
+    pub fn decode_cbor_canonical(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        check_canonical_cbor(bytes)?;
+        Self::decode_cbor(bytes)
+    }
+
     pub fn decode_cbor(bytes: &[u8]) -> Result<Self, ProtocolError> {
         let mut dec = Decoder::new(bytes);
         let mut version = None;
@@ -1192,7 +1931,7 @@ impl IslScapMessage {
         let mut recipient = None;
         let mut sequence = None;
         let mut timestamp = None;
-        let mut payload: Option<ScapPayload> = None;
+        let mut payload_at = None;
         let mut hmac = None;
 
         decode_map(&mut dec, |key, dec| {
@@ -1206,10 +1945,10 @@ impl IslScapMessage {
                 "recipient" => recipient = Some(dec.str()?.to_string()),
                 "sequence" => sequence = Some(dec.u64()?),
                 "timestamp" => timestamp = Some(dec.u64()?),
+                // Canonical order puts payload before msg_type, so decode it afterwards.
                 "payload" => {
-                    let msg_type_val =
-                        msg_type.ok_or_else(|| ProtocolError::new("isl missing msg_type"))?;
-                    payload = Some(decode_payload(msg_type_val, dec)?);
+                    payload_at = Some(dec.position());
+                    dec.skip()?;
                 }
                 "hmac" => hmac = Some(dec.bytes()?.to_vec()),
                 _ => {
@@ -1219,19 +1958,96 @@ impl IslScapMessage {
             Ok(())
         })?;
 
+        let msg_type = msg_type.ok_or_else(|| ProtocolError::new("isl missing msg_type"))?;
+        let payload_at = payload_at.ok_or_else(|| ProtocolError::new("isl missing payload"))?;
+        let mut payload_dec = Decoder::new(bytes);
+        payload_dec.set_position(payload_at);
+        let payload = decode_payload(msg_type, &mut payload_dec)?;
+
         Ok(IslScapMessage {
             version: version.ok_or_else(|| ProtocolError::new("isl missing version"))?,
-            msg_type: msg_type.ok_or_else(|| ProtocolError::new("isl missing msg_type"))?,
+            msg_type,
             sender: sender.ok_or_else(|| ProtocolError::new("isl missing sender"))?,
             recipient: recipient.ok_or_else(|| ProtocolError::new("isl missing recipient"))?,
             sequence: sequence.ok_or_else(|| ProtocolError::new("isl missing sequence"))?,
             timestamp: timestamp.ok_or_else(|| ProtocolError::new("isl missing timestamp"))?,
-            payload: payload.ok_or_else(|| ProtocolError::new("isl missing payload"))?,
+            payload,
             hmac,
         })
     }
 }
 
+/// JSON form: byte fields as hex, `payload` decoded according to `msg_type`.
+impl Serialize for IslScapMessage {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        struct Json<'a> {
+            version: u64,
+            msg_type: MessageType,
+            sender: &'a str,
+            recipient: &'a str,
+            sequence: u64,
+            timestamp: u64,
+            payload: &'a ScapPayload,
+            #[serde(skip_serializing_if = "Option::is_none", with = "hex_bytes_opt")]
+            hmac: &'a Option<Vec<u8>>,
+        }
+        Json {
+            version: self.version,
+            msg_type: self.msg_type,
+            sender: &self.sender,
+            recipient: &self.recipient,
+            sequence: self.sequence,
+            timestamp: self.timestamp,
+            payload: &self.payload,
+            hmac: &self.hmac,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for IslScapMessage {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+
+        #[derive(Deserialize)]
+        struct Json {
+            version: u64,
+            msg_type: MessageType,
+            sender: String,
+            recipient: String,
+            sequence: u64,
+            timestamp: u64,
+            payload: serde_json::Value,
+            #[serde(default, with = "hex_bytes_opt")]
+            hmac: Option<Vec<u8>>,
+        }
+        let json = Json::deserialize(deserializer)?;
+        let value = json.payload;
+        let payload = match json.msg_type {
+            MessageType::TaskRequest => serde_json::from_value(value).map(ScapPayload::TaskRequest),
+            MessageType::TaskResponse => {
+                serde_json::from_value(value).map(ScapPayload::TaskResponse)
+            }
+            MessageType::Proof => serde_json::from_value(value).map(ScapPayload::Proof),
+            MessageType::Heartbeat => serde_json::from_value(value).map(ScapPayload::Heartbeat),
+            MessageType::Dispute => serde_json::from_value(value).map(ScapPayload::Dispute),
+            MessageType::Lightning => serde_json::from_value(value).map(ScapPayload::Lightning),
+        }
+        .map_err(D::Error::custom)?;
+        Ok(IslScapMessage {
+            version: json.version,
+            msg_type: json.msg_type,
+            sender: json.sender,
+            recipient: json.recipient,
+            sequence: json.sequence,
+            timestamp: json.timestamp,
+            payload,
+            hmac: json.hmac,
+        })
+    }
+}
+
 fn encode_payload(payload: &ScapPayload, enc: &mut Encoder<&mut Vec<u8>>) -> Result<(), ProtocolError> {
     match payload {
         ScapPayload::TaskRequest(req) => req.encode_into(enc)?,
@@ -1242,6 +2058,9 @@ fn encode_payload(payload: &ScapPayload, enc: &mut Encoder<&mut Vec<u8>>) -> Res
             TaskResponse::Failed(failed) => failed.encode_into(enc)?,
         },
         ScapPayload::Proof(proof) => proof.encode_into(enc)?,
+        ScapPayload::Heartbeat(heartbeat) => heartbeat.encode_into(enc)?,
+        ScapPayload::Dispute(dispute) => dispute.encode_into(enc)?,
+        ScapPayload::Lightning(wrapper) => wrapper.encode_into(enc)?,
     }
     Ok(())
 }
@@ -1254,6 +2073,9 @@ fn decode_payload(
         MessageType::TaskRequest => Ok(ScapPayload::TaskRequest(BoundTaskRequest::decode_cbor_from(dec)?)),
         MessageType::TaskResponse => Ok(ScapPayload::TaskResponse(TaskResponse::decode_from(dec)?)),
         MessageType::Proof => Ok(ScapPayload::Proof(ExecutionProof::decode_cbor_from(dec)?)),
+        MessageType::Heartbeat => Ok(ScapPayload::Heartbeat(Heartbeat::decode_from(dec)?)),
+        MessageType::Dispute => Ok(ScapPayload::Dispute(DisputeMessage::decode_from(dec)?)),
+        MessageType::Lightning => Ok(ScapPayload::Lightning(LightningWrapper::decode_from(dec)?)),
     }
 }
 
@@ -1264,6 +2086,7 @@ impl BoundTaskRequest {
         let mut payment_amount_msat = None;
         let mut htlc_timeout_blocks = None;
         let mut binding_sig = None;
+        let mut proof_chain = Vec::new();
 
         decode_map(dec, |key, dec| {
             match key {
@@ -1272,6 +2095,12 @@ impl BoundTaskRequest {
                 "payment_amount_msat" => payment_amount_msat = Some(dec.u64()?),
                 "htlc_timeout_blocks" => htlc_timeout_blocks = Some(decode_u32(dec)?),
                 "binding_sig" => binding_sig = Some(dec.bytes()?.to_vec()),
+                "proof_chain" => {
+                    let len = dec.array()?.unwrap_or(0);
+                    for _ in 0..len {
+                        proof_chain.push(dec.bytes()?.to_vec());
+                    }
+                }
                 _ => {
                     dec.skip()?;
                 }
@@ -1290,6 +2119,7 @@ impl BoundTaskRequest {
                 .ok_or_else(|| ProtocolError::new("bound task missing htlc_timeout_blocks"))?,
             binding_sig: binding_sig
                 .ok_or_else(|| ProtocolError::new("bound task missing binding_sig"))?,
+            proof_chain,
         })
     }
 }
@@ -1333,6 +2163,94 @@ impl ExecutionProof {
     }
 }
 
+fn sign_executor(
+    tag: &str,
+    input: Result<Vec<u8>, ProtocolError>,
+    executor_key: &Keypair,
+) -> Result<Vec<u8>, ProtocolError> {
+    Ok(sign_tagged(tag, &input?, executor_key)?.to_vec())
+}
+
+fn verify_executor(
+    tag: &str,
+    input: Result<Vec<u8>, ProtocolError>,
+    executor_sig: &[u8],
+    executor_pubkey: &[u8],
+) -> Result<(), String> {
+    let xonly = parse_xonly(executor_pubkey)?;
+    let signature: [u8; 64] = executor_sig
+        .try_into()
+        .map_err(|_| "executor_sig length invalid".to_string())?;
+    let input = input.map_err(|err| err.reason)?;
+    if !verify_schnorr(&tagged_hash(tag, &input), &signature, &xonly) {
+        return Err("executor_sig invalid".to_string());
+    }
+    Ok(())
+}
+
+/// Serde adapter for byte fields, which JSON carries as hex.
+mod hex_bytes {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        let hex: String = bytes.iter().map(|b| format!("{b:02x}")).collect();
+        serializer.serialize_str(&hex)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        crate::hex_to_bytes(&hex).map_err(|err| serde::de::Error::custom(err.reason))
+    }
+}
+
+mod hex_bytes_opt {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        bytes: &Option<Vec<u8>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match bytes {
+            Some(bytes) => super::hex_bytes::serialize(bytes, serializer),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Vec<u8>>, D::Error> {
+        match Option::<String>::deserialize(deserializer)? {
+            Some(hex) => crate::hex_to_bytes(&hex)
+                .map(Some)
+                .map_err(|err| serde::de::Error::custom(err.reason)),
+            None => Ok(None),
+        }
+    }
+}
+
+mod hex_bytes_list {
+    use serde::ser::SerializeSeq;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(list: &[Vec<u8>], serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(list.len()))?;
+        for bytes in list {
+            let hex: String = bytes.iter().map(|b| format!("{b:02x}")).collect();
+            seq.serialize_element(&hex)?;
+        }
+        seq.end()
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<Vec<u8>>, D::Error> {
+        Vec::<String>::deserialize(deserializer)?
+            .iter()
+            .map(|hex| crate::hex_to_bytes(hex).map_err(|err| serde::de::Error::custom(err.reason)))
+            .collect()
+    }
+}
+
 fn decode_map<F>(dec: &mut Decoder<'_>, mut f: F) -> Result<(), ProtocolError>
 where
     F: FnMut(&str, &mut Decoder<'_>) -> Result<(), ProtocolError>,
@@ -1384,20 +2302,11 @@ fn to_err<E: core::fmt::Display>(err: E) -> ProtocolError {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::hex_to_bytes;
+    use crate::{hex_to_bytes, keypair_from_secret, pubkey_from_secret};
     use std::path::PathBuf;
 
-    fn spec_root() -> PathBuf {
-        let mut root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
-        root.push("..");
-        root.push("..");
-        root.push("..");
-        root.push("scrap-master");
-        root
-    }
-
     fn examples_dir() -> PathBuf {
-        spec_root().join("schemas").join("examples")
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("fixtures")
     }
 
     fn read_bytes(path: &PathBuf) -> Vec<u8> {
@@ -1409,67 +2318,6 @@ mod tests {
         serde_json::from_str(&raw).expect("parse json")
     }
 
-    fn decode_dispute_fields(bytes: &[u8]) -> (String, Vec<u8>) {
-        let mut dec = Decoder::new(bytes);
-        let mut task_jti: Option<String> = None;
-        let mut payment_hash: Option<Vec<u8>> = None;
-        decode_map(&mut dec, |key, dec| {
-            match key {
-                "task_jti" => task_jti = Some(dec.str()?.to_string()),
-                "payment_hash" => payment_hash = Some(dec.bytes()?.to_vec()),
-                _ => {
-                    dec.skip()?;
-                }
-            }
-            Ok(())
-        })
-        .expect("decode dispute");
-
-        (
-            task_jti.expect("task_jti"),
-            payment_hash.expect("payment_hash"),
-        )
-    }
-
-    fn decode_heartbeat_fields(bytes: &[u8]) -> (String, u64) {
-        let mut dec = Decoder::new(bytes);
-        let mut sender: Option<String> = None;
-        let mut pending_htlcs: Option<u64> = None;
-        decode_map(&mut dec, |key, dec| {
-            match key {
-                "sender" => sender = Some(dec.str()?.to_string()),
-                "pending_htlcs" => pending_htlcs = Some(dec.u64()?),
-                _ => {
-                    dec.skip()?;
-                }
-            }
-            Ok(())
-        })
-        .expect("decode heartbeat");
-
-        (
-            sender.expect("sender"),
-            pending_htlcs.expect("pending_htlcs"),
-        )
-    }
-
-    fn decode_lightning_bolt_payload(bytes: &[u8]) -> Vec<u8> {
-        let mut dec = Decoder::new(bytes);
-        let mut bolt_payload: Option<Vec<u8>> = None;
-        decode_map(&mut dec, |key, dec| {
-            match key {
-                "bolt_payload" => bolt_payload = Some(dec.bytes()?.to_vec()),
-                _ => {
-                    dec.skip()?;
-                }
-            }
-            Ok(())
-        })
-        .expect("decode lightning wrapper");
-
-        bolt_payload.expect("bolt_payload")
-    }
-
     #[test]
     fn decode_capability_token_fixture() {
         let cbor_path = examples_dir().join("capability_token.cbor");
@@ -1604,16 +2452,19 @@ mod tests {
     fn decode_dispute_and_heartbeat_fixtures() {
         let dispute_cbor = read_bytes(&examples_dir().join("dispute_message.cbor"));
         let dispute_json = read_json(&examples_dir().join("dispute_message.json"));
-        let (task_jti, payment_hash) = decode_dispute_fields(&dispute_cbor);
-        assert_eq!(task_jti, dispute_json["task_jti"].as_str().unwrap());
+        let dispute = DisputeMessage::decode_cbor(&dispute_cbor).expect("decode dispute");
+        assert_eq!(dispute.task_jti, dispute_json["task_jti"].as_str().unwrap());
         let expected_hash = hex_to_bytes(dispute_json["payment_hash"].as_str().unwrap()).unwrap();
-        assert_eq!(payment_hash, expected_hash);
+        assert_eq!(dispute.payment_hash, expected_hash);
 
         let heartbeat_cbor = read_bytes(&examples_dir().join("heartbeat.cbor"));
         let heartbeat_json = read_json(&examples_dir().join("heartbeat.json"));
-        let (sender, pending) = decode_heartbeat_fields(&heartbeat_cbor);
-        assert_eq!(sender, heartbeat_json["sender"].as_str().unwrap());
-        assert_eq!(pending, heartbeat_json["pending_htlcs"].as_u64().unwrap());
+        let heartbeat = Heartbeat::decode_cbor(&heartbeat_cbor).expect("decode heartbeat");
+        assert_eq!(heartbeat.sender, heartbeat_json["sender"].as_str().unwrap());
+        assert_eq!(
+            heartbeat.pending_htlcs,
+            heartbeat_json["pending_htlcs"].as_u64().unwrap()
+        );
     }
 
     #[test]
@@ -1639,8 +2490,276 @@ mod tests {
         assert!(matches!(failed, TaskResponse::Failed(_)));
 
         let lightning_cbor = read_bytes(&examples_dir().join("lightning_wrapper.cbor"));
-        let bolt_payload = decode_lightning_bolt_payload(&lightning_cbor);
-        assert!(!bolt_payload.is_empty());
+        let wrapper = LightningWrapper::decode_cbor(&lightning_cbor).expect("decode lightning");
+        assert!(!wrapper.bolt_payload.is_empty());
+    }
+
+    const EXECUTOR_SECRET: &str =
+        "4444444444444444444444444444444444444444444444444444444444444444";
+
+    fn resign(response: &mut TaskResponse, key: &Keypair) {
+        match response {
+            TaskResponse::Accepted(accepted) => accepted.sign(key).unwrap(),
+            TaskResponse::Rejected(rejected) => rejected.sign(key).unwrap(),
+            TaskResponse::Completed(completed) => completed.proof.sign(key).unwrap(),
+            TaskResponse::Failed(failed) => {
+                if let Some(proof) = failed.partial_proof.as_mut() {
+                    proof.sign(key).unwrap();
+                }
+                failed.sign(key).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn sign_and_verify_task_responses() {
+        let key = keypair_from_secret(EXECUTOR_SECRET).unwrap();
+        let executor = pubkey_from_secret(EXECUTOR_SECRET).unwrap();
+        let other = keypair_from_secret(&"55".repeat(32)).unwrap();
+        let proof = ExecutionProof {
+            task_jti: "task-1".to_string(),
+            payment_hash: vec![1u8; 32],
+            output_hash: vec![2u8; 32],
+            execution_timestamp: 30,
+            output_metadata: Some(OutputMetadata {
+                data_size_bytes: Some(1024),
+                data_format: Some("GeoTIFF".to_string()),
+                coverage_km2: Some(12.5),
+                acquisition_start: Some(20),
+                acquisition_end: Some(29),
+                sensor_mode: Some("msi".to_string()),
+                content_type: None,
+                size_bytes: None,
+                storage_location: Some("onboard://1".to_string()),
+            }),
+            executor_sig: Vec::new(),
+        };
+        let mut responses = [
+            TaskResponse::Accepted(TaskAccepted {
+                task_jti: "task-1".to_string(),
+                accepted_at: 10,
+                estimated_completion: 40,
+                executor_sig: Vec::new(),
+            }),
+            TaskResponse::Rejected(TaskRejected {
+                task_jti: "task-1".to_string(),
+                rejected_at: 10,
+                reason: "CONSTRAINT_VIOLATION".to_string(),
+                detail: Some("outside bounds".to_string()),
+                executor_sig: Vec::new(),
+            }),
+            TaskResponse::Completed(TaskCompleted {
+                task_jti: "task-1".to_string(),
+                proof: proof.clone(),
+                data_location: Some(DataLocation {
+                    method: "ISL_RELAY".to_string(),
+                    relay_satellite: Some("SAT-2".to_string()),
+                    ground_station: None,
+                    estimated_delivery: Some(90),
+                }),
+            }),
+            TaskResponse::Failed(TaskFailed {
+                task_jti: "task-1".to_string(),
+                failed_at: 35,
+                reason: "SENSOR_FAULT".to_string(),
+                detail: None,
+                partial_proof: Some(proof.clone()),
+                executor_sig: Vec::new(),
+            }),
+        ];
+        for response in responses.iter_mut() {
+            resign(response, &key);
+            let decoded = TaskResponse::decode_cbor(&response.encode_cbor().unwrap()).unwrap();
+            assert_eq!(&decoded, response);
+            assert!(decoded.verify(&executor).is_ok(), "{decoded:?}");
+
+            let mut forged = decoded.clone();
+            resign(&mut forged, &other);
+            assert!(forged.verify(&executor).is_err(), "{forged:?}");
+        }
+
+        let TaskResponse::Accepted(mut accepted) = responses[0].clone() else {
+            unreachable!()
+        };
+        accepted.estimated_completion += 1;
+        assert!(accepted.verify(&executor).is_err());
+
+        // The outer signature holds but the partial proof is someone else's.
+        let mut failed = TaskFailed {
+            task_jti: "task-1".to_string(),
+            failed_at: 35,
+            reason: "SENSOR_FAULT".to_string(),
+            detail: None,
+            partial_proof: Some(proof.clone()),
+            executor_sig: Vec::new(),
+        };
+        failed.partial_proof.as_mut().unwrap().sign(&other).unwrap();
+        failed.sign(&key).unwrap();
+        assert_eq!(
+            failed.verify(&executor).unwrap_err().reason,
+            "proof executor_sig invalid"
+        );
+        let mut mislabelled = proof;
+        mislabelled.task_jti = "task-2".to_string();
+        mislabelled.sign(&key).unwrap();
+        failed.partial_proof = Some(mislabelled);
+        failed.sign(&key).unwrap();
+        assert_eq!(
+            failed.verify(&executor).unwrap_err().reason,
+            "partial proof task_jti mismatch"
+        );
+    }
+
+    #[test]
+    fn isl_message_hmac_and_canonical_order() {
+        let link_key = [7u8; 32];
+        let mut message = IslScapMessage {
+            version: 1,
+            msg_type: MessageType::TaskResponse,
+            sender: "SAT-B".to_string(),
+            recipient: "SAT-A".to_string(),
+            sequence: 4,
+            timestamp: 100,
+            payload: ScapPayload::TaskResponse(TaskResponse::Accepted(TaskAccepted {
+                task_jti: "task-1".to_string(),
+                accepted_at: 100,
+                estimated_completion: 160,
+                executor_sig: vec![9u8; 64],
+            })),
+            hmac: None,
+        };
+        assert_eq!(
+            message.verify_hmac(&link_key).unwrap_err().reason,
+            "isl hmac missing"
+        );
+        message.authenticate(&link_key).unwrap();
+
+        let encoded = message.encode_cbor().unwrap();
+        let mut dec = Decoder::new(&encoded);
+        let mut keys = Vec::new();
+        decode_map(&mut dec, |key, dec| {
+            keys.push(key.to_string());
+            dec.skip()?;
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(
+            keys,
+            [
+                "hmac",
+                "sender",
+                "payload",
+                "version",
+                "msg_type",
+                "sequence",
+                "recipient",
+                "timestamp"
+            ]
+        );
+
+        let decoded = IslScapMessage::decode_cbor(&encoded).unwrap();
+        assert_eq!(decoded, message);
+        assert!(decoded.verify_hmac(&link_key).is_ok());
+        assert_eq!(
+            decoded.verify_hmac(&[8u8; 32]).unwrap_err().reason,
+            "isl hmac invalid"
+        );
+        let mut replayed = decoded;
+        replayed.sequence += 1;
+        assert_eq!(
+            replayed.verify_hmac(&link_key).unwrap_err().reason,
+            "isl hmac invalid"
+        );
+    }
+
+    #[test]
+    fn link_payloads_roundtrip_through_isl() {
+        let payloads = [
+            ScapPayload::Heartbeat(Heartbeat {
+                sender: "SAT-B".to_string(),
+                timestamp: 100,
+                pending_htlcs: 2,
+                capabilities: Some(vec!["cmd:imaging:msi".to_string()]),
+            }),
+            ScapPayload::Dispute(DisputeMessage {
+                task_jti: "task-1".to_string(),
+                payment_hash: vec![1u8; 32],
+                reason: "PROOF_INVALID".to_string(),
+                timestamp: 120,
+                evidence: Some(vec![2u8; 32]),
+            }),
+            // BOLT #1 ping: type 18, num_pong_bytes, byteslen.
+            ScapPayload::Lightning(LightningWrapper {
+                bolt_payload: vec![0x00, 0x12, 0x00, 0x04, 0x00, 0x00],
+            }),
+        ];
+        let msg_types = [
+            MessageType::Heartbeat,
+            MessageType::Dispute,
+            MessageType::Lightning,
+        ];
+        for (payload, msg_type) in payloads.into_iter().zip(msg_types) {
+            let message = IslScapMessage {
+                version: 1,
+                msg_type,
+                sender: "SAT-B".to_string(),
+                recipient: "SAT-A".to_string(),
+                sequence: 1,
+                timestamp: 100,
+                payload,
+                hmac: None,
+            };
+            let decoded = IslScapMessage::decode_cbor(&message.encode_cbor().unwrap()).unwrap();
+            assert_eq!(decoded, message);
+        }
+
+        let heartbeat = Heartbeat {
+            sender: "SAT-B".to_string(),
+            timestamp: 100,
+            pending_htlcs: 0,
+            capabilities: None,
+        };
+        let bytes = heartbeat.encode_cbor().unwrap();
+        assert_eq!(Heartbeat::decode_cbor(&bytes).unwrap(), heartbeat);
+        assert_eq!(
+            DisputeMessage::decode_cbor(&bytes).unwrap_err().reason,
+            "dispute missing task_jti"
+        );
+
+        let ping = LightningWrapper {
+            bolt_payload: vec![0x00, 0x12, 0x00, 0x04],
+        };
+        assert_eq!(ping.bolt_type(), Some(18));
+        let empty = LightningWrapper {
+            bolt_payload: vec![0x00],
+        };
+        assert_eq!(empty.bolt_type(), None);
+    }
+
+    #[test]
+    fn resign_response_fixtures() {
+        let key = keypair_from_secret(EXECUTOR_SECRET).unwrap();
+        let executor = pubkey_from_secret(EXECUTOR_SECRET).unwrap();
+        for name in [
+            "task_accepted",
+            "task_rejected",
+            "task_completed",
+            "task_failed",
+        ] {
+            let bytes = read_bytes(&examples_dir().join(format!("{name}.cbor")));
+            let mut response = TaskResponse::decode_cbor(&bytes).expect(name);
+            resign(&mut response, &key);
+            let decoded = TaskResponse::decode_cbor(&response.encode_cbor().unwrap()).unwrap();
+            assert_eq!(decoded, response, "{name}");
+            assert!(decoded.verify(&executor).is_ok(), "{name}");
+        }
+
+        let bytes = read_bytes(&examples_dir().join("execution_proof.cbor"));
+        let mut proof = ExecutionProof::decode_cbor(&bytes).expect("decode proof");
+        proof.sign(&key).unwrap();
+        let decoded = ExecutionProof::decode_cbor(&proof.encode_cbor().unwrap()).unwrap();
+        assert_eq!(decoded, proof);
+        assert!(decoded.verify(&executor).is_ok());
     }
 
     #[test]
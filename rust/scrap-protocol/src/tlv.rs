@@ -83,7 +83,17 @@ pub fn encode_records(records: &[TlvRecord]) -> Result<Vec<u8>, ProtocolError> {
     Ok(out)
 }
 
+/// Decodes records in strictly ascending type order.
 pub fn decode_records(bytes: &[u8]) -> Result<Vec<TlvRecord>, ProtocolError> {
+    decode_records_repeating(bytes, &[])
+}
+
+/// `decode_records` where the types in `repeatable` may appear several times
+/// in a row, e.g. one record per capability.
+pub fn decode_records_repeating(
+    bytes: &[u8],
+    repeatable: &[u64],
+) -> Result<Vec<TlvRecord>, ProtocolError> {
     let mut idx = 0usize;
     let mut records = Vec::new();
     let mut last_type: Option<u64> = None;
@@ -98,7 +108,12 @@ pub fn decode_records(bytes: &[u8]) -> Result<Vec<TlvRecord>, ProtocolError> {
         }
         if let Some(prev) = last_type {
             if t < prev {
-                return Err(ProtocolError::new("tlv: types must be ascending"));
+                return Err(ProtocolError::new(format!(
+                    "tlv: type {t} after {prev}, types must be ascending"
+                )));
+            }
+            if t == prev && !repeatable.contains(&t) {
+                return Err(ProtocolError::new(format!("tlv: type {t} repeated")));
             }
         }
         last_type = Some(t);
@@ -109,6 +124,15 @@ pub fn decode_records(bytes: &[u8]) -> Result<Vec<TlvRecord>, ProtocolError> {
     Ok(records)
 }
 
+/// "It's OK to be odd": a record type the decoder does not know is an error
+/// when even and ignored when odd.
+pub fn check_unknown_type(t: u64) -> Result<(), ProtocolError> {
+    if t & 1 == 1 {
+        return Ok(());
+    }
+    Err(ProtocolError::new(format!("unknown even tlv type {t}")))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -143,4 +167,37 @@ mod tests {
         assert_eq!(decoded[0].t, 0);
         assert_eq!(decoded[1].v, b"hello");
     }
+
+    #[test]
+    fn records_must_be_strictly_ascending() {
+        let record = |t: u64| TlvRecord { t, v: vec![t as u8] };
+        let twice = encode_records(&[record(2), record(4), record(4)]).unwrap();
+        assert_eq!(
+            decode_records(&twice).unwrap_err().reason,
+            "tlv: type 4 repeated"
+        );
+        assert_eq!(decode_records_repeating(&twice, &[4]).unwrap().len(), 3);
+
+        let mut reversed = Vec::new();
+        for t in [4u64, 2] {
+            write_bigsize(t, &mut reversed);
+            write_bigsize(1, &mut reversed);
+            reversed.push(t as u8);
+        }
+        assert_eq!(
+            decode_records_repeating(&reversed, &[2, 4])
+                .unwrap_err()
+                .reason,
+            "tlv: type 2 after 4, types must be ascending"
+        );
+    }
+
+    #[test]
+    fn unknown_types_ok_to_be_odd() {
+        assert!(check_unknown_type(31).is_ok());
+        assert_eq!(
+            check_unknown_type(30).unwrap_err().reason,
+            "unknown even tlv type 30"
+        );
+    }
 }
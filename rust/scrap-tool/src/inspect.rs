@@ -0,0 +1,1447 @@
+use crate::report::{relative, to_hex, utc, Report};
+use scrap_core_lite::{Envelope, Payload, RouteTable};
+use scrap_edge::{handle_envelope, Action, Context, ReplayCache, TokenVerifier};
+use scrap_linux_udp::{hex_encode, load_revoked, DevTokenVerifier};
+use scrap_protocol::{
+    audience_matches, derive_payment_hash, derive_preimage, normalize_pubkey, parse_xonly, sha256,
+    tagged_hash, verify_schnorr, BoundTaskRequest, CapPayload, ConstraintChecker, Constraints,
+    DemoMessage, DemoTaskRequest, DemoToken, ExecutionProof, IslScapMessage, SatCapToken,
+    ScapPayload, SpecMessage, SpecPaymentClaim, SpecPaymentLock, SpecProofOfExecution,
+    SpecTaskAccept, SpecTaskReject, SpecTaskRequest, SpecToken, SpecVerifier, TaskResponse,
+    Verifier,
+};
+use std::fs;
+
+/// What the receiving node knows: its identity, keys, revocation and replay
+/// state, and the time at which the input is evaluated.
+#[derive(Debug, Default)]
+pub struct Node {
+    pub at: u64,
+    pub node_id: Option<String>,
+    pub operator_pubkey: Option<Vec<u8>>,
+    pub executor_pubkey: Option<Vec<u8>>,
+    pub commander_pubkey: Option<String>,
+    pub routes: Option<RouteTable>,
+    /// HMAC key shared on the inter-satellite link.
+    pub link_key: Option<Vec<u8>>,
+    pub revoked_path: Option<String>,
+    pub replay_cache_path: Option<String>,
+    pub allow_mock_signatures: bool,
+}
+
+impl Node {
+    fn spec_verifier(&self) -> Option<SpecVerifier> {
+        Some(SpecVerifier {
+            operator_pubkey: self.operator_pubkey.clone()?,
+            executor_pubkey: self.executor_pubkey.clone()?,
+        })
+    }
+}
+
+/// Replay lookup against the node's cache file that never records the id.
+struct PeekReplayCache {
+    seen: Vec<String>,
+}
+
+impl ReplayCache for PeekReplayCache {
+    fn check_and_add(&mut self, token_id: &[u8]) -> bool {
+        let token_hex = hex_encode(token_id);
+        !self.seen.iter().any(|item| item == &token_hex)
+    }
+}
+
+fn load_string_list(path: &str) -> Vec<String> {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|raw| serde_json::from_str::<Vec<String>>(&raw).ok())
+        .unwrap_or_default()
+}
+
+fn display_id(bytes: &[u8]) -> String {
+    if bytes.len() != 32 && bytes.len() != 33 {
+        if let Ok(text) = std::str::from_utf8(bytes) {
+            if !text.is_empty() && text.chars().all(|c| c.is_ascii_graphic()) {
+                return format!("{text} (utf-8)");
+            }
+        }
+    }
+    to_hex(bytes)
+}
+
+fn short_hex(bytes: &[u8]) -> String {
+    let hex = to_hex(bytes);
+    if hex.len() > 16 {
+        format!("{}…", &hex[..16])
+    } else {
+        hex
+    }
+}
+
+fn expiry(expires_at: u64, at: u64) -> Result<String, String> {
+    if expires_at > at {
+        Ok(format!(
+            "expires {} ({})",
+            utc(expires_at),
+            relative(expires_at, at)
+        ))
+    } else {
+        Err(format!(
+            "expired {} ({})",
+            utc(expires_at),
+            relative(expires_at, at)
+        ))
+    }
+}
+
+fn same_key(a: &[u8], b: &[u8]) -> bool {
+    match (normalize_pubkey(a), normalize_pubkey(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+fn spec_signed_by(tag: &str, token: &SpecToken, key: &[u8]) -> Result<String, String> {
+    let xonly = parse_xonly(key)?;
+    let unsigned = token
+        .encode_tlv_without_signature()
+        .map_err(|err| err.reason)?;
+    if verify_schnorr(&tagged_hash(tag, &unsigned), &token.signature, &xonly) {
+        Ok(format!("{tag} by {}", short_hex(key)))
+    } else {
+        Err(format!(
+            "{tag} signature does not verify against {}",
+            short_hex(key)
+        ))
+    }
+}
+
+fn verdict_from(result: Result<(), scrap_protocol::VerifyError>) -> Result<String, String> {
+    result
+        .map(|_| "accept".to_string())
+        .map_err(|err| err.reason)
+}
+
+pub fn spec_token_fields(report: &mut Report, prefix: &str, token: &SpecToken) {
+    report.field(format!("{prefix}version"), token.version);
+    report.field(format!("{prefix}issuer"), display_id(&token.issuer));
+    report.field(format!("{prefix}subject"), display_id(&token.subject));
+    report.field(format!("{prefix}audience"), display_id(&token.audience));
+    report.ts(format!("{prefix}issued_at"), token.issued_at as u64);
+    report.ts(format!("{prefix}expires_at"), token.expires_at as u64);
+    report.hex(format!("{prefix}token_id"), &token.token_id);
+    report.field(
+        format!("{prefix}capabilities"),
+        token.capabilities.join(", "),
+    );
+    let constraints = &token.constraints;
+    if let Some(geo) = &constraints.geo {
+        report.field(format!("{prefix}constraints.geo"), geo);
+    }
+    if let Some((count, period)) = constraints.rate {
+        report.field(
+            format!("{prefix}constraints.rate"),
+            format!("{count} per {period}s"),
+        );
+    }
+    if let Some(amount) = constraints.amount {
+        report.field(
+            format!("{prefix}constraints.amount"),
+            format!("{amount} sats"),
+        );
+    }
+    if let Some(after) = constraints.not_before {
+        report.ts(format!("{prefix}constraints.not_before"), after as u64);
+    }
+    let delegation = &token.delegation;
+    if let Some(root) = &delegation.root_issuer {
+        report.field(format!("{prefix}delegation.root_issuer"), display_id(root));
+    }
+    if let Some(root_id) = &delegation.root_token_id {
+        report.hex(format!("{prefix}delegation.root_token_id"), root_id);
+    }
+    if let Some(parent_id) = &delegation.parent_token_id {
+        report.hex(format!("{prefix}delegation.parent_token_id"), parent_id);
+    }
+    if let Some(depth) = delegation.chain_depth {
+        report.field(format!("{prefix}delegation.chain_depth"), depth);
+    }
+    report.hex(format!("{prefix}signature"), &token.signature);
+}
+
+pub fn spec_token_checks(
+    report: &mut Report,
+    prefix: &str,
+    token: &SpecToken,
+    chain: &[SpecToken],
+    node: &Node,
+) {
+    let mut full_chain: Vec<&SpecToken> = chain.iter().collect();
+    full_chain.push(token);
+    let root = full_chain[0];
+    let at = node.at;
+
+    if chain.is_empty() && token.delegation.chain_depth.unwrap_or(0) != 0 {
+        report.check(
+            format!("{prefix}chain"),
+            Err(format!(
+                "delegated token (depth {}) but no parent tokens supplied",
+                token.delegation.chain_depth.unwrap_or(0)
+            )),
+        );
+    }
+
+    let root_name = if chain.is_empty() {
+        format!("{prefix}token")
+    } else {
+        format!("{prefix}root")
+    };
+    report.check(
+        format!("{root_name}.signature"),
+        spec_signed_by("SCRAP/token/v1", root, &root.issuer),
+    );
+    match &node.operator_pubkey {
+        Some(operator) if same_key(&root.issuer, operator) => {
+            report.check(
+                format!("{root_name}.issuer"),
+                Ok("issued by the operator".to_string()),
+            );
+        }
+        Some(operator) => {
+            report.check(
+                format!("{root_name}.issuer"),
+                Err(format!(
+                    "issuer {} is not operator {}",
+                    short_hex(&root.issuer),
+                    short_hex(operator)
+                )),
+            );
+        }
+        None => report.skip(format!("{root_name}.issuer"), "no operator pubkey supplied"),
+    }
+    if root.delegation.chain_depth.unwrap_or(0) != 0 && !chain.is_empty() {
+        report.check(
+            format!("{root_name}.chain_depth"),
+            Err("root token carries a non-zero chain_depth".to_string()),
+        );
+    }
+    match &node.executor_pubkey {
+        Some(executor) => {
+            let result = match audience_matches(&root.audience, executor) {
+                Ok(true) => Ok(format!("audience matches executor {}", short_hex(executor))),
+                Ok(false) => Err(format!(
+                    "audience {} is neither executor {} nor its key-id",
+                    display_id(&root.audience),
+                    short_hex(executor)
+                )),
+                Err(err) => Err(err.reason),
+            };
+            report.check(format!("{root_name}.audience"), result);
+        }
+        None => report.skip(
+            format!("{root_name}.audience"),
+            "no executor pubkey supplied",
+        ),
+    }
+    report.check(
+        format!("{root_name}.expiry"),
+        expiry(root.expires_at as u64, at),
+    );
+
+    for i in 1..full_chain.len() {
+        let parent = full_chain[i - 1];
+        let child = full_chain[i];
+        let name = if i == full_chain.len() - 1 {
+            format!("{prefix}leaf")
+        } else {
+            format!("{prefix}chain[{i}]")
+        };
+        if child.issuer == parent.subject {
+            report.check(
+                format!("{name}.issuer"),
+                Ok("issued by parent subject".to_string()),
+            );
+        } else {
+            report.check(
+                format!("{name}.issuer"),
+                Err(format!(
+                    "issuer {} is not parent subject {}",
+                    short_hex(&child.issuer),
+                    short_hex(&parent.subject)
+                )),
+            );
+        }
+        report.check(
+            format!("{name}.signature"),
+            spec_signed_by("SCRAP/delegation/v1", child, &parent.subject),
+        );
+        if child.expires_at <= parent.expires_at {
+            report.check(
+                format!("{name}.narrow_expiry"),
+                Ok("within parent".to_string()),
+            );
+        } else {
+            report.check(
+                format!("{name}.narrow_expiry"),
+                Err(format!(
+                    "expires {} after parent {}",
+                    utc(child.expires_at as u64),
+                    utc(parent.expires_at as u64)
+                )),
+            );
+        }
+        let widened: Vec<&str> = child
+            .capabilities
+            .iter()
+            .filter(|cap| !parent.allows(cap))
+            .map(|cap| cap.as_str())
+            .collect();
+        if widened.is_empty() {
+            report.check(
+                format!("{name}.narrow_capabilities"),
+                Ok("subset of parent".to_string()),
+            );
+        } else {
+            report.check(
+                format!("{name}.narrow_capabilities"),
+                Err(format!("not granted by parent: {}", widened.join(", "))),
+            );
+        }
+        let expected_depth = parent.delegation.chain_depth.unwrap_or(0) + 1;
+        if child.delegation.chain_depth == Some(expected_depth) {
+            report.check(
+                format!("{name}.chain_depth"),
+                Ok(format!("{expected_depth}")),
+            );
+        } else {
+            report.check(
+                format!("{name}.chain_depth"),
+                Err(format!(
+                    "expected {expected_depth}, found {:?}",
+                    child.delegation.chain_depth
+                )),
+            );
+        }
+        match &node.operator_pubkey {
+            Some(operator)
+                if child.delegation.root_issuer.as_deref() == Some(operator.as_slice()) =>
+            {
+                report.check(format!("{name}.root_issuer"), Ok("operator".to_string()));
+            }
+            Some(_) => {
+                report.check(
+                    format!("{name}.root_issuer"),
+                    Err("root_issuer is not the operator pubkey bytes".to_string()),
+                );
+            }
+            None => report.skip(format!("{name}.root_issuer"), "no operator pubkey supplied"),
+        }
+        if child.delegation.root_token_id == Some(root.token_id) {
+            report.check(
+                format!("{name}.root_token_id"),
+                Ok("matches root".to_string()),
+            );
+        } else {
+            report.check(
+                format!("{name}.root_token_id"),
+                Err("root_token_id does not match the root token".to_string()),
+            );
+        }
+        report.check(
+            format!("{name}.expiry"),
+            expiry(child.expires_at as u64, at),
+        );
+    }
+}
+
+pub fn spec_token(report: &mut Report, token: &SpecToken, chain: &[SpecToken], node: &Node) {
+    for (i, parent) in chain.iter().enumerate() {
+        spec_token_fields(report, &format!("chain[{i}]."), parent);
+    }
+    spec_token_fields(report, "", token);
+    spec_token_checks(report, "", token, chain, node);
+    if let Some(verifier) = node.spec_verifier() {
+        report.verdict = Some(verdict_from(
+            verifier.verify_token_chain(token, chain, node.at),
+        ));
+    }
+}
+
+pub fn spec_message(report: &mut Report, message: &SpecMessage, chain: &[SpecToken], node: &Node) {
+    match message {
+        SpecMessage::TaskRequest(request) => spec_request(report, request, chain, node),
+        SpecMessage::TaskAccept(accept) => spec_accept(report, accept, node),
+        SpecMessage::ProofOfExecution(proof) => spec_proof(report, proof, node),
+        SpecMessage::PaymentLock(lock) => spec_lock(report, lock),
+        SpecMessage::PaymentClaim(claim) => spec_claim(report, claim),
+        SpecMessage::TaskReject(reject) => spec_reject(report, reject),
+    }
+}
+
+fn spec_request(report: &mut Report, request: &SpecTaskRequest, chain: &[SpecToken], node: &Node) {
+    report.field("type", "task_request (0x01)");
+    report.field("task_id", &request.task_id);
+    report.ts("timestamp", request.timestamp as u64);
+    report.field("task_type", &request.task_type);
+    report.field("target", &request.target_json);
+    report.field("parameters", &request.parameters_json);
+    report.field("constraints", &request.constraints_json);
+    report.field("payment_max_sats", request.payment_max_sats);
+    report.field("timeout_blocks", request.timeout_blocks);
+    report.hex("request_hash", &request.request_hash());
+    report.hex("commander_signature", &request.commander_signature);
+
+    let mut embedded = Vec::new();
+    for (i, raw) in request.delegation_chain.iter().enumerate() {
+        match SpecToken::decode_tlv(raw) {
+            Ok(parent) => embedded.push(parent),
+            Err(err) => {
+                report.check(format!("delegation_chain[{i}]"), Err(err.reason));
+                return;
+            }
+        }
+    }
+    let chain = if embedded.is_empty() {
+        chain
+    } else {
+        &embedded
+    };
+    let token = match SpecToken::decode_tlv(&request.capability_token) {
+        Ok(token) => token,
+        Err(err) => {
+            report.check("capability_token", Err(err.reason));
+            return;
+        }
+    };
+    for (i, parent) in chain.iter().enumerate() {
+        spec_token_fields(report, &format!("delegation_chain[{i}]."), parent);
+    }
+    spec_token_fields(report, "capability_token.", &token);
+    spec_token_checks(report, "capability_token.", &token, chain, node);
+
+    let commander = parse_xonly(&token.subject).and_then(|key| {
+        if verify_schnorr(
+            &request.commander_signing_hash(),
+            &request.commander_signature,
+            &key,
+        ) {
+            Ok(format!(
+                "signed by token subject {}",
+                short_hex(&token.subject)
+            ))
+        } else {
+            Err(format!(
+                "signature does not verify against token subject {}",
+                short_hex(&token.subject)
+            ))
+        }
+    });
+    report.check("commander_signature", commander);
+    if token.allows(&request.task_type) {
+        report.check(
+            "capability",
+            Ok(format!("{} granted by token", request.task_type)),
+        );
+    } else {
+        report.check(
+            "capability",
+            Err(format!(
+                "{} not granted by [{}]",
+                request.task_type,
+                token.capabilities.join(", ")
+            )),
+        );
+    }
+    if let Some(verifier) = node.spec_verifier() {
+        let mut verified = request.clone();
+        if request.delegation_chain.is_empty() && !chain.is_empty() {
+            verified.delegation_chain = chain
+                .iter()
+                .filter_map(|parent| parent.encode_tlv().ok())
+                .collect();
+        }
+        report.verdict = Some(verdict_from(verifier.verify_request(&verified, node.at)));
+    }
+}
+
+fn spec_accept(report: &mut Report, accept: &SpecTaskAccept, node: &Node) {
+    report.field("type", "task_accept (0x02)");
+    report.field("task_id", &accept.task_id);
+    report.ts("timestamp", accept.timestamp as u64);
+    report.hex("in_reply_to", &accept.in_reply_to);
+    report.hex("payment_hash", &accept.payment_hash);
+    report.field("amount_sats", accept.amount_sats);
+    report.field("expiry_sec", accept.expiry_sec);
+    report.field("description", &accept.description);
+    report.field("estimated_duration_sec", accept.estimated_duration_sec);
+    report.ts("earliest_start", accept.earliest_start as u64);
+    report.field("data_volume_mb", accept.data_volume_mb);
+    report.field("quality_estimate", accept.quality_estimate);
+    report.hex("executor_signature", &accept.executor_signature);
+
+    if accept.payment_hash == derive_payment_hash(accept.in_reply_to) {
+        report.check("payment_hash", Ok("derived from in_reply_to".to_string()));
+    } else {
+        report.check(
+            "payment_hash",
+            Err("payment_hash is not derived from in_reply_to".to_string()),
+        );
+    }
+    match &node.executor_pubkey {
+        Some(executor) => {
+            let result = parse_xonly(executor).and_then(|key| {
+                if verify_schnorr(
+                    &accept.executor_signing_hash(),
+                    &accept.executor_signature,
+                    &key,
+                ) {
+                    Ok(format!("signed by executor {}", short_hex(executor)))
+                } else {
+                    Err(format!(
+                        "signature does not verify against {}",
+                        short_hex(executor)
+                    ))
+                }
+            });
+            report.check("executor_signature", result);
+        }
+        None => report.skip("executor_signature", "no executor pubkey supplied"),
+    }
+    report.check(
+        "offer_expiry",
+        expiry(accept.timestamp as u64 + accept.expiry_sec as u64, node.at),
+    );
+}
+
+fn spec_proof(report: &mut Report, proof: &SpecProofOfExecution, node: &Node) {
+    report.field("type", "proof_of_execution (0x04)");
+    report.field("task_id", &proof.task_id);
+    report.hex("task_token_id", &proof.task_token_id);
+    report.hex("payment_hash", &proof.payment_hash);
+    report.hex("output_hash", &proof.output_hash);
+    report.ts("execution_timestamp", proof.execution_timestamp as u64);
+    report.hex("executor_pubkey", &proof.executor_pubkey);
+    report.hex("proof_hash", &proof.proof_hash());
+    report.hex("executor_signature", &proof.executor_signature);
+
+    let result = parse_xonly(&proof.executor_pubkey).and_then(|key| {
+        if verify_schnorr(&proof.proof_hash(), &proof.executor_signature, &key) {
+            Ok("signed by embedded executor_pubkey".to_string())
+        } else {
+            Err("signature does not verify against embedded executor_pubkey".to_string())
+        }
+    });
+    report.check("executor_signature", result);
+    match &node.executor_pubkey {
+        Some(executor) if same_key(executor, &proof.executor_pubkey) => {
+            report.check("executor_pubkey", Ok("expected executor".to_string()));
+        }
+        Some(executor) => {
+            report.check(
+                "executor_pubkey",
+                Err(format!("expected executor {}", short_hex(executor))),
+            );
+        }
+        None => report.skip("executor_pubkey", "no executor pubkey supplied"),
+    }
+}
+
+fn spec_lock(report: &mut Report, lock: &SpecPaymentLock) {
+    report.field("type", "payment_lock (0x10)");
+    report.field("task_id", &lock.task_id);
+    report.hex("correlation_id", &lock.correlation_id);
+    report.hex("payment_hash", &lock.payment_hash);
+    report.field("amount_sats", lock.amount_sats);
+    report.field("timeout_blocks", lock.timeout_blocks);
+    report.ts("timestamp", lock.timestamp as u64);
+    if lock.payment_hash == derive_payment_hash(lock.correlation_id) {
+        report.check(
+            "payment_hash",
+            Ok("derived from correlation_id".to_string()),
+        );
+    } else {
+        report.check(
+            "payment_hash",
+            Err("payment_hash is not derived from correlation_id".to_string()),
+        );
+    }
+}
+
+fn spec_claim(report: &mut Report, claim: &SpecPaymentClaim) {
+    report.field("type", "payment_claim (0x11)");
+    report.field("task_id", &claim.task_id);
+    report.hex("correlation_id", &claim.correlation_id);
+    report.hex("payment_hash", &claim.payment_hash);
+    report.hex("preimage", &claim.preimage);
+    report.ts("timestamp", claim.timestamp as u64);
+    if sha256(&claim.preimage) == claim.payment_hash {
+        report.check(
+            "preimage",
+            Ok("sha256(preimage) == payment_hash".to_string()),
+        );
+    } else {
+        report.check(
+            "preimage",
+            Err("sha256(preimage) != payment_hash".to_string()),
+        );
+    }
+    if derive_preimage(claim.correlation_id) == claim.preimage {
+        report.check(
+            "correlation_id",
+            Ok("preimage derived from correlation_id".to_string()),
+        );
+    } else {
+        report.check(
+            "correlation_id",
+            Err("preimage is not derived from correlation_id".to_string()),
+        );
+    }
+}
+
+fn spec_reject(report: &mut Report, reject: &SpecTaskReject) {
+    report.field("type", "task_reject (0x03)");
+    report.field("task_id", &reject.task_id);
+    report.field("reason", &reject.reason);
+    report.field("details", &reject.details);
+    report.ts("timestamp", reject.timestamp as u64);
+}
+
+fn constraints_fields(report: &mut Report, prefix: &str, cns: &Constraints) {
+    if let Some(value) = cns.max_area_km2 {
+        report.field(format!("{prefix}max_area_km2"), value);
+    }
+    if let Some(value) = cns.max_range_km {
+        report.field(format!("{prefix}max_range_km"), value);
+    }
+    if let Some(value) = cns.max_hops {
+        report.field(format!("{prefix}max_hops"), value);
+    }
+    if let Some(bounds) = &cns.geographic_bounds {
+        if let (Some(lat_min), Some(lat_max)) = (bounds.lat_min, bounds.lat_max) {
+            report.field(
+                format!("{prefix}geographic_bounds.lat"),
+                format!("{lat_min}..{lat_max}"),
+            );
+        }
+        if let (Some(lon_min), Some(lon_max)) = (bounds.lon_min, bounds.lon_max) {
+            report.field(
+                format!("{prefix}geographic_bounds.lon"),
+                format!("{lon_min}..{lon_max}"),
+            );
+        }
+        if let Some(polygon) = &bounds.polygon {
+            let points: Vec<String> = polygon
+                .iter()
+                .map(|[lat, lon]| format!("({lat}, {lon})"))
+                .collect();
+            report.field(
+                format!("{prefix}geographic_bounds.polygon"),
+                points.join(" "),
+            );
+        }
+    }
+    if let Some(window) = &cns.time_window {
+        report.field(
+            format!("{prefix}time_window"),
+            format!("{} .. {}", utc(window.start), utc(window.end)),
+        );
+    }
+    if let Some(value) = cns.min_approach_distance_m {
+        report.field(format!("{prefix}min_approach_distance_m"), value);
+    }
+    if let Some(value) = cns.max_relative_velocity_m_s {
+        report.field(format!("{prefix}max_relative_velocity_m_s"), value);
+    }
+    if let Some(value) = cns.fuel_budget_kg {
+        report.field(format!("{prefix}fuel_budget_kg"), value);
+    }
+    if let Some(triggers) = &cns.abort_triggers {
+        report.field(format!("{prefix}abort_triggers"), triggers.join(", "));
+    }
+    if let Some(value) = cns.max_payment_msat {
+        report.field(format!("{prefix}max_payment_msat"), value);
+    }
+}
+
+fn cap_payload_fields(report: &mut Report, prefix: &str, payload: &CapPayload) {
+    report.field(format!("{prefix}iss"), &payload.iss);
+    report.field(format!("{prefix}sub"), &payload.sub);
+    report.field(format!("{prefix}aud"), &payload.aud);
+    report.ts(format!("{prefix}iat"), payload.iat);
+    report.ts(format!("{prefix}exp"), payload.exp);
+    report.field(format!("{prefix}jti"), &payload.jti);
+    report.field(format!("{prefix}cap"), payload.cap.join(", "));
+    if let Some(cns) = &payload.cns {
+        constraints_fields(report, &format!("{prefix}cns."), cns);
+    }
+    if let Some(prf) = &payload.prf {
+        report.field(format!("{prefix}prf"), prf);
+    }
+    if let Some(cmd_pub) = &payload.cmd_pub {
+        report.hex(format!("{prefix}cmd_pub"), cmd_pub);
+    }
+}
+
+pub fn sat_cap_token(report: &mut Report, prefix: &str, token: &SatCapToken, node: &Node) {
+    report.field(format!("{prefix}header.alg"), &token.header.alg);
+    report.field(format!("{prefix}header.typ"), &token.header.typ);
+    if let Some(enc) = &token.header.enc {
+        report.field(format!("{prefix}header.enc"), enc);
+    }
+    if let Some(chn) = token.header.chn {
+        report.field(format!("{prefix}header.chn"), chn);
+    }
+    cap_payload_fields(report, &format!("{prefix}payload."), &token.payload);
+    report.hex(format!("{prefix}signature"), &token.signature);
+
+    let payload = &token.payload;
+    let at = node.at;
+    let issuer = scrap_protocol::hex_to_bytes(&payload.iss).ok();
+    match &issuer {
+        Some(iss) => {
+            let result = token
+                .verify_signature(iss)
+                .map(|_| format!("{} by {}", token.header.alg, short_hex(iss)))
+                .map_err(|err| err.reason);
+            report.check(format!("{prefix}signature"), result);
+        }
+        None => {
+            report.check(
+                format!("{prefix}signature"),
+                Err("iss is not a pubkey hex".to_string()),
+            );
+        }
+    }
+    if payload.cmd_pub.is_some() {
+        let result = token
+            .commander_pubkey()
+            .map(|key| format!("commander {}", short_hex(&key)))
+            .map_err(|err| err.reason);
+        report.check(format!("{prefix}cmd_pub"), result);
+    }
+    match (&node.operator_pubkey, &issuer) {
+        (Some(operator), Some(iss)) if same_key(iss, operator) => {
+            report.check(
+                format!("{prefix}issuer"),
+                Ok("issued by the operator".to_string()),
+            );
+        }
+        (Some(operator), _) => {
+            report.check(
+                format!("{prefix}issuer"),
+                Err(format!("iss is not operator {}", short_hex(operator))),
+            );
+        }
+        (None, _) => report.skip(format!("{prefix}issuer"), "no operator pubkey supplied"),
+    }
+    let aud_bytes = scrap_protocol::hex_to_bytes(&payload.aud).ok();
+    let aud_is_executor = match (&aud_bytes, &node.executor_pubkey) {
+        (Some(aud), Some(executor)) => audience_matches(aud, executor).unwrap_or(false),
+        _ => false,
+    };
+    match &node.node_id {
+        Some(node_id) if node_id == &payload.aud || aud_is_executor => {
+            report.check(
+                format!("{prefix}audience"),
+                Ok(format!("addressed to {node_id}")),
+            );
+        }
+        Some(node_id) => {
+            report.check(
+                format!("{prefix}audience"),
+                Err(format!("aud {} is not {node_id}", payload.aud)),
+            );
+        }
+        None if aud_is_executor => {
+            report.check(
+                format!("{prefix}audience"),
+                Ok("executor pubkey".to_string()),
+            );
+        }
+        None => report.skip(format!("{prefix}audience"), "no node id supplied"),
+    }
+    if payload.iat <= at {
+        report.check(
+            format!("{prefix}issued_at"),
+            Ok(format!("issued {}", relative(payload.iat, at))),
+        );
+    } else {
+        report.check(
+            format!("{prefix}issued_at"),
+            Err(format!(
+                "not valid until {} ({})",
+                utc(payload.iat),
+                relative(payload.iat, at)
+            )),
+        );
+    }
+    report.check(format!("{prefix}expiry"), expiry(payload.exp, at));
+    if let Some(window) = payload
+        .cns
+        .as_ref()
+        .and_then(|cns| cns.time_window.as_ref())
+    {
+        match ConstraintChecker::check_time_window(window, at) {
+            None => report.check(
+                format!("{prefix}time_window"),
+                Ok("inside window".to_string()),
+            ),
+            Some(_) => report.check(
+                format!("{prefix}time_window"),
+                Err(format!(
+                    "{} is outside {} .. {}",
+                    utc(at),
+                    utc(window.start),
+                    utc(window.end)
+                )),
+            ),
+        }
+    }
+    if payload.prf.is_some() {
+        report.skip(format!("{prefix}prf"), "parent token not resolved");
+    }
+}
+
+fn execution_proof_fields(report: &mut Report, prefix: &str, proof: &ExecutionProof) {
+    report.field(format!("{prefix}task_jti"), &proof.task_jti);
+    report.hex(format!("{prefix}payment_hash"), &proof.payment_hash);
+    report.hex(format!("{prefix}output_hash"), &proof.output_hash);
+    report.ts(
+        format!("{prefix}execution_timestamp"),
+        proof.execution_timestamp,
+    );
+    if let Some(meta) = &proof.output_metadata {
+        if let Some(value) = meta.data_size_bytes {
+            report.field(format!("{prefix}output_metadata.data_size_bytes"), value);
+        }
+        if let Some(value) = &meta.data_format {
+            report.field(format!("{prefix}output_metadata.data_format"), value);
+        }
+        if let Some(value) = meta.coverage_km2 {
+            report.field(format!("{prefix}output_metadata.coverage_km2"), value);
+        }
+        if let Some(value) = meta.acquisition_start {
+            report.ts(format!("{prefix}output_metadata.acquisition_start"), value);
+        }
+        if let Some(value) = meta.acquisition_end {
+            report.ts(format!("{prefix}output_metadata.acquisition_end"), value);
+        }
+        if let Some(value) = &meta.sensor_mode {
+            report.field(format!("{prefix}output_metadata.sensor_mode"), value);
+        }
+        if let Some(value) = &meta.content_type {
+            report.field(format!("{prefix}output_metadata.content_type"), value);
+        }
+        if let Some(value) = meta.size_bytes {
+            report.field(format!("{prefix}output_metadata.size_bytes"), value);
+        }
+        if let Some(value) = &meta.storage_location {
+            report.field(format!("{prefix}output_metadata.storage_location"), value);
+        }
+    }
+    report.hex(format!("{prefix}executor_sig"), &proof.executor_sig);
+}
+
+fn bound_request(report: &mut Report, request: &BoundTaskRequest, node: &Node) {
+    report.hex("payload.payment_hash", &request.payment_hash);
+    report.field("payload.payment_amount_msat", request.payment_amount_msat);
+    report.field("payload.htlc_timeout_blocks", request.htlc_timeout_blocks);
+    report.hex("payload.binding_sig", &request.binding_sig);
+    if request.payment_hash.len() == 32 {
+        report.check("payload.payment_hash", Ok("32 bytes".to_string()));
+    } else {
+        report.check(
+            "payload.payment_hash",
+            Err(format!("{} bytes, expected 32", request.payment_hash.len())),
+        );
+    }
+    match SatCapToken::decode_cbor(&request.capability_token) {
+        Ok(token) => {
+            sat_cap_token(report, "payload.capability_token.", &token, node);
+            let result = token.commander_pubkey().and_then(|cmd_pub| {
+                request
+                    .verify(&cmd_pub)
+                    .map(|_| format!("SCRAP/bound_task/v1 by {}", short_hex(&cmd_pub)))
+            });
+            report.check("payload.binding_sig", result.map_err(|err| err.reason));
+        }
+        Err(err) => {
+            report.check("payload.capability_token", Err(err.reason));
+        }
+    }
+}
+
+pub fn isl_message(report: &mut Report, message: &IslScapMessage, node: &Node) {
+    report.field("version", message.version);
+    report.field("msg_type", format!("{:?}", message.msg_type));
+    report.field("sender", &message.sender);
+    report.field("recipient", &message.recipient);
+    report.field("sequence", message.sequence);
+    report.ts("timestamp", message.timestamp);
+    match &message.hmac {
+        Some(hmac) => report.hex("hmac", hmac),
+        None => report.field("hmac", "(none)"),
+    }
+    match &node.node_id {
+        Some(node_id) if node_id == &message.recipient => {
+            report.check("recipient", Ok(format!("addressed to {node_id}")));
+        }
+        Some(node_id) => {
+            report.check(
+                "recipient",
+                Err(format!("recipient {} is not {node_id}", message.recipient)),
+            );
+        }
+        None => report.skip("recipient", "no node id supplied"),
+    }
+    if message.hmac.is_some() {
+        match &node.link_key {
+            Some(link_key) => report.check(
+                "hmac",
+                message
+                    .verify_hmac(link_key)
+                    .map(|_| "HMAC-SHA256 under link key".to_string())
+                    .map_err(|err| err.reason),
+            ),
+            None => report.skip("hmac", "link key not supplied"),
+        }
+    } else {
+        report.advisory("hmac", Err("message is not authenticated".to_string()));
+    }
+    match &message.payload {
+        ScapPayload::TaskRequest(request) => bound_request(report, request, node),
+        ScapPayload::Proof(proof) => {
+            execution_proof_fields(report, "payload.", proof);
+            executor_sig_check(report, node, |key| proof.verify(key));
+        }
+        ScapPayload::TaskResponse(response) => {
+            task_response_fields(report, response);
+            executor_sig_check(report, node, |key| response.verify(key));
+        }
+        ScapPayload::Heartbeat(heartbeat) => {
+            report.field("payload.sender", &heartbeat.sender);
+            report.ts("payload.timestamp", heartbeat.timestamp);
+            report.field("payload.pending_htlcs", heartbeat.pending_htlcs);
+            if let Some(capabilities) = &heartbeat.capabilities {
+                report.field("payload.capabilities", capabilities.join(", "));
+            }
+            let result = if heartbeat.sender == message.sender {
+                Ok("matches message sender".to_string())
+            } else {
+                Err(format!(
+                    "heartbeat sender {} is not {}",
+                    heartbeat.sender, message.sender
+                ))
+            };
+            report.check("payload.sender", result);
+        }
+        ScapPayload::Dispute(dispute) => {
+            report.field("payload.task_jti", &dispute.task_jti);
+            report.hex("payload.payment_hash", &dispute.payment_hash);
+            report.field("payload.reason", &dispute.reason);
+            report.ts("payload.timestamp", dispute.timestamp);
+            if let Some(evidence) = &dispute.evidence {
+                report.hex("payload.evidence", evidence);
+            }
+        }
+        ScapPayload::Lightning(wrapper) => {
+            match wrapper.bolt_type() {
+                Some(bolt_type) => report.field("payload.bolt_type", bolt_type),
+                None => report.field("payload.bolt_type", "(truncated)"),
+            }
+            report.hex("payload.bolt_payload", &wrapper.bolt_payload);
+        }
+    }
+}
+
+fn executor_sig_check(
+    report: &mut Report,
+    node: &Node,
+    verify: impl FnOnce(&[u8]) -> Result<(), scrap_protocol::VerifyError>,
+) {
+    match &node.executor_pubkey {
+        Some(executor) => {
+            let result = verify(executor)
+                .map(|_| format!("signed by executor {}", short_hex(executor)))
+                .map_err(|err| err.reason);
+            report.check("payload.executor_sig", result);
+        }
+        None => report.skip("payload.executor_sig", "no executor pubkey supplied"),
+    }
+}
+
+fn task_response_fields(report: &mut Report, response: &TaskResponse) {
+    match response {
+        TaskResponse::Accepted(accepted) => {
+            report.field("payload.status", "ACCEPTED");
+            report.field("payload.task_jti", &accepted.task_jti);
+            report.ts("payload.accepted_at", accepted.accepted_at);
+            report.ts(
+                "payload.estimated_completion",
+                accepted.estimated_completion,
+            );
+            report.hex("payload.executor_sig", &accepted.executor_sig);
+        }
+        TaskResponse::Rejected(rejected) => {
+            report.field("payload.status", "REJECTED");
+            report.field("payload.task_jti", &rejected.task_jti);
+            report.ts("payload.rejected_at", rejected.rejected_at);
+            report.field("payload.reason", &rejected.reason);
+            if let Some(detail) = &rejected.detail {
+                report.field("payload.detail", detail);
+            }
+            report.hex("payload.executor_sig", &rejected.executor_sig);
+        }
+        TaskResponse::Completed(completed) => {
+            report.field("payload.status", "COMPLETED");
+            report.field("payload.task_jti", &completed.task_jti);
+            execution_proof_fields(report, "payload.proof.", &completed.proof);
+            if let Some(location) = &completed.data_location {
+                report.field("payload.data_location.method", &location.method);
+                if let Some(relay) = &location.relay_satellite {
+                    report.field("payload.data_location.relay_satellite", relay);
+                }
+                if let Some(ground) = &location.ground_station {
+                    report.field("payload.data_location.ground_station", ground);
+                }
+                if let Some(eta) = location.estimated_delivery {
+                    report.ts("payload.data_location.estimated_delivery", eta);
+                }
+            }
+        }
+        TaskResponse::Failed(failed) => {
+            report.field("payload.status", "FAILED");
+            report.field("payload.task_jti", &failed.task_jti);
+            report.ts("payload.failed_at", failed.failed_at);
+            report.field("payload.reason", &failed.reason);
+            if let Some(detail) = &failed.detail {
+                report.field("payload.detail", detail);
+            }
+            if let Some(partial) = &failed.partial_proof {
+                execution_proof_fields(report, "payload.partial_proof.", partial);
+            }
+            report.hex("payload.executor_sig", &failed.executor_sig);
+        }
+    }
+}
+
+pub fn core_lite_envelope(report: &mut Report, env: &Envelope, node: &Node) {
+    report.field("version", env.version);
+    report.field("msg_type", env.msg_type);
+    report.hex("trace_id", &env.trace_id);
+    report.field("src", &env.src);
+    report.field("dst", &env.dst);
+    report.field("hop_limit", env.hop_limit);
+    match &env.payload {
+        Payload::TaskRequest(task) => {
+            report.hex("payload.token.token_id", &task.token.token_id);
+            report.field("payload.token.subject", &task.token.subject);
+            report.field("payload.token.audience", &task.token.audience);
+            report.field("payload.token.capability", &task.token.capability);
+            report.ts("payload.token.issued_at", task.token.issued_at);
+            report.ts("payload.token.expires_at", task.token.expires_at);
+            report.field("payload.command", &task.command);
+            report.field("payload.args", &task.args);
+            report.field("payload.reply_to", &task.reply_to);
+            report.field("payload.commander_pubkey", &task.commander_pubkey);
+        }
+        Payload::TaskResult(result) => {
+            report.field("payload.status", result.status);
+            report.hex("payload.output_digest", &result.output_digest);
+            report.field(
+                "payload.telemetry.duration_ms",
+                result.telemetry.duration_ms,
+            );
+            report.field("payload.telemetry.node_id", &result.telemetry.node_id);
+        }
+        Payload::TaskRejected(rejected) => {
+            report.field("payload.reason", &rejected.reason);
+            report.field("payload.details", rejected.details.join("; "));
+        }
+        Payload::Heartbeat(heartbeat) => {
+            report.ts("payload.timestamp", heartbeat.timestamp);
+        }
+    }
+
+    let node_id = match &node.node_id {
+        Some(node_id) => node_id.clone(),
+        None => {
+            report.skip("node", "no node id supplied");
+            return;
+        }
+    };
+    // Contacts are resolved at the inspection time, as the node would.
+    let routes = node
+        .routes
+        .as_ref()
+        .map(|routes| routes.at(node.at, false))
+        .unwrap_or_else(|| RouteTable::new(Vec::new()));
+    let revoked = node
+        .revoked_path
+        .as_deref()
+        .map(load_revoked)
+        .unwrap_or_default();
+    let mut replay = PeekReplayCache {
+        seen: node
+            .replay_cache_path
+            .as_deref()
+            .map(load_string_list)
+            .unwrap_or_default(),
+    };
+    let verifier = DevTokenVerifier {
+        allow_mock_signatures: node.allow_mock_signatures,
+    };
+
+    if env.dst != node_id {
+        if env.hop_limit == 0 {
+            report.check(
+                "hop_limit",
+                Err("hop limit exhausted before forwarding".to_string()),
+            );
+        } else {
+            report.check("hop_limit", Ok(format!("{} hops left", env.hop_limit)));
+            match routes.next_hop(&env.dst) {
+                Some(next_hop) => {
+                    report.check("route", Ok(format!("{} via {next_hop}", env.dst)));
+                }
+                None => {
+                    report.check("route", Err(format!("no route to {}", env.dst)));
+                }
+            }
+        }
+    } else if let Payload::TaskRequest(task) = &env.payload {
+        if task.token.issued_at > node.at {
+            report.advisory(
+                "token.issued_at",
+                Err(format!(
+                    "issued in the future ({})",
+                    relative(task.token.issued_at, node.at)
+                )),
+            );
+        }
+        match verifier.verify(
+            &task.token,
+            &task.commander_pubkey,
+            &node_id,
+            &task.command,
+            node.at,
+            &revoked,
+            node.commander_pubkey.as_deref(),
+        ) {
+            Ok(()) => {
+                report.check(
+                    "token",
+                    Ok("signature, expiry, subject, audience, capability, revocation".to_string()),
+                );
+            }
+            Err(issues) => {
+                for issue in issues {
+                    report.check("token", Err(issue));
+                }
+            }
+        }
+        if replay.check_and_add(&task.token.token_id) {
+            report.check("replay", Ok("token_id not seen".to_string()));
+        } else {
+            report.check("replay", Err("token_id already used".to_string()));
+        }
+    }
+
+    let mut ctx = Context {
+        node_id: &node_id,
+        routes: &routes,
+        replay: &mut replay,
+        revoked: &revoked,
+        commander_pubkey: node.commander_pubkey.as_deref(),
+        allow_mock_signatures: node.allow_mock_signatures,
+        verifier: &verifier,
+    };
+    report.verdict = Some(match handle_envelope(&mut ctx, env.clone(), node.at) {
+        Action::Execute { task, .. } => Ok(format!("execute {} {}", task.command, task.args)),
+        Action::Forward { next_hop, envelope } => Ok(format!(
+            "forward to {next_hop} (hop_limit {})",
+            envelope.hop_limit
+        )),
+        Action::Reply { envelope } => match envelope.payload {
+            Payload::TaskRejected(rejected) => Err(format!(
+                "{} ({})",
+                rejected.reason,
+                rejected.details.join("; ")
+            )),
+            _ => Ok("reply".to_string()),
+        },
+        Action::Drop => Err("dropped (not a task request for this node)".to_string()),
+    });
+}
+
+fn demo_token_checks(report: &mut Report, token: &DemoToken, node: &Node) {
+    if token.expires_at < node.at {
+        report.check(
+            "token.expiry",
+            Err(format!(
+                "token expired {}",
+                relative(token.expires_at, node.at)
+            )),
+        );
+    } else {
+        report.check(
+            "token.expiry",
+            Ok(format!("expires {}", relative(token.expires_at, node.at))),
+        );
+    }
+    match &node.node_id {
+        Some(node_id) if node_id == &token.audience => {
+            report.check("token.audience", Ok(format!("addressed to {node_id}")));
+        }
+        Some(node_id) => {
+            report.check(
+                "token.audience",
+                Err(format!(
+                    "token audience mismatch ({} is not {node_id})",
+                    token.audience
+                )),
+            );
+        }
+        None => report.skip("token.audience", "no node id supplied"),
+    }
+    match &node.revoked_path {
+        Some(path) => {
+            if load_string_list(path)
+                .iter()
+                .any(|id| id == &token.token_id)
+            {
+                report.check("token.revocation", Err("token revoked".to_string()));
+            } else {
+                report.check("token.revocation", Ok(format!("not in {path}")));
+            }
+        }
+        None => report.skip("token.revocation", "no revocation list supplied"),
+    }
+    // scrap-executor does not verify demo signatures; report what it would see.
+    if token.signature == "mock" {
+        report.advisory("token.signature", Err("mock signature".to_string()));
+    } else {
+        match &node.operator_pubkey {
+            Some(operator) => {
+                let result = token
+                    .to_spec_token(operator)
+                    .map_err(|err| err.reason)
+                    .and_then(|spec| spec_signed_by("SCRAP/token/v1", &spec, operator));
+                report.advisory("token.signature", result);
+            }
+            None => report.skip("token.signature", "no operator pubkey supplied"),
+        }
+    }
+}
+
+fn demo_token_fields(report: &mut Report, prefix: &str, token: &DemoToken) {
+    report.field(format!("{prefix}version"), token.version);
+    report.field(format!("{prefix}token_id"), &token.token_id);
+    report.field(format!("{prefix}subject"), &token.subject);
+    report.field(format!("{prefix}audience"), &token.audience);
+    report.field(format!("{prefix}capability"), &token.capability);
+    report.ts(format!("{prefix}issued_at"), token.issued_at);
+    report.ts(format!("{prefix}expires_at"), token.expires_at);
+    report.field(format!("{prefix}signature"), &token.signature);
+}
+
+pub fn demo_token(report: &mut Report, token: &DemoToken, node: &Node) {
+    demo_token_fields(report, "", token);
+    demo_token_checks(report, token, node);
+}
+
+fn demo_request(report: &mut Report, request: &DemoTaskRequest, node: &Node) {
+    report.field("type", &request.msg_type);
+    report.field("task_id", &request.task_id);
+    report.field("requested_capability", &request.requested_capability);
+    report.field("commander_pubkey", &request.commander_pubkey);
+    report.field("commander_signature", &request.commander_signature);
+    demo_token_fields(report, "token.", &request.token);
+    demo_token_checks(report, &request.token, node);
+    if request.token.capability == request.requested_capability {
+        report.check("capability", Ok(request.requested_capability.clone()));
+    } else {
+        report.check(
+            "capability",
+            Err(format!(
+                "capability mismatch ({} requested, token grants {})",
+                request.requested_capability, request.token.capability
+            )),
+        );
+    }
+    let authorized = node
+        .commander_pubkey
+        .as_ref()
+        .map(|expected| expected == &request.commander_pubkey)
+        .unwrap_or(true);
+    if request.token.subject == request.commander_pubkey && authorized {
+        report.check(
+            "commander",
+            Ok("token subject is commander_pubkey".to_string()),
+        );
+    } else {
+        report.check(
+            "commander",
+            Err("token subject does not match commander_pubkey".to_string()),
+        );
+    }
+}
+
+pub fn demo_message(report: &mut Report, message: &DemoMessage, node: &Node) {
+    match message {
+        DemoMessage::TaskRequest(request) => demo_request(report, request, node),
+        DemoMessage::TaskAccepted(accepted) => {
+            report.field("type", &accepted.msg_type);
+            report.field("task_id", &accepted.task_id);
+            report.field("payment_hash", &accepted.payment_hash);
+        }
+        DemoMessage::Proof(proof) => {
+            report.field("type", &proof.msg_type);
+            report.field("task_id", &proof.task_id);
+            report.field("proof_hash", &proof.proof_hash);
+        }
+        DemoMessage::TaskRejected(rejected) => {
+            report.field("type", &rejected.msg_type);
+            report.field("task_id", &rejected.task_id);
+            report.field("details", rejected.details.join("; "));
+            report.field("notes", rejected.notes.join("; "));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::Status;
+    use scrap_protocol::fixtures::{EXECUTOR_SECRET, ISSUED_AT, LINK_KEY, OPERATOR_SECRET};
+    use scrap_protocol::pubkey_from_secret;
+
+    fn fixture(name: &str) -> Vec<u8> {
+        let path = format!(
+            "{}/../scrap-protocol/fixtures/{name}.cbor",
+            env!("CARGO_MANIFEST_DIR")
+        );
+        fs::read(&path).expect(&path)
+    }
+
+    fn executor_node() -> Node {
+        Node {
+            at: ISSUED_AT + 300,
+            node_id: Some("SAT-EXEC-1".to_string()),
+            operator_pubkey: Some(pubkey_from_secret(OPERATOR_SECRET).unwrap()),
+            executor_pubkey: Some(pubkey_from_secret(EXECUTOR_SECRET).unwrap()),
+            link_key: Some(LINK_KEY.to_vec()),
+            ..Node::default()
+        }
+    }
+
+    fn field<'a>(report: &'a Report, name: &str) -> &'a str {
+        report
+            .fields
+            .iter()
+            .find(|(field, _)| field == name)
+            .map(|(_, value)| value.as_str())
+            .unwrap_or_else(|| panic!("no field {name}"))
+    }
+
+    fn status(report: &Report, name: &str) -> Status {
+        report
+            .checks
+            .iter()
+            .find(|check| check.name == name)
+            .map(|check| check.status)
+            .unwrap_or_else(|| panic!("no check {name}"))
+    }
+
+    #[test]
+    fn capability_token_fixture_is_accepted() {
+        let token = SatCapToken::decode_cbor(&fixture("capability_token")).unwrap();
+        let mut report = Report::new("sat-cap", 0);
+        sat_cap_token(&mut report, "", &token, &executor_node());
+
+        assert_eq!(field(&report, "payload.jti"), "cap-root-0001");
+        assert_eq!(field(&report, "header.alg"), "BIP340");
+        assert_eq!(status(&report, "signature"), Status::Ok);
+        assert_eq!(status(&report, "issuer"), Status::Ok);
+        assert_eq!(status(&report, "audience"), Status::Ok);
+        assert_eq!(status(&report, "time_window"), Status::Ok);
+        assert_eq!(report.outcome(), Ok("accept".to_string()));
+    }
+
+    #[test]
+    fn capability_token_fixture_fails_after_expiry_and_under_another_operator() {
+        let token = SatCapToken::decode_cbor(&fixture("capability_token")).unwrap();
+        let mut node = executor_node();
+        node.at = ISSUED_AT + 86_400;
+        node.operator_pubkey = Some(pubkey_from_secret(EXECUTOR_SECRET).unwrap());
+        let mut report = Report::new("sat-cap", 0);
+        sat_cap_token(&mut report, "", &token, &node);
+
+        assert_eq!(status(&report, "signature"), Status::Ok);
+        assert_eq!(status(&report, "issuer"), Status::Fail);
+        assert_eq!(status(&report, "expiry"), Status::Fail);
+        assert!(report.outcome().is_err());
+    }
+
+    #[test]
+    fn delegation_token_fixture_leaves_its_parent_unresolved() {
+        let token = SatCapToken::decode_cbor(&fixture("delegation_token")).unwrap();
+        let mut report = Report::new("sat-cap", 0);
+        sat_cap_token(&mut report, "", &token, &executor_node());
+
+        assert_eq!(field(&report, "header.chn"), "1");
+        assert_eq!(field(&report, "payload.prf"), "cap-root-0001");
+        assert_eq!(status(&report, "prf"), Status::Skip);
+        assert_eq!(status(&report, "issuer"), Status::Fail);
+    }
+
+    #[test]
+    fn isl_fixture_checks_hmac_binding_and_embedded_token() {
+        let message = IslScapMessage::decode_cbor(&fixture("isl_tasklib_message")).unwrap();
+        let mut report = Report::new("isl", 0);
+        isl_message(&mut report, &message, &executor_node());
+
+        assert_eq!(field(&report, "sender"), "TASKLIB-GS-1");
+        assert_eq!(field(&report, "sequence"), "42");
+        assert_eq!(status(&report, "recipient"), Status::Ok);
+        assert_eq!(status(&report, "hmac"), Status::Ok);
+        assert_eq!(status(&report, "payload.binding_sig"), Status::Ok);
+        assert_eq!(
+            status(&report, "payload.capability_token.signature"),
+            Status::Ok
+        );
+        assert_eq!(report.outcome(), Ok("accept".to_string()));
+
+        let mut node = executor_node();
+        node.link_key = Some(vec![0x66; 32]);
+        let mut report = Report::new("isl", 0);
+        isl_message(&mut report, &message, &node);
+        assert_eq!(status(&report, "hmac"), Status::Fail);
+        assert!(report.outcome().is_err());
+    }
+
+    #[test]
+    fn isl_fixture_without_a_link_key_skips_the_hmac() {
+        let message = IslScapMessage::decode_cbor(&fixture("isl_tasklib_message")).unwrap();
+        let mut node = executor_node();
+        node.link_key = None;
+        let mut report = Report::new("isl", 0);
+        isl_message(&mut report, &message, &node);
+
+        assert_eq!(status(&report, "hmac"), Status::Skip);
+        assert_eq!(
+            report.outcome(),
+            Ok("accept (some checks skipped)".to_string())
+        );
+    }
+
+    #[test]
+    fn tampered_fixture_signature_is_reported() {
+        let mut token = SatCapToken::decode_cbor(&fixture("capability_token")).unwrap();
+        token.payload.cap.push("cmd:propulsion:burn".to_string());
+        let mut report = Report::new("sat-cap", 0);
+        sat_cap_token(&mut report, "", &token, &executor_node());
+
+        assert_eq!(status(&report, "signature"), Status::Fail);
+        assert!(report.outcome().is_err());
+    }
+}
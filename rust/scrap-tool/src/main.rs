@@ -0,0 +1,397 @@
+mod inspect;
+mod report;
+
+use clap::{Parser, Subcommand};
+use inspect::Node;
+use report::{utc, Report};
+use scrap_linux_udp::{load_node_config, load_node_routes};
+use scrap_protocol::{
+    check_canonical_cbor, hex_to_bytes, pubkey_from_secret, DemoMessageCodec, DemoToken,
+    IslScapMessage, MessageCodec, SatCapToken, SpecMessageCodec, SpecToken,
+};
+use serde::Deserialize;
+use std::fs;
+use std::io::Read;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Parser, Debug)]
+#[command(name = "scrap-tool", about = "SCRAP token and message inspector")]
+struct Args {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Decode a token or message, print every field and run the receiving
+    /// node's checks.
+    Inspect {
+        /// File to read, or `-` for stdin. Raw bytes or hex text.
+        input: String,
+
+        #[arg(long, default_value = "auto", value_parser = ["auto", "envelope", "spec-token", "spec-message", "sat-cap", "isl", "demo-json"])]
+        format: String,
+
+        /// Evaluate at this unix time instead of now.
+        #[arg(long)]
+        at: Option<u64>,
+
+        #[arg(long)]
+        node: Option<String>,
+
+        /// scrap-node config (node_id, routes, revocation, replay cache).
+        #[arg(long)]
+        node_config: Option<String>,
+
+        /// scrap-executor policy (node_id, revocation list, replay cache).
+        #[arg(long)]
+        policy: Option<String>,
+
+        /// Keys JSON; `<role>_pubkey` or `<role>_privkey` fields are used.
+        #[arg(long)]
+        keys: Option<String>,
+
+        #[arg(long)]
+        operator_pubkey: Option<String>,
+
+        #[arg(long)]
+        executor_pubkey: Option<String>,
+
+        #[arg(long)]
+        commander_pubkey: Option<String>,
+
+        /// Hex HMAC key for ISL messages (keys JSON: `isl_link_key`).
+        #[arg(long)]
+        link_key: Option<String>,
+
+        /// Parent tokens of a delegated TLV token, root first.
+        #[arg(long = "chain")]
+        chain: Vec<String>,
+
+        #[arg(long)]
+        revoked: Option<String>,
+
+        #[arg(long, action = clap::ArgAction::SetTrue)]
+        allow_mock_signatures: bool,
+
+        #[arg(long, action = clap::ArgAction::SetTrue)]
+        json: bool,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+struct Policy {
+    node_id: Option<String>,
+    replay_cache_path: Option<String>,
+    revocation_list_path: Option<String>,
+}
+
+fn unix_ts() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn fail(msg: &str) -> ! {
+    eprintln!("scrap-tool: {msg}");
+    std::process::exit(2);
+}
+
+/// Reads raw bytes; input that is entirely hex text is decoded first.
+fn read_input(path: &str) -> Vec<u8> {
+    let raw = if path == "-" {
+        let mut buf = Vec::new();
+        std::io::stdin()
+            .read_to_end(&mut buf)
+            .unwrap_or_else(|err| fail(&format!("read stdin failed: {err}")));
+        buf
+    } else {
+        fs::read(path).unwrap_or_else(|err| fail(&format!("read {path} failed: {err}")))
+    };
+    if let Ok(text) = std::str::from_utf8(&raw) {
+        let compact: String = text.split_whitespace().collect();
+        if !compact.is_empty() && compact.chars().all(|c| c.is_ascii_hexdigit()) {
+            if let Ok(bytes) = hex_to_bytes(&compact) {
+                return bytes;
+            }
+        }
+    }
+    raw
+}
+
+fn parse_key(value: &str, what: &str) -> Vec<u8> {
+    hex_to_bytes(value).unwrap_or_else(|_| fail(&format!("{what} is not hex")))
+}
+
+fn key_from_file(keys: &serde_json::Value, role: &str) -> Option<Vec<u8>> {
+    if let Some(pubkey) = keys.get(format!("{role}_pubkey")).and_then(|v| v.as_str()) {
+        if let Ok(bytes) = hex_to_bytes(pubkey) {
+            return Some(bytes);
+        }
+    }
+    keys.get(format!("{role}_privkey"))
+        .and_then(|v| v.as_str())
+        .and_then(|secret| pubkey_from_secret(secret).ok())
+}
+
+/// Guesses the format of `bytes`. JSON is only taken for demo JSON when it
+/// parses as a `DemoToken` or a demo message; the JSON views of the spec
+/// fixtures are not wire formats and are reported as such.
+fn detect(bytes: &[u8]) -> Result<&'static str, String> {
+    let first = bytes.iter().find(|b| !b.is_ascii_whitespace());
+    if first == Some(&b'{') {
+        if DemoMessageCodec.decode_message(bytes).is_ok()
+            || serde_json::from_slice::<DemoToken>(bytes).is_ok()
+        {
+            return Ok("demo-json");
+        }
+        return Err(
+            "JSON input is not a demo token or message; inspect the CBOR form of spec fixtures"
+                .to_string(),
+        );
+    }
+    if SpecMessageCodec.decode_message(bytes).is_ok() {
+        return Ok("spec-message");
+    }
+    if SpecToken::decode_tlv(bytes).is_ok() {
+        return Ok("spec-token");
+    }
+    if scrap_core_lite::decode_envelope(bytes).is_ok() {
+        return Ok("envelope");
+    }
+    if IslScapMessage::decode_cbor(bytes).is_ok() {
+        return Ok("isl");
+    }
+    if SatCapToken::decode_cbor(bytes).is_ok() {
+        return Ok("sat-cap");
+    }
+    Err("input does not decode as any known format; try --format".to_string())
+}
+
+/// Executors only accept deterministic CBOR on signed paths.
+fn canonical_check(report: &mut Report, result: Result<(), String>) {
+    let result = result.map(|_| "deterministic (RFC 8949 4.2.1)".to_string());
+    report.check("canonical_cbor", result);
+}
+
+fn main() {
+    let args = Args::parse();
+    match args.command {
+        Command::Inspect {
+            input,
+            format,
+            at,
+            node,
+            node_config,
+            policy,
+            keys,
+            operator_pubkey,
+            executor_pubkey,
+            commander_pubkey,
+            link_key,
+            chain,
+            revoked,
+            allow_mock_signatures,
+            json,
+        } => {
+            let mut ctx = Node {
+                at: at.unwrap_or_else(unix_ts),
+                allow_mock_signatures,
+                ..Node::default()
+            };
+
+            if let Some(path) = &node_config {
+                let config = load_node_config(path).unwrap_or_else(|err| fail(&err));
+                ctx.routes = Some(load_node_routes(&config).unwrap_or_else(|err| fail(&err)));
+                ctx.node_id = Some(config.node_id);
+                ctx.commander_pubkey = config.commander_pubkey;
+                ctx.revoked_path = Some(config.revoked_path);
+                ctx.replay_cache_path = Some(config.replay_cache_path);
+                ctx.allow_mock_signatures |= config.allow_mock_signatures;
+            }
+            if let Some(path) = &policy {
+                let raw = fs::read_to_string(path)
+                    .unwrap_or_else(|err| fail(&format!("read {path} failed: {err}")));
+                let parsed: Policy = serde_json::from_str(&raw)
+                    .unwrap_or_else(|err| fail(&format!("policy parse failed: {err}")));
+                ctx.node_id = parsed.node_id.or(ctx.node_id);
+                ctx.revoked_path = parsed.revocation_list_path.or(ctx.revoked_path);
+                ctx.replay_cache_path = parsed.replay_cache_path.or(ctx.replay_cache_path);
+            }
+            if let Some(path) = &keys {
+                let raw = fs::read_to_string(path)
+                    .unwrap_or_else(|err| fail(&format!("read {path} failed: {err}")));
+                let parsed: serde_json::Value = serde_json::from_str(&raw)
+                    .unwrap_or_else(|err| fail(&format!("keys parse failed: {err}")));
+                ctx.operator_pubkey = key_from_file(&parsed, "operator");
+                ctx.executor_pubkey = key_from_file(&parsed, "executor");
+                ctx.link_key = parsed
+                    .get("isl_link_key")
+                    .and_then(|v| v.as_str())
+                    .and_then(|v| hex_to_bytes(v).ok());
+                if ctx.commander_pubkey.is_none() {
+                    ctx.commander_pubkey = parsed
+                        .get("commander_pubkey")
+                        .and_then(|v| v.as_str())
+                        .map(|v| v.to_string());
+                }
+            }
+            if let Some(value) = &operator_pubkey {
+                ctx.operator_pubkey = Some(parse_key(value, "--operator-pubkey"));
+            }
+            if let Some(value) = &executor_pubkey {
+                ctx.executor_pubkey = Some(parse_key(value, "--executor-pubkey"));
+            }
+            if commander_pubkey.is_some() {
+                ctx.commander_pubkey = commander_pubkey;
+            }
+            if let Some(value) = &link_key {
+                ctx.link_key = Some(parse_key(value, "--link-key"));
+            }
+            if node.is_some() {
+                ctx.node_id = node;
+            }
+            if revoked.is_some() {
+                ctx.revoked_path = revoked;
+            }
+
+            let parents: Vec<SpecToken> = chain
+                .iter()
+                .map(|path| {
+                    SpecToken::decode_tlv(&read_input(path))
+                        .unwrap_or_else(|err| fail(&format!("{path}: {err}")))
+                })
+                .collect();
+
+            let bytes = read_input(&input);
+            let format = if format == "auto" {
+                detect(&bytes).unwrap_or_else(|err| fail(&err))
+            } else {
+                format.as_str()
+            };
+            let mut report = Report::new(format, bytes.len());
+            let decode_failed =
+                |err: String| -> ! { fail(&format!("decode {format} failed: {err}")) };
+            match format {
+                "spec-token" => {
+                    let token = SpecToken::decode_tlv(&bytes)
+                        .unwrap_or_else(|err| decode_failed(err.reason));
+                    inspect::spec_token(&mut report, &token, &parents, &ctx);
+                }
+                "spec-message" => {
+                    let message = SpecMessageCodec
+                        .decode_message(&bytes)
+                        .unwrap_or_else(|err| decode_failed(err.reason));
+                    inspect::spec_message(&mut report, &message, &parents, &ctx);
+                }
+                "envelope" => {
+                    let env = scrap_core_lite::decode_envelope(&bytes)
+                        .unwrap_or_else(|err| decode_failed(err.to_string()));
+                    inspect::core_lite_envelope(&mut report, &env, &ctx);
+                    canonical_check(
+                        &mut report,
+                        scrap_core_lite::check_canonical(&bytes).map_err(|err| err.to_string()),
+                    );
+                }
+                "isl" => {
+                    let message = IslScapMessage::decode_cbor(&bytes)
+                        .unwrap_or_else(|err| decode_failed(err.reason));
+                    inspect::isl_message(&mut report, &message, &ctx);
+                    canonical_check(
+                        &mut report,
+                        check_canonical_cbor(&bytes).map_err(|err| err.reason),
+                    );
+                }
+                "sat-cap" => {
+                    let token = SatCapToken::decode_cbor(&bytes)
+                        .unwrap_or_else(|err| decode_failed(err.reason));
+                    inspect::sat_cap_token(&mut report, "", &token, &ctx);
+                    canonical_check(
+                        &mut report,
+                        check_canonical_cbor(&bytes).map_err(|err| err.reason),
+                    );
+                }
+                _ => {
+                    let value: serde_json::Value = serde_json::from_slice(&bytes)
+                        .unwrap_or_else(|err| decode_failed(err.to_string()));
+                    if value.get("type").is_some() {
+                        let message = DemoMessageCodec
+                            .decode_message(&bytes)
+                            .unwrap_or_else(|err| decode_failed(err.reason));
+                        inspect::demo_message(&mut report, &message, &ctx);
+                    } else {
+                        let token: DemoToken = serde_json::from_value(value)
+                            .unwrap_or_else(|err| decode_failed(err.to_string()));
+                        inspect::demo_token(&mut report, &token, &ctx);
+                    }
+                }
+            }
+
+            let context = format!(
+                "node {} at {}",
+                ctx.node_id.as_deref().unwrap_or("?"),
+                utc(ctx.at)
+            );
+            if json {
+                println!("{}", report.to_json(&context));
+            } else {
+                report.print(&context);
+            }
+            if report.outcome().is_err() {
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(name: &str) -> Vec<u8> {
+        let path = format!(
+            "{}/../scrap-protocol/fixtures/{name}",
+            env!("CARGO_MANIFEST_DIR")
+        );
+        fs::read(&path).expect(&path)
+    }
+
+    #[test]
+    fn detects_the_vendored_fixtures() {
+        assert_eq!(detect(&fixture("capability_token.cbor")), Ok("sat-cap"));
+        assert_eq!(detect(&fixture("delegation_token.cbor")), Ok("sat-cap"));
+        assert_eq!(detect(&fixture("isl_tasklib_message.cbor")), Ok("isl"));
+        // The JSON views of spec fixtures are not demo tokens.
+        assert!(detect(&fixture("capability_token.json")).is_err());
+        assert!(detect(&fixture("isl_tasklib_message.json")).is_err());
+    }
+
+    #[test]
+    fn detects_demo_json_by_its_fields() {
+        let token = DemoToken {
+            version: 1,
+            token_id: "tok-1".to_string(),
+            subject: "commander".to_string(),
+            audience: "sat-1".to_string(),
+            capability: "cmd:imaging:msi".to_string(),
+            issued_at: 1_710_000_000,
+            expires_at: 1_710_000_600,
+            signature: "mock".to_string(),
+        };
+        let bytes = serde_json::to_vec(&token).unwrap();
+        assert_eq!(detect(&bytes), Ok("demo-json"));
+        assert!(detect(br#"{"capability": "cmd:imaging:msi"}"#).is_err());
+    }
+
+    #[test]
+    fn reads_hex_text_as_bytes() {
+        let cbor = fixture("capability_token.cbor");
+        let hex: String = cbor.iter().map(|b| format!("{b:02x}")).collect();
+        let path = std::env::temp_dir().join(format!("scrap-tool-{}.hex", std::process::id()));
+        fs::write(&path, format!("{}\n{}\n", &hex[..40], &hex[40..])).unwrap();
+        let read = read_input(path.to_str().unwrap());
+        let _ = fs::remove_file(&path);
+        assert_eq!(read, cbor);
+    }
+}
@@ -0,0 +1,215 @@
+use serde_json::{json, Value};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Ok,
+    Fail,
+    Warn,
+    Skip,
+}
+
+impl Status {
+    fn label(self) -> &'static str {
+        match self {
+            Status::Ok => "ok",
+            Status::Fail => "FAIL",
+            Status::Warn => "warn",
+            Status::Skip => "skip",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Check {
+    pub status: Status,
+    pub name: String,
+    pub detail: String,
+}
+
+#[derive(Debug)]
+pub struct Report {
+    pub format: String,
+    pub size: usize,
+    pub fields: Vec<(String, String)>,
+    pub checks: Vec<Check>,
+    pub verdict: Option<Result<String, String>>,
+}
+
+impl Report {
+    pub fn new(format: &str, size: usize) -> Self {
+        Self {
+            format: format.to_string(),
+            size,
+            fields: Vec::new(),
+            checks: Vec::new(),
+            verdict: None,
+        }
+    }
+
+    pub fn field(&mut self, name: impl Into<String>, value: impl ToString) {
+        self.fields.push((name.into(), value.to_string()));
+    }
+
+    pub fn hex(&mut self, name: impl Into<String>, bytes: &[u8]) {
+        self.field(name, to_hex(bytes));
+    }
+
+    pub fn ts(&mut self, name: impl Into<String>, ts: u64) {
+        self.field(name, human_ts(ts));
+    }
+
+    pub fn check(&mut self, name: impl Into<String>, result: Result<String, String>) {
+        let (status, detail) = match result {
+            Ok(detail) => (Status::Ok, detail),
+            Err(detail) => (Status::Fail, detail),
+        };
+        self.checks.push(Check {
+            status,
+            name: name.into(),
+            detail,
+        });
+    }
+
+    /// A check the receiving node does not enforce; failures are reported as
+    /// warnings and do not change the verdict.
+    pub fn advisory(&mut self, name: impl Into<String>, result: Result<String, String>) {
+        let (status, detail) = match result {
+            Ok(detail) => (Status::Ok, detail),
+            Err(detail) => (Status::Warn, detail),
+        };
+        self.checks.push(Check {
+            status,
+            name: name.into(),
+            detail,
+        });
+    }
+
+    pub fn skip(&mut self, name: impl Into<String>, why: impl Into<String>) {
+        self.checks.push(Check {
+            status: Status::Skip,
+            name: name.into(),
+            detail: why.into(),
+        });
+    }
+
+    pub fn outcome(&self) -> Result<String, String> {
+        if let Some(verdict) = &self.verdict {
+            return verdict.clone();
+        }
+        if let Some(failed) = self.checks.iter().find(|c| c.status == Status::Fail) {
+            return Err(format!("{}: {}", failed.name, failed.detail));
+        }
+        if self.checks.iter().any(|c| c.status == Status::Skip) {
+            return Ok("accept (some checks skipped)".to_string());
+        }
+        Ok("accept".to_string())
+    }
+
+    pub fn print(&self, context: &str) {
+        println!("format: {} ({} bytes)", self.format, self.size);
+        let width = self
+            .fields
+            .iter()
+            .map(|(name, _)| name.len())
+            .max()
+            .unwrap_or(0);
+        for (name, value) in &self.fields {
+            println!("  {name:<width$}  {value}");
+        }
+        println!("checks ({context}):");
+        let width = self.checks.iter().map(|c| c.name.len()).max().unwrap_or(0);
+        for check in &self.checks {
+            println!(
+                "  {:<4}  {:<width$}  {}",
+                check.status.label(),
+                check.name,
+                check.detail
+            );
+        }
+        match self.outcome() {
+            Ok(detail) => println!("verdict: {detail}"),
+            Err(reason) => println!("verdict: reject: {reason}"),
+        }
+    }
+
+    pub fn to_json(&self, context: &str) -> Value {
+        let fields: serde_json::Map<String, Value> = self
+            .fields
+            .iter()
+            .map(|(name, value)| (name.clone(), json!(value)))
+            .collect();
+        let checks: Vec<Value> = self
+            .checks
+            .iter()
+            .map(|c| json!({"status": c.status.label(), "name": c.name, "detail": c.detail}))
+            .collect();
+        let (accepted, verdict) = match self.outcome() {
+            Ok(detail) => (true, detail),
+            Err(reason) => (false, reason),
+        };
+        json!({
+            "format": self.format,
+            "size": self.size,
+            "context": context,
+            "fields": fields,
+            "checks": checks,
+            "accepted": accepted,
+            "verdict": verdict
+        })
+    }
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        out.push_str(&format!("{:02x}", b));
+    }
+    out
+}
+
+pub fn human_ts(ts: u64) -> String {
+    format!("{ts} ({})", utc(ts))
+}
+
+pub fn utc(ts: u64) -> String {
+    // Days-to-civil conversion (proleptic Gregorian, UTC).
+    let days = (ts / 86_400) as i64;
+    let secs = ts % 86_400;
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        secs / 3_600,
+        (secs / 60) % 60,
+        secs % 60
+    )
+}
+
+pub fn relative(ts: u64, at: u64) -> String {
+    if ts == at {
+        return "now".to_string();
+    }
+    let (delta, future) = if ts >= at {
+        (ts - at, true)
+    } else {
+        (at - ts, false)
+    };
+    let amount = match delta {
+        0..=119 => format!("{delta}s"),
+        120..=7_199 => format!("{}m", delta / 60),
+        7_200..=172_799 => format!("{}h", delta / 3_600),
+        _ => format!("{}d", delta / 86_400),
+    };
+    if future {
+        format!("in {amount}")
+    } else {
+        format!("{amount} ago")
+    }
+}